@@ -0,0 +1,86 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+
+/// Local alarm output (siren, relay, ...) driven by detections, either by running a command
+/// or by driving a sysfs GPIO value file high for a while.
+pub struct Alarm {
+    command: Option<String>,
+    gpio: Option<PathBuf>,
+    classes: Vec<i32>,
+    duration: Duration,
+    debounce: Duration,
+    last_fired: Option<Instant>,
+}
+
+impl Alarm {
+    pub fn new(
+        command: Option<String>,
+        gpio: Option<PathBuf>,
+        classes: Vec<i32>,
+        duration: Duration,
+        debounce: Duration,
+    ) -> Option<Alarm> {
+        if command.is_none() && gpio.is_none() {
+            return None;
+        }
+        Some(Alarm {
+            command,
+            gpio,
+            classes,
+            duration,
+            debounce,
+            last_fired: None,
+        })
+    }
+
+    pub fn wants_class(&self, class_id: i32) -> bool {
+        self.classes.is_empty() || self.classes.contains(&class_id)
+    }
+
+    /// Sounds the alarm unless it already went off within the debounce interval.
+    pub fn fire(&mut self, monitor_id: u32, description: &str) {
+        if let Some(last_fired) = self.last_fired {
+            if last_fired.elapsed() < self.debounce {
+                debug!("{}: Alarm debounced", monitor_id);
+                return;
+            }
+        }
+        self.last_fired = Some(Instant::now());
+        info!("{}: Sounding alarm: {}", monitor_id, description);
+
+        if let Some(command) = &self.command {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("AIDECT_MONITOR_ID", monitor_id.to_string())
+                .env("AIDECT_DESCRIPTION", description)
+                .spawn();
+            match child {
+                // reap it in the background, we don't care how long the command takes
+                Ok(mut child) => {
+                    std::thread::spawn(move || child.wait());
+                }
+                Err(e) => error!("{}: Failed to run alarm command: {}", monitor_id, e),
+            }
+        }
+
+        if let Some(gpio) = &self.gpio {
+            if let Err(e) = fs::write(gpio, "1") {
+                error!("{}: Failed to set alarm GPIO {}: {}", monitor_id, gpio.display(), e);
+                return;
+            }
+            let gpio = gpio.clone();
+            let duration = self.duration;
+            std::thread::spawn(move || {
+                std::thread::sleep(duration);
+                if let Err(e) = fs::write(&gpio, "0") {
+                    error!("Failed to reset alarm GPIO {}: {}", gpio.display(), e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use opencv::core::{Mat, MatTraitConst, Point, Rect, Scalar, Size, Vector};
+use opencv::imgproc::{FONT_HERSHEY_SIMPLEX, LINE_8};
+use opencv::types::{VectorOfPoint, VectorOfVectorOfPoint};
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::zoneminder::zone::{self, ZoneConfig, ZoneShape};
+
+// Colors are RGB, like the images we work with.
+fn zone_color() -> Scalar {
+    Scalar::new(0.0, 255.0, 0.0, 0.0)
+}
+
+fn roi_color() -> Scalar {
+    Scalar::new(0.0, 128.0, 255.0, 0.0)
+}
+
+fn ignore_color() -> Scalar {
+    Scalar::new(255.0, 0.0, 0.0, 0.0)
+}
+
+fn detection_color() -> Scalar {
+    Scalar::new(255.0, 255.0, 0.0, 0.0)
+}
+
+fn caption_color() -> Scalar {
+    Scalar::new(255.0, 255.0, 255.0, 0.0)
+}
+
+fn caption_background() -> Scalar {
+    Scalar::new(0.0, 0.0, 0.0, 0.0)
+}
+
+fn draw_polygon(image: &mut Mat, shape: &ZoneShape, color: Scalar) -> Result<()> {
+    let points: VectorOfPoint = shape.iter().map(|&(x, y)| Point::new(x, y)).collect();
+    let mut polygons = VectorOfVectorOfPoint::new();
+    polygons.push(points);
+    opencv::imgproc::polylines(image, &polygons, true, color, 2, LINE_8, 0)?;
+    Ok(())
+}
+
+fn draw_label(image: &mut Mat, text: &str, at: Point, color: Scalar) -> Result<()> {
+    opencv::imgproc::put_text(
+        image,
+        text,
+        at,
+        FONT_HERSHEY_SIMPLEX,
+        0.5,
+        color,
+        1,
+        LINE_8,
+        false,
+    )?;
+    Ok(())
+}
+
+/// Draws the zone polygon (green) and the ignore zones (red).
+pub fn draw_zones(image: &mut Mat, zone_config: &ZoneConfig) -> Result<()> {
+    draw_polygon(image, &zone_config.shape, zone_color())?;
+    for zone in &zone_config.ignore {
+        draw_polygon(image, &zone.shape, ignore_color())?;
+    }
+    Ok(())
+}
+
+/// Draws text (e.g. a timestamp) into the top left corner, on a black background to be readable
+/// on any image.
+pub fn draw_caption(image: &mut Mat, text: &str) -> Result<()> {
+    let mut baseline = 0;
+    let size = opencv::imgproc::get_text_size(text, FONT_HERSHEY_SIMPLEX, 0.5, 1, &mut baseline)?;
+    let background = Rect::new(0, 0, size.width + 8, size.height + baseline + 8);
+    opencv::imgproc::rectangle(image, background, caption_background(), -1, LINE_8, 0)?;
+    draw_label(image, text, Point::new(4, size.height + 4), caption_color())
+}
+
+/// Draws the zone polygon, the region of interest handed to the model and the detections onto
+/// a copy of the (RGB) image.
+pub fn annotate(
+    image: &Mat,
+    zone_config: &ZoneConfig,
+    roi: Rect,
+    detections: &[Detection],
+    describe: impl Fn(&Detection) -> String,
+) -> Result<Mat> {
+    let mut annotated = image.try_clone()?;
+
+    opencv::imgproc::rectangle(&mut annotated, roi, roi_color(), 1, LINE_8, 0)?;
+    draw_zones(&mut annotated, zone_config)?;
+
+    for d in detections {
+        opencv::imgproc::rectangle(
+            &mut annotated,
+            d.bounding_box,
+            detection_color(),
+            2,
+            LINE_8,
+            0,
+        )?;
+        let label_at = Point::new(d.bounding_box.x, (d.bounding_box.y - 5).max(12));
+        draw_label(&mut annotated, &describe(d), label_at, detection_color())?;
+    }
+
+    Ok(annotated)
+}
+
+/// Blurs the heads of the people among detections (given in a frame of detections_size) beyond
+/// recognition, taking them to be the top fraction of the bounding box.
+pub fn blur_heads(image: &mut Mat, detections: &[Detection], detections_size: (u32, u32), fraction: f32) -> Result<()> {
+    let size = image.size()?;
+    let human = detect::class_id("Human");
+    for d in detections.iter().filter(|d| Some(d.class_id) == human) {
+        let bounding_box = zone::scale_rect(d.bounding_box, detections_size, (size.width as u32, size.height as u32));
+        let head = match head_region(bounding_box, fraction, size) {
+            Some(head) => head,
+            None => continue,
+        };
+        // a kernel half the size of the head leaves nothing of the face
+        let kernel = (head.width.max(head.height) / 2) | 1;
+        let mut blurred = Mat::default();
+        opencv::imgproc::gaussian_blur(
+            &Mat::roi(image, head)?,
+            &mut blurred,
+            Size::new(kernel, kernel),
+            0.0,
+            0.0,
+            opencv::core::BORDER_DEFAULT,
+        )?;
+        // the RoI shares image's data
+        blurred.copy_to(&mut Mat::roi(image, head)?)?;
+    }
+    Ok(())
+}
+
+/// The top fraction of bounding_box, within an image of size. None if that's empty.
+fn head_region(bounding_box: Rect, fraction: f32, size: Size) -> Option<Rect> {
+    let height = (bounding_box.height as f32 * fraction).ceil() as i32;
+    let (x, y) = (bounding_box.x.max(0), bounding_box.y.max(0));
+    let right = (bounding_box.x + bounding_box.width).min(size.width);
+    let bottom = (bounding_box.y + height).min(size.height);
+    (right > x && bottom > y).then(|| Rect::new(x, y, right - x, bottom - y))
+}
+
+/// Encodes an RGB image as JPEG.
+pub fn encode_jpeg(image: &Mat) -> Result<Vec<u8>> {
+    encode(image, ".jpg")
+}
+
+/// Encodes an RGB image as PNG.
+pub fn encode_png(image: &Mat) -> Result<Vec<u8>> {
+    encode(image, ".png")
+}
+
+fn encode(image: &Mat, extension: &str) -> Result<Vec<u8>> {
+    let mut bgr = Mat::default();
+    opencv::imgproc::cvt_color(image, &mut bgr, opencv::imgproc::COLOR_RGB2BGR, 0)?;
+    let mut buffer = Vector::new();
+    if !opencv::imgcodecs::imencode(extension, &bgr, &mut buffer, &Vector::new())? {
+        return Err(anyhow!("Failed to encode image"));
+    }
+    Ok(buffer.to_vec())
+}
+
+/// Reads an image file as RGB.
+pub fn load(path: &Path) -> Result<Mat> {
+    let filename = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid path {}", path.display()))?;
+    let bgr = opencv::imgcodecs::imread(filename, opencv::imgcodecs::IMREAD_COLOR)?;
+    if bgr.empty() {
+        return Err(anyhow!("Failed to read image {}", path.display()));
+    }
+    let mut image = Mat::default();
+    opencv::imgproc::cvt_color(&bgr, &mut image, opencv::imgproc::COLOR_BGR2RGB, 0)?;
+    Ok(image)
+}
+
+/// Writes an RGB image to a file, the format is determined by the extension.
+pub fn save(path: &Path, image: &Mat) -> Result<()> {
+    let mut bgr = Mat::default();
+    opencv::imgproc::cvt_color(image, &mut bgr, opencv::imgproc::COLOR_RGB2BGR, 0)?;
+    let filename = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid path {}", path.display()))?;
+    if !opencv::imgcodecs::imwrite(filename, &bgr, &Vector::new())? {
+        return Err(anyhow!("Failed to write image {}", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_region() {
+        let size = Size::new(640, 480);
+        assert_eq!(head_region(Rect::new(100, 50, 60, 200), 0.25, size), Some(Rect::new(100, 50, 60, 50)));
+        assert_eq!(head_region(Rect::new(600, -10, 60, 200), 0.25, size), Some(Rect::new(600, 0, 40, 40)));
+        assert_eq!(head_region(Rect::new(100, 50, 60, 200), 0.0, size), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Accuracy audit: `run --audit-dir` keeps a sample of the frames in which nothing was detected
+//! (cropped to what the model saw), and `zm-aidect audit` runs them through a bigger model to
+//! estimate how many objects were missed.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use opencv::core::{Mat, Rect};
+use prometheus::core::Collector;
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+
+use zoneminder_aidect::{detect, ml};
+
+use crate::{annotate, instrumentation};
+
+/// Whether the current frame is sampled, for sampling a fraction rate of all frames. Spreads the
+/// samples evenly instead of drawing random numbers.
+fn due(credit: &mut f32, rate: f32) -> bool {
+    *credit += rate;
+    if *credit >= 1.0 {
+        *credit -= 1.0;
+        return true;
+    }
+    false
+}
+
+/// Saves every 1/rate-th frame it's offered, up to max_samples files in dir (including those
+/// from earlier runs).
+pub struct Sampler {
+    dir: PathBuf,
+    monitor_id: u32,
+    rate: f32,
+    credit: f32,
+    remaining: usize,
+}
+
+impl Sampler {
+    pub fn new(dir: PathBuf, monitor_id: u32, rate: f32, max_samples: usize) -> Result<Sampler> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create audit directory {}", dir.display()))?;
+        let existing = samples(&dir)?.len();
+        info!(
+            "{}: Sampling {:.1}% of frames without detections into {} ({} there already)",
+            monitor_id,
+            rate * 100.0,
+            dir.display(),
+            existing
+        );
+        Ok(Sampler {
+            dir,
+            monitor_id,
+            rate,
+            credit: 0.0,
+            remaining: max_samples.saturating_sub(existing),
+        })
+    }
+
+    /// image is in order, roi the part the model looked at.
+    pub fn offer(&mut self, image: &Mat, order: ml::PixelOrder, roi: Rect) -> Result<()> {
+        if self.remaining == 0 || !due(&mut self.credit, self.rate) {
+            return Ok(());
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = self.dir.join(format!("{}-{}.jpg", self.monitor_id, time));
+        annotate::save(&path, &order.into_rgb(Mat::roi(image, roi)?)?)?;
+        debug!("{}: Audit sample {}", self.monitor_id, path.display());
+        self.remaining -= 1;
+        instrumentation::AUDIT_SAMPLES
+            .with_label_values(&[&self.monitor_id.to_string()])
+            .inc();
+        Ok(())
+    }
+}
+
+fn samples(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |extension| extension == "jpg") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditReport {
+    pub samples: usize,
+    /// Samples in which the audit model found something
+    pub misses: usize,
+    pub miss_rate: f64,
+    pub misses_per_class: BTreeMap<String, usize>,
+    /// The files of the misses, to have a look at them
+    pub missed: Vec<String>,
+}
+
+/// Runs the (bigger) model over the samples in dir.
+pub fn audit(dir: &Path, model_dir: &Path, options: &ml::Options) -> Result<AuditReport> {
+    let mut model = ml::load(model_dir, options)?;
+    let mut report = AuditReport {
+        samples: 0,
+        misses: 0,
+        miss_rate: 0.0,
+        misses_per_class: BTreeMap::new(),
+        missed: Vec::new(),
+    };
+    for path in samples(dir)? {
+        let image = annotate::load(&path)?;
+        let detections: Vec<_> = model
+            .infer(&image)?
+            .into_iter()
+            .filter(|d| detect::known_class(d.class_id))
+            .collect();
+        report.samples += 1;
+        if detections.is_empty() {
+            continue;
+        }
+        report.misses += 1;
+        report.missed.push(path.display().to_string());
+        for d in &detections {
+            *report
+                .misses_per_class
+                .entry(detect::class_name(d.class_id).to_string())
+                .or_default() += 1;
+        }
+    }
+    if report.samples > 0 {
+        report.miss_rate = report.misses as f64 / report.samples as f64;
+    }
+    Ok(report)
+}
+
+/// Writes the report as metrics in the Prometheus text format, e.g. for node_exporter's
+/// textfile collector.
+pub fn write_metrics(path: &Path, report: &AuditReport) -> Result<()> {
+    instrumentation::AUDIT_CHECKED.set(report.samples as f64);
+    instrumentation::AUDIT_MISSES.set(report.misses as f64);
+    instrumentation::AUDIT_MISS_RATE.set(report.miss_rate);
+    let families = [
+        instrumentation::AUDIT_CHECKED.collect(),
+        instrumentation::AUDIT_MISSES.collect(),
+        instrumentation::AUDIT_MISS_RATE.collect(),
+    ]
+    .concat();
+    let mut file = File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    TextEncoder::new().encode(&families, &mut file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let mut credit = 0.0;
+        let sampled = (0..100).filter(|_| due(&mut credit, 0.25)).count();
+        assert_eq!(sampled, 25);
+
+        let mut credit = 0.0;
+        assert!((0..10).all(|_| due(&mut credit, 1.0)));
+        assert!(!(0..10).any(|_| due(&mut credit, 0.0)));
+    }
+}
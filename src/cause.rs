@@ -0,0 +1,206 @@
+//! What the events zm-aidect triggers say (`run --cause` and `--description`), so that filters
+//! matching on Cause can tell them apart from motion events, or one monitor's from another's.
+
+use anyhow::{anyhow, Result};
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::zoneminder::zone::{Polygon, ZoneShape};
+
+const PLACEHOLDERS: [&str; 6] = ["monitor", "monitor_id", "zone", "class", "score", "description"];
+
+/// ZoneMinder keeps this many bytes of a cause (less if that would cut a character in two)
+const CAUSE_LEN: usize = 31;
+
+pub struct Templates {
+    cause: String,
+    description: String,
+    monitor_id: u32,
+    monitor_name: String,
+    /// See zone::named_zones
+    zones: Vec<(String, ZoneShape)>,
+}
+
+impl Templates {
+    pub fn new(
+        cause: &str,
+        description: &str,
+        monitor_id: u32,
+        monitor_name: &str,
+        zones: Vec<(String, ZoneShape)>,
+    ) -> Result<Templates> {
+        check(cause).map_err(|e| e.context("Invalid --cause"))?;
+        check(description).map_err(|e| e.context("Invalid --description"))?;
+        Ok(Templates {
+            cause: cause.to_string(),
+            description: description.to_string(),
+            monitor_id,
+            monitor_name: monitor_name.to_string(),
+            zones,
+        })
+    }
+
+    /// description is the long description of d, e.g. from detect::describe
+    pub fn cause(&self, d: &Detection, description: &str) -> String {
+        self.expand(&self.cause, d, description)
+    }
+
+    pub fn description(&self, d: &Detection, description: &str) -> String {
+        self.expand(&self.description, d, description)
+    }
+
+    /// Whether causes name the monitor ({monitor} or {monitor_id}), so that those of other
+    /// instances triggering the same monitor can be told apart.
+    pub fn identifies_monitor(&self) -> bool {
+        self.cause.contains("{monitor}") || self.cause.contains("{monitor_id}")
+    }
+
+    /// Whether cause (as read back from ZoneMinder, so possibly cut off) could have been made
+    /// by this instance, for this monitor or (with "-linked" appended) a linked one.
+    pub fn is_own_cause(&self, cause: &str) -> bool {
+        let pattern = self
+            .cause
+            .replace("{monitor}", &self.monitor_name)
+            .replace("{monitor_id}", &self.monitor_id.to_string());
+        let truncated = cause.len() + 4 > CAUSE_LEN;
+        matches(&pattern, cause, truncated)
+            || matches(&format!("{}-linked", pattern), cause, truncated)
+    }
+
+    /// The name of the first of the zones d's center lies in, empty if none
+    fn zone(&self, d: &Detection) -> &str {
+        let (x, y) = (
+            d.bounding_box.x + d.bounding_box.width / 2,
+            d.bounding_box.y + d.bounding_box.height / 2,
+        );
+        self.zones
+            .iter()
+            .find(|(_, shape)| shape.contains(x, y))
+            .map_or("", |(name, _)| name)
+    }
+
+    fn expand(&self, template: &str, d: &Detection, description: &str) -> String {
+        template
+            .replace("{monitor}", &self.monitor_name)
+            .replace("{monitor_id}", &self.monitor_id.to_string())
+            .replace("{zone}", self.zone(d))
+            .replace("{class}", detect::class_name(d.class_id))
+            .replace("{score}", &((d.confidence * 100.0) as u32).to_string())
+            .replace("{description}", description)
+    }
+}
+
+/// Whether text is an expansion of template, the placeholders left in it matching anything.
+/// A truncated text only has to be the start of one.
+fn matches(template: &str, text: &str, truncated: bool) -> bool {
+    match template.find('{') {
+        None => text == template || (truncated && template.starts_with(text)),
+        Some(start) => {
+            let literal = &template[..start];
+            if !text.starts_with(literal) {
+                return truncated && literal.starts_with(text);
+            }
+            let rest = match template[start..].find('}') {
+                Some(end) => &template[start + end + 1..],
+                None => return false,
+            };
+            let text = &text[literal.len()..];
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| matches(rest, &text[i..], truncated))
+        }
+    }
+}
+
+/// Fails on unknown placeholders, which would otherwise end up in the events verbatim.
+fn check(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed {{ in \"{}\"", template))?;
+        let name = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(anyhow!(
+                "Unknown placeholder {{{}}}, known are {}",
+                name,
+                PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Rect;
+
+    #[test]
+    fn test_check() {
+        assert!(check("aidect").is_ok());
+        assert!(check("aidect {class} {monitor_id}").is_ok());
+        assert!(check("aidect {zone}").is_ok());
+        assert!(check("aidect {zones}").is_err());
+        assert!(check("aidect {class").is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let templates = Templates::new("aidect {class}", "{monitor} ({monitor_id}): {description}", 3, "Driveway", vec![]).unwrap();
+        let d = Detection {
+            confidence: 0.91,
+            class_id: 1,
+            bounding_box: Rect::new(0, 0, 10, 20),
+            runner_up: None,
+            label: None,
+        };
+        assert_eq!(templates.cause(&d, "long"), format!("aidect {}", detect::class_name(1)));
+        assert_eq!(templates.description(&d, "long"), "Driveway (3): long");
+        assert!(!templates.identifies_monitor());
+    }
+
+    #[test]
+    fn test_expand_zone() {
+        let zones = vec![
+            ("Street".to_string(), vec![(0, 0), (100, 0), (100, 50), (0, 50)]),
+            ("Driveway".to_string(), vec![(0, 50), (100, 50), (100, 100), (0, 100)]),
+        ];
+        let templates = Templates::new("aidect {zone}", "{description}", 3, "Front", zones).unwrap();
+        let d = |y| Detection {
+            confidence: 0.91,
+            class_id: 1,
+            bounding_box: Rect::new(40, y, 20, 20),
+            runner_up: None,
+            label: None,
+        };
+        assert_eq!(templates.cause(&d(10), ""), "aidect Street");
+        assert_eq!(templates.cause(&d(70), ""), "aidect Driveway");
+        assert_eq!(templates.cause(&d(200), ""), "aidect ");
+    }
+
+    #[test]
+    fn test_is_own_cause() {
+        let templates = Templates::new("aidect {monitor_id} {class}", "{description}", 3, "Driveway", vec![]).unwrap();
+        assert!(templates.identifies_monitor());
+        assert!(templates.is_own_cause("aidect 3 Human"));
+        assert!(templates.is_own_cause("aidect 3 Human-linked"));
+        assert!(!templates.is_own_cause("aidect 13 Human"));
+        assert!(!templates.is_own_cause("aidect 4 Human"));
+        assert!(!templates.is_own_cause("Motion"));
+
+        let templates = Templates::new("{monitor}: {class} {score}%", "{description}", 3, "Front door", vec![]).unwrap();
+        assert!(templates.is_own_cause("Front door: Human 91%"));
+        assert!(!templates.is_own_cause("Back door: Human 91%"));
+        // cut off by ZoneMinder
+        let templates = Templates::new("aidect {description} on {monitor}", "", 3, "Driveway", vec![]).unwrap();
+        assert!(templates.is_own_cause("aidect Human (91.2%) 120x310 at"));
+        assert!(!templates.is_own_cause("aidect Human"));
+
+        let templates = Templates::new("aidect", "{description}", 3, "Driveway", vec![]).unwrap();
+        assert!(templates.is_own_cause("aidect"));
+        assert!(!templates.is_own_cause("aidect2"));
+    }
+}
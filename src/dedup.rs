@@ -0,0 +1,404 @@
+//! Deduplication across monitors covering the same area: `zm-aidect dedup` keeps track of which
+//! monitor (of a DedupGroup) triggered for which class recently, and `run --dedup-socket` asks it
+//! before triggering whether another monitor got there first.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// The coordinator has to answer quickly, otherwise monitors trigger on their own
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Is somebody else on it? Sent before triggering.
+    Claim {
+        group: String,
+        class: String,
+        monitor_id: u32,
+    },
+    /// The event the trigger ended up in, so that duplicates can refer to it
+    Event {
+        group: String,
+        class: String,
+        monitor_id: u32,
+        event_id: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Owner {
+    pub monitor_id: u32,
+    /// None while its event is still being created
+    pub event_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Response {
+    duplicate_of: Option<Owner>,
+}
+
+struct Claim {
+    owner: Owner,
+    last_seen: Instant,
+}
+
+/// The first monitor to see a class owns it, for as long as it keeps seeing it (and window after).
+struct Claims {
+    window: Duration,
+    claims: HashMap<(String, String), Claim>,
+}
+
+impl Claims {
+    fn new(window: Duration) -> Claims {
+        Claims {
+            window,
+            claims: HashMap::new(),
+        }
+    }
+
+    /// Returns the monitor which already owns it, if it isn't monitor_id.
+    fn claim(&mut self, group: &str, class: &str, monitor_id: u32, now: Instant) -> Option<Owner> {
+        let key = (group.to_string(), class.to_string());
+        match self.claims.get_mut(&key) {
+            Some(claim) if now.saturating_duration_since(claim.last_seen) <= self.window => {
+                if claim.owner.monitor_id == monitor_id {
+                    claim.last_seen = now;
+                    return None;
+                }
+                Some(claim.owner)
+            }
+            _ => {
+                let owner = Owner {
+                    monitor_id,
+                    event_id: None,
+                };
+                self.claims.insert(key, Claim { owner, last_seen: now });
+                None
+            }
+        }
+    }
+
+    fn event(&mut self, group: &str, class: &str, monitor_id: u32, event_id: u64) {
+        let key = (group.to_string(), class.to_string());
+        if let Some(claim) = self.claims.get_mut(&key) {
+            if claim.owner.monitor_id == monitor_id {
+                claim.owner.event_id = Some(event_id);
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        let now = Instant::now();
+        // forget about objects which are long gone
+        let window = self.window;
+        self.claims
+            .retain(|_, claim| now.saturating_duration_since(claim.last_seen) <= window);
+        match request {
+            Request::Claim {
+                group,
+                class,
+                monitor_id,
+            } => Response {
+                duplicate_of: self.claim(&group, &class, monitor_id, now),
+            },
+            Request::Event {
+                group,
+                class,
+                monitor_id,
+                event_id,
+            } => {
+                self.event(&group, &class, monitor_id, event_id);
+                Response { duplicate_of: None }
+            }
+        }
+    }
+}
+
+/// The coordinator, runs until killed. Requests and responses are JSON lines.
+pub fn serve(socket: &Path, window: Duration) -> Result<()> {
+    // left over from a previous run
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    info!("Deduplicating detections within {:?} on {}", window, socket.display());
+
+    let claims = Arc::new(Mutex::new(Claims::new(window)));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let claims = claims.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, &claims) {
+                debug!("Connection failed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream, claims: &Mutex<Claims>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let request: Request = serde_json::from_str(&line?)?;
+        debug!("{:?}", request);
+        let response = claims.lock().unwrap().handle(request);
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// What to do when another monitor already triggered for the same object
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    /// Don't trigger
+    Suppress,
+    /// Trigger, but mention the other monitor (and event) in the alarm cause
+    Link,
+}
+
+impl TryFrom<&str> for Mode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "suppress" => Ok(Mode::Suppress),
+            "link" => Ok(Mode::Link),
+            _ => Err(anyhow!("Invalid dedup mode {}", value)),
+        }
+    }
+}
+
+/// Client side, used by run's I/O thread. Connects for every request, so that the coordinator
+/// can be restarted at any time.
+pub struct Dedup {
+    socket: PathBuf,
+    group: String,
+    monitor_id: u32,
+    pub mode: Mode,
+}
+
+impl Dedup {
+    pub fn new(socket: PathBuf, group: String, monitor_id: u32, mode: Mode) -> Dedup {
+        Dedup {
+            socket,
+            group,
+            monitor_id,
+            mode,
+        }
+    }
+
+    fn request(&self, request: &Request) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        serde_json::to_writer(&mut stream, request)?;
+        stream.write_all(b"\n")?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Which monitor already triggered for class, if any.
+    pub fn claim(&self, class: &str) -> Result<Option<Owner>> {
+        let response = self.request(&Request::Claim {
+            group: self.group.clone(),
+            class: class.to_string(),
+            monitor_id: self.monitor_id,
+        })?;
+        Ok(response.duplicate_of)
+    }
+
+    pub fn event(&self, class: &str, event_id: u64) -> Result<()> {
+        self.request(&Request::Event {
+            group: self.group.clone(),
+            class: class.to_string(),
+            monitor_id: self.monitor_id,
+            event_id,
+        })?;
+        Ok(())
+    }
+}
+
+/// Claiming, as run sees it: Dedup asks the coordinator
+pub trait Claimant {
+    fn mode(&self) -> Mode;
+    /// Which monitor already triggered for class, if any.
+    fn claim(&self, class: &str) -> Result<Option<Owner>>;
+}
+
+impl Claimant for Dedup {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn claim(&self, class: &str) -> Result<Option<Owner>> {
+        Dedup::claim(self, class)
+    }
+}
+
+/// Whether to start a trigger for class, and if so what to add to its description. The rate limit
+/// (allow_trigger) is asked before the coordinator: a trigger it suppresses must not claim the
+/// class, the other monitors would leave the object to a trigger that never happens.
+pub fn admit_trigger(
+    monitor_id: u32,
+    class: &str,
+    allow_trigger: impl FnOnce() -> Result<bool>,
+    dedup: Option<&impl Claimant>,
+) -> Result<Option<String>> {
+    if !allow_trigger()? {
+        return Ok(None);
+    }
+    let dedup = match dedup {
+        Some(dedup) => dedup,
+        None => return Ok(Some(String::new())),
+    };
+    // without the coordinator, every monitor triggers on its own
+    match dedup.claim(class) {
+        Ok(Some(owner)) if dedup.mode() == Mode::Suppress => {
+            debug!(
+                "{}: Not triggering, monitor {} already did for {}",
+                monitor_id, owner.monitor_id, class
+            );
+            Ok(None)
+        }
+        Ok(Some(owner)) => Ok(Some(link_text(&owner))),
+        Ok(None) => Ok(Some(String::new())),
+        Err(e) => {
+            warn!("{}: Deduplication failed: {:#}", monitor_id, e);
+            Ok(Some(String::new()))
+        }
+    }
+}
+
+/// e.g. " (also monitor 4, event 1234)"
+pub fn link_text(owner: &Owner) -> String {
+    match owner.event_id {
+        Some(event_id) => format!(" (also monitor {}, event {})", owner.monitor_id, event_id),
+        None => format!(" (also monitor {})", owner.monitor_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        let mut claims = Claims::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(claims.claim("driveway", "Human", 1, at(0)), None);
+        // the monitor which saw it first keeps it
+        assert_eq!(claims.claim("driveway", "Human", 1, at(5)), None);
+        assert_eq!(
+            claims.claim("driveway", "Human", 2, at(6)),
+            Some(Owner {
+                monitor_id: 1,
+                event_id: None
+            })
+        );
+        claims.event("driveway", "Human", 1, 42);
+        assert_eq!(
+            claims.claim("driveway", "Human", 2, at(14)),
+            Some(Owner {
+                monitor_id: 1,
+                event_id: Some(42)
+            })
+        );
+        // other classes and groups are separate
+        assert_eq!(claims.claim("driveway", "Car", 2, at(14)), None);
+        assert_eq!(claims.claim("garden", "Human", 2, at(14)), None);
+        // monitor 1 lost sight of it long enough ago
+        assert_eq!(claims.claim("driveway", "Human", 2, at(16)), None);
+        assert!(claims.claim("driveway", "Human", 1, at(17)).is_some());
+    }
+
+    /// Claims in claims, like the coordinator would
+    struct LocalClaimant<'a> {
+        mode: Mode,
+        monitor_id: u32,
+        claims: &'a RefCell<Claims>,
+        requests: Cell<u32>,
+    }
+
+    impl<'a> LocalClaimant<'a> {
+        fn new(mode: Mode, monitor_id: u32, claims: &'a RefCell<Claims>) -> LocalClaimant<'a> {
+            LocalClaimant {
+                mode,
+                monitor_id,
+                claims,
+                requests: Cell::new(0),
+            }
+        }
+    }
+
+    impl Claimant for LocalClaimant<'_> {
+        fn mode(&self) -> Mode {
+            self.mode
+        }
+
+        fn claim(&self, class: &str) -> Result<Option<Owner>> {
+            self.requests.set(self.requests.get() + 1);
+            Ok(self
+                .claims
+                .borrow_mut()
+                .claim("driveway", class, self.monitor_id, Instant::now()))
+        }
+    }
+
+    #[test]
+    fn test_admit_trigger() {
+        let claims = RefCell::new(Claims::new(Duration::from_secs(10)));
+        let dedup = LocalClaimant::new(Mode::Suppress, 1, &claims);
+        // suppressed by the rate limit, so it doesn't claim
+        assert_eq!(admit_trigger(1, "Human", || Ok(false), Some(&dedup)).unwrap(), None);
+        assert_eq!(dedup.requests.get(), 0);
+        // the next one is allowed, and claims
+        assert_eq!(
+            admit_trigger(1, "Human", || Ok(true), Some(&dedup)).unwrap(),
+            Some(String::new())
+        );
+        assert_eq!(dedup.requests.get(), 1);
+
+        // which makes the other monitor's a duplicate
+        let other = LocalClaimant::new(Mode::Suppress, 2, &claims);
+        assert_eq!(admit_trigger(2, "Human", || Ok(true), Some(&other)).unwrap(), None);
+        let other = LocalClaimant::new(Mode::Link, 2, &claims);
+        assert_eq!(
+            admit_trigger(2, "Human", || Ok(true), Some(&other)).unwrap(),
+            Some(" (also monitor 1)".to_string())
+        );
+        assert_eq!(
+            admit_trigger(2, "Human", || Ok(true), None::<&LocalClaimant>).unwrap(),
+            Some(String::new())
+        );
+    }
+
+    #[test]
+    fn test_link_text() {
+        let owner = Owner {
+            monitor_id: 4,
+            event_id: Some(1234),
+        };
+        assert_eq!(link_text(&owner), " (also monitor 4, event 1234)");
+    }
+}
@@ -0,0 +1,1409 @@
+//! Object detection within an aidect zone, on live images or on event recordings.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::slice;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use opencv::core::{Mat, MatTraitConst, Point2f, Rect, Scalar, Size};
+use opencv::prelude::*;
+use opencv::types::{VectorOfMat, VectorOfPoint2f};
+
+use crate::error::Error;
+use crate::ml::{self, Detection, PixelOrder};
+use crate::report::{DetectionReport, EventReport, FrameReport, TimingStats, Totals};
+use crate::vio;
+use crate::zoneminder::db::{self, Event};
+use crate::zoneminder::zone::{self, Bounding, FrameTransform, Precision, Preprocess, Rotation, ZoneConfig};
+
+lazy_static! {
+    /// The classes zm-aidect reports, all other detections are dropped.
+    pub static ref CLASSES: HashMap<i32, &'static str> = [  // TODO this should be loaded at runtime from the model definition
+        (1, "Human"),
+        (3, "Car"),
+        (15, "Bird"),
+        (16, "Cat"),
+        (17, "Dog"),
+    ].into();
+
+    /// Classes of the names files of Model= models which aren't in CLASSES, from CUSTOM_CLASS_IDS on.
+    static ref CUSTOM_CLASSES: RwLock<Vec<&'static str>> = RwLock::new(Vec::new());
+}
+
+/// The ID of the first custom class, well above the COCO classes
+const CUSTOM_CLASS_IDS: i32 = 1000;
+
+pub fn class_name(class_id: i32) -> &'static str {
+    if let Some(name) = CLASSES.get(&class_id) {
+        return *name;
+    }
+    let custom = CUSTOM_CLASSES.read().unwrap();
+    usize::try_from(class_id - CUSTOM_CLASS_IDS)
+        .ok()
+        .and_then(|i| custom.get(i).copied())
+        .unwrap_or("?")
+}
+
+/// Whether zm-aidect reports this class, all other detections are dropped.
+pub fn known_class(class_id: i32) -> bool {
+    class_name(class_id) != "?"
+}
+
+/// The class with this name (case doesn't matter) or ID.
+pub fn class_id(name: &str) -> Option<i32> {
+    if let Ok(class_id) = name.parse::<i32>() {
+        return known_class(class_id).then_some(class_id);
+    }
+    let custom = CUSTOM_CLASSES.read().unwrap();
+    CLASSES
+        .iter()
+        .map(|(class_id, class)| (*class_id, *class))
+        .chain((CUSTOM_CLASS_IDS..).zip(custom.iter().copied()))
+        .find(|(_, class)| class.eq_ignore_ascii_case(name))
+        .map(|(class_id, _)| class_id)
+}
+
+/// The ID of a class from a model's names file: the built-in class of that name, or a custom
+/// class, registered on first use.
+fn register_class(name: &str) -> i32 {
+    if let Some((class_id, _)) = CLASSES.iter().find(|(_, class)| class.eq_ignore_ascii_case(name)) {
+        return *class_id;
+    }
+    // looked up under the write lock, the monitors of a group load their models in parallel
+    let mut custom = CUSTOM_CLASSES.write().unwrap();
+    match custom.iter().position(|class| class.eq_ignore_ascii_case(name)) {
+        Some(i) => CUSTOM_CLASS_IDS + i as i32,
+        None => {
+            custom.push(Box::leak(name.to_string().into_boxed_str()));
+            CUSTOM_CLASS_IDS + custom.len() as i32 - 1
+        }
+    }
+}
+
+/// e.g. "Human (91.2%) 40x120 (=4800) at 300x200", plus the estimated height if the zone is calibrated.
+/// A classifier's label follows the class, e.g. "Car [delivery van] (88.0%) ...".
+pub fn describe(d: &Detection, zone_config: &ZoneConfig) -> String {
+    let class = match &d.label {
+        Some(label) => format!("{} [{}]", class_name(d.class_id), label),
+        None => class_name(d.class_id).to_string(),
+    };
+    let description = format!(
+        "{} ({:.1}%) {}x{} (={}) at {}x{}",
+        class,
+        d.confidence * 100.0,
+        d.bounding_box.width,
+        d.bounding_box.height,
+        d.bounding_box.width * d.bounding_box.height,
+        d.bounding_box.x,
+        d.bounding_box.y,
+    );
+    match zone_config.calibration {
+        Some(calibration) => format!(
+            "{} ~{:.1}m tall",
+            description,
+            calibration.height_m(&d.bounding_box)
+        ),
+        None => description,
+    }
+}
+
+pub fn detection_report(d: &Detection, zone_config: &ZoneConfig) -> DetectionReport {
+    DetectionReport::new(d, class_name(d.class_id), describe(d, zone_config))
+}
+
+/// Detections below Threshold down to this confidence are reported as candidates
+pub const CANDIDATE_THRESHOLD: f32 = 0.05;
+
+/// Inferences on a synthetic image before the first frame, see Detector::warm_up
+const WARM_UP_RUNS: usize = 3;
+
+/// Size=auto: the input sizes tried, smallest first
+const AUTO_SIZES: [u32; 5] = [256, 320, 416, 512, 608];
+
+/// Tiles=CxR: how much of its size each tile shares with its neighbours
+const TILE_OVERLAP: f32 = 0.2;
+/// Detections from different tiles overlapping more than this (IoU) are the same object
+const TILE_NMS_THRESHOLD: f32 = 0.4;
+
+pub struct Inferred {
+    pub duration: Duration,
+    pub detections: Vec<Detection>,
+    /// What passed the same filters, but was below Threshold (down to CANDIDATE_THRESHOLD), the
+    /// most confident first. Shows what Threshold would have been needed. Empty unless the
+    /// detector was loaded for candidates.
+    pub candidates: Vec<Detection>,
+    /// For each detection at or above Threshold which didn't pass the filters, the name of the
+    /// filter rejecting it (see FILTERS)
+    pub rejected: Vec<&'static str>,
+    /// What the filters rejected, at any confidence the model reported, e.g. to blur people in
+    /// ignore zones too
+    pub filtered_out: Vec<Detection>,
+}
+
+/// A loaded model, possibly used by several detectors
+type SharedModel = Arc<Mutex<Box<dyn ml::Detector>>>;
+
+/// Models shared by the detectors of several monitors in one process, by how they were loaded, so
+/// that each is only loaded (and in memory) once. Inferences on a shared model take turns.
+#[derive(Default)]
+pub struct ModelCache {
+    models: Mutex<HashMap<String, SharedModel>>,
+}
+
+impl ModelCache {
+    fn get_or_load(&self, key: String, load: impl FnOnce() -> Result<Box<dyn ml::Detector>>) -> Result<SharedModel> {
+        let mut models = self.models.lock().unwrap();
+        if let Some(model) = models.get(&key) {
+            return Ok(model.clone());
+        }
+        let model = Arc::new(Mutex::new(load()?));
+        models.insert(key, model.clone());
+        Ok(model)
+    }
+}
+
+/// The model, configured from an aidect zone (Threshold, Size, Model).
+pub struct Detector {
+    model: SharedModel,
+    /// With a names file (Model=), the class ID of each of the model's outputs
+    class_map: Option<Vec<i32>>,
+    /// Classify.<Class>=, by class ID
+    classifiers: HashMap<i32, CropClassifier>,
+    threshold: f32,
+    size: u32,
+    channels: u32,
+    raw_dump: Option<RawDump>,
+}
+
+impl Detector {
+    /// With candidates the model reports detections down to CANDIDATE_THRESHOLD (see
+    /// Inferred::candidates), otherwise only those at the zone's Threshold.
+    pub fn new(model_dir: &Path, zone_config: &ZoneConfig, candidates: bool) -> Result<Detector> {
+        Detector::load(model_dir, zone_config, candidates, None)
+    }
+
+    /// Like new, but with the model from models if another detector loaded it the same way already
+    pub fn new_shared(
+        model_dir: &Path,
+        zone_config: &ZoneConfig,
+        candidates: bool,
+        models: &ModelCache,
+    ) -> Result<Detector> {
+        Detector::load(model_dir, zone_config, candidates, Some(models))
+    }
+
+    fn load(
+        model_dir: &Path,
+        zone_config: &ZoneConfig,
+        candidates: bool,
+        models: Option<&ModelCache>,
+    ) -> Result<Detector> {
+        let entry = match &zone_config.model {
+            Some(name) => Some((name, ml::table::lookup(model_dir, name).context(Error::Model)?)),
+            None => None,
+        };
+        let options = match &entry {
+            Some((_, entry)) => entry.options(zone_config),
+            None => ml::Options::from_zone(zone_config),
+        };
+        // for candidates the model does the lower threshold, Threshold is applied afterwards
+        let model_options = match candidates {
+            true => ml::Options {
+                confidence_threshold: options.confidence_threshold.min(CANDIDATE_THRESHOLD),
+                ..options
+            },
+            false => options,
+        };
+        let load_model = || match &entry {
+            Some((name, entry)) => ml::load_entry(name, entry, &model_options).context(Error::Model),
+            None => ml::load(model_dir, &model_options).context(Error::Model),
+        };
+        let model = match models {
+            Some(models) => {
+                let key = format!("{}|{:?}|{:?}", model_dir.display(), zone_config.model, model_options);
+                models.get_or_load(key, load_model)?
+            }
+            None => Arc::new(Mutex::new(load_model()?)),
+        };
+        let class_map = match &entry {
+            Some((_, entry)) => entry
+                .class_names()
+                .context(Error::Model)?
+                .map(|names| names.iter().map(|name| register_class(name)).collect()),
+            None => None,
+        };
+        // after the model, whose names file may bring the class
+        let mut classifiers = HashMap::new();
+        for (class, name) in &zone_config.classifiers {
+            let class_id = class_id(class)
+                .ok_or_else(|| anyhow!("Unknown class {} in Classify.{}", class, class))
+                .context(Error::Config)?;
+            classifiers.insert(class_id, CropClassifier::load(model_dir, name, zone_config)?);
+        }
+        Ok(Detector {
+            model,
+            class_map,
+            classifiers,
+            threshold: options.confidence_threshold,
+            size: options.size,
+            channels: options.channels,
+            raw_dump: None,
+        })
+    }
+
+    /// Runs the model a few times on a synthetic image, so that the first real frame isn't slow
+    /// (lazy initialization, GPU kernel compilation), and checks that its output makes sense, to
+    /// fail at startup rather than with garbage detections later. Returns the last inference time.
+    pub fn warm_up(&mut self) -> Result<Duration> {
+        let typ = match self.channels {
+            1 => opencv::core::CV_8UC1,
+            _ => opencv::core::CV_8UC3,
+        };
+        let size = self.size as i32;
+        let image = Mat::new_rows_cols_with_default(size, size, typ, Scalar::all(114.0))?;
+        let mut model = self.model.lock().unwrap();
+        let mut duration = Duration::ZERO;
+        for _ in 0..WARM_UP_RUNS {
+            let start = Instant::now();
+            model.infer(&image).context(Error::Model).with_context(|| {
+                format!(
+                    "The model failed on a {}x{} test image, check that Size matches the model and that Backend/Device are available (zm-aidect doctor)",
+                    size, size
+                )
+            })?;
+            duration = start.elapsed();
+        }
+        if let Some(classes) = model.classes() {
+            match &self.class_map {
+                Some(class_map) => check_names(classes, class_map.len()),
+                None => check_classes(classes),
+            }
+            .context(Error::Model)?;
+        }
+        Ok(duration)
+    }
+
+    /// Size=auto: the detector at the largest of AUTO_SIZES whose inference (after warming up)
+    /// takes at most budget per frame, or at the smallest if none is that fast, and the inference
+    /// time at each size tried. With Tiles=CxR a frame takes C·R inferences, which share the
+    /// budget. Sizes the model can't take (e.g. ONNX exports are for one size) are skipped.
+    pub fn with_auto_size(
+        model_dir: &Path,
+        zone_config: &ZoneConfig,
+        candidates: bool,
+        budget: Duration,
+    ) -> Result<(Detector, Vec<(u32, Duration)>)> {
+        let inferences = zone_config.tiles.map_or(1, |(columns, rows)| columns * rows).max(1);
+        let budget = budget / inferences;
+        let mut timings = Vec::new();
+        let mut picked = None;
+        let mut first_error = None;
+        for size in AUTO_SIZES {
+            let zone_config = ZoneConfig {
+                size: Some(size),
+                ..zone_config.clone()
+            };
+            let mut detector = match Detector::new(model_dir, &zone_config, candidates) {
+                Ok(detector) => detector,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            let duration = match detector.warm_up() {
+                Ok(duration) => duration,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            timings.push((size, duration));
+            if duration > budget && picked.is_some() {
+                // larger sizes only take longer
+                break;
+            }
+            picked = Some(detector);
+            if duration > budget {
+                break;
+            }
+        }
+        match (picked, first_error) {
+            (Some(detector), _) => Ok((detector, timings)),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// The input size the model runs at
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The precision the model actually runs at
+    pub fn precision(&self) -> Precision {
+        self.model.lock().unwrap().precision()
+    }
+
+    /// Writes the model's raw output for the frames analyzed from now on into dump
+    pub fn dump_raw(&mut self, dump: RawDump) -> Result<()> {
+        if !self.model.lock().unwrap().keep_raw_outputs() {
+            return Err(anyhow!("The model's backend doesn't give its raw output"));
+        }
+        self.raw_dump = Some(dump);
+        Ok(())
+    }
+
+    /// Runs the model on the zone's bounding box of image (RGB24), and filters the detections by
+    /// class, MinArea, Overlap and the ignore zones. Bounding boxes are relative to the whole image.
+    /// captured is when the image was taken, for Schedule.
+    pub fn detect(
+        &mut self,
+        image: &Mat,
+        captured: SystemTime,
+        bounding_box: Rect,
+        zone_config: &ZoneConfig,
+    ) -> Result<Inferred> {
+        Ok(self
+            .detect_batch(slice::from_ref(image), &[captured], bounding_box, zone_config)?
+            .remove(0))
+    }
+
+    /// Like detect for an image in the given order, see detect_batch_ordered
+    pub fn detect_ordered(
+        &mut self,
+        image: &Mat,
+        order: PixelOrder,
+        captured: SystemTime,
+        bounding_box: Rect,
+        zone_config: &ZoneConfig,
+    ) -> Result<Inferred> {
+        Ok(self
+            .detect_batch_ordered(slice::from_ref(image), order, &[captured], bounding_box, zone_config)?
+            .remove(0))
+    }
+
+    /// Like detect for several images (of the same zone) at once, with a single forward pass if the
+    /// backend supports batching. The duration of each is its share of the batch. With Tiles, all
+    /// tiles of all images go into that forward pass.
+    pub fn detect_batch(
+        &mut self,
+        images: &[Mat],
+        captured: &[SystemTime],
+        bounding_box: Rect,
+        zone_config: &ZoneConfig,
+    ) -> Result<Vec<Inferred>> {
+        self.detect_batch_ordered(images, PixelOrder::Rgb, captured, bounding_box, zone_config)
+    }
+
+    /// Like detect_batch for images in the given order. BGR images are only converted where
+    /// Preprocess needs RGB, and only the zone's bounding box of them.
+    pub fn detect_batch_ordered(
+        &mut self,
+        images: &[Mat],
+        order: PixelOrder,
+        captured: &[SystemTime],
+        bounding_box: Rect,
+        zone_config: &ZoneConfig,
+    ) -> Result<Vec<Inferred>> {
+        if images.is_empty() {
+            return Ok(vec![]);
+        }
+        if captured.len() != images.len() {
+            return Err(anyhow!("{} capture times for {} images", captured.len(), images.len()));
+        }
+        if let Some(image) = images.iter().find(|image| image.typ() != opencv::core::CV_8UC3) {
+            return Err(anyhow!(
+                "Expected 8-bit 3-channel images, got OpenCV type {}",
+                image.typ()
+            ));
+        }
+        let scale = zone_config.downscale.and_then(|max_factor| {
+            downscale_factor((bounding_box.width, bounding_box.height), self.size, max_factor)
+        });
+        let gray = zone_config.channels == Some(1);
+        let convert = order == PixelOrder::Bgr && !zone_config.preprocess.is_empty();
+        // what goes into the model, grayscale inputs have no order
+        let input_order = match convert || gray {
+            true => PixelOrder::Rgb,
+            false => order,
+        };
+        // TODO: blank remaining area outside zone polygon
+        let rois = images
+            .iter()
+            .map(|image| {
+                let mut roi = Mat::roi(image, bounding_box)?;
+                if let Some(scale) = scale {
+                    // first, so that the rest works on fewer pixels. Area interpolation looks better than
+                    // the blob's bilinear from far above
+                    let mut downscaled = Mat::default();
+                    opencv::imgproc::resize(
+                        &roi,
+                        &mut downscaled,
+                        Size::default(),
+                        scale,
+                        scale,
+                        opencv::imgproc::INTER_AREA,
+                    )?;
+                    roi = downscaled;
+                }
+                let mut roi_order = order;
+                if convert {
+                    roi = order.into_rgb(roi)?;
+                    roi_order = PixelOrder::Rgb;
+                }
+                roi = preprocess(roi, &zone_config.preprocess)?;
+                if gray {
+                    // IR images are gray anyway, a third of the input is just as good and faster
+                    let code = match roi_order {
+                        PixelOrder::Rgb => opencv::imgproc::COLOR_RGB2GRAY,
+                        PixelOrder::Bgr => opencv::imgproc::COLOR_BGR2GRAY,
+                    };
+                    let mut gray = Mat::default();
+                    opencv::imgproc::cvt_color(&roi, &mut gray, code, 0)?;
+                    roi = gray;
+                }
+                Ok(roi)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tiles = match zone_config.tiles {
+            Some(grid) => tile_rects(rois[0].size()?, grid),
+            None => vec![Rect::new(0, 0, rois[0].cols(), rois[0].rows())],
+        };
+        let inputs = match zone_config.tiles {
+            Some(_) => rois
+                .iter()
+                .flat_map(|roi| tiles.iter().map(move |tile| Mat::roi(roi, *tile)))
+                .collect::<opencv::Result<Vec<_>>>()?,
+            None => rois,
+        };
+
+        let mut model = self.model.lock().unwrap();
+        let start = Instant::now();
+        let mut detections = model.infer_batch_ordered(&inputs, input_order)?;
+        let duration = start.elapsed() / images.len() as u32;
+        if let Some(dump) = &mut self.raw_dump {
+            dump.write(model.raw_outputs(), &inputs, images.len())?;
+        }
+        drop(model);
+        if let Some(class_map) = &self.class_map {
+            for detections in detections.iter_mut() {
+                *detections = map_classes(std::mem::take(detections), class_map);
+            }
+        }
+
+        let mut inferred: Vec<Inferred> = detections
+            .chunks(tiles.len())
+            .zip(images.iter().zip(captured))
+            .map(|(per_tile, (image, &captured))| {
+                let mut detections = merge_tiles(per_tile, &tiles);
+                if let Some(scale) = scale {
+                    for d in detections.iter_mut() {
+                        d.bounding_box = unscale(d.bounding_box, scale);
+                    }
+                }
+                let (passed, rejected, filtered_out) = filter(
+                    detections,
+                    image,
+                    order,
+                    captured,
+                    bounding_box,
+                    zone_config,
+                    self.threshold,
+                );
+                let (detections, mut candidates): (Vec<_>, Vec<_>) =
+                    passed.into_iter().partition(|d| d.confidence >= self.threshold);
+                candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+                Inferred {
+                    duration,
+                    detections,
+                    candidates,
+                    rejected,
+                    filtered_out,
+                }
+            })
+            .collect();
+        if !self.classifiers.is_empty() {
+            for (image, inferred) in images.iter().zip(inferred.iter_mut()) {
+                for d in inferred.detections.iter_mut() {
+                    if let Some(classifier) = self.classifiers.get_mut(&d.class_id) {
+                        d.label = classifier.label(image, order, &d.bounding_box)?;
+                    }
+                }
+            }
+        }
+        Ok(inferred)
+    }
+}
+
+/// `--dump-raw`: the model's raw output (see ml::RawOutput) for some of the frames analyzed, as a
+/// JSON line per model input (several per frame with Tiles), with the input's size.
+pub struct RawDump {
+    out: BufWriter<File>,
+    /// Frame numbers counted from 0, all if empty
+    frames: Vec<usize>,
+    next_frame: usize,
+}
+
+impl RawDump {
+    pub fn create(path: &Path, frames: Vec<usize>) -> Result<RawDump> {
+        let out = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(RawDump {
+            out: BufWriter::new(out),
+            frames,
+            next_frame: 0,
+        })
+    }
+
+    /// outputs and inputs of the next frames (images of them)
+    fn write(&mut self, outputs: &[ml::RawOutput], inputs: &[Mat], frames: usize) -> Result<()> {
+        let per_frame = inputs.len() / frames;
+        for (i, (output, input)) in outputs.iter().zip(inputs).enumerate() {
+            let frame = self.next_frame + i / per_frame;
+            if self.frames.is_empty() || self.frames.contains(&frame) {
+                let size = input.size()?;
+                let line = raw_line(frame, i % per_frame, (size.width, size.height), output);
+                writeln!(self.out, "{}", line)?;
+            }
+        }
+        self.next_frame += frames;
+        // for frames analyzed before something fails
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn raw_line(frame: usize, input: usize, (width, height): (i32, i32), output: &ml::RawOutput) -> serde_json::Value {
+    let rows: Vec<&[f32]> = output.rows.chunks(output.row_len.max(1)).collect();
+    serde_json::json!({
+        "frame": frame,
+        "input": input,
+        "width": width,
+        "height": height,
+        "rows": rows,
+    })
+}
+
+/// Input size of classifiers which don't give one, the usual ImageNet size
+const CLASSIFIER_SIZE: u32 = 224;
+
+/// Labels less probable than this are left out of the description
+const MIN_LABEL_CONFIDENCE: f32 = 0.5;
+
+/// The classifier for the crops of one class (Classify.<Class>=NAME), with the labels of its names file.
+struct CropClassifier {
+    model: Box<dyn ml::Classifier>,
+    labels: Vec<String>,
+}
+
+impl CropClassifier {
+    fn load(model_dir: &Path, name: &str, zone_config: &ZoneConfig) -> Result<CropClassifier> {
+        let entry = ml::table::lookup(model_dir, name).context(Error::Model)?;
+        let labels = entry
+            .class_names()?
+            .ok_or_else(|| anyhow!("Classifier {} has no names file", name))
+            .context(Error::Model)?;
+        // Size= is the detector's
+        let options = ml::Options::from_zone(zone_config);
+        let options = ml::Options {
+            size: entry.size.unwrap_or(CLASSIFIER_SIZE),
+            backend: entry.backend.unwrap_or(options.backend),
+            ..options
+        };
+        let model = ml::load_classifier(name, &entry, &options).context(Error::Model)?;
+        Ok(CropClassifier { model, labels })
+    }
+
+    /// The label of the bounding box's crop of image, None if the classifier isn't sure
+    fn label(&mut self, image: &Mat, order: PixelOrder, bounding_box: &Rect) -> Result<Option<String>> {
+        let crop = match clamp_rect(*bounding_box, image.size()?) {
+            Some(crop) => Mat::roi(image, crop)?,
+            None => return Ok(None),
+        };
+        let scores = self.model.classify(&crop, order)?;
+        Ok(ml::best_class(&scores)
+            .filter(|(_, probability)| *probability >= MIN_LABEL_CONFIDENCE)
+            .and_then(|(best, _)| self.labels.get(best).cloned()))
+    }
+}
+
+/// The part of rect within an image of size, None if that's empty
+fn clamp_rect(rect: Rect, size: Size) -> Option<Rect> {
+    let (x, y) = (rect.x.max(0), rect.y.max(0));
+    let right = (rect.x + rect.width).min(size.width);
+    let bottom = (rect.y + rect.height).min(size.height);
+    (right > x && bottom > y).then(|| Rect::new(x, y, right - x, bottom - y))
+}
+
+/// Canny hysteresis thresholds for the edges of Texture=, high enough that sensor noise isn't edges
+const TEXTURE_CANNY_THRESHOLDS: (f64, f64) = (50.0, 150.0);
+
+/// The contrast (standard deviation of the gray levels) and edge density (fraction of edge pixels)
+/// of the bounding box's crop of image, None if nothing of it is in the image.
+fn texture(image: &Mat, order: PixelOrder, bounding_box: &Rect) -> Result<Option<(f32, f32)>> {
+    let crop = match clamp_rect(*bounding_box, image.size()?) {
+        Some(crop) => Mat::roi(image, crop)?,
+        None => return Ok(None),
+    };
+    let code = match order {
+        PixelOrder::Rgb => opencv::imgproc::COLOR_RGB2GRAY,
+        PixelOrder::Bgr => opencv::imgproc::COLOR_BGR2GRAY,
+    };
+    let mut gray = Mat::default();
+    opencv::imgproc::cvt_color(&crop, &mut gray, code, 0)?;
+    let (mut mean, mut stddev) = (Mat::default(), Mat::default());
+    opencv::core::mean_std_dev(&gray, &mut mean, &mut stddev, &Mat::default())?;
+    let mut edges = Mat::default();
+    let (low, high) = TEXTURE_CANNY_THRESHOLDS;
+    opencv::imgproc::canny(&gray, &mut edges, low, high, 3, false)?;
+    let pixels = (gray.rows() * gray.cols()) as f32;
+    Ok(Some((
+        *stddev.at::<f64>(0)? as f32,
+        opencv::core::count_non_zero(&edges)? as f32 / pixels,
+    )))
+}
+
+/// zm-aidect reports COCO classes (CLASSES), a model with fewer outputs is some other model or
+/// one whose output layer isn't decoded correctly.
+fn check_classes(classes: usize) -> Result<()> {
+    let (&highest, &name) = CLASSES.iter().max_by_key(|(class_id, _)| **class_id).unwrap();
+    if classes < highest as usize {
+        return Err(anyhow!(
+            "The model outputs {} classes, but zm-aidect needs the COCO classes (up to {} = {}), use a model trained on COCO",
+            classes,
+            highest,
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// A names file has a name for each output of the model, one with fewer outputs doesn't belong to it.
+fn check_names(classes: usize, names: usize) -> Result<()> {
+    if classes < names {
+        return Err(anyhow!(
+            "The model outputs {} classes, but its names file has {}",
+            classes,
+            names
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces the model's (1-based) output indices with the class IDs of class_map, dropping the
+/// outputs without a name.
+fn map_classes(detections: Vec<Detection>, class_map: &[i32]) -> Vec<Detection> {
+    detections
+        .into_iter()
+        .filter_map(|d| {
+            let class_id = *class_map.get(usize::try_from(d.class_id - 1).ok()?)?;
+            Some(Detection { class_id, ..d })
+        })
+        .collect()
+}
+
+/// Downscale=K: the factor to scale a RoI of roi_size down by, so that its longer side is at most
+/// max_factor times the network input size. None if it's small enough already.
+fn downscale_factor((width, height): (i32, i32), size: u32, max_factor: f32) -> Option<f64> {
+    let max_side = size as f64 * max_factor as f64;
+    let side = width.max(height) as f64;
+    (side > max_side).then(|| max_side / side)
+}
+
+/// The columns x rows tiles covering an image of size, each overlapping its neighbours by
+/// TILE_OVERLAP of its size, so that an object on a tile border is whole in one of them.
+fn tile_rects(size: Size, (columns, rows): (u32, u32)) -> Vec<Rect> {
+    // (start, length) of each tile along one axis
+    let split = |length: i32, count: u32| -> Vec<(i32, i32)> {
+        let tile = (length as f32 / (count as f32 - (count - 1) as f32 * TILE_OVERLAP)).ceil() as i32;
+        let tile = tile.min(length);
+        (0..count as i32)
+            .map(|i| match count {
+                1 => (0, length),
+                _ => ((length - tile) * i / (count as i32 - 1), tile),
+            })
+            .collect()
+    };
+    let (xs, ys) = (split(size.width, columns), split(size.height, rows));
+    ys.iter()
+        .flat_map(|&(y, height)| xs.iter().map(move |&(x, width)| Rect::new(x, y, width, height)))
+        .collect()
+}
+
+/// The detections of each tile, moved onto the whole RoI. Objects in the overlap of two tiles are
+/// detected twice, only the more confident detection is kept.
+fn merge_tiles(per_tile: &[Vec<Detection>], tiles: &[Rect]) -> Vec<Detection> {
+    if let [detections] = per_tile {
+        return detections.clone();
+    }
+    let detections = per_tile
+        .iter()
+        .zip(tiles)
+        .flat_map(|(detections, tile)| {
+            detections.iter().map(move |d| Detection {
+                bounding_box: Rect::new(
+                    d.bounding_box.x + tile.x,
+                    d.bounding_box.y + tile.y,
+                    d.bounding_box.width,
+                    d.bounding_box.height,
+                ),
+                ..d.clone()
+            })
+        })
+        .collect();
+    ml::nms(detections, TILE_NMS_THRESHOLD)
+}
+
+/// Maps a bounding box on a RoI downscaled by scale back onto the RoI.
+fn unscale(rect: Rect, scale: f64) -> Rect {
+    Rect::new(
+        (rect.x as f64 / scale).round() as i32,
+        (rect.y as f64 / scale).round() as i32,
+        (rect.width as f64 / scale).round() as i32,
+        (rect.height as f64 / scale).round() as i32,
+    )
+}
+
+/// Applies the zone's Preprocess steps to the RoI (RGB24).
+fn preprocess(mut image: Mat, steps: &[Preprocess]) -> Result<Mat> {
+    for step in steps {
+        let mut processed = Mat::default();
+        match *step {
+            Preprocess::Clahe(clip_limit) => {
+                let mut clahe = opencv::imgproc::create_clahe(clip_limit as f64, Size::new(8, 8))?;
+                processed = map_lightness(&image, |l, out| Ok(clahe.apply(l, out)?))?;
+            }
+            Preprocess::Equalize => {
+                processed = map_lightness(&image, |l, out| Ok(opencv::imgproc::equalize_hist(l, out)?))?;
+            }
+            Preprocess::Gamma(gamma) => {
+                let table = Mat::from_slice(&gamma_table(gamma))?;
+                opencv::core::lut(&image, &table, &mut processed)?;
+            }
+            Preprocess::Contrast(alpha) => image.convert_to(&mut processed, -1, alpha as f64, 0.0)?,
+            Preprocess::Brightness(beta) => image.convert_to(&mut processed, -1, 1.0, beta as f64)?,
+        }
+        image = processed;
+    }
+    Ok(image)
+}
+
+/// Runs f on the lightness channel only, so that colors stay the same.
+fn map_lightness(image: &Mat, mut f: impl FnMut(&Mat, &mut Mat) -> Result<()>) -> Result<Mat> {
+    let mut lab = Mat::default();
+    opencv::imgproc::cvt_color(image, &mut lab, opencv::imgproc::COLOR_RGB2Lab, 0)?;
+    let mut channels = VectorOfMat::new();
+    opencv::core::split(&lab, &mut channels)?;
+    let mut lightness = Mat::default();
+    f(&channels.get(0)?, &mut lightness)?;
+    channels.set(0, lightness)?;
+    opencv::core::merge(&channels, &mut lab)?;
+    let mut rgb = Mat::default();
+    opencv::imgproc::cvt_color(&lab, &mut rgb, opencv::imgproc::COLOR_Lab2RGB, 0)?;
+    Ok(rgb)
+}
+
+/// Lookup table mapping each 8-bit value v to 255 * (v / 255)^gamma
+fn gamma_table(gamma: f32) -> Vec<u8> {
+    (0..=255u8)
+        .map(|v| ((v as f32 / 255.0).powf(gamma) * 255.0).round() as u8)
+        .collect()
+}
+
+/// Rotate= and Keystone=: image as the zones are drawn on it. Any channel order.
+pub fn transform_frame(image: Mat, transform: &FrameTransform) -> Result<Mat> {
+    let mut image = image;
+    if let Some(rotation) = transform.rotation {
+        let code = match rotation {
+            Rotation::Rotate90 => opencv::core::ROTATE_90_CLOCKWISE,
+            Rotation::Rotate180 => opencv::core::ROTATE_180,
+            Rotation::Rotate270 => opencv::core::ROTATE_90_COUNTERCLOCKWISE,
+        };
+        let mut rotated = Mat::default();
+        opencv::core::rotate(&image, &mut rotated, code)?;
+        image = rotated;
+    }
+    if let Some(keystone) = transform.keystone {
+        let size = image.size()?;
+        let points = |corners: [(f32, f32); 4]| -> VectorOfPoint2f {
+            corners.iter().map(|&(x, y)| Point2f::new(x, y)).collect()
+        };
+        let (width, height) = (size.width as f32, size.height as f32);
+        let from = points(FrameTransform::keystone_quad(keystone, (size.width as u32, size.height as u32)));
+        let to = points([(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)]);
+        let matrix = opencv::imgproc::get_perspective_transform(&from, &to, opencv::core::DECOMP_LU)?;
+        let mut corrected = Mat::default();
+        opencv::imgproc::warp_perspective(
+            &image,
+            &mut corrected,
+            &matrix,
+            size,
+            opencv::imgproc::INTER_LINEAR,
+            opencv::core::BORDER_CONSTANT,
+            Scalar::default(),
+        )?;
+        image = corrected;
+    }
+    Ok(image)
+}
+
+/// What filters see of the zone besides the detection
+pub struct FilterContext<'a> {
+    pub zone_config: &'a ZoneConfig,
+    /// The frame the detections are from
+    pub image: &'a Mat,
+    pub order: PixelOrder,
+    /// Local minute of the day the frame was captured, for Schedule
+    pub minute: u32,
+}
+
+/// A rule of the aidect zone which detections have to pass, see FILTERS.
+pub trait DetectionFilter: Sync {
+    /// Label of the detections_rejected metric
+    fn name(&self) -> &'static str;
+
+    /// d is relative to the frame
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool;
+}
+
+/// Classes zm-aidect knows, those of CLASSES and of names files
+struct KnownClass;
+/// MinArea=
+struct MinArea;
+/// MinAreaPct= and MaxAreaPct=
+struct AreaFraction;
+/// Schedule.<Class>=
+struct ClassSchedule;
+/// Aspect.<Class>=
+struct AspectRatio;
+/// Overlap=
+struct ZoneOverlap;
+/// Margin.<Class>=
+struct ClassMargin;
+/// "aidect-ignore" zones
+struct IgnoreZones;
+/// Texture.<Class>=
+struct MinTexture;
+
+impl DetectionFilter for KnownClass {
+    fn name(&self) -> &'static str {
+        "class"
+    }
+
+    fn accepts(&self, d: &Detection, _context: &FilterContext) -> bool {
+        known_class(d.class_id)
+    }
+}
+
+impl DetectionFilter for MinArea {
+    fn name(&self) -> &'static str {
+        "min_area"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        (d.bounding_box.width * d.bounding_box.height) as u32 > context.zone_config.min_area.unwrap_or(0)
+    }
+}
+
+impl DetectionFilter for AreaFraction {
+    fn name(&self) -> &'static str {
+        "area_pct"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        context.zone_config.accepts_area(&d.bounding_box)
+    }
+}
+
+impl DetectionFilter for ClassSchedule {
+    fn name(&self) -> &'static str {
+        "schedule"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        context
+            .zone_config
+            .schedules
+            .iter()
+            .filter(|(class, _)| class_id(class) == Some(d.class_id))
+            .all(|(_, schedule)| schedule.active(context.minute))
+    }
+}
+
+impl DetectionFilter for AspectRatio {
+    fn name(&self) -> &'static str {
+        "aspect"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        context
+            .zone_config
+            .aspect_ratios
+            .iter()
+            .filter(|(class, _)| class_id(class) == Some(d.class_id))
+            .all(|(_, ratio)| ratio.accepts(&d.bounding_box))
+    }
+}
+
+impl DetectionFilter for ClassMargin {
+    fn name(&self) -> &'static str {
+        "margin"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        // models which only give the best class can't be held to a margin
+        let runner_up = match d.runner_up {
+            Some(runner_up) => runner_up,
+            None => return true,
+        };
+        context
+            .zone_config
+            .margins
+            .iter()
+            .filter(|(class, _)| class_id(class) == Some(d.class_id))
+            .all(|(_, margin)| d.confidence - runner_up >= *margin)
+    }
+}
+
+impl DetectionFilter for ZoneOverlap {
+    fn name(&self) -> &'static str {
+        "overlap"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        match context.zone_config.overlap {
+            Some(overlap) => overlap.accepts(&context.zone_config.shape, &d.bounding_box),
+            None => true,
+        }
+    }
+}
+
+impl DetectionFilter for IgnoreZones {
+    fn name(&self) -> &'static str {
+        "ignore_zone"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        !context
+            .zone_config
+            .ignore
+            .iter()
+            .any(|zone| zone.matches(d.class_id, &d.bounding_box))
+    }
+}
+
+impl DetectionFilter for MinTexture {
+    fn name(&self) -> &'static str {
+        "texture"
+    }
+
+    fn accepts(&self, d: &Detection, context: &FilterContext) -> bool {
+        let mut textures = context
+            .zone_config
+            .textures
+            .iter()
+            .filter(|(class, _)| class_id(class) == Some(d.class_id))
+            .peekable();
+        if textures.peek().is_none() {
+            return true;
+        }
+        // a crop which can't be measured is kept, like without Texture
+        match texture(context.image, context.order, &d.bounding_box) {
+            Ok(Some((contrast, edges))) => textures.all(|(_, texture)| texture.accepts(contrast, edges)),
+            Ok(None) | Err(_) => true,
+        }
+    }
+}
+
+/// The filters in the order they're applied, cheap ones first. A detection is rejected by the
+/// first filter it doesn't pass, the others aren't asked.
+pub static FILTERS: &[&dyn DetectionFilter] = &[
+    &KnownClass,
+    &MinArea,
+    &AreaFraction,
+    &ClassSchedule,
+    &AspectRatio,
+    &ClassMargin,
+    &ZoneOverlap,
+    &IgnoreZones,
+    &MinTexture,
+];
+
+/// The filter rejecting d, if any
+fn rejecting_filter(d: &Detection, context: &FilterContext) -> Option<&'static str> {
+    FILTERS
+        .iter()
+        .find(|filter| !filter.accepts(d, context))
+        .map(|filter| filter.name())
+}
+
+/// The detections (relative to the RoI at bounding_box) passing FILTERS, relative to the frame,
+/// the names of the filters which rejected those at or above threshold, and the rejected ones.
+fn filter(
+    detections: Vec<Detection>,
+    image: &Mat,
+    order: PixelOrder,
+    captured: SystemTime,
+    bounding_box: Rect,
+    zone_config: &ZoneConfig,
+    threshold: f32,
+) -> (Vec<Detection>, Vec<&'static str>, Vec<Detection>) {
+    let context = FilterContext {
+        zone_config,
+        image,
+        order,
+        minute: match zone_config.schedules.is_empty() {
+            true => 0,
+            false => zone::local_minute_of_day(captured),
+        },
+    };
+    let mut passed = vec![];
+    let mut rejected = vec![];
+    let mut filtered_out = vec![];
+    for d in detections {
+        // Adjust bounding box to zone bounding box (RoI)
+        let d = Detection {
+            bounding_box: Rect {
+                x: d.bounding_box.x + bounding_box.x,
+                y: d.bounding_box.y + bounding_box.y,
+                ..d.bounding_box
+            },
+            ..d
+        };
+        match rejecting_filter(&d, &context) {
+            None => passed.push(d),
+            Some(name) => {
+                if d.confidence >= threshold {
+                    rejected.push(name);
+                }
+                filtered_out.push(d);
+            }
+        }
+    }
+    (passed, rejected, filtered_out)
+}
+
+/// Runs detection over event recordings, like the `event` subcommand.
+pub struct EventAnalyzer<'a> {
+    detector: &'a mut Detector,
+    zone_config: &'a ZoneConfig,
+    monitor_id: u32,
+    /// The resolution the zone config was drawn at, after Rotate
+    monitor_size: (u32, u32),
+    fps: f32,
+}
+
+impl<'a> EventAnalyzer<'a> {
+    pub fn new(
+        detector: &'a mut Detector,
+        zone_config: &'a ZoneConfig,
+        monitor_id: u32,
+        monitor_size: (u32, u32),
+        fps: f32,
+    ) -> EventAnalyzer<'a> {
+        EventAnalyzer {
+            detector,
+            zone_config,
+            monitor_id,
+            monitor_size,
+            fps,
+        }
+    }
+
+    /// Analyzes the recording of event, calling on_frame for every frame.
+    pub fn analyze(
+        &mut self,
+        event: &Event,
+        mut on_frame: impl FnMut(&FrameReport, Duration),
+    ) -> Result<EventReport> {
+        let video_path = event.video_path()?;
+        // Analyze the recording at its own resolution, rather than scaling every frame to the monitor's
+        let props = vio::properties(&video_path)?;
+        let (width, height) = (props.width, props.height);
+        let transform = self.zone_config.transform;
+        let zone_config = self.zone_config.scaled(self.monitor_size, transform.size((width, height)));
+        let bounding_box = zone_config.shape.bounding_box();
+
+        let mut inference_durations = vec![];
+        let mut frames = vec![];
+        let mut totals = Totals::default();
+        // ffmpeg resamples the recording to fps, so frame n is n/fps seconds in, and the recording
+        // starts with the event
+        let mut frame_index = 0;
+        let start_time = event.start_time()?;
+        // All frames are there already, so batches only wait for the decoder
+        let batch_size = zone_config.batch.unwrap_or(1).max(1) as usize;
+        let mut decoder = vio::stream_file(&video_path, width, height, self.fps)?;
+        let mut stream = decoder.by_ref().peekable();
+        while stream.peek().is_some() {
+            let images = stream
+                .by_ref()
+                .take(batch_size)
+                .map(|image| transform_frame(image, &transform))
+                .collect::<Result<Vec<Mat>>>()?;
+            let captured: Vec<SystemTime> = (frame_index..frame_index + images.len())
+                .map(|n| start_time + Duration::from_secs_f64(n as f64 / self.fps as f64))
+                .collect();
+            for result in self.detector.detect_batch(&images, &captured, bounding_box, &zone_config)? {
+                let time = frame_index as f64 / self.fps as f64;
+                let frame = FrameReport {
+                    time: time as f32,
+                    frame_id: db::frame_id_at(&event.frames, time),
+                    inference_ms: result.duration.as_secs_f64() * 1000.0,
+                    detections: result
+                        .detections
+                        .iter()
+                        .map(|d| detection_report(d, &zone_config))
+                        .collect(),
+                };
+                on_frame(&frame, result.duration);
+                if frame.detections.len() > 0 {
+                    totals.add_frame(&frame);
+                    frames.push(frame);
+                }
+                inference_durations.push(result.duration);
+                frame_index += 1;
+            }
+        }
+        totals.frames = inference_durations.len();
+        drop(stream);
+        let complete = decoder.finish().is_ok();
+
+        Ok(EventReport {
+            event_id: event.id,
+            monitor_id: self.monitor_id,
+            video_path: video_path.display().to_string(),
+            frames,
+            totals,
+            inference: TimingStats::new(&inference_durations),
+            complete,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoDetections;
+
+    impl ml::Detector for NoDetections {
+        fn infer(&mut self, _image: &Mat) -> Result<Vec<Detection>> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn test_model_cache() {
+        let models = ModelCache::default();
+        let mut loads = 0;
+        let mut load = |key: &str| {
+            models.get_or_load(key.to_string(), || {
+                loads += 1;
+                Ok(Box::new(NoDetections))
+            })
+        };
+        let first = load("yolo|416").unwrap();
+        let again = load("yolo|416").unwrap();
+        let other = load("yolo|608").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(!Arc::ptr_eq(&first, &other));
+        drop(load);
+        assert_eq!(loads, 2);
+        assert!(models.get_or_load("broken".to_string(), || Err(anyhow!("no model"))).is_err());
+    }
+
+    #[test]
+    fn test_class_id() {
+        assert_eq!(class_id("Car"), Some(3));
+        assert_eq!(class_id("human"), Some(1));
+        assert_eq!(class_id("17"), Some(17));
+        assert_eq!(class_id("2"), None);
+        assert_eq!(class_id("Giraffe"), None);
+    }
+
+    #[test]
+    fn test_check_classes() {
+        // yolov4-tiny, 80 classes plus objectness
+        assert!(check_classes(81).is_ok());
+        assert!(check_classes(17).is_ok());
+        assert!(check_classes(1).is_err());
+        assert!(check_classes(0).is_err());
+        assert!(check_names(2, 2).is_ok());
+        assert!(check_names(1, 2).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let zone = |id: u32, name: &str, coords: &str| (id, name.to_string(), coords.to_string());
+        let zones = [
+            zone(
+                1,
+                "aidect MinArea=1000 Texture.Dog=10 Margin.Car=20",
+                "0,0 1000,0 1000,1000 0,1000",
+            ),
+            zone(2, "aidect-ignore", "0,0 100,0 100,100 0,100"),
+        ];
+        let zone_config = ZoneConfig::from_zones(1, &zones, true).unwrap();
+        let detection = |confidence: f32, class_id: i32, x: i32, y: i32, size: i32| Detection {
+            confidence,
+            class_id,
+            bounding_box: Rect::new(x, y, size, size),
+            runner_up: None,
+            label: None,
+        };
+        let detections = vec![
+            detection(0.9, 1, 500, 500, 50),
+            detection(0.2, 1, 500, 500, 50),
+            detection(0.9, 2, 500, 500, 50),
+            detection(0.9, 1, 500, 500, 10),
+            detection(0.1, 1, 500, 500, 10),
+            // at 50,50 in the frame, centered in the ignore zone
+            detection(0.9, 1, 0, 0, 40),
+            // on a blank image
+            detection(0.9, 17, 500, 500, 50),
+            // a car, or maybe a truck
+            Detection {
+                runner_up: Some(0.8),
+                ..detection(0.9, 3, 500, 500, 50)
+            },
+            Detection {
+                runner_up: Some(0.5),
+                ..detection(0.9, 3, 500, 500, 50)
+            },
+        ];
+        let image = Mat::new_rows_cols_with_default(1000, 1000, opencv::core::CV_8UC3, Scalar::all(0.0)).unwrap();
+        let (passed, rejected, filtered_out) = filter(
+            detections,
+            &image,
+            PixelOrder::Rgb,
+            SystemTime::now(),
+            Rect::new(50, 50, 900, 900),
+            &zone_config,
+            0.5,
+        );
+        assert_eq!(passed.len(), 3);
+        assert_eq!(passed[0].bounding_box, Rect::new(550, 550, 50, 50));
+        assert_eq!(rejected, ["class", "min_area", "ignore_zone", "texture", "margin"]);
+        // including the one below threshold
+        assert_eq!(filtered_out.len(), 6);
+        assert_eq!(filtered_out[3].bounding_box, Rect::new(50, 50, 40, 40));
+    }
+
+    #[test]
+    fn test_raw_line() {
+        let output = ml::RawOutput {
+            row_len: 3,
+            rows: vec![0.5, 0.25, 1.0, 0.0, 0.75, 0.5],
+        };
+        assert_eq!(
+            raw_line(7, 1, (416, 416), &output).to_string(),
+            r#"{"frame":7,"height":416,"input":1,"rows":[[0.5,0.25,1.0],[0.0,0.75,0.5]],"width":416}"#
+        );
+    }
+
+    #[test]
+    fn test_texture() {
+        let image = Mat::new_rows_cols_with_default(100, 100, opencv::core::CV_8UC3, Scalar::all(20.0)).unwrap();
+        assert_eq!(
+            texture(&image, PixelOrder::Bgr, &Rect::new(0, 0, 50, 50)).unwrap(),
+            Some((0.0, 0.0))
+        );
+        let mut square = Mat::roi(&image, Rect::new(10, 10, 20, 20)).unwrap();
+        square.set_to(&Scalar::all(200.0), &Mat::default()).unwrap();
+        let (contrast, edges) = texture(&image, PixelOrder::Bgr, &Rect::new(0, 0, 50, 50))
+            .unwrap()
+            .unwrap();
+        assert!(contrast > 50.0);
+        assert!(edges > 0.0 && edges < 0.2);
+        assert_eq!(
+            texture(&image, PixelOrder::Bgr, &Rect::new(100, 0, 50, 50)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_clamp_rect() {
+        let size = Size::new(640, 480);
+        assert_eq!(clamp_rect(Rect::new(10, 20, 30, 40), size), Some(Rect::new(10, 20, 30, 40)));
+        assert_eq!(clamp_rect(Rect::new(-10, 450, 30, 40), size), Some(Rect::new(0, 450, 20, 30)));
+        assert_eq!(clamp_rect(Rect::new(640, 0, 30, 40), size), None);
+    }
+
+    #[test]
+    fn test_map_classes() {
+        let plate = register_class("LicensePlate");
+        assert!(plate >= CUSTOM_CLASS_IDS);
+        assert_eq!(register_class("licenseplate"), plate);
+        assert_eq!(class_name(plate), "LicensePlate");
+        assert_eq!(class_id("licenseplate"), Some(plate));
+        let class_map = vec![register_class("person"), register_class("Car"), plate];
+        assert_eq!(class_map[1], 3);
+
+        let detection = |class_id| Detection {
+            class_id,
+            confidence: 0.9,
+            bounding_box: Rect::new(0, 0, 10, 10),
+            runner_up: None,
+            label: None,
+        };
+        let mapped = map_classes(vec![detection(3), detection(2), detection(4), detection(0)], &class_map);
+        assert_eq!(
+            mapped.iter().map(|d| d.class_id).collect::<Vec<_>>(),
+            vec![plate, 3]
+        );
+    }
+
+    #[test]
+    fn test_tile_rects() {
+        assert_eq!(
+            tile_rects(Size::new(1000, 400), (2, 1)),
+            vec![Rect::new(0, 0, 556, 400), Rect::new(444, 0, 556, 400)]
+        );
+        assert_eq!(tile_rects(Size::new(640, 480), (1, 1)), vec![Rect::new(0, 0, 640, 480)]);
+        let tiles = tile_rects(Size::new(1200, 800), (3, 2));
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[2], Rect::new(738, 0, 462, 445));
+        assert_eq!(tiles[5], Rect::new(738, 355, 462, 445));
+    }
+
+    #[test]
+    fn test_merge_tiles() {
+        let detection = |x| Detection {
+            confidence: 0.8,
+            class_id: 1,
+            bounding_box: Rect::new(x, 10, 50, 100),
+            runner_up: None,
+            label: None,
+        };
+        let tiles = [Rect::new(0, 0, 556, 400), Rect::new(444, 0, 556, 400)];
+        // the same person in the overlap, seen by both tiles
+        let merged = merge_tiles(&[vec![detection(480)], vec![detection(36), detection(300)]], &tiles);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].bounding_box, Rect::new(480, 10, 50, 100));
+        assert_eq!(merged[1].bounding_box, Rect::new(744, 10, 50, 100));
+    }
+
+    #[test]
+    fn test_downscale_factor() {
+        assert_eq!(downscale_factor((3840, 2160), 416, 2.0), Some(832.0 / 3840.0));
+        assert_eq!(downscale_factor((1000, 2000), 500, 2.0), None);
+        assert_eq!(downscale_factor((640, 480), 416, 2.0), None);
+        let scale = downscale_factor((4000, 1000), 500, 2.0).unwrap();
+        assert_eq!(unscale(Rect::new(10, 20, 50, 100), scale), Rect::new(40, 80, 200, 400));
+    }
+
+    #[test]
+    fn test_gamma_table() {
+        let table = gamma_table(1.0);
+        assert_eq!(table.len(), 256);
+        assert!(table.iter().enumerate().all(|(v, &mapped)| v as u8 == mapped));
+        // brightens dark parts, keeps black and white
+        let table = gamma_table(0.5);
+        assert_eq!((table[0], table[64], table[255]), (0, 128, 255));
+    }
+}
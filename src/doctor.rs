@@ -0,0 +1,163 @@
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::Path;
+#[cfg(feature = "detect")]
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "detect")]
+use zoneminder_aidect::ml;
+use zoneminder_aidect::zoneminder::{db, ZoneMinderConf};
+
+enum Outcome {
+    Ok(String),
+    /// Works, but not fully
+    Warn(String),
+}
+
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, what: &str, result: Result<Outcome>) {
+        match result {
+            Ok(Outcome::Ok(detail)) => println!("[ OK ] {}: {}", what, detail),
+            Ok(Outcome::Warn(detail)) => {
+                self.warnings += 1;
+                println!("[WARN] {}: {}", what, detail)
+            }
+            Err(e) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {:#}", what, e)
+            }
+        }
+    }
+}
+
+/// Checks everything zm-aidect needs, and prints a line for each check. Errors if any of them failed.
+pub fn doctor(
+    conf_dir: Option<&Path>,
+    perl_lib: Option<&Path>,
+    #[cfg(feature = "detect")] model_dir: Option<&Path>,
+) -> Result<()> {
+    let mut report = Report::default();
+
+    let zm_conf = ZoneMinderConf::parse(conf_dir, perl_lib);
+    let zm_conf = match zm_conf {
+        Ok(zm_conf) => {
+            report.check(
+                "ZoneMinder configuration",
+                Ok(Outcome::Ok("zm.conf parsed".to_string())),
+            );
+            Some(zm_conf)
+        }
+        Err(e) => {
+            report.check("ZoneMinder configuration", Err(e));
+            None
+        }
+    };
+
+    if let Some(zm_conf) = &zm_conf {
+        report.check(
+            "Shared memory layout",
+            zm_conf
+                .check_shm_layout()
+                .map(|memory_pm| Outcome::Ok(format!("{} matches", memory_pm.display()))),
+        );
+
+        match db::check_schema(zm_conf) {
+            Ok(problems) => {
+                report.check("Database", Ok(Outcome::Ok("connected".to_string())));
+                report.check(
+                    "Database schema",
+                    if problems.is_empty() {
+                        Ok(Outcome::Ok(
+                            "all tables and columns used are present".to_string(),
+                        ))
+                    } else {
+                        Err(anyhow!(problems.join("; ")))
+                    },
+                );
+                match db::monitor_ids(zm_conf) {
+                    Ok(monitor_ids) => {
+                        for monitor_id in monitor_ids {
+                            report.check(
+                                &format!("Monitor {} shared memory", monitor_id),
+                                check_mmap(&zm_conf.mmap_file(monitor_id)),
+                            );
+                        }
+                    }
+                    Err(e) => report.check("Monitors", Err(e)),
+                }
+            }
+            Err(e) => report.check("Database", Err(e.context("Failed to connect"))),
+        }
+    }
+
+    #[cfg(feature = "detect")]
+    {
+        report.check(
+            "Model",
+            ml::find_model_dir(model_dir).and_then(|dir| {
+                ml::load(&dir, &ml::Options::default())?;
+                Ok(Outcome::Ok(format!("loaded from {}", dir.display())))
+            }),
+        );
+        // event, backfill, filter and --source-url
+        report.check("ffmpeg", check_tool("ffmpeg", "-version"));
+        report.check("ffprobe", check_tool("ffprobe", "-version"));
+        // model fetch and webhooks
+        report.check("curl", check_tool("curl", "--version"));
+    }
+
+    match (report.failures, report.warnings) {
+        (0, 0) => println!("All checks passed"),
+        (0, warnings) => println!("All checks passed, {} warnings", warnings),
+        (failures, _) => return Err(anyhow!("{} checks failed", failures)),
+    }
+    Ok(())
+}
+
+fn check_mmap(path: &str) -> Result<Outcome> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => Ok(Outcome::Ok(format!("{} is readable and writable", path))),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            OpenOptions::new()
+                .read(true)
+                .open(path)
+                .with_context(|| format!("{} is not readable", path))?;
+            Ok(Outcome::Warn(format!(
+                "{} is read-only, triggering only works through zmtrigger",
+                path
+            )))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Outcome::Warn(format!(
+            "{} doesn't exist, is the monitor running?",
+            path
+        ))),
+        Err(e) => Err(e).with_context(|| format!("Failed to open {}", path)),
+    }
+}
+
+#[cfg(feature = "detect")]
+fn check_tool(name: &str, version_arg: &str) -> Result<Outcome> {
+    let output = Command::new(name)
+        .arg(version_arg)
+        .output()
+        .with_context(|| format!("Failed to run {}", name))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            name,
+            version_arg,
+            output.status
+        ));
+    }
+    let version = String::from_utf8_lossy(&output.stdout);
+    Ok(Outcome::Ok(
+        version.lines().next().unwrap_or("").to_string(),
+    ))
+}
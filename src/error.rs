@@ -0,0 +1,100 @@
+//! Kinds of errors callers can act on, e.g. to tell whether reconnecting could help. Errors are
+//! anyhow::Error throughout, the places where the kind is known attach an Error (as the error or
+//! its context), and Error::kind finds it again.
+
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The monitor's mmap file went away or was replaced, i.e. its capture process restarted
+    ShmStale,
+    /// ZoneMinder marked the monitor's shared memory invalid, e.g. while capture restarts
+    ShmInvalid,
+    /// The shared memory layout (Memory.pm) isn't one zm-aidect understands
+    ShmLayout,
+    /// Talking to ZoneMinder's database (or API) failed, e.g. the connection dropped
+    Db,
+    /// The database refused a query, e.g. one the schema of this ZoneMinder version doesn't fit
+    DbQuery,
+    /// The model can't be loaded, or doesn't work on this machine
+    Model,
+    /// The monitor or zone configuration can't be used as it is
+    Config,
+}
+
+impl Error {
+    /// Whether the problem may go away by itself, so that reconnecting is worth a try.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::ShmStale | Error::ShmInvalid | Error::Db)
+    }
+
+    /// The kind of e, if known. Database errors count even if nobody attached Error::Db, as Db if
+    /// the connection failed and as DbQuery otherwise.
+    pub fn kind(e: &anyhow::Error) -> Option<Error> {
+        if let Some(kind) = e.downcast_ref::<Error>() {
+            return Some(*kind);
+        }
+        e.chain()
+            .find_map(|cause| cause.downcast_ref::<mysql::Error>())
+            .map(|e| match is_connection_error(e) {
+                true => Error::Db,
+                false => Error::DbQuery,
+            })
+    }
+}
+
+/// Whether e is about reaching the server rather than about the query
+fn is_connection_error(e: &mysql::Error) -> bool {
+    use mysql::DriverError;
+    match e {
+        mysql::Error::IoError(_) => true,
+        mysql::Error::DriverError(e) => matches!(
+            e,
+            DriverError::CouldNotConnect(_) | DriverError::ConnectTimeout | DriverError::Timeout
+        ),
+        // too many connections, server shutting down, server gone away, lost connection
+        mysql::Error::MySqlError(e) => matches!(e.code, 1040 | 1053 | 2006 | 2013),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::ShmStale => "Monitor shm is stale, must reconnect",
+            Error::ShmInvalid => "Monitor shm is not valid",
+            Error::ShmLayout => "Unsupported shared memory layout",
+            Error::Db => "Database error",
+            Error::DbQuery => "Database query failed",
+            Error::Model => "Model error",
+            Error::Config => "Invalid configuration",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_kind() {
+        let stale = anyhow::Error::new(Error::ShmStale).context("Reading monitor 1");
+        assert_eq!(Error::kind(&stale), Some(Error::ShmStale));
+        let model: anyhow::Result<()> = Err(anyhow!("No such file"));
+        let model = model.context(Error::Model).context("Loading the model").unwrap_err();
+        assert_eq!(Error::kind(&model), Some(Error::Model));
+        assert!(!Error::Model.is_transient());
+        assert_eq!(Error::kind(&anyhow!("Something else")), None);
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let dropped = anyhow::Error::new(mysql::Error::IoError(io)).context("Querying monitor 1");
+        assert_eq!(Error::kind(&dropped), Some(Error::Db));
+        assert!(Error::Db.is_transient());
+        let query = mysql::Error::DriverError(mysql::DriverError::MixedParams);
+        assert_eq!(Error::kind(&anyhow::Error::new(query)), Some(Error::DbQuery));
+        assert!(!Error::DbQuery.is_transient());
+    }
+}
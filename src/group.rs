@@ -0,0 +1,156 @@
+//! `zm-aidect run` with several monitors: one process, in which every monitor has its capture,
+//! inference and I/O threads as usual, but inference runs on a limited number of slots shared by
+//! all of them. For many low-fps cameras that keeps the cores busy without oversubscribing them,
+//! and saves the overhead of a process (and database connection, OpenCV runtime) per camera.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+
+use zoneminder_aidect::detect::ModelCache;
+use zoneminder_aidect::zoneminder::ZoneMinderConf;
+
+use crate::RunArgs;
+
+/// Limits how many inferences run at the same time, across monitors. Monitors wait for a slot in
+/// the order they asked for one, roughly. Monitors with the same model configuration share its
+/// model.
+pub struct InferencePool {
+    free: Mutex<usize>,
+    released: Condvar,
+    pub models: ModelCache,
+}
+
+/// Set once a monitor of the group gave up, the others stop then
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the monitors of the group are to stop, because one of them gave up
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// A slot of the pool, given back when dropped
+pub struct Slot<'a> {
+    pool: &'a InferencePool,
+}
+
+impl InferencePool {
+    pub fn new(workers: usize) -> InferencePool {
+        InferencePool {
+            free: Mutex::new(workers.max(1)),
+            released: Condvar::new(),
+            models: ModelCache::default(),
+        }
+    }
+
+    /// Waits for a free slot
+    pub fn acquire(&self) -> Slot {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.released.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot { pool: self }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.pool.free.lock().unwrap() += 1;
+        self.pool.released.notify_one();
+    }
+}
+
+/// Options which name a single file or device, and would clash between monitors
+fn check_args(args: &RunArgs) -> Result<()> {
+    let clashing = [
+        ("--trace", args.trace.is_some()),
+        ("--heatmap-file", args.heatmap_file.is_some()),
+        ("--stationary-file", args.stationary_file.is_some()),
+        ("--source-url", args.source_url.is_some()),
+        ("--alarm-gpio", args.alarm_gpio.is_some()),
+    ];
+    match clashing.iter().find(|(_, given)| *given) {
+        Some((option, _)) => Err(anyhow!("{} can't be used with several monitors", option)),
+        None => Ok(()),
+    }
+}
+
+/// Runs all of args.monitor_ids. If one of them gives up, the others stop (releasing their
+/// triggers) and its error is returned, so that the service manager restarts all of them rather
+/// than leaving that monitor unwatched.
+pub fn run_group(zm_conf: &ZoneMinderConf, model_dir: Option<&Path>, args: RunArgs) -> Result<()> {
+    check_args(&args)?;
+    let workers = args
+        .inference_workers
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+    info!(
+        "Running monitors {:?} with {} inference workers",
+        args.monitor_ids, workers
+    );
+    let pool = InferencePool::new(workers);
+    let pool = &pool;
+    std::thread::scope(|scope| {
+        let runs: Vec<_> = args
+            .monitor_ids
+            .iter()
+            .enumerate()
+            .map(|(i, &monitor_id)| {
+                let mut args = RunArgs {
+                    monitor_id,
+                    ..args.clone()
+                };
+                if i > 0 {
+                    // the metrics are the process's, served and pushed once, for the first monitor
+                    args.instrumentation_address = None;
+                    args.pushgateway_url = None;
+                }
+                scope.spawn(move || {
+                    let result = crate::run(zm_conf, model_dir, args, Some(pool));
+                    match &result {
+                        Ok(()) => info!("{}: Done", monitor_id),
+                        Err(e) => {
+                            error!("{}: {:#}, stopping all monitors", monitor_id, e);
+                            STOPPING.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    result.map_err(|e| e.context(format!("Monitor {}", monitor_id)))
+                })
+            })
+            .collect();
+        // the first error, after all monitors stopped
+        runs.into_iter()
+            .map(|run| run.join().unwrap_or_else(|_| Err(anyhow!("Monitor thread panicked"))))
+            .fold(Ok(()), Result::and)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_inference_pool() {
+        let pool = InferencePool::new(2);
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let _slot = pool.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(*pool.free.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use opencv::core::{Mat, Rect, Size};
+use opencv::prelude::*;
+
+use zoneminder_aidect::zoneminder::zone::ZoneConfig;
+
+use crate::annotate;
+
+/// Pixels per heatmap cell, in both directions
+const CELL: i32 = 8;
+
+/// Where objects were detected, accumulated over time (at monitor resolution), to tune the zone
+/// polygon and MinArea against real traffic.
+pub struct Heatmap {
+    size: (i32, i32),
+    columns: i32,
+    rows: i32,
+    /// Detections covering each cell, row-major
+    counts: Vec<u32>,
+    /// Drawn on top
+    zone_config: ZoneConfig,
+}
+
+impl Heatmap {
+    pub fn new((width, height): (u32, u32), zone_config: ZoneConfig) -> Heatmap {
+        let (width, height) = (width as i32, height as i32);
+        let (columns, rows) = ((width + CELL - 1) / CELL, (height + CELL - 1) / CELL);
+        Heatmap {
+            size: (width, height),
+            columns,
+            rows,
+            counts: vec![0; (columns * rows) as usize],
+            zone_config,
+        }
+    }
+
+    pub fn add(&mut self, bounding_box: &Rect) {
+        let clamp = |v: i32, max: i32| v.max(0).min(max);
+        let (left, top) = (
+            clamp(bounding_box.x / CELL, self.columns),
+            clamp(bounding_box.y / CELL, self.rows),
+        );
+        let right = clamp((bounding_box.x + bounding_box.width + CELL - 1) / CELL, self.columns);
+        let bottom = clamp((bounding_box.y + bounding_box.height + CELL - 1) / CELL, self.rows);
+        for row in top..bottom {
+            for column in left..right {
+                self.counts[(row * self.columns + column) as usize] += 1;
+            }
+        }
+    }
+
+    /// RGB image at monitor resolution, from blue (nothing) to red (most detections), with the
+    /// zone and ignore zones drawn on it.
+    pub fn render(&self) -> Result<Mat> {
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1);
+        let levels: Vec<u8> = self
+            .counts
+            .iter()
+            .map(|&count| (count as u64 * 255 / max as u64) as u8)
+            .collect();
+        let cells = Mat::from_slice(&levels)?.reshape(1, self.rows)?;
+        let mut scaled = Mat::default();
+        opencv::imgproc::resize(
+            &cells,
+            &mut scaled,
+            Size::new(self.size.0, self.size.1),
+            0.0,
+            0.0,
+            opencv::imgproc::INTER_NEAREST,
+        )?;
+        let mut colored = Mat::default();
+        opencv::imgproc::apply_color_map(&scaled, &mut colored, opencv::imgproc::COLORMAP_JET)?;
+        let mut image = Mat::default();
+        opencv::imgproc::cvt_color(&colored, &mut image, opencv::imgproc::COLOR_BGR2RGB, 0)?;
+        annotate::draw_zones(&mut image, &self.zone_config)?;
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let mut heatmap = Heatmap::new((64, 32), ZoneConfig::parse("aidect", "0,0 64,0 64,32 0,32"));
+        assert_eq!((heatmap.columns, heatmap.rows), (8, 4));
+        heatmap.add(&Rect::new(4, 4, 8, 8));
+        // partially covered cells count as well
+        assert_eq!(&heatmap.counts[0..3], &[1, 1, 0]);
+        assert_eq!(&heatmap.counts[8..11], &[1, 1, 0]);
+        assert_eq!(heatmap.counts.iter().sum::<u32>(), 4);
+        // clipped to the image
+        heatmap.add(&Rect::new(56, 24, 100, 100));
+        assert_eq!(heatmap.counts[31], 1);
+        assert_eq!(heatmap.counts.iter().sum::<u32>(), 5);
+    }
+}
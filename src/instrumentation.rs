@@ -1,16 +1,82 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
+use log::{debug, warn};
 use prometheus::{
-    register_counter, register_gauge, register_histogram, Counter, Encoder, Gauge, Histogram,
-    TextEncoder, DEFAULT_BUCKETS,
+    register_counter_vec, register_gauge, register_gauge_vec, register_histogram_vec, CounterVec,
+    Encoder, Gauge, GaugeVec, HistogramVec, TextEncoder,
 };
 
+use crate::annotate;
+use crate::heatmap::Heatmap;
+
+/// Bucket boundaries of the latency histograms in ms, covering CPU inference at small sizes
+/// (tens of ms) up to large ones which can't keep up with more than one fps
+pub const DEFAULT_LATENCY_BUCKETS: &[f64] = &[
+    5.0, 10.0, 20.0, 30.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0, 500.0, 1000.0, 2000.0,
+];
+
+static LATENCY_BUCKETS: OnceLock<Vec<f64>> = OnceLock::new();
+
+/// Sets the bucket boundaries of the latency histograms (run --latency-buckets). Has to be called
+/// before they are first used, later calls are ignored.
+pub fn set_latency_buckets(buckets: Vec<f64>) -> Result<()> {
+    check_buckets(&buckets)?;
+    let _ = LATENCY_BUCKETS.set(buckets);
+    Ok(())
+}
+
+fn check_buckets(buckets: &[f64]) -> Result<()> {
+    if buckets.is_empty() {
+        return Err(anyhow!("No latency buckets given"));
+    }
+    if buckets.iter().any(|bucket| !bucket.is_finite() || *bucket <= 0.0) {
+        return Err(anyhow!("Latency buckets must be positive"));
+    }
+    if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(anyhow!("Latency buckets must be in increasing order"));
+    }
+    Ok(())
+}
+
+fn latency_buckets() -> Vec<f64> {
+    LATENCY_BUCKETS
+        .get()
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_LATENCY_BUCKETS.to_vec())
+}
+
+// Everything of run is by monitor, several can run in one process (run with several monitor IDs)
 lazy_static! {
-    // DEFAULT_BUCKETS are a good fit here actually.
-    pub static ref INFERENCE_DURATION: Histogram = register_histogram!("inference_duration", "Duration of ML inference in ms", DEFAULT_BUCKETS[0..].into()).unwrap();
-    pub static ref INFERENCES: Counter = register_counter!("inferences", "Number of ML inferences").unwrap();
-    pub static ref FPS: Gauge = register_gauge!("fps", "Current fps").unwrap();
-    pub static ref FPS_DEVIATION: Gauge = register_gauge!("fps_deviation", "Current deviation from configured fps (positive=faster, negative=slower)").unwrap();
-    pub static ref SIZE: Gauge = register_gauge!("size", "ML network input size").unwrap();
+    pub static ref INFERENCE_DURATION: HistogramVec = register_histogram_vec!("inference_duration", "Duration of ML inference in ms", &["monitor"], latency_buckets()).unwrap();
+    pub static ref LOOP_DURATION: HistogramVec = register_histogram_vec!("loop_duration", "Time from capturing a frame to the end of its analysis (waiting for inference, inference, postprocessing) in ms", &["monitor"], latency_buckets()).unwrap();
+    pub static ref INFERENCES: CounterVec = register_counter_vec!("inferences", "Number of ML inferences", &["monitor"]).unwrap();
+    pub static ref FPS: GaugeVec = register_gauge_vec!("fps", "Current fps", &["monitor"]).unwrap();
+    pub static ref FPS_DEVIATION: GaugeVec = register_gauge_vec!("fps_deviation", "Current deviation from configured fps (positive=faster, negative=slower)", &["monitor"]).unwrap();
+    pub static ref PACING_JITTER: HistogramVec = register_histogram_vec!("pacing_jitter", "How late frames were captured relative to their schedule in ms", &["monitor"], vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0]).unwrap();
+    pub static ref PACING_OVERRUNS: CounterVec = register_counter_vec!("pacing_overruns", "Frames which took longer than the frame interval", &["monitor"]).unwrap();
+    pub static ref INFERENCE_FPS: GaugeVec = register_gauge_vec!("inference_fps", "Current inference rate limit, lower while idle with IdleFPS", &["monitor"]).unwrap();
+    pub static ref FRAMES_DROPPED: CounterVec = register_counter_vec!("frames_dropped", "Captured frames skipped because analysis was busy", &["monitor"]).unwrap();
+    pub static ref WATCHDOG_EXPIRED: CounterVec = register_counter_vec!("watchdog_expired", "Times the watchdog expired", &["monitor"]).unwrap();
+    pub static ref PIPELINE_RESTARTS: CounterVec = register_counter_vec!("pipeline_restarts", "Reconnects after the pipeline failed or got stuck, by stage (capture, inference, database, reconnect)", &["monitor", "stage"]).unwrap();
+    pub static ref AUDIT_SAMPLES: CounterVec = register_counter_vec!("audit_samples", "Frames without detections saved for the accuracy audit", &["monitor"]).unwrap();
+    pub static ref AUDIT_CHECKED: Gauge = register_gauge!("audit_checked", "Audit samples checked with the audit model").unwrap();
+    pub static ref AUDIT_MISSES: Gauge = register_gauge!("audit_misses", "Audit samples in which the audit model detected something").unwrap();
+    pub static ref AUDIT_MISS_RATE: Gauge = register_gauge!("audit_miss_rate", "Fraction of audit samples in which the audit model detected something").unwrap();
+    pub static ref NEAR_MISS_CONFIDENCE: HistogramVec = register_histogram_vec!("near_miss_confidence", "Confidence of the most confident detection below Threshold in sampled frames without detections (--near-misses), by class", &["monitor", "class"], vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9]).unwrap();
+    pub static ref STATIONARY_SUPPRESSED: CounterVec = register_counter_vec!("stationary_suppressed", "Detections ignored because the object stayed put for Stationary=MINUTES", &["monitor"]).unwrap();
+    pub static ref DETECTIONS_REJECTED: CounterVec = register_counter_vec!("detections_rejected", "Detections above Threshold rejected by a zone rule, by filter (class, min_area, area_pct, schedule, aspect, margin, overlap, ignore_zone, texture)", &["monitor", "filter"]).unwrap();
+    pub static ref SIZE: GaugeVec = register_gauge_vec!("size", "ML network input size", &["monitor"]).unwrap();
+
+    pub static ref EVENTS_TRACKED: CounterVec = register_counter_vec!("events_tracked", "Number of events detections were coalesced for", &["monitor"]).unwrap();
+    pub static ref DETECTIONS_PER_EVENT: HistogramVec = register_histogram_vec!("detections_per_event", "Number of detections coalesced per event", &["monitor"], vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0]).unwrap();
+    pub static ref EVENT_FLUSHES: CounterVec = register_counter_vec!("event_flushes", "Coalesced events flushed, by reason (idle, new_event, paused)", &["monitor", "reason"]).unwrap();
+    pub static ref TRIGGERS_SUPPRESSED: CounterVec = register_counter_vec!("triggers_suppressed", "Triggers suppressed by --max-triggers-per-minute", &["monitor"]).unwrap();
+    pub static ref NOTES_UPDATES: CounterVec = register_counter_vec!("event_notes_updates", "Event notes updates, by result (success, failure)", &["monitor", "result"]).unwrap();
 }
 
 fn collect() -> String {
@@ -21,12 +87,91 @@ fn collect() -> String {
     String::from_utf8(buffer.clone()).unwrap()
 }
 
-pub fn spawn_prometheus_client(address: String, port: u16) {
+fn render_heatmap(heatmap: &Mutex<Heatmap>) -> Result<Vec<u8>> {
+    let image = heatmap.lock().unwrap().render()?;
+    annotate::encode_png(&image)
+}
+
+/// Serves the metrics, and the heatmap (if any) at /heatmap.png.
+pub fn spawn_prometheus_client(address: String, port: u16, heatmap: Option<Arc<Mutex<Heatmap>>>) {
     std::thread::spawn(move || {
         let server = tiny_http::Server::http((address, port)).unwrap();
         for request in server.incoming_requests() {
-            let response = tiny_http::Response::from_string(collect());
+            let response = match &heatmap {
+                Some(heatmap) if request.url() == "/heatmap.png" => match render_heatmap(heatmap) {
+                    Ok(png) => tiny_http::Response::from_data(png).with_header(
+                        "Content-Type: image/png".parse::<tiny_http::Header>().unwrap(),
+                    ),
+                    Err(e) => tiny_http::Response::from_string(format!("{:#}", e)).with_status_code(500),
+                },
+                _ => tiny_http::Response::from_string(collect()),
+            };
             let _ = request.respond(response);
         }
     });
 }
+
+/// Grouping key path of the metrics on a Pushgateway at url. The metrics have a monitor label of
+/// their own, the group is the process, named after its (first) monitor.
+fn push_url(url: &str, job: &str, monitor_id: u32) -> Result<String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_-.".contains(c);
+    if job.is_empty() || !job.chars().all(valid) {
+        return Err(anyhow!("Invalid Pushgateway job name {:?}, use letters, digits, _, - and .", job));
+    }
+    Ok(format!("{}/metrics/job/{}/instance/{}", url.trim_end_matches('/'), job, monitor_id))
+}
+
+fn push(url: &str) -> Result<()> {
+    let mut child = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--max-time", "10", "--request", "PUT"])
+        .args(["--header", "Content-Type: text/plain; version=0.0.4", "--data-binary", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(collect().as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Pushes the metrics to a Prometheus Pushgateway every interval, for hosts Prometheus can't
+/// scrape. PUT replaces the whole group (job and instance), so that metrics don't go stale there.
+pub fn spawn_pushgateway(url: &str, job: &str, monitor_id: u32, interval: Duration) -> Result<()> {
+    let url = push_url(url, job, monitor_id)?;
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        match push(&url) {
+            Ok(()) => debug!("{}: Pushed metrics to {}", monitor_id, url),
+            Err(e) => warn!("{}: Failed to push metrics to {}: {:#}", monitor_id, url, e),
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_buckets() {
+        assert!(check_buckets(DEFAULT_LATENCY_BUCKETS).is_ok());
+        assert!(check_buckets(&[]).is_err());
+        assert!(check_buckets(&[0.0, 10.0]).is_err());
+        assert!(check_buckets(&[10.0, 10.0]).is_err());
+        assert!(check_buckets(&[20.0, 10.0]).is_err());
+    }
+
+    #[test]
+    fn test_push_url() {
+        assert_eq!(
+            push_url("http://push:9091/", "zm-aidect", 3).unwrap(),
+            "http://push:9091/metrics/job/zm-aidect/instance/3"
+        );
+        assert!(push_url("http://push:9091", "a/b", 3).is_err());
+        assert!(push_url("http://push:9091", "", 3).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+//! zm-aidect as a library, for use by other programs.
+//!
+//! - [zoneminder]: read images straight from a monitor's shared memory, trigger events and read
+//!   monitor, zone and event configuration.
+//! - [detect]: run object detection within an aidect zone, on live images ([detect::Detector])
+//!   or on event recordings ([detect::EventAnalyzer]).
+//!
+//! Everything but triggering and configuration needs the (default) `detect` feature, which
+//! pulls in OpenCV.
+
+#[cfg(feature = "detect")]
+pub mod detect;
+pub mod error;
+#[cfg(feature = "detect")]
+pub mod ml;
+#[cfg(feature = "detect")]
+pub mod report;
+#[cfg(feature = "detect")]
+pub mod vio;
+pub mod zoneminder;
@@ -1,29 +1,85 @@
-use std::collections::HashMap;
-use std::env;
-use std::sync::mpsc;
-use std::time::{Duration, Instant};
-
-use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "detect")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "detect")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "detect")]
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(feature = "detect")]
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "detect")]
+use anyhow::anyhow;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use lazy_static::lazy_static;
+#[cfg(feature = "detect")]
+use clap::Args;
+#[cfg(feature = "detect")]
 use log::{debug, error, info, warn};
+#[cfg(feature = "detect")]
 use opencv::core::{Mat, MatTraitConst, Rect};
-use simple_moving_average::SMA;
-
-use crate::ml::Detection;
-use crate::zoneminder::db::Bounding;
-use crate::zoneminder::{MonitorTrait};
-
+#[cfg(feature = "detect")]
+use pacemaker::{Activity, Pacemaker, RateLimiter, RealtimePacemaker, TokenBucket};
+#[cfg(feature = "detect")]
+use zoneminder_aidect::zoneminder::zone::Bounding;
+use zoneminder_aidect::zoneminder::{self, MonitorTrait};
+
+#[cfg(feature = "detect")]
+use zoneminder_aidect::error::Error as ErrorKind;
+#[cfg(feature = "detect")]
+use zoneminder_aidect::ml::Detection;
+#[cfg(feature = "detect")]
+use zoneminder_aidect::{detect, ml, report, vio};
+
+#[cfg(feature = "detect")]
+mod alarm;
+#[cfg(feature = "detect")]
+mod annotate;
+#[cfg(feature = "detect")]
+mod audit;
+#[cfg(feature = "detect")]
+mod cause;
+#[cfg(feature = "detect")]
+mod dedup;
+mod doctor;
+#[cfg(feature = "detect")]
+mod group;
+#[cfg(feature = "detect")]
+mod heatmap;
+#[cfg(feature = "detect")]
 mod instrumentation;
-mod ml;
-mod vio;
-mod zoneminder;
+#[cfg(feature = "detect")]
+mod models;
+#[cfg(feature = "detect")]
+mod notify;
+#[cfg(feature = "detect")]
+mod plates;
+#[cfg(feature = "detect")]
+mod render;
+#[cfg(feature = "detect")]
+mod replay;
+#[cfg(feature = "detect")]
+mod stationary;
+#[cfg(feature = "telegram")]
+mod telegram;
+#[cfg(feature = "detect")]
+mod trace;
+#[cfg(feature = "detect")]
+mod upload;
+#[cfg(feature = "detect")]
+mod validate;
+#[cfg(feature = "detect")]
+mod zones;
+#[cfg(feature = "detect")]
+mod verify;
+#[cfg(feature = "detect")]
+mod writes;
 
 // TODO: Heed analysis images setting in ZM and generate those from within zm-aidect (sparsely, only for frames actually analyzed, not sure if the DB schema allows for that)
 
 #[derive(Parser, Debug)]
 #[clap(disable_help_subcommand = true)]
-struct Args {
+struct Cli {
     #[clap(
         long,
         short = 'v',
@@ -33,26 +89,72 @@ struct Args {
     )]
     verbose: usize,
 
+    /// ZoneMinder configuration directory with zm.conf and conf.d [default: detected, usually /etc/zm]
+    #[clap(long, global = true, env = "ZM_AIDECT_CONF_DIR")]
+    zm_conf_dir: Option<PathBuf>,
+
+    /// Directory with ZoneMinder's perl modules (ZoneMinder/Memory.pm) [default: detected]
+    #[clap(long, global = true, env = "ZM_AIDECT_PERL_LIB")]
+    zm_perl_lib: Option<PathBuf>,
+
+    /// File with ZM_API_URL, ZM_API_USER and ZM_API_PASS (which can also be set in the environment),
+    /// to query monitors and zones and update event notes through ZoneMinder's API instead of the
+    /// database
+    #[clap(long, global = true, env = "ZM_AIDECT_API_CONFIG")]
+    zm_api_config: Option<PathBuf>,
+
+    /// Exit on unknown keys and values which don't parse in the names of aidect zones, instead of
+    /// warning and ignoring them
+    #[clap(long, global = true, env = "ZM_AIDECT_STRICT_ZONES")]
+    strict_zones: bool,
+
+    /// Directory with yolov4-tiny.weights and yolov4-tiny.cfg (or yolov5.onnx) [default: next to the
+    /// executable, XDG data directories (e.g. /usr/share/zm-aidect) or the current directory]
+    #[cfg(feature = "detect")]
+    #[clap(long, global = true, env = "ZM_AIDECT_MODEL_DIR")]
+    model_dir: Option<PathBuf>,
+
     #[clap(subcommand)]
     mode: Mode,
 }
 
 #[derive(Subcommand, Debug)]
 enum Mode {
-    Run {
-        /// Zoneminder monitor ID
-        #[clap(value_parser)]
-        monitor_id: u32,
-        #[clap(long)]
-        instrumentation_address: Option<String>,
-        #[clap(long, default_value_t = 9000)]
-        instrumentation_port: u16,
-    },
+    #[cfg(feature = "detect")]
+    Run(RunArgs),
+    #[cfg(feature = "detect")]
     Test {
         /// Zoneminder monitor ID
         #[clap(value_parser)]
         monitor_id: u32,
+
+        /// Analyze this video file (e.g. a problematic clip) with the monitor's zone configuration
+        /// instead of grabbing images, and don't trigger an event
+        #[clap(long)]
+        file: Option<PathBuf>,
+
+        /// Write the grabbed images with zone and detections drawn on them into this directory
+        #[clap(long)]
+        save_images: Option<PathBuf>,
+
+        /// Run the model at this precision instead of the zone's Precision, to compare inference times
+        #[clap(long, value_parser = ["fp32", "fp16", "int8"])]
+        precision: Option<String>,
+
+        /// Also print up to this many of the most confident detections below Threshold per image
+        #[clap(long, default_value_t = 3)]
+        candidates: usize,
+
+        /// Write the model's raw output rows (before Threshold and NMS) into this file, a JSON line
+        /// per frame, e.g. to check the input scaling of a converted model
+        #[clap(long)]
+        dump_raw: Option<PathBuf>,
+
+        /// Only dump these analyzed frames, counted from 0 (default: all)
+        #[clap(long, use_value_delimiter = true)]
+        dump_frames: Vec<usize>,
     },
+    #[cfg(feature = "detect")]
     Event {
         /// Zoneminder event ID to check for detections
         #[clap(value_parser)]
@@ -61,13 +163,506 @@ enum Mode {
         /// Zoneminder monitor ID for the zone configuration
         #[clap(long, short = 'm')]
         monitor_id: Option<u32>,
+
+        /// Print human-readable lines or a JSON document for scripts
+        #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+        output: String,
+
+        /// Write a JSON line per analyzed frame (timings and detections) into this file
+        #[clap(long)]
+        trace: Option<PathBuf>,
+
+        /// Re-encode the recording with detections, labels and timestamps burned in into this
+        /// file (e.g. out.mp4), with ffmpeg
+        #[clap(long)]
+        render: Option<PathBuf>,
+
+        /// Write the model's raw output rows (before Threshold and NMS) into this file, a JSON line
+        /// per frame, e.g. to check the input scaling of a converted model
+        #[clap(long)]
+        dump_raw: Option<PathBuf>,
+
+        /// Only dump these analyzed frames, counted from 0 (default: all)
+        #[clap(long, use_value_delimiter = true)]
+        dump_frames: Vec<usize>,
+    },
+    /// Analyze still images with a monitor's zone configuration
+    #[cfg(feature = "detect")]
+    Image {
+        /// Image files (JPEG, PNG, ...)
+        #[clap(value_parser, required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Zoneminder monitor ID for the zone configuration
+        #[clap(long, short = 'm')]
+        monitor_id: u32,
+
+        /// Print human-readable lines or a JSON document for scripts
+        #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+        output: String,
+
+        /// Write the images with zone and detections drawn on them into this directory
+        #[clap(long)]
+        save_images: Option<PathBuf>,
+    },
+    /// Run a recorded clip through what `run` does with the monitor's frames, printing the events
+    /// it would trigger instead of triggering them
+    #[cfg(feature = "detect")]
+    Replay {
+        /// Video file, e.g. an event recording or a clip of an incident
+        #[clap(long)]
+        file: PathBuf,
+
+        /// Zoneminder monitor ID for the zone configuration
+        #[clap(long, short = 'm')]
+        monitor_id: u32,
+
+        /// Analyze as fast as possible instead of at the speed the monitor would be analyzed at
+        #[clap(long)]
+        max_speed: bool,
+
+        /// Seconds an event of the mock monitor goes on after the last trigger
+        #[clap(long, default_value_t = 10.0, value_parser = parse_seconds)]
+        post_event: f32,
+    },
+    /// Analyze the recordings of many events of a monitor
+    #[cfg(feature = "detect")]
+    Backfill(BackfillArgs),
+    /// Run the model (e.g. a bigger one with --model-dir, at a larger --size) over the frames
+    /// `run --audit-dir` sampled, to estimate how many objects were missed
+    #[cfg(feature = "detect")]
+    Audit {
+        /// Directory with the samples
+        #[clap(value_parser)]
+        dir: PathBuf,
+        /// Network input size
+        #[clap(long, default_value_t = 416)]
+        size: u32,
+        /// Minimum confidence in percent
+        #[clap(long, default_value_t = 50)]
+        threshold: u32,
+        /// Print human-readable lines or a JSON document for scripts
+        #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+        output: String,
+        /// Also write the results as Prometheus metrics into this file (e.g. for node_exporter's
+        /// textfile collector)
+        #[clap(long)]
+        metrics_file: Option<PathBuf>,
+    },
+    /// Analyze the events ZoneMinder's motion detection records once they end, and tag or delete
+    /// those without detections
+    #[cfg(feature = "detect")]
+    Filter(FilterArgs),
+    /// Download or list the object detection models
+    #[cfg(feature = "detect")]
+    Model {
+        #[clap(subcommand)]
+        command: ModelCommand,
+    },
+    /// Show the settings `run` would pick up if it (re)started now, from the zones and the model
+    /// directory, and the problems it would run into, without running anything
+    #[cfg(feature = "detect")]
+    Validate {
+        /// Zoneminder monitor IDs, all monitors with an aidect zone if none are given
+        #[clap(value_parser)]
+        monitor_ids: Vec<u32>,
+
+        /// Print human-readable lines or a JSON document for scripts
+        #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+        output: String,
+    },
+    /// Check a monitor's zones, or put together the name of an aidect zone
+    #[cfg(feature = "detect")]
+    Zone {
+        #[clap(subcommand)]
+        command: ZoneCommand,
+    },
+    /// Keep track of which monitor triggered for what, so that monitors covering the same area
+    /// (run --dedup-socket) don't all trigger for the same object
+    #[cfg(feature = "detect")]
+    Dedup {
+        /// Unix socket to listen on
+        #[clap(long, default_value = "/run/zm-aidect/dedup.sock")]
+        socket: PathBuf,
+        /// Seconds after a monitor last saw an object until another monitor may trigger for it
+        #[clap(long, default_value_t = 10)]
+        window: u64,
+    },
+    /// Check the ZoneMinder configuration, database, shared memory, model and tools zm-aidect needs
+    Doctor,
+    /// Trigger an event on a monitor, e.g. on behalf of an external detection system
+    Trigger {
+        /// Zoneminder monitor ID
+        #[clap(value_parser)]
+        monitor_id: u32,
+        #[clap(long, default_value = "aidect")]
+        cause: String,
+        /// Alarm description
+        #[clap(long, default_value = "")]
+        text: String,
+        #[clap(long, default_value_t = 100)]
+        score: u32,
+        /// Set the notes of the triggered event
+        #[clap(long)]
+        notes: Option<String>,
+        /// Trigger through the monitor's shared memory, zmtrigger or the latter if the former fails
+        #[clap(long, default_value = "auto", value_parser = ["auto", "shm", "zmtrigger"])]
+        trigger_method: String,
+        /// Seconds to wait for ZoneMinder to go into alarm before giving up
+        #[clap(long, default_value_t = 5.0, value_parser = parse_seconds)]
+        trigger_timeout: f32,
     },
 }
 
-fn main() -> Result<()> {
-    env::set_current_dir(env::current_exe()?.parent().unwrap())?;
+#[cfg(feature = "detect")]
+#[derive(Subcommand, Debug)]
+enum ZoneCommand {
+    /// List the monitor's zones, with what zm-aidect makes of the aidect zones and the problems
+    /// in their names (unknown keys, values which don't parse)
+    List {
+        /// Zoneminder monitor ID
+        #[clap(value_parser)]
+        monitor_id: u32,
+    },
+    /// Print the name of an aidect zone with these settings, after checking them
+    Name {
+        /// Network input size
+        #[clap(long)]
+        size: Option<u32>,
+        /// Minimum confidence in percent
+        #[clap(long)]
+        threshold: Option<u32>,
+        #[clap(long)]
+        fps: Option<f32>,
+        #[clap(long)]
+        min_area: Option<u32>,
+        #[clap(long)]
+        min_hits: Option<u32>,
+        /// Monitor to trigger instead
+        #[clap(long)]
+        trigger: Option<u32>,
+        /// Any other key, e.g. --set Trigger.Car=5 --set Gate=zm-motion
+        #[clap(long, value_name = "KEY=VALUE")]
+        set: Vec<String>,
+    },
+}
+
+#[cfg(feature = "detect")]
+#[derive(Subcommand, Debug)]
+enum ModelCommand {
+    /// Download a model into --model-dir (default: where it's installed already,
+    /// or /usr/local/share/zm-aidect) and verify its checksums
+    Fetch {
+        #[clap(default_value = "yolov4-tiny")]
+        name: String,
+        /// Download again even if the files are installed and intact
+        #[clap(long)]
+        force: bool,
+    },
+    /// Show the known models and where they are installed
+    List,
+}
 
-    let args: Args = Args::parse();
+#[cfg(feature = "detect")]
+#[derive(Args, Debug)]
+struct BackfillArgs {
+    /// Zoneminder monitor ID whose events to analyze (also used for the zone configuration)
+    #[clap(value_parser)]
+    monitor_id: u32,
+    #[clap(long)]
+    first_event: Option<u64>,
+    #[clap(long)]
+    last_event: Option<u64>,
+    /// Only events which started at or after this (local) time, e.g. "2022-07-01 00:00:00"
+    #[clap(long)]
+    since: Option<String>,
+    /// Only events which started at or before this (local) time
+    #[clap(long)]
+    until: Option<String>,
+    /// Write the best detection into the notes of events where something was found,
+    /// and raise their max score accordingly
+    #[clap(long)]
+    update: bool,
+    /// Remember progress in this file, so that an interrupted backfill continues where it left off
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "detect")]
+#[derive(Args, Debug)]
+struct FilterArgs {
+    /// Zoneminder monitor ID whose events to filter (also used for the zone configuration)
+    #[clap(value_parser)]
+    monitor_id: u32,
+    /// Delete events without detections, instead of setting their notes
+    #[clap(long)]
+    delete: bool,
+    /// Notes for events without detections
+    #[clap(long, default_value = "aidect: no objects")]
+    notes: String,
+    /// Seconds to wait after an event ended before analyzing it, so its video file is complete
+    #[clap(long, default_value_t = 10)]
+    settle: u64,
+    /// Start with the events after this one [default: only events from now on]
+    #[clap(long)]
+    after_event: Option<u64>,
+    /// Remember progress in this file, so that no event is skipped across restarts
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "detect")]
+#[derive(Args, Debug, Clone)]
+struct RunArgs {
+    /// Zoneminder monitor ID(s). Several monitors run in one process, sharing --inference-workers
+    #[clap(value_parser, required = true)]
+    monitor_ids: Vec<u32>,
+    /// The monitor this run is for, one of monitor_ids
+    #[clap(skip)]
+    monitor_id: u32,
+    /// With several monitors, run at most this many inferences at the same time [default: number
+    /// of CPUs]
+    #[clap(long)]
+    inference_workers: Option<usize>,
+    #[clap(long)]
+    instrumentation_address: Option<String>,
+    #[clap(long, default_value_t = 9000)]
+    instrumentation_port: u16,
+    /// Push the metrics to this Prometheus Pushgateway (e.g. http://pushgateway:9091), for hosts
+    /// Prometheus can't scrape
+    #[clap(long, env = "ZM_AIDECT_PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+    /// Job label of the pushed metrics, the monitor label is the monitor ID
+    #[clap(long, default_value = "zm-aidect")]
+    pushgateway_job: String,
+    /// Push this often
+    #[clap(long, value_name = "SECS", default_value_t = 15)]
+    pushgateway_interval: u64,
+    /// Bucket boundaries (ms) of the inference_duration and loop_duration histograms
+    #[clap(long, env = "ZM_AIDECT_LATENCY_BUCKETS", use_value_delimiter = true)]
+    latency_buckets: Vec<f64>,
+
+    /// Command to run (using sh -c) when an object is detected
+    #[clap(long)]
+    alarm_command: Option<String>,
+    /// GPIO value file driven high when an object is detected, e.g. /sys/class/gpio/gpio17/value
+    #[clap(long)]
+    alarm_gpio: Option<PathBuf>,
+    /// Classes sounding the alarm (default: all)
+    #[clap(long, use_value_delimiter = true)]
+    alarm_classes: Vec<i32>,
+    /// Seconds the alarm GPIO is held high
+    #[clap(long, default_value_t = 10)]
+    alarm_duration: u64,
+    /// Minimum seconds between alarms
+    #[clap(long, default_value_t = 60)]
+    alarm_debounce: u64,
+
+    /// Capture and analyze, but never trigger events, write event notes or sound alarms
+    #[clap(long)]
+    dry_run: bool,
+    /// Run only in some of ZoneMinder's run states: "Home=off" pauses while Home is the active
+    /// state, "Away=on,Night=on" runs only in Away and Night
+    #[clap(long, value_name = "STATE=on|off", use_value_delimiter = true)]
+    run_states: Vec<String>,
+
+    /// After stalls, also analyze up to this many frames which were missed (newest first)
+    #[clap(long, default_value_t = 0)]
+    backlog: usize,
+
+    /// Trigger through the monitor's shared memory, zmtrigger or the latter if the former fails
+    #[clap(long, default_value = "auto", value_parser = ["auto", "shm", "zmtrigger"])]
+    trigger_method: String,
+    /// Seconds to wait for ZoneMinder to go into alarm after triggering
+    #[clap(long, default_value_t = 5.0, value_parser = parse_seconds)]
+    trigger_timeout: f32,
+    /// If ZoneMinder doesn't go into alarm in time: trigger once more, give up, or trigger once
+    /// more through zmtrigger
+    #[clap(long, default_value = "skip", value_parser = ["retry", "skip", "zmtrigger"])]
+    trigger_timeout_policy: String,
+    /// Also trigger the monitors which have a trigger monitor among their Linked Monitors in
+    /// ZoneMinder, like ZoneMinder's own motion detection would
+    #[clap(long)]
+    linked_monitors: bool,
+    /// Cause of the events zm-aidect triggers. {monitor}, {monitor_id}, {zone}, {class}, {score}
+    /// and {description} are replaced, ZoneMinder keeps the first 31 characters
+    #[clap(long, default_value = "aidect")]
+    cause: String,
+    /// Alarm description of the events zm-aidect triggers, with the same placeholders as --cause
+    #[clap(long, default_value = "{description}")]
+    description: String,
+
+    /// Analyze this stream (e.g. the camera's low resolution RTSP substream) instead of the monitor's images,
+    /// zones and detections are mapped between its resolution and the monitor's
+    #[clap(long)]
+    source_url: Option<String>,
+
+    /// When an event ends, set its MaxScore/AvgScore from the confidences of all detections during it
+    #[clap(long)]
+    rescore: bool,
+
+    /// Show the current detection (e.g. "Human 91%") in place of %Q in the monitor's timestamp label,
+    /// so that it's burned into the recording
+    #[clap(long)]
+    show_text: bool,
+
+    /// Add the bounding boxes of detections to ZoneMinder's Stats table, so that the event view
+    /// shows where the objects were
+    #[clap(long)]
+    stats: bool,
+
+    /// POST a JSON document to this URL for every event zm-aidect triggers
+    #[clap(long)]
+    webhook_url: Option<String>,
+    /// Send the frame with the detection along (as multipart/form-data with the JSON in "metadata"
+    /// and a JPEG in "image")
+    #[clap(long)]
+    webhook_image: bool,
+    /// Instead of one request per event, send a summary (counts per class, the best detection and its frame)
+    /// of everything detected within this many seconds
+    #[clap(long)]
+    webhook_digest: Option<u64>,
+
+    /// Blur the heads of people in the frames sent out (webhook, Telegram, S3, plate crops), not in
+    /// ZoneMinder's recordings
+    #[clap(long)]
+    blur_heads: bool,
+    /// The top fraction of a person's bounding box taken as the head by --blur-heads
+    #[clap(long, value_name = "FRACTION", default_value_t = 0.25)]
+    head_fraction: f32,
+
+    /// Upload the frame of every event zm-aidect triggers, with the detection drawn on it, to this
+    /// S3-compatible bucket (path style URL, e.g. https://minio.example.com/evidence) as
+    /// MONITOR/YYYY-MM-DD/EVENT.jpg
+    #[clap(long)]
+    s3_url: Option<String>,
+    #[clap(long, default_value = "us-east-1")]
+    s3_region: String,
+    /// Better set in the environment, which doesn't show up in the process list
+    #[clap(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    s3_access_key: Option<String>,
+    #[clap(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    s3_secret_key: Option<String>,
+
+    /// Write crops of cars in "aidect-plates" zones to this directory, as MONITOR-UNIXMS.jpg, e.g. for
+    /// an ALPR service watching it
+    #[clap(long)]
+    plate_dir: Option<PathBuf>,
+    /// Run this command (using sh -c) with each crop of a car in an "aidect-plates" zone as a JPEG
+    /// on stdin
+    #[clap(long)]
+    plate_command: Option<String>,
+    /// Export at most one crop this often
+    #[clap(long, value_name = "SECS", default_value_t = 1.0)]
+    plate_interval: f32,
+
+    /// Send the frame and a caption for every event zm-aidect triggers to a Telegram chat, through
+    /// the bot with this token
+    #[cfg(feature = "telegram")]
+    #[clap(long, env = "ZM_AIDECT_TELEGRAM_TOKEN")]
+    telegram_token: Option<String>,
+    /// Chat ID to send to (for a group, the bot has to be a member)
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    telegram_chat: Option<String>,
+    /// Only send events of these classes (comma-separated names or IDs) [default: all]
+    #[cfg(feature = "telegram")]
+    #[clap(long, use_value_delimiter = true)]
+    telegram_classes: Vec<String>,
+    /// Send at most one message this often, events in between are counted in the next one
+    #[cfg(feature = "telegram")]
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    telegram_interval: u64,
+
+    /// OpenCV threads used for inference, overrides the Threads zone key [default: 1]
+    #[clap(long)]
+    threads: Option<u32>,
+    /// Pin zm-aidect to these CPUs, e.g. "4-7" or "2,3", overrides the CPUs zone key
+    #[clap(long)]
+    cpus: Option<String>,
+
+    /// Write a JSON line per analyzed frame (capture time, shared memory slot, timings and detections)
+    /// into this file
+    #[clap(long)]
+    trace: Option<PathBuf>,
+
+    /// Before triggering, ask the `zm-aidect dedup` listening on this socket whether another monitor
+    /// of the same DedupGroup already triggered for the object
+    #[clap(long)]
+    dedup_socket: Option<PathBuf>,
+    /// What to do with duplicates: not trigger at all, or trigger and mention the other monitor's event
+    #[clap(long, default_value = "suppress", value_parser = ["suppress", "link"])]
+    dedup_mode: String,
+
+    /// Accumulate a heatmap of where objects are detected, served as /heatmap.png by the
+    /// instrumentation server
+    #[clap(long)]
+    heatmap: bool,
+    /// Write the heatmap to this PNG file every minute (implies --heatmap)
+    #[clap(long)]
+    heatmap_file: Option<PathBuf>,
+
+    /// Keep the objects learned for Stationary=MINUTES in this file, so that a parked car doesn't
+    /// trigger again after a restart
+    #[clap(long)]
+    stationary_file: Option<PathBuf>,
+
+    /// Trigger each trigger monitor into a new event at most this often on average, to protect
+    /// ZoneMinder from event storms (e.g. flickering IR lights). Suppressed triggers are counted in
+    /// triggers_suppressed
+    #[clap(long)]
+    max_triggers_per_minute: Option<f32>,
+    /// Triggers allowed in a row before --max-triggers-per-minute kicks in
+    #[clap(long, default_value_t = 10.0)]
+    trigger_burst: f32,
+
+    /// Treat an event starting within this many seconds of the last detection in the previous one
+    /// as the same incident (e.g. split at ZoneMinder's section length), and refer to each other in
+    /// their notes
+    #[clap(long, value_name = "SECS")]
+    continue_events: Option<u64>,
+    /// Also store classes, counts, the highest confidence and the times of the first and last
+    /// detection of every event as JSON: as the last line of its notes, or in the AidectEvents
+    /// table (created if needed)
+    #[clap(long, value_parser = ["notes", "table"])]
+    event_metadata: Option<String>,
+
+    /// Analyze the recording of every event zm-aidect triggers again this many seconds after it
+    /// started, and update its notes and score if the object is seen better later on
+    #[clap(long, value_name = "SECS")]
+    late_verify: Option<u64>,
+
+    /// Save (the analyzed part of) some of the frames in which nothing was detected into this
+    /// directory, for `zm-aidect audit`
+    #[clap(long)]
+    audit_dir: Option<PathBuf>,
+    /// Fraction of the frames without detections to save
+    #[clap(long, default_value_t = 0.01)]
+    audit_rate: f32,
+    /// Stop saving once the directory has this many samples
+    #[clap(long, default_value_t = 1000)]
+    audit_max: usize,
+
+    /// Every N analyzed frames in which nothing was detected, log the most confident detection below
+    /// Threshold (and count it in the near_miss_confidence metric), to see what is barely missed
+    #[clap(long, value_name = "N")]
+    near_misses: Option<u32>,
+    /// Also save these frames into this directory, with the near miss drawn on them
+    #[clap(long, value_name = "DIR")]
+    near_miss_dir: Option<PathBuf>,
+}
+
+/// A number of seconds, which Duration::from_secs_f32 can take
+fn parse_seconds(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(secs),
+        Ok(_) => Err("must be a number of seconds, 0 or more".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn main() -> Result<()> {
+    let args: Cli = Cli::parse();
     stderrlog::new()
         .module(module_path!())
         .verbosity(args.verbose + 1)
@@ -75,96 +670,580 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
 
+    // Doesn't need ZoneMinder at all, so it works before ZoneMinder is set up
+    #[cfg(feature = "detect")]
+    if let Mode::Model { command } = args.mode {
+        return model(args.model_dir.as_deref(), command);
+    }
+    #[cfg(feature = "detect")]
+    if let Mode::Zone {
+        command:
+            ZoneCommand::Name {
+                size,
+                threshold,
+                fps,
+                min_area,
+                min_hits,
+                trigger,
+                set,
+            },
+    } = args.mode
+    {
+        let items = [
+            size.map(|v| format!("Size={}", v)),
+            threshold.map(|v| format!("Threshold={}", v)),
+            fps.map(|v| format!("FPS={}", v)),
+            min_area.map(|v| format!("MinArea={}", v)),
+            min_hits.map(|v| format!("MinHits={}", v)),
+            trigger.map(|v| format!("Trigger={}", v)),
+        ];
+        let items: Vec<String> = items.into_iter().flatten().chain(set).collect();
+        return zones::name(&items);
+    }
+    #[cfg(feature = "detect")]
+    if let Mode::Dedup { socket, window } = args.mode {
+        return dedup::serve(&socket, Duration::from_secs(window));
+    }
+    #[cfg(feature = "detect")]
+    if let Mode::Audit {
+        dir,
+        size,
+        threshold,
+        output,
+        metrics_file,
+    } = args.mode
+    {
+        let options = ml::Options {
+            confidence_threshold: threshold as f32 / 100.0,
+            size,
+            ..ml::Options::default()
+        };
+        return audit(
+            &dir,
+            args.model_dir.as_deref(),
+            &options,
+            output == "json",
+            metrics_file.as_deref(),
+        );
+    }
+    // Reports problems with the configuration, instead of failing on the first one
+    if let Mode::Doctor = args.mode {
+        return doctor::doctor(
+            args.zm_conf_dir.as_deref(),
+            args.zm_perl_lib.as_deref(),
+            #[cfg(feature = "detect")]
+            args.model_dir.as_deref(),
+        );
+    }
+
+    let zm_conf = zoneminder::ZoneMinderConf::parse(
+        args.zm_conf_dir.as_deref(),
+        args.zm_perl_lib.as_deref(),
+    )?
+    .with_api(zoneminder::api::Api::from_config(args.zm_api_config.as_deref())?)
+    .with_strict_zones(args.strict_zones);
+    #[cfg(feature = "detect")]
+    let model_dir = args.model_dir.as_deref();
+
     match args.mode {
-        Mode::Run { monitor_id, instrumentation_address, instrumentation_port } => run(monitor_id, instrumentation_address, instrumentation_port),
-        Mode::Test { monitor_id } => test(monitor_id),
+        #[cfg(feature = "detect")]
+        Mode::Run(args) if args.monitor_ids.len() > 1 => group::run_group(&zm_conf, model_dir, args),
+        #[cfg(feature = "detect")]
+        Mode::Run(args) => {
+            let args = RunArgs {
+                monitor_id: args.monitor_ids[0],
+                ..args
+            };
+            run(&zm_conf, model_dir, args, None)
+        }
+        #[cfg(feature = "detect")]
+        Mode::Test {
+            monitor_id,
+            file,
+            save_images,
+            precision,
+            candidates,
+            dump_raw,
+            dump_frames,
+        } => {
+            let dump_raw = dump_raw.map(|path| (path, dump_frames));
+            test(&zm_conf, model_dir, monitor_id, file, save_images, precision, candidates, dump_raw)
+        }
+        #[cfg(feature = "detect")]
         Mode::Event {
             event_id,
             monitor_id,
-        } => event(event_id, monitor_id),
+            output,
+            trace,
+            render,
+            dump_raw,
+            dump_frames,
+        } => {
+            let dump_raw = dump_raw.map(|path| (path, dump_frames));
+            event(&zm_conf, model_dir, event_id, monitor_id, output == "json", trace, render, dump_raw)
+        }
+        #[cfg(feature = "detect")]
+        Mode::Image {
+            paths,
+            monitor_id,
+            output,
+            save_images,
+        } => image(&zm_conf, model_dir, &paths, monitor_id, output == "json", save_images),
+        #[cfg(feature = "detect")]
+        Mode::Replay {
+            file,
+            monitor_id,
+            max_speed,
+            post_event,
+        } => replay::replay(
+            &zm_conf,
+            model_dir,
+            &file,
+            monitor_id,
+            max_speed,
+            Duration::from_secs_f32(post_event),
+        ),
+        #[cfg(feature = "detect")]
+        Mode::Backfill(args) => backfill(&zm_conf, model_dir, args),
+        #[cfg(feature = "detect")]
+        Mode::Filter(args) => filter(&zm_conf, model_dir, args),
+        #[cfg(feature = "detect")]
+        Mode::Validate { monitor_ids, output } => {
+            validate::validate(&zm_conf, model_dir, &monitor_ids, output == "json")
+        }
+        #[cfg(feature = "detect")]
+        Mode::Zone {
+            command: ZoneCommand::List { monitor_id },
+        } => zones::list(&zm_conf, monitor_id),
+        #[cfg(feature = "detect")]
+        Mode::Zone { .. } => unreachable!(),
+        #[cfg(feature = "detect")]
+        Mode::Model { .. } => unreachable!(),
+        #[cfg(feature = "detect")]
+        Mode::Dedup { .. } => unreachable!(),
+        #[cfg(feature = "detect")]
+        Mode::Audit { .. } => unreachable!(),
+        Mode::Doctor => unreachable!(),
+        Mode::Trigger {
+            monitor_id,
+            cause,
+            text,
+            score,
+            notes,
+            trigger_method,
+            trigger_timeout,
+        } => trigger_event(
+            &zm_conf,
+            monitor_id,
+            &cause,
+            &text,
+            score,
+            notes,
+            &trigger_method,
+            trigger_timeout,
+        ),
     }
 }
 
-fn event(event_id: u64, monitor_id: Option<u32>) -> Result<()> {
-    let zm_conf = zoneminder::ZoneMinderConf::parse_default()?;
-    let event = zoneminder::db::Event::query(&zm_conf, event_id)?;
+fn trigger_event(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor_id: u32,
+    cause: &str,
+    text: &str,
+    score: u32,
+    notes: Option<String>,
+    trigger_method: &str,
+    trigger_timeout: f32,
+) -> Result<()> {
+    let mut monitor = zoneminder::Monitor::connect(zm_conf, monitor_id)?;
+    monitor.set_trigger_method(trigger_method.try_into()?);
+    monitor.set_trigger_timeout(Duration::from_secs_f32(trigger_timeout));
+    let event_id = monitor
+        .trigger(cause, text, score)
+        .with_context(|| format!("Failed to trigger monitor ID {}", monitor_id))?;
+    if let Some(notes) = notes {
+        zoneminder::db::update_event_notes(zm_conf, event_id, &notes)?;
+    }
+    println!("{}", event_id);
+    Ok(())
+}
+
+#[cfg(feature = "detect")]
+fn event(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    event_id: u64,
+    monitor_id: Option<u32>,
+    json: bool,
+    trace: Option<PathBuf>,
+    render: Option<PathBuf>,
+    dump_raw: Option<(PathBuf, Vec<usize>)>,
+) -> Result<()> {
+    let event = zoneminder::db::Event::query(zm_conf, event_id)?;
+    let mut trace = trace.as_deref().map(trace::Trace::create).transpose()?;
     let monitor_id = monitor_id.unwrap_or(event.monitor_id);
-    let mut ctx = connect_zm(monitor_id, &zm_conf)?; // TODO: If this errors on "Error: No aidect zone found for monitor 6", suggest --monitor-id
+    let mut ctx = connect_zm(monitor_id, zm_conf, model_dir, false, None)?; // TODO: If this errors on "Error: No aidect zone found for monitor 6", suggest --monitor-id
+    if let Some((path, frames)) = dump_raw {
+        // analyzed frame n is n/FPS seconds into the recording
+        ctx.detector.dump_raw(detect::RawDump::create(&path, frames)?)?;
+    }
+
+    // Keep stdout clean for the JSON document
+    let note = |message: &str| {
+        if json {
+            eprintln!("{}", message)
+        } else {
+            println!("{}", message)
+        }
+    };
 
     let video_path = event.video_path()?;
-    println!("Analyzing video file {}", video_path.display());
+    note(&format!("Analyzing video file {}", video_path.display()));
     let props = vio::properties(&video_path)?;
 
-    if props.width != ctx.monitor_settings.width || props.height != ctx.monitor_settings.height {
-        println!("Note: Recording is from a different (higher?) resolution, so performance is not indicative due to rescaling");
+    let (width, height) = ctx.monitor_settings.image_dimensions();
+    if props.width != width || props.height != height {
+        note(&format!(
+            "Note: Recording is {}x{}, but the monitor {}x{}: zones are scaled to the recording, and timings aren't comparable",
+            props.width, props.height, width, height
+        ));
     }
 
-    println!("Note: Timestamps [mm:ss:ts] are at best a rough approximation.");
-    println!("Note: Because analysis start frames aren't aligned between what zm-aidect might have originally done,");
-    println!("      and this run, results can and will differ."); // TODO: This can be a good thing of course, but maybe add a way to analyse the logged alarm frames only or something like that
-
-    let mut inference_durations = vec![];
-    let mut videotime = Duration::default(); // EXTREMELY approximate
-    let timestep = Duration::from_secs_f32(1f32 / ctx.max_fps); // video people are crying at this
-    for image in vio::stream_file(
-        &video_path,
-        ctx.monitor_settings.width,
-        ctx.monitor_settings.height,
-        ctx.max_fps,
-    )? {
-        let result = infer(image, ctx.bounding_box, &ctx.zone_config, &mut ctx.yolo)?;
-        if result.detections.len() > 0 {
-            // TODO: How could we get the actual frame number or timestamp here?
-
-            let ts = videotime.as_secs_f32();
-            let frac = (ts.fract() * 1000f32) as u32;
-            let seconds = ts.trunc() as u32;
-            let secs = seconds % 60;
-            let mins = seconds / 60;
-
-            let description: Vec<String> = result
-                .detections
-                .iter()
-                .map(|d| describe(&CLASSES, &d))
-                .collect();
-            println!(
-                "[{:02}:{:02}:{:03}] Inference took {:?}: {}",
-                mins,
-                secs,
-                frac,
-                result.duration,
-                description.join(", ")
-            );
+    if event.frames.is_empty() {
+        note("Note: The event has no rows in the Frames table, so frame IDs are unknown.");
+    }
+    note("Note: Because analysis start frames aren't aligned between what zm-aidect might have originally done,");
+    note("      and this run, results can and will differ."); // TODO: This can be a good thing of course, but maybe add a way to analyse the logged alarm frames only or something like that
+
+    let report = analyze_event(&mut ctx, &event, |frame, duration| {
+        if let Some(trace) = trace.as_mut() {
+            let line = trace::FrameTrace {
+                time: frame.time as f64,
+                index: None,
+                convert_ms: None,
+                inference_ms: frame.inference_ms,
+                postprocess_ms: None,
+                detections: &frame.detections,
+            };
+            if let Err(e) = trace.write(&line) {
+                error!("Failed to write trace: {:#}", e);
+            }
+        }
+        if json || frame.detections.is_empty() {
+            return;
         }
-        inference_durations.push(result.duration);
-        videotime += timestep;
+        let ts = frame.time;
+        let frac = (ts.fract() * 1000f32) as u32;
+        let seconds = ts.trunc() as u32;
+        let secs = seconds % 60;
+        let mins = seconds / 60;
+
+        let frame_id = match frame.frame_id {
+            Some(frame_id) => format!(" frame {}", frame_id),
+            None => String::new(),
+        };
+
+        let description: Vec<&str> = frame
+            .detections
+            .iter()
+            .map(|d| d.description.as_str())
+            .collect();
+        println!(
+            "[{:02}:{:02}:{:03}{}] Inference took {:?}: {}",
+            mins,
+            secs,
+            frac,
+            frame_id,
+            duration,
+            description.join(", ")
+        );
+    })?;
+
+    if let Some(path) = &render {
+        note(&format!("Rendering {}", path.display()));
+        let transform = ctx.zone_config.transform;
+        let zone_config = ctx.zone_config.scaled(ctx.monitor_size, transform.size((props.width, props.height)));
+        render::render(&event, &report, &zone_config, ctx.max_fps, path)?;
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
     }
 
-    let total_duration = inference_durations.iter().sum::<Duration>();
     println!(
         "Processed {} frames, total ML time {:?}, average time {:?}",
-        inference_durations.len(),
-        total_duration,
-        total_duration / inference_durations.len() as u32
+        report.totals.frames,
+        Duration::from_secs_f64(report.inference.total / 1000.0),
+        Duration::from_secs_f64(report.inference.mean / 1000.0)
     );
 
     Ok(())
 }
 
+/// Runs detection over the recording of an event, calling on_frame for every frame.
+#[cfg(feature = "detect")]
+fn analyze_event(
+    ctx: &mut MonitorContext,
+    event: &zoneminder::db::Event,
+    on_frame: impl FnMut(&report::FrameReport, Duration),
+) -> Result<report::EventReport> {
+    detect::EventAnalyzer::new(
+        &mut ctx.detector,
+        &ctx.zone_config,
+        ctx.monitor.id(),
+        ctx.monitor_size,
+        ctx.max_fps,
+    )
+    .analyze(event, on_frame)
+}
+
+#[cfg(feature = "detect")]
+fn backfill(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    args: BackfillArgs,
+) -> Result<()> {
+    let mut ctx = connect_zm(args.monitor_id, zm_conf, model_dir, false, None)?;
+
+    let range = zoneminder::db::EventRange {
+        first_id: args.first_event,
+        last_id: args.last_event,
+        since: args.since,
+        until: args.until,
+    };
+    let mut event_ids = zoneminder::db::Event::ids(zm_conf, args.monitor_id, &range)?;
+    if let Some(state_file) = &args.state_file {
+        if let Some(done) = read_backfill_state(state_file)? {
+            println!("Resuming after event {} ({})", done, state_file.display());
+            event_ids.retain(|&id| id > done);
+        }
+    }
+
+    let started = Instant::now();
+    let (mut found, mut failed) = (0, 0);
+    for (n, &event_id) in event_ids.iter().enumerate() {
+        let progress = format!("[{}/{}] Event {}", n + 1, event_ids.len(), event_id);
+        let result = zoneminder::db::Event::query(zm_conf, event_id)
+            .and_then(|event| analyze_event(&mut ctx, &event, |_, _| {}));
+        match result {
+            Ok(report) => {
+                match report.best_detection() {
+                    Some(best) => {
+                        found += 1;
+                        println!(
+                            "{}: {} detections in {} of {} frames, best {}",
+                            progress,
+                            report.totals.detections,
+                            report.totals.frames_with_detections,
+                            report.totals.frames,
+                            best.description
+                        );
+                        if args.update {
+                            let score = (best.confidence * 100.0) as u32;
+                            zoneminder::db::update_event_notes(zm_conf, event_id, &best.description)?;
+                            zoneminder::db::raise_event_max_score(zm_conf, event_id, score)?;
+                        }
+                    }
+                    None => println!("{}: nothing found in {} frames", progress, report.totals.frames),
+                }
+            }
+            Err(e) => {
+                // e.g. recording already deleted, no reason to stop
+                failed += 1;
+                error!("{}: Failed to analyze: {:#}", progress, e);
+            }
+        }
+        if let Some(state_file) = &args.state_file {
+            write_backfill_state(state_file, event_id)?;
+        }
+    }
+
+    println!(
+        "Analyzed {} events in {:?}, found something in {}, {} failed",
+        event_ids.len(),
+        started.elapsed(),
+        found,
+        failed
+    );
+    Ok(())
+}
+
+#[cfg(feature = "detect")]
+const FILTER_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often filter tries an event before giving up on it, one poll apart
+#[cfg(feature = "detect")]
+const FILTER_ATTEMPTS: u32 = 3;
+
+#[cfg(feature = "detect")]
+fn filter(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    args: FilterArgs,
+) -> Result<()> {
+    let monitor_id = args.monitor_id;
+    let mut ctx = connect_zm(monitor_id, zm_conf, model_dir, false, None)?;
+
+    let state = match &args.state_file {
+        Some(state_file) => read_backfill_state(state_file)?,
+        None => None,
+    };
+    let mut last_id = match (args.after_event, state) {
+        (Some(after_event), _) => after_event,
+        (None, Some(done)) => done,
+        (None, None) => zoneminder::db::Event::latest_id(zm_conf, monitor_id)?.unwrap_or(0),
+    };
+    info!("{}: Filtering motion events after event {}", monitor_id, last_id);
+
+    // failed attempts at the event after last_id
+    let mut failures = 0;
+    loop {
+        let event_ids =
+            zoneminder::db::Event::closed_motion_ids(zm_conf, monitor_id, last_id, args.settle)?;
+        for event_id in event_ids {
+            let result = zoneminder::db::Event::query(zm_conf, event_id).and_then(|event| {
+                let report = analyze_event(&mut ctx, &event, |_, _| {})?;
+                match report.best_detection() {
+                    Some(best) => info!("{}: Event {}: {}", monitor_id, event_id, best.description),
+                    // never delete what we couldn't look at in full
+                    None if args.delete && (!report.complete || report.totals.frames == 0) => {
+                        warn!(
+                            "{}: Event {}: nothing found, but the recording couldn't be decoded completely, keeping it",
+                            monitor_id, event_id
+                        );
+                    }
+                    None if args.delete => {
+                        info!("{}: Event {}: nothing found, deleting", monitor_id, event_id);
+                        event.delete(zm_conf)?;
+                    }
+                    None => {
+                        info!("{}: Event {}: nothing found", monitor_id, event_id);
+                        zoneminder::db::update_event_notes(zm_conf, event_id, &args.notes)?;
+                    }
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                // never delete what we couldn't look at
+                failures += 1;
+                if failures < FILTER_ATTEMPTS {
+                    warn!("{}: Failed to filter event {}, retrying: {:#}", monitor_id, event_id, e);
+                    break;
+                }
+                // e.g. the recording is gone, don't hold up the events after it forever
+                error!("{}: Failed to filter event {}, skipping it: {:#}", monitor_id, event_id, e);
+            }
+            failures = 0;
+            last_id = event_id;
+            if let Some(state_file) = &args.state_file {
+                write_backfill_state(state_file, event_id)?;
+            }
+        }
+        std::thread::sleep(FILTER_POLL_INTERVAL);
+    }
+}
+
+/// The state file just holds the ID of the last event which was analyzed.
+#[cfg(feature = "detect")]
+fn read_backfill_state(path: &Path) -> Result<Option<u64>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents.trim().parse().with_context(|| {
+            format!("Invalid backfill state file {}", path.display())
+        })?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(feature = "detect")]
+fn write_backfill_state(path: &Path, event_id: u64) -> Result<()> {
+    // write-and-rename, so that being interrupted never leaves a truncated file behind
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, format!("{}\n", event_id))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write backfill state file {}", path.display()))
+}
+
+#[cfg(feature = "detect")]
 struct MonitorContext<'zm_conf> {
     zm_conf: &'zm_conf zoneminder::ZoneMinderConf,
     monitor: zoneminder::Monitor<'zm_conf>,
     trigger_monitor: zoneminder::Monitor<'zm_conf>,
-    zone_config: zoneminder::db::ZoneConfig,
+    /// Trigger.<Class>=XX: the monitors detections of these classes trigger instead
+    class_triggers: Vec<(Vec<i32>, zoneminder::Monitor<'zm_conf>)>,
+    /// Triggered along with the trigger monitors, see connect_linked
+    linked_monitors: Vec<zoneminder::Monitor<'zm_conf>>,
+    zone_config: zoneminder::zone::ZoneConfig,
     monitor_settings: zoneminder::db::MonitorSettings,
+    /// The resolution zones are drawn at: the monitor's, after Rotate
+    monitor_size: (u32, u32),
     bounding_box: Rect,
-    yolo: ml::YoloV4Tiny,
+    detector: detect::Detector,
     max_fps: f32,
+    /// How often the model runs at most, max_fps unless InferenceFPS is lower
+    inference_fps: f32,
+    /// IdleFPS, if it's below inference_fps
+    idle_fps: Option<f32>,
+}
+
+/// Size=auto: the share of the time between analyzed frames inference may take, the rest is for
+/// capture and postprocessing
+#[cfg(feature = "detect")]
+const AUTO_SIZE_BUDGET: f32 = 0.8;
+
+/// The zone's detector (see Detector::new for candidates), with its model from models if given.
+/// With Size=auto the largest size at which inference keeps up with fps is picked, and becomes the
+/// zone's Size.
+#[cfg(feature = "detect")]
+fn load_detector(
+    monitor_id: u32,
+    model_dir: Option<&Path>,
+    zone_config: &mut zoneminder::zone::ZoneConfig,
+    fps: f32,
+    candidates: bool,
+    models: Option<&detect::ModelCache>,
+) -> Result<detect::Detector> {
+    let model_dir = ml::find_model_dir(model_dir)?;
+    if !zone_config.auto_size {
+        return match models {
+            Some(models) => detect::Detector::new_shared(&model_dir, zone_config, candidates, models),
+            None => detect::Detector::new(&model_dir, zone_config, candidates),
+        };
+    }
+    let budget = Duration::from_secs_f32(AUTO_SIZE_BUDGET / fps);
+    let (detector, timings) = detect::Detector::with_auto_size(&model_dir, zone_config, candidates, budget)?;
+    let timings: Vec<_> = timings
+        .iter()
+        .map(|(size, duration)| format!("{} in {:?}", size, duration))
+        .collect();
+    info!(
+        "{}: Size=auto picked {} for {:?} per frame at {} fps (inference{} at {})",
+        monitor_id,
+        detector.size(),
+        budget,
+        fps,
+        if zone_config.tiles.is_some() { " per tile" } else { "" },
+        timings.join(", ")
+    );
+    zone_config.size = Some(detector.size());
+    match models {
+        // measured on a model of its own, used is the shared one
+        Some(models) => detect::Detector::new_shared(&model_dir, zone_config, candidates, models),
+        None => Ok(detector),
+    }
 }
 
-fn connect_zm(monitor_id: u32, zm_conf: &zoneminder::ZoneMinderConf) -> Result<MonitorContext> {
+#[cfg(feature = "detect")]
+fn connect_zm<'zm_conf>(
+    monitor_id: u32,
+    zm_conf: &'zm_conf zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    candidates: bool,
+    models: Option<&detect::ModelCache>,
+) -> Result<MonitorContext<'zm_conf>> {
     let monitor = zoneminder::Monitor::connect(zm_conf, monitor_id)?;
-    let zone_config = zoneminder::db::ZoneConfig::get_zone_config(zm_conf, monitor_id)?;
+    let mut zone_config = zoneminder::zone::ZoneConfig::get_zone_config(zm_conf, monitor_id)?;
     let monitor_settings = zoneminder::db::MonitorSettings::query(zm_conf, monitor_id)?;
 
     info!(
@@ -175,110 +1254,230 @@ fn connect_zm(monitor_id: u32, zm_conf: &zoneminder::ZoneMinderConf) -> Result<M
     let bounding_box = zone_config.shape.bounding_box();
     info!("{}: Picked up zone bounds {:?}", monitor_id, bounding_box);
 
-    let max_fps = monitor_settings.analysis_fps_limit;
-    let max_fps = zone_config.fps.or(max_fps);
-    let max_fps = max_fps.ok_or(anyhow!("No analysis FPS limit set - set either \"Analysis FPS\" in the Zoneminder web console, or set the FPS key in the aidect zone."))?;
+    let (rates, ignored) = zone_config
+        .analysis_rates(monitor_settings.analysis_fps_limit)
+        .ok_or(anyhow!("No analysis FPS limit set - set either \"Analysis FPS\" in the Zoneminder web console, or set the FPS key in the aidect zone."))
+        .context(ErrorKind::Config)?;
+    for reason in ignored {
+        warn!("{}: {}", monitor_id, reason);
+    }
+    let zoneminder::zone::AnalysisRates {
+        max_fps,
+        inference_fps,
+        idle_fps,
+    } = rates;
     info!("{}: Setting maximum fps to {}", monitor_id, max_fps);
+    if inference_fps < max_fps {
+        info!("{}: Running inference at up to {} fps", monitor_id, inference_fps);
+    }
+    if let Some(idle_fps) = idle_fps {
+        info!("{}: Running inference at {} fps while idle", monitor_id, idle_fps);
+    }
+
+    // before the class names are checked, a Model= model may bring its own classes
+    let mut detector = load_detector(monitor_id, model_dir, &mut zone_config, inference_fps, candidates, models)?;
 
     let trigger_id = zone_config.trigger.unwrap_or(monitor_id);
     info!("{}: Connecting to trigger monitor {}", monitor_id, trigger_id);
     let trigger_monitor = zoneminder::Monitor::connect(zm_conf, trigger_id)?;
+    let mut class_triggers: Vec<(Vec<i32>, zoneminder::Monitor)> = Vec::new();
+    for (class, class_trigger_id) in &zone_config.class_triggers {
+        let class_id = detect::class_id(class)
+            .ok_or_else(|| anyhow!("Unknown class {} in Trigger.{}", class, class))
+            .context(ErrorKind::Config)?;
+        if *class_trigger_id == trigger_id {
+            continue;
+        }
+        match class_triggers.iter_mut().find(|(_, m)| m.id() == *class_trigger_id) {
+            Some((classes, _)) => classes.push(class_id),
+            None => {
+                info!("{}: Connecting to trigger monitor {} for {}", monitor_id, class_trigger_id, class);
+                let monitor = zoneminder::Monitor::connect(zm_conf, *class_trigger_id)?;
+                class_triggers.push((vec![class_id], monitor));
+            }
+        }
+    }
 
-    let size = zone_config.size.unwrap_or(256);
-    let threshold = zone_config.threshold.unwrap_or(0.5);
-    let yolo = ml::YoloV4Tiny::new(
-        threshold,
-        size,
-        false,
+    for (class, _) in &zone_config.aspect_ratios {
+        detect::class_id(class)
+            .ok_or_else(|| anyhow!("Unknown class {} in Aspect.{}", class, class))
+            .context(ErrorKind::Config)?;
+    }
+    for (class, _) in &zone_config.schedules {
+        detect::class_id(class)
+            .ok_or_else(|| anyhow!("Unknown class {} in Schedule.{}", class, class))
+            .context(ErrorKind::Config)?;
+    }
+
+    let mut linked_monitors = Vec::new();
+    connect_linked(
+        zm_conf,
+        monitor_id,
+        &zone_config.linked,
+        &trigger_monitor,
+        &class_triggers,
+        &mut linked_monitors,
     )?;
 
-    instrumentation::SIZE.set(size as f64);
+    let warm_up = detector.warm_up()?;
+    info!(
+        "{}: Model warmed up at {:?}, inference takes {:?}",
+        monitor_id,
+        detector.precision(),
+        warm_up
+    );
+
+    instrumentation::SIZE.with_label_values(&[&monitor_id.to_string()]).set(detector.size() as f64);
 
     Ok(MonitorContext {
         zm_conf,
         monitor,
         trigger_monitor,
+        class_triggers,
+        linked_monitors,
+        monitor_size: zone_config.transform.size(monitor_settings.image_dimensions()),
         zone_config,
         monitor_settings,
         bounding_box,
-        yolo,
+        detector,
         max_fps,
+        inference_fps,
+        idle_fps,
     })
 }
 
-struct Inferred {
-    duration: Duration,
-    detections: Vec<Detection>,
+/// Connects to the monitors in ids which aren't trigger monitors or linked already.
+#[cfg(feature = "detect")]
+fn connect_linked<'zm_conf>(
+    zm_conf: &'zm_conf zoneminder::ZoneMinderConf,
+    monitor_id: u32,
+    ids: &[u32],
+    trigger_monitor: &zoneminder::Monitor,
+    class_triggers: &[(Vec<i32>, zoneminder::Monitor)],
+    linked_monitors: &mut Vec<zoneminder::Monitor<'zm_conf>>,
+) -> Result<()> {
+    for &linked_id in ids {
+        let known = linked_id == trigger_monitor.id()
+            || class_triggers.iter().any(|(_, m)| m.id() == linked_id)
+            || linked_monitors.iter().any(|m| m.id() == linked_id);
+        if known {
+            continue;
+        }
+        info!("{}: Connecting to linked monitor {}", monitor_id, linked_id);
+        linked_monitors.push(zoneminder::Monitor::connect(zm_conf, linked_id)?);
+    }
+    Ok(())
 }
 
-fn infer(
-    image: Mat,
-    bounding_box: Rect,
-    zone_config: &zoneminder::db::ZoneConfig,
-    yolo: &mut ml::YoloV4Tiny,
-) -> Result<Inferred> {
-    assert_eq!(image.typ(), opencv::core::CV_8UC3);
-    // TODO: blank remaining area outside zone polygon
-    let image = Mat::roi(&image, bounding_box)?;
-
-    let start = Instant::now();
-    let detections = yolo.infer(&image)?;
-    let duration = start.elapsed();
-
-    let detections: Vec<Detection> = detections
-        .iter()
-        .filter(|d| CLASSES.contains_key(&d.class_id))
-        .filter(|d| {
-            (d.bounding_box.width * d.bounding_box.height) as u32
-                > zone_config.min_area.unwrap_or(0)
-        })
-        .map(|d| Detection {
-            // Adjust bounding box to zone bounding box (RoI)
-            bounding_box: Rect {
-                x: d.bounding_box.x + bounding_box.x,
-                y: d.bounding_box.y + bounding_box.y,
-                ..d.bounding_box
-            },
-            ..*d
-        })
-        .collect();
-
-    Ok(Inferred {
-        duration,
-        detections,
-    })
+#[cfg(feature = "detect")]
+fn audit(
+    dir: &Path,
+    model_dir: Option<&Path>,
+    options: &ml::Options,
+    json: bool,
+    metrics_file: Option<&Path>,
+) -> Result<()> {
+    let report = audit::audit(dir, &ml::find_model_dir(model_dir)?, options)?;
+    if let Some(path) = metrics_file {
+        audit::write_metrics(path, &report)?;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for path in &report.missed {
+        println!("{}", path);
+    }
+    println!(
+        "{} of {} samples have objects in them ({:.1}% missed)",
+        report.misses,
+        report.samples,
+        report.miss_rate * 100.0
+    );
+    for (class, count) in &report.misses_per_class {
+        println!("  {}: {}", class, count);
+    }
+    Ok(())
 }
 
-fn trigger(ctx: &MonitorContext, description: &str, score: u32) -> Result<u64> {
-    ctx.trigger_monitor
-        .trigger("aidect", description, score)
-        .with_context(|| format!("Failed to trigger monitor ID {}", ctx.trigger_monitor.id()))
+#[cfg(feature = "detect")]
+fn model(model_dir: Option<&Path>, command: ModelCommand) -> Result<()> {
+    match command {
+        ModelCommand::Fetch { name, force } => {
+            let model = models::by_name(&name)?;
+            let dir = match model_dir {
+                Some(dir) => dir.to_path_buf(),
+                None => ml::find_model_dir(None).unwrap_or_else(|_| PathBuf::from(models::DEFAULT_INSTALL_DIR)),
+            };
+            models::fetch(model, &dir, force)
+        }
+        ModelCommand::List => {
+            let dirs = match model_dir {
+                Some(dir) => vec![dir.to_path_buf()],
+                None => ml::model_dir_candidates(),
+            };
+            models::list(&dirs)
+        }
+    }
 }
 
-fn test(monitor_id: u32) -> Result<()> {
-    let zm_conf = zoneminder::ZoneMinderConf::parse_default()?;
-    let mut ctx = connect_zm(monitor_id, &zm_conf)?;
+#[cfg(feature = "detect")]
+fn test(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    monitor_id: u32,
+    file: Option<PathBuf>,
+    save_images: Option<PathBuf>,
+    precision: Option<String>,
+    candidates: usize,
+    dump_raw: Option<(PathBuf, Vec<usize>)>,
+) -> Result<()> {
+    let mut ctx = connect_zm(monitor_id, zm_conf, model_dir, candidates > 0, None)?;
 
     println!(
         "Connected to monitor ID {}: {}",
         monitor_id, ctx.monitor_settings.name
     );
 
+    if let Some(precision) = precision {
+        ctx.zone_config.precision = Some(precision.as_str().try_into()?);
+        ctx.detector = detect::Detector::new(&ml::find_model_dir(model_dir)?, &ctx.zone_config, candidates > 0)?;
+    }
+    println!("Model runs at {:?}", ctx.detector.precision());
+    if let Some((path, frames)) = dump_raw {
+        ctx.detector.dump_raw(detect::RawDump::create(&path, frames)?)?;
+    }
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let save_as = |name: String| {
+        save_images
+            .as_ref()
+            .map(|dir| saved_image_path(dir, monitor_id, started, &name))
+    };
+
+    if let Some(file) = file {
+        // like zm-aidect event: at the file's own resolution, at the analysis FPS, and without triggering
+        let props = vio::properties(&file)?;
+        println!("Analyzing {} ({})", file.display(), props.to_string());
+        let transform = ctx.zone_config.transform;
+        let zone_config = ctx
+            .zone_config
+            .scaled(ctx.monitor_size, transform.size((props.width, props.height)));
+        let stream = vio::stream_file(&file, props.width, props.height, ctx.max_fps)?;
+        for (n, image) in stream.enumerate() {
+            let image = detect::transform_frame(image, &transform)?;
+            let time = Duration::from_secs_f32(n as f32 / ctx.max_fps);
+            let prefix = format!("[{:02}:{:02}:{:03}] ", time.as_secs() / 60, time.as_secs() % 60, time.subsec_millis());
+            test_image(&mut ctx.detector, &image, &zone_config, candidates, &prefix, save_as(n.to_string()))?;
+        }
+        return Ok(());
+    }
+
     let num_images = 3;
     println!("Grabbing {} images and running detection", num_images);
-    for image in ctx.monitor.stream_images()?.take(num_images) {
-        let image = image?.convert_to_rgb24()?;
-        let result = infer(image, ctx.bounding_box, &ctx.zone_config, &mut ctx.yolo)?;
-        let description: Vec<String> = result
-            .detections
-            .iter()
-            .map(|d| describe(&CLASSES, &d))
-            .collect();
-        println!(
-            "Inference took {:?}: {}",
-            result.duration,
-            description.join(", ")
-        );
+    for (n, image) in ctx.monitor.stream_images()?.take(num_images).enumerate() {
+        let image = detect::transform_frame(image?.convert_to_rgb24()?, &ctx.zone_config.transform)?;
+        test_image(&mut ctx.detector, &image, &ctx.zone_config, candidates, "", save_as(n.to_string()))?;
     }
 
     println!("Triggering an event on monitor {}", ctx.trigger_monitor.id());
@@ -288,57 +1487,813 @@ fn test(monitor_id: u32) -> Result<()> {
     Ok(())
 }
 
-lazy_static! {
-    static ref CLASSES: HashMap<i32, &'static str> = [  // TODO this should be loaded at runtime from the model definition
-        (1, "Human"),
-        (3, "Car"),
-        (15, "Bird"),
-        (16, "Cat"),
-        (17, "Dog"),
-    ].into();
+/// Runs detection on still images. Doesn't need the monitor to be running, only its configuration,
+/// so that it works anywhere the database can be reached (e.g. in CI).
+#[cfg(feature = "detect")]
+fn image(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    paths: &[PathBuf],
+    monitor_id: u32,
+    json: bool,
+    save_images: Option<PathBuf>,
+) -> Result<()> {
+    let mut zone_config = zoneminder::zone::ZoneConfig::get_zone_config(zm_conf, monitor_id)?;
+    let monitor_settings = zoneminder::db::MonitorSettings::query(zm_conf, monitor_id)?;
+    let monitor_size = zone_config.transform.size(monitor_settings.image_dimensions());
+    // Size=auto picks what `run` would
+    let fps = zone_config
+        .analysis_rates(monitor_settings.analysis_fps_limit)
+        .map_or(1.0, |(rates, _)| rates.inference_fps);
+    let mut detector = load_detector(monitor_id, model_dir, &mut zone_config, fps, false, None)?;
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let mut reports = Vec::new();
+    for path in paths {
+        let image = detect::transform_frame(annotate::load(path)?, &zone_config.transform)?;
+        let zone_config = zone_config.scaled(monitor_size, (image.cols() as u32, image.rows() as u32));
+        let save_as = match &save_images {
+            Some(dir) => {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                let save_as = saved_image_path(dir, monitor_id, started, &name);
+                // e.g. two images of the same name from different directories
+                if save_as.exists() {
+                    return Err(anyhow!("Not overwriting {}", save_as.display()));
+                }
+                Some(save_as)
+            }
+            None => None,
+        };
+        if !json {
+            test_image(&mut detector, &image, &zone_config, 0, &format!("{}: ", path.display()), save_as)?;
+            continue;
+        }
+        let bounding_box = zone_config.shape.bounding_box();
+        let result = detector.detect(&image, SystemTime::now(), bounding_box, &zone_config)?;
+        if let Some(path) = save_as {
+            let annotated = annotate::annotate(&image, &zone_config, bounding_box, &result.detections, |d| {
+                detect::describe(d, &zone_config)
+            })?;
+            annotate::save(&path, &annotated)?;
+        }
+        reports.push(report::ImageReport {
+            path: path.display().to_string(),
+            inference_ms: result.duration.as_secs_f64() * 1000.0,
+            detections: result
+                .detections
+                .iter()
+                .map(|d| detect::detection_report(d, &zone_config))
+                .collect(),
+        });
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+    Ok(())
 }
 
-fn run(monitor_id: u32, instrumentation_address: Option<String>, instrumentation_port: u16) -> Result<()> {
-    let zm_conf = zoneminder::ZoneMinderConf::parse_default()?;
-    let mut ctx = connect_zm(monitor_id, &zm_conf)?;
+/// Where --save-images puts the image name: DIR/monitorID-STARTED-NAME.jpg, with the time the run
+/// started (Unix milliseconds), so that neither runs nor monitors overwrite each other's images.
+#[cfg(feature = "detect")]
+fn saved_image_path(dir: &Path, monitor_id: u32, started: u128, name: &str) -> PathBuf {
+    dir.join(format!("monitor{}-{}-{}.jpg", monitor_id, started, name))
+}
 
-    if let Some(address) = instrumentation_address {
-        instrumentation::spawn_prometheus_client(address, instrumentation_port + monitor_id as u16);
+/// Runs detection on an image for test, prints the detections (after prefix) and up to candidates
+/// of the candidates, and saves the image with them drawn on it to save_as.
+#[cfg(feature = "detect")]
+fn test_image(
+    detector: &mut detect::Detector,
+    image: &Mat,
+    zone_config: &zoneminder::zone::ZoneConfig,
+    candidates: usize,
+    prefix: &str,
+    save_as: Option<PathBuf>,
+) -> Result<detect::Inferred> {
+    let bounding_box = zone_config.shape.bounding_box();
+    let result = detector.detect(image, SystemTime::now(), bounding_box, zone_config)?;
+    let description: Vec<String> = result
+        .detections
+        .iter()
+        .map(|d| detect::describe(d, zone_config))
+        .collect();
+    println!(
+        "{}Inference took {:?}: {}",
+        prefix,
+        result.duration,
+        description.join(", ")
+    );
+    for d in result.candidates.iter().take(candidates) {
+        // Threshold is compared with >=, and the zone key is in whole percent
+        println!(
+            "  Below Threshold: {} (detected with Threshold={})",
+            detect::describe(d, zone_config),
+            (d.confidence * 100.0).floor()
+        );
     }
 
-    let mut pacemaker = RealtimePacemaker::new(ctx.max_fps);
-    let mut event_tracker = coalescing::EventTracker::new();
-
-    // watchdog is set to 20x max_fps frame interval
-    let watchdog = ThreadedWatchdog::new(Duration::from_secs_f32(20.0 / ctx.max_fps));
+    if let Some(path) = save_as {
+        let annotated = annotate::annotate(image, zone_config, bounding_box, &result.detections, |d| {
+            detect::describe(d, zone_config)
+        })?;
+        annotate::save(&path, &annotated)?;
+        println!("Saved annotated image to {}", path.display());
+    }
+    Ok(result)
+}
 
-    fn process_update_event(ctx: &MonitorContext, update: Option<coalescing::UpdateEvent>) {
-        if let Some(update) = update {
-            let description = describe(&CLASSES, &update.detection);
-            if let Err(e) =
-                zoneminder::db::update_event_notes(&ctx.zm_conf, update.event_id, &description)
-            {
-                error!(
-                    "{}: Failed to update event {} notes: {}",
-                    ctx.trigger_monitor.id(), update.event_id, e
-                );
+#[cfg(feature = "detect")]
+fn set_cpu_affinity(cpus: &[usize]) -> Result<()> {
+    // Safe: cpu_set_t is a plain bitmask, and CPU_SET is only called with in-range CPUs
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(anyhow!("Invalid CPU {}", cpu));
             }
+            libc::CPU_SET(cpu, &mut set);
         }
+        // pid 0 is the calling thread, threads spawned afterwards inherit the mask
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to pin to CPUs {:?}", cpus));
+        }
+    }
+    Ok(())
+}
+
+/// How often run() reconnects and restarts the pipeline after it failed or got stuck, before
+/// giving up. Restarts after the pipeline ran fine for HEALTHY_RUN don't count.
+#[cfg(feature = "detect")]
+const MAX_RESTARTS: u32 = 3;
+/// How long the pipeline has to run (and analyze frames) for its restarts to start over, so that
+/// a pipeline failing right after its first frames doesn't restart forever
+#[cfg(feature = "detect")]
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+#[cfg(feature = "detect")]
+const HEATMAP_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "detect")]
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// The most the watchdog waits, unless frames come even less often
+#[cfg(feature = "detect")]
+const MAX_WATCHDOG_TIMEOUT: f32 = 60.0;
+
+#[cfg(feature = "detect")]
+fn run(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    model_dir: Option<&Path>,
+    args: RunArgs,
+    pool: Option<&group::InferencePool>,
+) -> Result<()> {
+    let monitor_id = args.monitor_id;
+    if !args.latency_buckets.is_empty() {
+        instrumentation::set_latency_buckets(args.latency_buckets.clone())?;
+    }
+    let connect = || -> Result<MonitorContext> {
+        // --blur-heads blurs the people below Threshold as well
+        let candidates = args.near_misses.is_some() || args.blur_heads;
+        let mut ctx = connect_zm(monitor_id, zm_conf, model_dir, candidates, pool.map(|pool| &pool.models))?;
+        if args.linked_monitors {
+            let trigger_ids: Vec<u32> = ctx
+                .class_triggers
+                .iter()
+                .map(|(_, monitor)| monitor.id())
+                .chain([ctx.trigger_monitor.id()])
+                .collect();
+            for trigger_id in trigger_ids {
+                let linked = zoneminder::db::linked_to(zm_conf, trigger_id)?;
+                connect_linked(
+                    zm_conf,
+                    monitor_id,
+                    &linked,
+                    &ctx.trigger_monitor,
+                    &ctx.class_triggers,
+                    &mut ctx.linked_monitors,
+                )?;
+            }
+        }
+        let trigger_method = args.trigger_method.as_str().try_into()?;
+        let trigger_timeout = Duration::from_secs_f32(args.trigger_timeout);
+        let trigger_monitors = [&mut ctx.trigger_monitor]
+            .into_iter()
+            .chain(ctx.class_triggers.iter_mut().map(|(_, monitor)| monitor))
+            .chain(ctx.linked_monitors.iter_mut());
+        for monitor in trigger_monitors {
+            monitor.set_trigger_method(trigger_method);
+            monitor.set_trigger_timeout(trigger_timeout);
+        }
+        Ok(ctx)
+    };
+    let mut ctx = connect()?;
+
+    // Before any threads are spawned, so that they all inherit it
+    let cpus = match &args.cpus {
+        Some(list) => Some(
+            zoneminder::zone::parse_cpu_list(list).ok_or_else(|| anyhow!("Invalid CPU list {:?}", list))?,
+        ),
+        None => ctx.zone_config.cpus.clone(),
+    };
+    if let Some(cpus) = cpus {
+        info!("{}: Pinning to CPUs {:?}", monitor_id, cpus);
+        set_cpu_affinity(&cpus)?;
+    }
+
+    let heatmap = (args.heatmap || args.heatmap_file.is_some()).then(|| {
+        Arc::new(Mutex::new(heatmap::Heatmap::new(
+            ctx.monitor_size,
+            ctx.zone_config.clone(),
+        )))
+    });
+    if let (Some(heatmap), Some(path)) = (&heatmap, args.heatmap_file.clone()) {
+        let heatmap = heatmap.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(HEATMAP_INTERVAL);
+            let image = heatmap.lock().unwrap().render();
+            if let Err(e) = image.and_then(|image| annotate::save(&path, &image)) {
+                error!("{}: Failed to write heatmap: {:#}", monitor_id, e);
+            }
+        });
     }
 
+    if let Some(address) = args.instrumentation_address.clone() {
+        instrumentation::spawn_prometheus_client(
+            address,
+            args.instrumentation_port + monitor_id as u16,
+            heatmap.clone(),
+        );
+    }
+    if let Some(url) = &args.pushgateway_url {
+        instrumentation::spawn_pushgateway(
+            url,
+            &args.pushgateway_job,
+            monitor_id,
+            Duration::from_secs(args.pushgateway_interval.max(1)),
+        )?;
+    }
+
+    let mut notifiers: Vec<Box<dyn notify::Notifier + Send>> = Vec::new();
+    if let Some(url) = args.webhook_url.clone() {
+        let webhook = notify::Webhook::new(url, args.webhook_image);
+        notifiers.push(match args.webhook_digest {
+            Some(window) => Box::new(notify::Digest::new(
+                ctx.trigger_monitor.id(),
+                webhook,
+                Duration::from_secs(window),
+            )),
+            None => Box::new(webhook),
+        });
+    }
+    if let Some(url) = args.s3_url.clone() {
+        let credentials = args.s3_access_key.clone().zip(args.s3_secret_key.clone());
+        let (access_key, secret_key) =
+            credentials.ok_or_else(|| anyhow!("--s3-url needs --s3-access-key and --s3-secret-key"))?;
+        notifiers.push(Box::new(upload::Uploader::new(
+            url,
+            args.s3_region.clone(),
+            access_key,
+            secret_key,
+            ctx.zone_config.clone(),
+            ctx.monitor_size,
+        )));
+    }
+    if args.plate_dir.is_some() || args.plate_command.is_some() {
+        if ctx.zone_config.plates.is_empty() {
+            warn!("{}: --plate-dir and --plate-command need \"aidect-plates\" zones, there are none", monitor_id);
+        }
+        notifiers.push(Box::new(plates::PlateExport::new(
+            monitor_id,
+            ctx.zone_config.plates.clone(),
+            ctx.monitor_size,
+            args.plate_dir.clone(),
+            args.plate_command.clone(),
+            Duration::from_secs_f32(args.plate_interval.max(0.0)),
+        )));
+    }
+    #[cfg(feature = "telegram")]
+    if let Some(token) = args.telegram_token.clone() {
+        let chat_id = args
+            .telegram_chat
+            .clone()
+            .ok_or_else(|| anyhow!("--telegram-token needs --telegram-chat"))?;
+        let classes = match args.telegram_classes.is_empty() {
+            true => None,
+            false => Some(
+                args.telegram_classes
+                    .iter()
+                    .map(|class| detect::class_id(class).ok_or_else(|| anyhow!("Unknown class {}", class)))
+                    .collect::<Result<Vec<i32>>>()?,
+            ),
+        };
+        notifiers.push(Box::new(telegram::Telegram::new(
+            token,
+            chat_id,
+            classes,
+            Duration::from_secs(args.telegram_interval),
+        )));
+    }
+    let mut notifier = match notifiers.len() {
+        0 => None,
+        1 => notifiers.pop(),
+        _ => Some(Box::new(notifiers) as Box<dyn notify::Notifier + Send>),
+    };
+
+    let mut alarm = alarm::Alarm::new(
+        args.alarm_command.clone(),
+        args.alarm_gpio.clone(),
+        args.alarm_classes.clone(),
+        Duration::from_secs(args.alarm_duration),
+        Duration::from_secs(args.alarm_debounce),
+    );
+
     // For yolov4-tiny and moderate input sizes, multithreading does speed things up, but at the expense
     // of higher overall CPU usage. As you would usually have multiple zm-aidect processes running, as
     // well as zmc, there is no particular need for a single zm-aidect process to scale to multiple cores,
     // especially when that comes with an efficiency hit. Large inputs and/or high framerates aren't
-    // sensible on a CPU anyway.
-    opencv::core::set_num_threads(1)?;
+    // sensible on a CPU anyway. Where latency matters more than watts (or there are idle cores),
+    // this can be raised with Threads=N / --threads.
+    let threads = args.threads.or(ctx.zone_config.threads).unwrap_or(1);
+    if threads != 1 {
+        info!("{}: Using {} threads for inference", monitor_id, threads);
+    }
+    opencv::core::set_num_threads(threads as i32)?;
+
+    let mut trace = args.trace.as_deref().map(trace::Trace::create).transpose()?;
+    let mut sampler = match &args.audit_dir {
+        Some(dir) => Some(audit::Sampler::new(
+            dir.clone(),
+            monitor_id,
+            args.audit_rate,
+            args.audit_max,
+        )?),
+        None => None,
+    };
+    let mut stationary = match ctx.zone_config.stationary {
+        Some(minutes) => Some(stationary::Stationary::new(
+            monitor_id,
+            Duration::from_secs_f32(minutes * 60.0),
+            args.stationary_file.clone(),
+        )?),
+        None => None,
+    };
+    // a model of its own, so that it doesn't have to share with live analysis
+    let mut late_detector = match args.late_verify {
+        Some(_) => Some(detect::Detector::new(&ml::find_model_dir(model_dir)?, &ctx.zone_config, false)?),
+        None => None,
+    };
+    let source = match &args.source_url {
+        Some(url) => {
+            let props = vio::properties(url)
+                .with_context(|| format!("Failed to probe source {}", url))?;
+            info!("{}: Analyzing {} ({})", monitor_id, url, props.to_string());
+            Some((url.as_str(), (props.width, props.height)))
+        }
+        None => None,
+    };
+
+    // Reconnecting gets a fresh shm mapping, database connection and model, which is all that
+    // can get stuck short of ZoneMinder itself.
+    let mut failures = 0;
+    loop {
+        // 20 capture intervals, but enough for a few inferences, which may take longer than that.
+        // Frames skipped at IdleFPS count as progress, so the idle interval doesn't matter.
+        let timeout = (20.0 / ctx.max_fps)
+            .max(5.0 / ctx.inference_fps)
+            .min(MAX_WATCHDOG_TIMEOUT)
+            .max(2.0 / ctx.max_fps);
+        let watchdog = ThreadedWatchdog::new(monitor_id, Duration::from_secs_f32(timeout));
+        let started = Instant::now();
+        let mut error = match run_pipeline(
+            ctx,
+            &args,
+            source,
+            &mut alarm,
+            &mut notifier,
+            &mut trace,
+            heatmap.as_deref(),
+            sampler.as_mut(),
+            stationary.as_mut(),
+            late_detector.as_mut(),
+            pool,
+            &watchdog,
+        ) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if group::stopping() {
+            // the group fails with the error of the monitor which gave up
+            return Ok(());
+        }
+        if watchdog.progressed() && started.elapsed() >= HEALTHY_RUN {
+            failures = 0;
+        }
+        let mut stage = watchdog.stage();
+        drop(watchdog);
+
+        ctx = loop {
+            // e.g. a broken model or configuration, which reconnecting won't fix
+            if let Some(kind) = ErrorKind::kind(&error).filter(|kind| !kind.is_transient()) {
+                error!("{}: {}, not restarting", monitor_id, kind);
+                return Err(error);
+            }
+            failures += 1;
+            instrumentation::PIPELINE_RESTARTS
+                .with_label_values(&[&monitor_id.to_string(), stage.name()])
+                .inc();
+            if failures > MAX_RESTARTS {
+                error!(
+                    "{}: Giving up after {} restarts, stuck in {}",
+                    monitor_id,
+                    MAX_RESTARTS,
+                    stage.name()
+                );
+                return Err(error);
+            }
+            warn!(
+                "{}: Stuck in {}: {:#}, restarting ({} of {})",
+                monitor_id,
+                stage.name(),
+                error,
+                failures,
+                MAX_RESTARTS
+            );
+            std::thread::sleep(RESTART_DELAY);
+            match connect() {
+                Ok(ctx) => break ctx,
+                Err(e) => (error, stage) = (e, Stage::Reconnect),
+            }
+        };
+    }
+}
+
+// The pipeline is three threads connected by channels:
+// capture (reading images from shm, pacing) -> inference (this thread) -> I/O (triggering, alarm,
+// event notes). Capture blocks on handing over a frame while inference is busy, so it always reads
+// fresh images, while slow triggering or database writes never hold up inference or capture. Late
+// verification (--late-verify) is another thread with a model of its own, fed by the I/O thread.
+#[cfg(feature = "detect")]
+fn run_pipeline(
+    ctx: MonitorContext,
+    args: &RunArgs,
+    source: Option<(&str, (u32, u32))>,
+    alarm: &mut Option<alarm::Alarm>,
+    notifier: &mut Option<Box<dyn notify::Notifier + Send>>,
+    trace: &mut Option<trace::Trace>,
+    heatmap: Option<&Mutex<heatmap::Heatmap>>,
+    sampler: Option<&mut audit::Sampler>,
+    stationary: Option<&mut stationary::Stationary>,
+    late_detector: Option<&mut detect::Detector>,
+    pool: Option<&group::InferencePool>,
+    watchdog: &ThreadedWatchdog,
+) -> Result<()> {
+    let monitor_id = args.monitor_id;
+    let target_interval = 1.0 / ctx.inference_fps;
+    let MonitorContext {
+        zm_conf,
+        monitor,
+        trigger_monitor,
+        class_triggers,
+        linked_monitors,
+        zone_config,
+        monitor_settings,
+        monitor_size,
+        bounding_box,
+        mut detector,
+        max_fps,
+        inference_fps,
+        idle_fps,
+    } = ctx;
+    let (monitor, trigger_monitor, zone_config) = (&monitor, &trigger_monitor, &zone_config);
+    let gate = zone_config.gate.unwrap_or(zoneminder::zone::Gate::Always);
+    let fps_window = zone_config.fps_window.unwrap_or(10) as usize;
+    let dedup = match &args.dedup_socket {
+        Some(socket) => Some(dedup::Dedup::new(
+            socket.clone(),
+            zone_config.dedup_group.clone().unwrap_or_else(|| "default".to_string()),
+            trigger_monitor.id(),
+            args.dedup_mode.as_str().try_into()?,
+        )),
+        None => None,
+    };
+    let timeout_policy = args.trigger_timeout_policy.as_str().try_into()?;
+    let run_states = zoneminder::db::RunStates::parse(&args.run_states).context(ErrorKind::Config)?;
+    let run_states = &run_states;
+    let metadata = args
+        .event_metadata
+        .as_deref()
+        .map(coalescing::MetadataTarget::try_from)
+        .transpose()?;
+    let zones = zoneminder::zone::named_zones(&zoneminder::zone::monitor_zones(zm_conf, monitor_id)?);
+    let templates = cause::Templates::new(&args.cause, &args.description, monitor_id, &monitor_settings.name, zones)?;
+    let templates = &templates;
+    let (class_triggers, linked_monitors) = (&class_triggers, &linked_monitors);
+    let zm_writes = writes::ZoneMinderWrites {
+        zm_conf,
+        monitor_id,
+        templates,
+        triggered: [trigger_monitor]
+            .into_iter()
+            .chain(class_triggers.iter().map(|(_, monitor)| monitor))
+            .collect(),
+        linked: linked_monitors,
+    };
+    let metadata = writes::startup(&zm_writes, monitor_id, args.dry_run, metadata)?;
+
+    // Zones are drawn at the monitor's resolution, detections are mapped back to it, so that
+    // notes and alarm zones work the same as when analyzing the monitor's images.
+    let transform = zone_config.transform;
+    let analysis_zone_config = match source {
+        Some((_, source_size)) => zone_config.scaled(monitor_size, transform.size(source_size)),
+        None => zone_config.clone(),
+    };
+    let analysis_bounding_box = match source {
+        Some(_) => analysis_zone_config.shape.bounding_box(),
+        None => bounding_box,
+    };
+
+    let activity = Activity::default();
+    let activity = &activity;
+    // set by capture once the monitor is active again after a pause
+    let resumed = AtomicBool::new(false);
+    let resumed = &resumed;
+
+    std::thread::scope(|scope| {
+        let (frame_tx, frame_rx) = mpsc::sync_channel(0);
+        let (detections_tx, detections_rx) = mpsc::channel();
+
+        let capture_watchdog = watchdog.clone();
+        let capture = scope.spawn(move || match source {
+            Some((url, source_size)) => capture_url(
+                zm_conf,
+                monitor,
+                trigger_monitor.id(),
+                run_states,
+                url,
+                source_size,
+                transform,
+                max_fps,
+                (inference_fps, idle_fps),
+                activity,
+                resumed,
+                gate,
+                &capture_watchdog,
+                frame_tx,
+            ),
+            None => capture_frames(
+                zm_conf,
+                monitor,
+                trigger_monitor.id(),
+                run_states,
+                transform,
+                max_fps,
+                (inference_fps, idle_fps),
+                activity,
+                resumed,
+                fps_window,
+                args.backlog,
+                gate,
+                &capture_watchdog,
+                frame_tx,
+            ),
+        });
+        let late_verify = match (args.late_verify, late_detector) {
+            (Some(secs), Some(late_detector)) => {
+                let (requests_tx, requests_rx) = mpsc::channel();
+                let (results_tx, results_rx) = mpsc::channel();
+                scope.spawn(move || {
+                    verify::verify_events(
+                        zm_conf,
+                        late_detector,
+                        zone_config,
+                        monitor_id,
+                        monitor_size,
+                        max_fps,
+                        requests_rx,
+                        results_tx,
+                    )
+                });
+                Some(verify::LateVerify {
+                    delay: Duration::from_secs(secs),
+                    requests: requests_tx,
+                    results: results_rx,
+                })
+            }
+            _ => None,
+        };
+        let io = scope.spawn(move || {
+            handle_detections(
+                zm_conf,
+                monitor_id,
+                trigger_monitor,
+                class_triggers,
+                linked_monitors,
+                zone_config,
+                alarm,
+                args.rescore,
+                // not even clearing it, that would wipe what a production instance shows
+                args.show_text && !args.dry_run,
+                args.stats,
+                notifier,
+                dedup.as_ref(),
+                late_verify,
+                timeout_policy,
+                templates,
+                args.continue_events.map(Duration::from_secs),
+                metadata,
+                args.max_triggers_per_minute.map(|per_minute| (per_minute, args.trigger_burst)),
+                resumed,
+                detections_rx,
+            )
+        });
+
+        // Once inference returns (e.g. failed), frame_rx and detections_tx are gone, which ends the
+        // other threads.
+        let inferred = infer_frames(
+            monitor_id,
+            &mut detector,
+            zone_config,
+            (&analysis_zone_config, analysis_bounding_box),
+            source.map(|(_, source_size)| transform.size(source_size)),
+            monitor_size,
+            &InferenceOptions {
+                target_interval,
+                near_misses: args.near_misses.map(|every| (every, args.near_miss_dir.as_deref())),
+                dry_run: args.dry_run,
+                blur_heads: Some(args.head_fraction).filter(|_| args.blur_heads),
+            },
+            activity,
+            heatmap,
+            trace,
+            sampler,
+            stationary,
+            pool,
+            watchdog,
+            &|| io.is_finished(),
+            frame_rx,
+            &detections_tx,
+        );
+        drop(detections_tx);
+        inferred?;
+
+        io.join().unwrap()?;
+        writes::shutdown(&zm_writes, args.dry_run, args.show_text);
+        capture.join().unwrap()
+    })
+}
+
+/// What run's flags change about inference
+#[cfg(feature = "detect")]
+struct InferenceOptions<'a> {
+    /// Seconds inference may take per frame to keep up
+    target_interval: f32,
+    /// Save every Nth frame without detections (to the directory, if given)
+    near_misses: Option<(u32, Option<&'a Path>)>,
+    dry_run: bool,
+    /// Blur the heads of people (the fraction of their height) in the images passed on
+    blur_heads: Option<f32>,
+}
 
-    for image in ctx.monitor.stream_images()? {
-        let image = image?.convert_to_rgb24()?;
-        let Inferred {
+/// Inference thread: runs the model on the frames capture hands over, confirms what it finds
+/// (MinHits, Stationary) and passes that on to the I/O thread, until capture or the I/O thread
+/// (io_finished) is done. Detections on a source_size stream are mapped to monitor_size. replay
+/// goes through here as well, so that it analyzes clips exactly like run analyzes monitors.
+#[cfg(feature = "detect")]
+fn infer_frames(
+    monitor_id: u32,
+    detector: &mut detect::Detector,
+    zone_config: &zoneminder::zone::ZoneConfig,
+    (analysis_zone_config, analysis_bounding_box): (&zoneminder::zone::ZoneConfig, Rect),
+    source_size: Option<(u32, u32)>,
+    monitor_size: (u32, u32),
+    options: &InferenceOptions,
+    activity: &Activity,
+    heatmap: Option<&Mutex<heatmap::Heatmap>>,
+    trace: &mut Option<trace::Trace>,
+    mut sampler: Option<&mut audit::Sampler>,
+    mut stationary: Option<&mut stationary::Stationary>,
+    pool: Option<&group::InferencePool>,
+    watchdog: &impl Watchdog,
+    io_finished: &dyn Fn() -> bool,
+    frames: mpsc::Receiver<Frame>,
+    detected: &mpsc::Sender<(Vec<Detection>, Mat, SystemTime)>,
+) -> Result<()> {
+    let mut hits = smoothing::HitTracker::new(zone_config.min_hits.unwrap_or(1));
+    let mut negative_frames: u32 = 0;
+    loop {
+        if group::stopping() {
+            info!("{}: Stopping, another monitor gave up", monitor_id);
+            break;
+        }
+        watchdog.enter(Stage::Capture);
+        // not blocking for good, to notice the watchdog expiring while capture is stuck
+        let frame = match frames.recv_timeout(Duration::from_millis(100)) {
+            Ok(frame) => frame,
+            Err(mpsc::RecvTimeoutError::Timeout) if watchdog.expired() => {
+                return Err(anyhow!("No frames for {:?}", watchdog.timeout()));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if io_finished() {
+            // I/O thread failed, e.g. triggering
+            break;
+        }
+        watchdog.enter(Stage::Inference);
+        let (image, order) = (frame.image, frame.order);
+        let slot = pool.map(|pool| pool.acquire());
+        let detect::Inferred {
             duration: inference_duration,
-            detections,
-        } = infer(image, ctx.bounding_box, &ctx.zone_config, &mut ctx.yolo)?;
+            mut detections,
+            candidates,
+            rejected,
+            filtered_out,
+        } = detector.detect_ordered(&image, order, frame.captured, analysis_bounding_box, analysis_zone_config)?;
+        drop(slot);
+        for filter in rejected {
+            instrumentation::DETECTIONS_REJECTED
+                .with_label_values(&[&monitor_id.to_string(), filter])
+                .inc();
+        }
+        let postprocess_start = Instant::now();
+        if !detections.is_empty() {
+            // before MinHits, so that IdleFPS speeds up while the object is being confirmed
+            activity.detected(postprocess_start);
+        }
+        // everyone the model saw, also those below Threshold, filtered out or not confirmed yet
+        let mut people: Vec<Detection> = match options.blur_heads {
+            Some(_) => detections
+                .iter()
+                .chain(&candidates)
+                .chain(&filtered_out)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        if let Some(source_size) = source_size {
+            for d in detections.iter_mut().chain(people.iter_mut()) {
+                d.bounding_box = zoneminder::zone::scale_rect(d.bounding_box, source_size, monitor_size);
+            }
+        }
+        let detections = hits.filter(detections);
+        if let Some(heatmap) = heatmap {
+            let mut heatmap = heatmap.lock().unwrap();
+            for d in &detections {
+                heatmap.add(&d.bounding_box);
+            }
+        }
+        if let Some(writer) = trace.as_mut() {
+            let reports: Vec<_> = detections
+                .iter()
+                .map(|d| detect::detection_report(d, zone_config))
+                .collect();
+            let written = writer.write(&trace::FrameTrace {
+                time: frame
+                    .captured
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                index: frame.index,
+                convert_ms: frame.convert.map(|d| d.as_secs_f64() * 1000.0),
+                inference_ms: inference_duration.as_secs_f64() * 1000.0,
+                postprocess_ms: Some(postprocess_start.elapsed().as_secs_f64() * 1000.0),
+                detections: &reports,
+            });
+            // e.g. a full disk, which is no reason to stop detecting
+            if let Err(e) = written {
+                error!("{}: Failed to write trace, not tracing any more: {:#}", monitor_id, e);
+                *trace = None;
+            }
+        }
+
+        if let Some((every, dir)) = options.near_misses.filter(|_| detections.is_empty()) {
+            negative_frames += 1;
+            if negative_frames % every.max(1) == 0 {
+                let near_miss = candidates.first();
+                let logged = order.into_rgb(image.try_clone()?).and_then(|rgb| {
+                    log_near_miss(monitor_id, &rgb, near_miss, analysis_zone_config, analysis_bounding_box, dir)
+                });
+                if let Err(e) = logged {
+                    warn!("{}: Failed to save near miss: {:#}", monitor_id, e);
+                }
+            }
+        }
+
+        if let Some(sampler) = sampler.as_mut().filter(|_| detections.is_empty()) {
+            // rarely, and a small crop, not worth bothering the I/O thread with
+            if let Err(e) = sampler.offer(&image, order, analysis_bounding_box) {
+                warn!("{}: Failed to save audit sample: {:#}", monitor_id, e);
+            }
+        }
+
+        let detections = match stationary.as_mut() {
+            Some(stationary) => {
+                let moving = stationary.filter(detections, frame.captured);
+                if let Err(e) = stationary.save_if_due(frame.captured) {
+                    warn!("{}: {:#}", monitor_id, e);
+                }
+                moving
+            }
+            None => detections,
+        };
 
         if detections.len() > 0 {
             debug!(
@@ -346,199 +2301,1692 @@ fn run(monitor_id: u32, instrumentation_address: Option<String>, instrumentation
                 monitor_id, inference_duration, detections
             );
 
-            let d = detections
-                .iter()
-                .max_by_key(|d| (d.confidence * 1000.0) as u32)
-                .unwrap(); // generally there will only be one anyway
-            let score = (d.confidence * 100.0) as u32;
-            let description = describe(&CLASSES, &d);
-
-            let event_id =  trigger(&ctx, &description, score)?;
-            let update = event_tracker.push_detection(d.clone(), event_id);
-            process_update_event(&ctx, update);
-        }
-
-        if ctx.trigger_monitor.is_idle()? {
-            // Not recording any more, flush current event description if any
-            let update = event_tracker.clear();
-            if update.is_some() {
-                debug!("Flushing event because idle");
+            if options.dry_run {
+                let d = best_detection(&detections);
+                info!(
+                    "{}: Dry run, not triggering: {}",
+                    monitor_id,
+                    detect::describe(d, zone_config)
+                );
+            } else {
+                let mut image = order.into_rgb(image)?;
+                if let Some(head_fraction) = options.blur_heads {
+                    annotate::blur_heads(&mut image, &people, monitor_size, head_fraction)?;
+                }
+                if detected.send((detections, image, frame.captured)).is_err() {
+                    break;
+                }
             }
-            process_update_event(&ctx, update);
         }
 
-        if inference_duration.as_secs_f32() > pacemaker.target_interval {
+        if inference_duration.as_secs_f32() > options.target_interval {
             warn!(
                 "{}: Cannot keep up with max-analysis-fps (inference taking {:?})!",
                 monitor_id, inference_duration,
             );
         }
 
-        instrumentation::INFERENCE_DURATION.observe(inference_duration.as_secs_f64());
-        instrumentation::INFERENCES.inc();
+        let monitor_label = [monitor_id.to_string()];
+        instrumentation::INFERENCE_DURATION
+            .with_label_values(&[&monitor_label[0]])
+            .observe(inference_duration.as_secs_f64() * 1000.0);
+        instrumentation::LOOP_DURATION
+            .with_label_values(&[&monitor_label[0]])
+            .observe(frame.captured.elapsed().unwrap_or_default().as_secs_f64() * 1000.0);
+        instrumentation::INFERENCES.with_label_values(&[&monitor_label[0]]).inc();
 
-        pacemaker.tick();
         watchdog.reset();
+    }
+    Ok(())
+}
+
+/// A captured frame on its way to inference
+#[cfg(feature = "detect")]
+struct Frame {
+    image: Mat,
+    captured: SystemTime,
+    /// Channel order of image, BGR monitors' frames aren't converted
+    order: ml::PixelOrder,
+    /// Slot in the shared memory ring buffer
+    index: Option<u32>,
+    /// Conversion to RGB, and Rotate and Keystone
+    convert: Option<Duration>,
+}
+
+/// Capture thread: reads images at (up to) max_fps and hands them to inference at (up to)
+/// inference_fps (idle_fps while idle). Motion gating and catching up on stalls run at the
+/// capture rate.
+#[cfg(feature = "detect")]
+fn capture_frames(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor: &zoneminder::Monitor,
+    trigger_id: u32,
+    run_states: &zoneminder::db::RunStates,
+    transform: zoneminder::zone::FrameTransform,
+    max_fps: f32,
+    (inference_fps, idle_fps): (f32, Option<f32>),
+    activity: &Activity,
+    resumed: &AtomicBool,
+    fps_window: usize,
+    backlog: usize,
+    gate: zoneminder::zone::Gate,
+    watchdog: &impl Watchdog,
+    frames: mpsc::SyncSender<Frame>,
+) -> Result<()> {
+    let monitor_id = monitor.id();
+    let mut pacemaker = RealtimePacemaker::new(max_fps, fps_window);
+    let mut overruns = 0;
+    let mut inference_rate = RateLimiter::new(inference_fps);
+    instrumentation::INFERENCE_FPS.with_label_values(&[&monitor_id.to_string()]).set(inference_fps as f64);
+
+    wait_until_active(zm_conf, monitor_id, trigger_id, run_states, watchdog);
+    let mut last_active_check = Instant::now();
+
+    let mut stream = monitor.stream_images()?;
+    stream.set_timeout(watchdog.timeout());
+    // Frames skipped while paused weren't dropped because we were too slow
+    let mut paused = false;
+    let mut is_backlog = false;
+    loop {
+        if watchdog.expired() {
+            return Err(anyhow!("Watchdog expired"));
+        }
+        if gate == zoneminder::zone::Gate::ZmMotion && !is_backlog {
+            paused |= wait_for_motion(monitor, watchdog)?;
+            if group::stopping() {
+                break;
+            }
+        }
+        let image = match stream.next() {
+            Some(image) => image?,
+            None => break,
+        };
+        let iteration_start = Instant::now();
+        is_backlog = image.backlog;
+        if !paused {
+            instrumentation::FRAMES_DROPPED.with_label_values(&[&monitor_id.to_string()]).inc_by(image.skipped as f64);
+        }
+        paused = false;
+        if let Some(idle_fps) = idle_fps {
+            let busy = activity.recent(iteration_start) || monitor.in_motion()?;
+            adapt_inference_rate(monitor_id, &mut inference_rate, if busy { inference_fps } else { idle_fps });
+        }
+        // frames missed while stalled are all analyzed, that's what catching up is for
+        if is_backlog || inference_rate.ready(iteration_start) {
+            let captured = SystemTime::now();
+            let convert_start = Instant::now();
+            let index = image.index;
+            let (image, order) = image.convert_to_rgb24_or_bgr24()?;
+            let image = detect::transform_frame(image, &transform)?;
+            let frame = Frame {
+                image,
+                order,
+                captured,
+                index: Some(index),
+                convert: Some(convert_start.elapsed()),
+            };
+            if frames.send(frame).is_err() {
+                // inference is gone, and its error is the interesting one
+                return Ok(());
+            }
+        } else {
+            watchdog.skipped();
+        }
+
+        if last_active_check.elapsed() > ACTIVE_CHECK_INTERVAL {
+            paused = wait_until_active(zm_conf, monitor_id, trigger_id, run_states, watchdog);
+            if paused {
+                resumed.store(true, Ordering::SeqCst);
+            }
+            last_active_check = Instant::now();
+        }
+        if is_backlog {
+            // catching up, don't wait
+            continue;
+        }
+        if backlog > 0 && iteration_start.elapsed() > 2 * pacemaker.target_interval {
+            debug!("{}: Stalled, catching up on up to {} missed frames", monitor_id, backlog);
+            stream.catch_up(backlog);
+        }
+        pacemaker.tick();
         let current_fps = pacemaker.current_frequency() as f64;
-        instrumentation::FPS.set(current_fps);
-        instrumentation::FPS_DEVIATION.set(current_fps - ctx.max_fps as f64);
+        let monitor_label = [monitor_id.to_string()];
+        instrumentation::FPS.with_label_values(&[&monitor_label[0]]).set(current_fps);
+        instrumentation::FPS_DEVIATION
+            .with_label_values(&[&monitor_label[0]])
+            .set(current_fps - max_fps as f64);
+        instrumentation::PACING_JITTER
+            .with_label_values(&[&monitor_label[0]])
+            .observe(pacemaker.jitter().as_secs_f64() * 1000.0);
+        instrumentation::PACING_OVERRUNS
+            .with_label_values(&[&monitor_label[0]])
+            .inc_by((pacemaker.overruns() - overruns) as f64);
+        overruns = pacemaker.overruns();
+    }
+    Ok(())
+}
+
+/// Capture thread for --source-url: decodes the stream and hands frames to inference whenever
+/// it's ready for one. The stream is read continuously, so that frames are never stale.
+#[cfg(feature = "detect")]
+fn capture_url(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor: &zoneminder::Monitor,
+    trigger_id: u32,
+    run_states: &zoneminder::db::RunStates,
+    url: &str,
+    (width, height): (u32, u32),
+    transform: zoneminder::zone::FrameTransform,
+    max_fps: f32,
+    (inference_fps, idle_fps): (f32, Option<f32>),
+    activity: &Activity,
+    resumed: &AtomicBool,
+    gate: zoneminder::zone::Gate,
+    watchdog: &impl Watchdog,
+    frames: mpsc::SyncSender<Frame>,
+) -> Result<()> {
+    let monitor_id = monitor.id();
+    wait_until_active(zm_conf, monitor_id, trigger_id, run_states, watchdog);
+    let mut last_active_check = Instant::now();
+    let mut inference_rate = RateLimiter::new(inference_fps);
+    instrumentation::INFERENCE_FPS.with_label_values(&[&monitor_id.to_string()]).set(inference_fps as f64);
+
+    for image in vio::stream_url(url, width, height, max_fps)? {
+        if watchdog.expired() {
+            return Err(anyhow!("Watchdog expired"));
+        }
+        if gate == zoneminder::zone::Gate::ZmMotion && !monitor.in_motion()? {
+            watchdog.reset();
+            continue;
+        }
+        let now = Instant::now();
+        if let Some(idle_fps) = idle_fps {
+            let busy = activity.recent(now) || monitor.in_motion()?;
+            adapt_inference_rate(monitor_id, &mut inference_rate, if busy { inference_fps } else { idle_fps });
+        }
+        if !inference_rate.ready(now) {
+            watchdog.skipped();
+            continue;
+        }
+        let frame = Frame {
+            image: detect::transform_frame(image, &transform)?,
+            order: ml::PixelOrder::Rgb,
+            captured: SystemTime::now(),
+            index: None,
+            convert: None,
+        };
+        match frames.try_send(frame) {
+            Ok(()) => {}
+            // inference is busy
+            Err(mpsc::TrySendError::Full(_)) => instrumentation::FRAMES_DROPPED.with_label_values(&[&monitor_id.to_string()]).inc(),
+            // inference is gone, and its error is the interesting one
+            Err(mpsc::TrySendError::Disconnected(_)) => return Ok(()),
+        }
+        if last_active_check.elapsed() > ACTIVE_CHECK_INTERVAL {
+            if wait_until_active(zm_conf, monitor_id, trigger_id, run_states, watchdog) {
+                resumed.store(true, Ordering::SeqCst);
+            }
+            last_active_check = Instant::now();
+        }
+    }
+    Err(anyhow!("Stream {} ended", url))
+}
+
+/// Capture thread for replay: decodes a recorded clip at max_fps, in real time unless max_speed,
+/// and hands frames to inference at inference_fps. Frames are stamped with start plus their
+/// offset into the clip, which stands in for the wall clock, so that --max-speed behaves the same.
+#[cfg(feature = "detect")]
+fn capture_file(
+    path: &Path,
+    (width, height): (u32, u32),
+    transform: zoneminder::zone::FrameTransform,
+    max_fps: f32,
+    inference_fps: f32,
+    fps_window: usize,
+    max_speed: bool,
+    start: SystemTime,
+    frames: mpsc::SyncSender<Frame>,
+) -> Result<()> {
+    let mut pacemaker = RealtimePacemaker::new(max_fps, fps_window);
+    let mut inference_rate = RateLimiter::new(inference_fps);
+    let clip_start = Instant::now();
+    let mut decoder = vio::stream_file(path, width, height, max_fps)?;
+    for (n, image) in decoder.by_ref().enumerate() {
+        let offset = Duration::from_secs_f64(n as f64 / max_fps as f64);
+        if !max_speed {
+            pacemaker.tick();
+        }
+        if !inference_rate.ready(clip_start + offset) {
+            continue;
+        }
+        let frame = Frame {
+            image: detect::transform_frame(image, &transform)?,
+            order: ml::PixelOrder::Rgb,
+            captured: start + offset,
+            index: None,
+            convert: None,
+        };
+        if frames.send(frame).is_err() {
+            // inference is gone, and its error is the interesting one
+            return Ok(());
+        }
+    }
+    decoder.finish()
+}
+
+/// IdleFPS: switches between the idle and the full inference rate.
+#[cfg(feature = "detect")]
+fn adapt_inference_rate(monitor_id: u32, inference_rate: &mut RateLimiter, fps: f32) {
+    if inference_rate.set_frequency(fps) {
+        instrumentation::INFERENCE_FPS.with_label_values(&[&monitor_id.to_string()]).set(fps as f64);
+    }
+}
+
+/// With Gate=zm-motion: blocks until ZoneMinder's motion detection sees something, or the group
+/// is stopping. Returns whether it had to wait.
+#[cfg(feature = "detect")]
+fn wait_for_motion(monitor: &zoneminder::Monitor, watchdog: &impl Watchdog) -> Result<bool> {
+    if monitor.in_motion()? {
+        return Ok(false);
+    }
+    debug!("{}: Waiting for motion", monitor.id());
+    while !monitor.in_motion()? {
+        if group::stopping() {
+            return Ok(true);
+        }
+        watchdog.reset();
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    debug!("{}: Motion, analyzing", monitor.id());
+    Ok(true)
+}
+
+#[cfg(feature = "detect")]
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Triggering state of one trigger monitor
+#[cfg(feature = "detect")]
+struct TriggerTarget<'a, 'zm_conf> {
+    monitor: &'a zoneminder::Monitor<'zm_conf>,
+    /// The classes it triggers for, None for all classes not routed to another monitor
+    classes: Option<&'a [i32]>,
+    event_tracker: coalescing::EventTracker,
+    // The frame is kept for notifications
+    pending: Option<(zoneminder::PendingTrigger, Vec<Detection>, Mat)>,
+    notified_event_id: Option<u64>,
+    verify_event_id: Option<u64>,
+    /// --max-triggers-per-minute
+    trigger_limit: Option<TokenBucket>,
+    /// Triggers suppressed by trigger_limit since it last let one through
+    suppressed: u32,
+}
+
+#[cfg(feature = "detect")]
+impl TriggerTarget<'_, '_> {
+    /// routed: the classes routed to other monitors
+    fn wants(&self, class_id: i32, routed: &[i32]) -> bool {
+        match self.classes {
+            Some(classes) => classes.contains(&class_id),
+            None => !routed.contains(&class_id),
+        }
+    }
+
+    /// Whether the trigger rate limit lets another trigger through, logging when it starts and
+    /// stops suppressing them. Only triggers which start a new event count, those extending the
+    /// current one always go through, so that its detections are still coalesced into its notes.
+    fn allow_trigger(&mut self, monitor_id: u32) -> Result<bool> {
+        let trigger_limit = match self.trigger_limit.as_mut() {
+            Some(trigger_limit) => trigger_limit,
+            None => return Ok(true),
+        };
+        if !self.monitor.is_idle()? {
+            return Ok(true);
+        }
+        if !trigger_limit.take(Instant::now()) {
+            if self.suppressed == 0 {
+                warn!(
+                    "{}: Triggering monitor {} too often, suppressing triggers (--max-triggers-per-minute)",
+                    monitor_id,
+                    self.monitor.id()
+                );
+            }
+            self.suppressed += 1;
+            instrumentation::TRIGGERS_SUPPRESSED.with_label_values(&[&monitor_id.to_string()]).inc();
+            return Ok(false);
+        }
+        if self.suppressed > 0 {
+            info!(
+                "{}: Triggering monitor {} again, suppressed {} triggers",
+                monitor_id,
+                self.monitor.id(),
+                self.suppressed
+            );
+            self.suppressed = 0;
+        }
+        Ok(true)
+    }
+}
+
+/// Cancels triggers an earlier zm-aidect left on when it died, see Monitor::clear_stale_trigger.
+/// Unless the cause names the monitor, those of another instance triggering the same monitors
+/// can't be told apart, so then only the analyzed monitor itself is checked: other instances
+/// only trigger it when it's their Trigger= or Linked= monitor.
+#[cfg(feature = "detect")]
+fn clear_stale_triggers<'a>(
+    monitor_id: u32,
+    templates: &cause::Templates,
+    monitors: impl Iterator<Item = &'a zoneminder::Monitor<'a>>,
+) {
+    let identifies_monitor = templates.identifies_monitor();
+    for monitor in monitors {
+        if !identifies_monitor && monitor.id() != monitor_id {
+            continue;
+        }
+        if let Err(e) = monitor.clear_stale_trigger(|cause| templates.is_own_cause(cause)) {
+            warn!("{}: Failed to check monitor {} for a trigger left on: {:#}", monitor_id, monitor.id(), e);
+        }
+    }
+}
+
+/// I/O thread: triggers events, sounds the alarm and updates event notes for detections.
+/// Waiting for ZoneMinder to act on a trigger doesn't block: detections arriving in the meantime
+/// are queued and attributed to the event once its ID is known. Detections of classes routed
+/// elsewhere (Trigger.<Class>=XX) go to their own trigger monitor, the others to trigger_monitor.
+/// Whenever one of them is triggered, the linked monitors are as well, but their events are left
+/// alone otherwise.
+#[cfg(feature = "detect")]
+fn handle_detections(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor_id: u32,
+    trigger_monitor: &zoneminder::Monitor,
+    class_triggers: &[(Vec<i32>, zoneminder::Monitor)],
+    linked_monitors: &[zoneminder::Monitor],
+    zone_config: &zoneminder::zone::ZoneConfig,
+    alarm: &mut Option<alarm::Alarm>,
+    rescore: bool,
+    show_text: bool,
+    stats: bool,
+    notifier: &mut Option<Box<dyn notify::Notifier + Send>>,
+    dedup: Option<&dedup::Dedup>,
+    late_verify: Option<verify::LateVerify>,
+    timeout_policy: zoneminder::TimeoutPolicy,
+    templates: &cause::Templates,
+    continuation: Option<Duration>,
+    metadata: Option<coalescing::MetadataTarget>,
+    trigger_limit: Option<(f32, f32)>,
+    resumed: &AtomicBool,
+    detections: mpsc::Receiver<(Vec<Detection>, Mat, SystemTime)>,
+) -> Result<()> {
+    let update_event = |trigger_id: u32, update: Option<coalescing::UpdateEvent>| {
+        process_update_event(zm_conf, zone_config, monitor_id, trigger_id, rescore, metadata, update)
+    };
+    let set_showtext = |monitor: &zoneminder::Monitor, text: &str| {
+        if !show_text {
+            return;
+        }
+        if let Err(e) = monitor.set_showtext(text) {
+            warn!("{}: Failed to set showtext: {:#}", monitor_id, e);
+        }
+    };
+    let routed: Vec<i32> = class_triggers
+        .iter()
+        .flat_map(|(classes, _)| classes.iter().copied())
+        .collect();
+    let mut targets: Vec<TriggerTarget> = class_triggers
+        .iter()
+        .map(|(classes, monitor)| (monitor, Some(classes.as_slice())))
+        .chain([(trigger_monitor, None)])
+        .map(|(monitor, classes)| TriggerTarget {
+            monitor,
+            classes,
+            event_tracker: coalescing::EventTracker::new(monitor_id, continuation),
+            pending: None,
+            notified_event_id: None,
+            verify_event_id: None,
+            trigger_limit: trigger_limit.map(|(per_minute, burst)| TokenBucket::new(per_minute / 60.0, burst)),
+            suppressed: 0,
+        })
+        .collect();
+    let mut linked_pending: Vec<(&zoneminder::Monitor, zoneminder::PendingTrigger)> = Vec::new();
+    // errors end handling detections wherever they happen, possibly while a trigger is on
+    let _trigger_guards: Vec<zoneminder::TriggerGuard> = targets
+        .iter()
+        .map(|target| target.monitor)
+        .chain(linked_monitors.iter())
+        .map(|monitor| monitor.trigger_guard())
+        .collect();
+
+    loop {
+        let pending = targets.iter().any(|target| target.pending.is_some()) || !linked_pending.is_empty();
+        let timeout = if pending {
+            zoneminder::TRIGGER_POLL_INTERVAL
+        } else {
+            IDLE_CHECK_INTERVAL
+        };
+        let received = detections.recv_timeout(timeout);
+        if resumed.swap(false, Ordering::SeqCst) {
+            // whatever events were being tracked are long gone, detections from now on are new ones
+            for target in targets.iter_mut() {
+                let update = target.event_tracker.clear();
+                if update.is_some() {
+                    debug!("Flushing event because the monitor was paused");
+                    instrumentation::EVENT_FLUSHES
+                        .with_label_values(&[&monitor_id.to_string(), "paused"])
+                        .inc();
+                }
+                update_event(target.monitor.id(), update);
+            }
+        }
+        match received {
+            Ok((detections, image, _)) => {
+                if let Some(notifier) = notifier.as_mut() {
+                    let reports: Vec<_> = detections.iter().map(|d| detect::detection_report(d, zone_config)).collect();
+                    notifier.detections(&reports, &image);
+                }
+
+                if let Some(alarm) = alarm.as_mut() {
+                    let alarming = detections.iter().find(|d| {
+                        alarm.wants_class(d.class_id)
+                            && (zone_config.alarm.is_empty()
+                                || zone_config
+                                    .alarm
+                                    .iter()
+                                    .any(|zone| zone.matches(d.class_id, &d.bounding_box)))
+                    });
+                    if let Some(d) = alarming {
+                        alarm.fire(monitor_id, &detect::describe(d, zone_config));
+                    }
+                }
+
+                for target in targets.iter_mut() {
+                    let detections: Vec<Detection> = detections
+                        .iter()
+                        .filter(|d| target.wants(d.class_id, &routed))
+                        .cloned()
+                        .collect();
+                    if detections.is_empty() {
+                        continue;
+                    }
+                    let d = best_detection(&detections);
+                    let score = (d.confidence * 100.0) as u32;
+                    let description = detect::describe(d, zone_config);
+                    set_showtext(target.monitor, &short_label(d));
+
+                    match target.pending.as_mut() {
+                        // ZoneMinder is about to go into alarm for us anyway, so these belong to the same event
+                        Some((_, queued, _)) => queued.push(d.clone()),
+                        None => {
+                            let class = detect::class_name(d.class_id);
+                            let allow_trigger = || target.allow_trigger(monitor_id);
+                            if let Some(link_text) = dedup::admit_trigger(monitor_id, class, allow_trigger, dedup)? {
+                                let description = description + &link_text;
+                                let cause = templates.cause(d, &description);
+                                let description = templates.description(d, &description);
+                                let trigger = target
+                                    .monitor
+                                    .start_trigger(&cause, &description, score)
+                                    .with_context(|| {
+                                        format!("Failed to trigger monitor ID {}", target.monitor.id())
+                                    })?;
+                                target.pending = Some((trigger, vec![d.clone()], image.clone()));
+                                for linked in linked_monitors {
+                                    if linked_pending.iter().any(|(m, _)| m.id() == linked.id()) {
+                                        continue;
+                                    }
+                                    // the linked monitors are a bonus, failing them doesn't stop triggering
+                                    let cause = format!("{}-linked", cause);
+                                    match linked.start_trigger(&cause, &description, score) {
+                                        Ok(trigger) => linked_pending.push((linked, trigger)),
+                                        Err(e) => warn!(
+                                            "{}: Failed to trigger linked monitor ID {}: {:#}",
+                                            monitor_id,
+                                            linked.id(),
+                                            e
+                                        ),
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(notifier) = notifier.as_mut() {
+            notifier.poll();
+        }
+
+        if let Some(late_verify) = &late_verify {
+            while let Ok(verified) = late_verify.results.try_recv() {
+                let target = match targets.iter_mut().find(|target| target.monitor.id() == verified.trigger_id) {
+                    Some(target) => target,
+                    None => continue,
+                };
+                let detections: Vec<Detection> = verified
+                    .detections
+                    .into_iter()
+                    .filter(|d| target.wants(d.class_id, &routed))
+                    .collect();
+                if detections.is_empty() {
+                    continue;
+                }
+                let d = best_detection(&detections).clone();
+                update_event(
+                    verified.trigger_id,
+                    target.event_tracker.push_late_detection(d, verified.event_id),
+                );
+            }
+        }
+
+        linked_pending.retain(|(linked, trigger)| match linked.poll_trigger(trigger) {
+            Ok(Some(event_id)) => {
+                debug!("{}: Linked monitor {} is in alarm, event {}", monitor_id, linked.id(), event_id);
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                warn!("{}: Failed to trigger linked monitor ID {}: {:#}", monitor_id, linked.id(), e);
+                false
+            }
+        });
+
+        for target in targets.iter_mut() {
+            let trigger_id = target.monitor.id();
+            if let Some((trigger, _, _)) = &target.pending {
+                match target.monitor.poll_trigger(trigger) {
+                    Ok(Some(event_id)) => {
+                        let (_, queued, image) = target.pending.take().unwrap();
+                        if let Some(late_verify) = &late_verify {
+                            if target.verify_event_id != Some(event_id) {
+                                target.verify_event_id = Some(event_id);
+                                let request = verify::Request {
+                                    trigger_id,
+                                    event_id,
+                                    due: Instant::now() + late_verify.delay,
+                                };
+                                // only fails if verification died, which it only does when shutting down
+                                let _ = late_verify.requests.send(request);
+                            }
+                        }
+                        if let Some(dedup) = dedup {
+                            if let Err(e) = dedup.event(detect::class_name(queued[0].class_id), event_id) {
+                                warn!("{}: Deduplication failed: {:#}", monitor_id, e);
+                            }
+                        }
+                        if let Some(notifier) = notifier.as_mut() {
+                            if target.notified_event_id != Some(event_id) {
+                                target.notified_event_id = Some(event_id);
+                                let detection = detect::detection_report(&queued[0], zone_config);
+                                let notification = notify::Notification::new(trigger_id, event_id, detection);
+                                notifier.event(&notification, &image);
+                            }
+                        }
+                        for d in queued {
+                            if stats {
+                                insert_alarm_stats(zm_conf, trigger_id, zone_config, event_id, &d);
+                            }
+                            update_event(trigger_id, target.event_tracker.push_detection(d, event_id, SystemTime::now()));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let retry = e
+                            .downcast_ref::<zoneminder::TriggerTimeout>()
+                            .and_then(|_| timeout_policy.retry(trigger));
+                        match retry {
+                            Some(via_zmtrigger) => {
+                                warn!(
+                                    "{}: {:#}, triggering again{}",
+                                    monitor_id,
+                                    e,
+                                    if via_zmtrigger { " through zmtrigger" } else { "" }
+                                );
+                                match target.monitor.retry_trigger(trigger, via_zmtrigger) {
+                                    Ok(retried) => target.pending.as_mut().unwrap().0 = retried,
+                                    Err(e) => {
+                                        error!(
+                                            "{}: Failed to trigger monitor ID {}: {:#}",
+                                            monitor_id, trigger_id, e
+                                        );
+                                        target.pending = None;
+                                    }
+                                }
+                            }
+                            None => {
+                                error!(
+                                    "{}: Failed to trigger monitor ID {}: {:#}",
+                                    monitor_id, trigger_id, e
+                                );
+                                target.pending = None;
+                            }
+                        }
+                    }
+                }
+            }
+
+            if target.pending.is_none() && target.monitor.is_idle()? {
+                // Not recording any more, flush current event description if any
+                let update = target.event_tracker.clear();
+                if update.is_some() {
+                    debug!("Flushing event because idle");
+                    instrumentation::EVENT_FLUSHES
+                        .with_label_values(&[&monitor_id.to_string(), "idle"])
+                        .inc();
+                    set_showtext(target.monitor, "");
+                }
+                update_event(trigger_id, update);
+            }
+        }
+    }
+    // shutting down, don't lose what we know about the current events (the showtext is cleared by
+    // writes::shutdown)
+    for target in targets.iter_mut() {
+        update_event(target.monitor.id(), target.event_tracker.clear());
+    }
+    if let Some(notifier) = notifier.as_mut() {
+        notifier.flush();
     }
     Ok(())
 }
 
-fn describe(classes: &HashMap<i32, &str>, d: &Detection) -> String {
+#[cfg(feature = "detect")]
+fn insert_alarm_stats(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    trigger_id: u32,
+    zone_config: &zoneminder::zone::ZoneConfig,
+    event_id: u64,
+    d: &Detection,
+) {
+    let b = d.bounding_box;
+    let stats = zoneminder::db::AlarmStats {
+        monitor_id: trigger_id,
+        zone_id: zone_config.id,
+        event_id,
+        min: (b.x, b.y),
+        max: (b.x + b.width - 1, b.y + b.height - 1),
+        score: (d.confidence * 100.0) as u32,
+    };
+    if let Err(e) = zoneminder::db::insert_alarm_stats(zm_conf, &stats) {
+        error!("{}: Failed to add stats to event {}: {:#}", trigger_id, event_id, e);
+    }
+}
+
+#[cfg(feature = "detect")]
+fn process_update_event(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    zone_config: &zoneminder::zone::ZoneConfig,
+    monitor_id: u32,
+    trigger_id: u32,
+    rescore: bool,
+    metadata: Option<coalescing::MetadataTarget>,
+    update: Option<coalescing::UpdateEvent>,
+) {
+    if let Some(update) = update {
+        let notes = coalescing::notes(&detect::describe(&update.detection, zone_config), &update, metadata);
+        if let Err(e) = zoneminder::db::update_event_notes(zm_conf, update.event_id, &notes) {
+            instrumentation::NOTES_UPDATES
+                .with_label_values(&[&monitor_id.to_string(), "failure"])
+                .inc();
+            error!(
+                "{}: Failed to update event {} notes: {}",
+                trigger_id, update.event_id, e
+            );
+        } else {
+            instrumentation::NOTES_UPDATES
+                .with_label_values(&[&monitor_id.to_string(), "success"])
+                .inc();
+        }
+        match metadata {
+            Some(coalescing::MetadataTarget::Table) => {
+                let json = serde_json::to_string(&update.metadata).unwrap();
+                if let Err(e) = zoneminder::db::upsert_event_metadata(zm_conf, update.event_id, trigger_id, &json) {
+                    error!(
+                        "{}: Failed to store event {} metadata: {:#}",
+                        trigger_id, update.event_id, e
+                    );
+                }
+            }
+            Some(coalescing::MetadataTarget::Log) => {
+                let json = serde_json::to_string(&update.metadata).unwrap();
+                info!("{}: Event {} metadata: {}", trigger_id, update.event_id, json);
+            }
+            _ => {}
+        }
+        if rescore {
+            if let Err(e) = zoneminder::db::update_event_scores(
+                zm_conf,
+                update.event_id,
+                update.max_score,
+                update.avg_score,
+            ) {
+                error!(
+                    "{}: Failed to update event {} scores: {}",
+                    trigger_id, update.event_id, e
+                );
+            }
+        }
+    }
+}
+
+/// --near-misses: logs what the model almost detected in a frame without detections, and saves
+/// the frame into dir.
+#[cfg(feature = "detect")]
+fn log_near_miss(
+    monitor_id: u32,
+    image: &Mat,
+    near_miss: Option<&Detection>,
+    zone_config: &zoneminder::zone::ZoneConfig,
+    bounding_box: Rect,
+    dir: Option<&Path>,
+) -> Result<()> {
+    match near_miss {
+        Some(d) => {
+            info!(
+                "{}: Nothing detected, closest below Threshold: {}",
+                monitor_id,
+                detect::describe(d, zone_config)
+            );
+            instrumentation::NEAR_MISS_CONFIDENCE
+                .with_label_values(&[&monitor_id.to_string(), detect::class_name(d.class_id)])
+                .observe(d.confidence as f64);
+        }
+        None => info!(
+            "{}: Nothing detected, not even at {:.0}% confidence",
+            monitor_id,
+            detect::CANDIDATE_THRESHOLD * 100.0
+        ),
+    }
+    if let Some(dir) = dir {
+        let near_misses = near_miss.map(std::slice::from_ref).unwrap_or(&[]);
+        let annotated = annotate::annotate(image, zone_config, bounding_box, near_misses, |d| {
+            detect::describe(d, zone_config)
+        })?;
+        std::fs::create_dir_all(dir)?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        annotate::save(&dir.join(format!("{}-{}.jpg", monitor_id, time)), &annotated)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "detect")]
+fn best_detection(detections: &[Detection]) -> &Detection {
+    detections
+        .iter()
+        .max_by_key(|d| (d.confidence * 1000.0) as u32)
+        .unwrap() // generally there will only be one anyway
+}
+
+#[cfg(feature = "detect")]
+const ACTIVE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why analyzing the monitor is pointless right now: it (or the one being triggered) is disabled
+/// or in a function where triggering it does nothing, or ZoneMinder is in a run state zm-aidect is
+/// off in. None if it's active. Errors (e.g. database down) are logged and count as active.
+#[cfg(feature = "detect")]
+fn inactive_reason(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor_id: u32,
+    trigger_id: u32,
+    run_states: &zoneminder::db::RunStates,
+) -> Option<String> {
+    let check = |monitor_id| -> Result<bool> {
+        Ok(zoneminder::db::MonitorSettings::query(zm_conf, monitor_id)?.is_active())
+    };
+    let result = (|| -> Result<Option<String>> {
+        if !(check(monitor_id)? && (trigger_id == monitor_id || check(trigger_id)?)) {
+            return Ok(Some("Monitor is disabled or its function doesn't allow triggering".to_string()));
+        }
+        if !run_states.is_empty() {
+            let state = zoneminder::db::active_run_state(zm_conf)?;
+            if !run_states.allows(state.as_deref()) {
+                return Ok(Some(format!("Run state {} is off", state.as_deref().unwrap_or("(none)"))));
+            }
+        }
+        Ok(None)
+    })();
+    result.unwrap_or_else(|e| {
+        warn!("{}: Failed to query monitor state: {}", monitor_id, e);
+        None
+    })
+}
+
+/// Blocks while the monitor is inactive (see inactive_reason), e.g. disabled in the web UI.
+/// Returns whether it paused.
+#[cfg(feature = "detect")]
+fn wait_until_active(
+    zm_conf: &zoneminder::ZoneMinderConf,
+    monitor_id: u32,
+    trigger_id: u32,
+    run_states: &zoneminder::db::RunStates,
+    watchdog: &impl Watchdog,
+) -> bool {
+    let check_active = || {
+        let previous = watchdog.enter(Stage::Database);
+        let reason = inactive_reason(zm_conf, monitor_id, trigger_id, run_states);
+        watchdog.enter(previous);
+        reason
+    };
+    match check_active() {
+        Some(reason) => info!("{}: {}, pausing", monitor_id, reason),
+        None => return false,
+    }
+    loop {
+        // the watchdog timeout can be well below a second
+        let paused_at = Instant::now();
+        while paused_at.elapsed() < ACTIVE_CHECK_INTERVAL {
+            if group::stopping() {
+                return true;
+            }
+            watchdog.reset();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        if check_active().is_none() {
+            break;
+        }
+    }
+    info!("{}: Monitor is active again, resuming", monitor_id);
+    true
+}
+
+/// Short enough to fit into a timestamp label, e.g. "Human 91%".
+#[cfg(feature = "detect")]
+fn short_label(d: &Detection) -> String {
     format!(
-        "{} ({:.1}%) {}x{} (={}) at {}x{}",
-        classes[&d.class_id],
-        d.confidence * 100.0,
-        d.bounding_box.width,
-        d.bounding_box.height,
-        d.bounding_box.width * d.bounding_box.height,
-        d.bounding_box.x,
-        d.bounding_box.y,
+        "{} {:.0}%",
+        detect::class_name(d.class_id),
+        d.confidence * 100.0
     )
 }
 
+#[cfg(feature = "detect")]
 mod coalescing {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use anyhow::anyhow;
     use log::trace;
+    use serde::Serialize;
 
-    use crate::ml::Detection;
+    use crate::instrumentation;
+    use zoneminder_aidect::detect;
+    use zoneminder_aidect::ml::Detection;
 
     struct TrackedEvent {
         event_id: u64,
         detections: Vec<Detection>,
+        /// When the first and the last live detection were pushed
+        first_seen: SystemTime,
+        last_seen: SystemTime,
+        continued_from: Option<u64>,
+        continued_in: Option<u64>,
     }
 
     pub struct UpdateEvent {
         pub event_id: u64,
+        /// Most confident detection
         pub detection: Detection,
+        /// Scores (confidence in percent) over all detections during the event
+        pub max_score: u32,
+        pub avg_score: u32,
+        /// The previous event of the same incident, see EventTracker::new
+        pub continued_from: Option<u64>,
+        /// The next event of the same incident
+        pub continued_in: Option<u64>,
+        pub metadata: EventMetadata,
+    }
+
+    /// Summary of an event for machine processing (run --event-metadata)
+    #[derive(Serialize, Debug, PartialEq)]
+    pub struct EventMetadata {
+        /// Detections per class
+        pub classes: BTreeMap<&'static str, u32>,
+        pub max_confidence: f32,
+        pub detections: u32,
+        /// Unix time of the first and last detection while the event was live
+        pub first: f64,
+        pub last: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub continued_from: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub continued_in: Option<u64>,
+    }
+
+    /// Where run --event-metadata puts EventMetadata
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum MetadataTarget {
+        /// As JSON on the last line of the notes
+        Notes,
+        /// Into the AidectEvents table
+        Table,
+        /// Only logged, what Table becomes in a dry run
+        Log,
+    }
+
+    impl TryFrom<&str> for MetadataTarget {
+        type Error = anyhow::Error;
+
+        fn try_from(value: &str) -> Result<Self, Self::Error> {
+            match value {
+                "notes" => Ok(MetadataTarget::Notes),
+                "table" => Ok(MetadataTarget::Table),
+                _ => Err(anyhow!("Invalid event metadata target: {}", value)),
+            }
+        }
     }
 
     pub struct EventTracker {
+        /// The monitor analyzed, for the metrics
+        monitor_id: u32,
         current_event: Option<TrackedEvent>,
+        /// The previous current_event, for late detections
+        flushed_event: Option<TrackedEvent>,
+        continuation: Option<Duration>,
     }
 
     impl EventTracker {
-        pub fn new() -> EventTracker {
+        /// A new event starting within continuation of the last detection in the previous one
+        /// continues the same incident (e.g. ZoneMinder started a new event at the section
+        /// length), and the notes of both refer to each other.
+        pub fn new(monitor_id: u32, continuation: Option<Duration>) -> EventTracker {
             EventTracker {
+                monitor_id,
                 current_event: None,
+                flushed_event: None,
+                continuation,
             }
         }
 
-        pub fn push_detection(&mut self, d: Detection, event_id: u64) -> Option<UpdateEvent> {
+        pub fn push_detection(&mut self, d: Detection, event_id: u64, now: SystemTime) -> Option<UpdateEvent> {
+            if let Some(current_event) = self.current_event.as_mut().filter(|e| e.event_id == event_id) {
+                current_event.detections.push(d);
+                current_event.last_seen = now;
+                return None;
+            }
+            let continued_from = self.continues(event_id, now);
             let mut update = None;
             if let Some(current_event) = self.current_event.as_mut() {
-                if current_event.event_id != event_id {
-                    trace!("Flushing event {} -> {}", current_event.event_id, event_id);
-                    update = self.clear();
-                } else {
-                    current_event.detections.push(d);
-                    return None;
+                trace!("Flushing event {} -> {}", current_event.event_id, event_id);
+                instrumentation::EVENT_FLUSHES
+                    .with_label_values(&[&self.monitor_id.to_string(), "new_event"])
+                    .inc();
+                if continued_from.is_some() {
+                    current_event.continued_in = Some(event_id);
                 }
+                update = self.clear();
+            } else if let Some(flushed_event) = self.flushed_event.as_mut().filter(|_| continued_from.is_some()) {
+                // flushed while idle in between, its notes need the reference as well
+                flushed_event.continued_in = Some(event_id);
+                update = Some(summarize(flushed_event));
             }
+            instrumentation::EVENTS_TRACKED
+                .with_label_values(&[&self.monitor_id.to_string()])
+                .inc();
             self.current_event = Some(TrackedEvent {
                 event_id,
                 detections: vec![d],
+                first_seen: now,
+                last_seen: now,
+                continued_from,
+                continued_in: None,
             });
             update
         }
 
+        /// The event event_id continues, if any
+        fn continues(&self, event_id: u64, now: SystemTime) -> Option<u64> {
+            let continuation = self.continuation?;
+            let previous = self.current_event.as_ref().or(self.flushed_event.as_ref())?;
+            let gap = now.duration_since(previous.last_seen).unwrap_or_default();
+            (previous.event_id < event_id && gap <= continuation)
+                .then(|| previous.event_id)
+        }
+
+        /// Detections found in the event's recording later on, which are coalesced with the
+        /// others. Returns the update if the event was flushed already and d is its best detection now.
+        pub fn push_late_detection(&mut self, d: Detection, event_id: u64) -> Option<UpdateEvent> {
+            if let Some(current_event) = self.current_event.as_mut().filter(|e| e.event_id == event_id) {
+                current_event.detections.push(d);
+                return None;
+            }
+            let flushed_event = self.flushed_event.as_mut().filter(|e| e.event_id == event_id)?;
+            let best = flushed_event.detections.iter().all(|other| d.confidence > other.confidence);
+            flushed_event.detections.push(d);
+            best.then(|| summarize(flushed_event))
+        }
+
         pub fn clear(&mut self) -> Option<UpdateEvent> {
             let current_event = self.current_event.take()?;
-            let detection = current_event
-                .detections
-                .iter()
-                .max_by_key(|d| (d.confidence * 1000.0) as u32)
-                .unwrap();
-            // TODO: aggregate by classes, annotate counts.
-            trace!(
-                "Coalesce {} with {:?} to {:?}",
-                current_event.event_id,
-                current_event.detections,
-                detection
+            instrumentation::DETECTIONS_PER_EVENT
+                .with_label_values(&[&self.monitor_id.to_string()])
+                .observe(current_event.detections.len() as f64);
+            let update = summarize(&current_event);
+            self.flushed_event = Some(current_event);
+            Some(update)
+        }
+    }
+
+    fn summarize(event: &TrackedEvent) -> UpdateEvent {
+        let detection = event
+            .detections
+            .iter()
+            .max_by_key(|d| (d.confidence * 1000.0) as u32)
+            .unwrap();
+        // TODO: aggregate by classes, annotate counts.
+        let scores: Vec<u32> = event
+            .detections
+            .iter()
+            .map(|d| (d.confidence * 100.0) as u32)
+            .collect();
+        trace!(
+            "Coalesce {} with {:?} to {:?}",
+            event.event_id,
+            event.detections,
+            detection
+        );
+        UpdateEvent {
+            event_id: event.event_id,
+            detection: detection.clone(),
+            max_score: *scores.iter().max().unwrap(),
+            avg_score: scores.iter().sum::<u32>() / scores.len() as u32,
+            continued_from: event.continued_from,
+            continued_in: event.continued_in,
+            metadata: metadata(event),
+        }
+    }
+
+    fn metadata(event: &TrackedEvent) -> EventMetadata {
+        let mut classes = BTreeMap::new();
+        for d in &event.detections {
+            *classes.entry(detect::class_name(d.class_id)).or_default() += 1;
+        }
+        let unix_time = |time: SystemTime| {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+            (since_epoch * 1000.0).round() / 1000.0
+        };
+        EventMetadata {
+            classes,
+            max_confidence: event.detections.iter().map(|d| d.confidence).fold(0.0, f32::max),
+            detections: event.detections.len() as u32,
+            first: unix_time(event.first_seen),
+            last: unix_time(event.last_seen),
+            continued_from: event.continued_from,
+            continued_in: event.continued_in,
+        }
+    }
+
+    /// Event notes: the description of the best detection, the other events of the incident and
+    /// with MetadataTarget::Notes the metadata on a line of its own
+    pub fn notes(description: &str, update: &UpdateEvent, target: Option<MetadataTarget>) -> String {
+        let mut notes = description.to_string();
+        if let Some(event_id) = update.continued_from {
+            notes += &format!(" (continued from event {})", event_id);
+        }
+        if let Some(event_id) = update.continued_in {
+            notes += &format!(" (continued in event {})", event_id);
+        }
+        if target == Some(MetadataTarget::Notes) {
+            notes += "\n";
+            notes += &serde_json::to_string(&update.metadata).unwrap();
+        }
+        notes
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use opencv::core::Rect;
+
+        fn detection(confidence: f32) -> Detection {
+            Detection {
+                confidence,
+                class_id: 1,
+                bounding_box: Rect::new(0, 0, 100, 100),
+                runner_up: None,
+                label: None,
+            }
+        }
+
+        #[test]
+        fn test_push_late_detection() {
+            let mut tracker = EventTracker::new(1, None);
+            assert!(tracker.push_detection(detection(0.6), 1, SystemTime::now()).is_none());
+            // still going, coalesced with the others
+            assert!(tracker.push_late_detection(detection(0.7), 1).is_none());
+            let update = tracker.clear().unwrap();
+            assert_eq!((update.max_score, update.avg_score), (70, 65));
+
+            // flushed already, only an update if it's better
+            assert!(tracker.push_late_detection(detection(0.5), 1).is_none());
+            let update = tracker.push_late_detection(detection(0.9), 1).unwrap();
+            assert_eq!(update.event_id, 1);
+            assert_eq!(update.detection.confidence, 0.9);
+            // some other event
+            assert!(tracker.push_late_detection(detection(0.9), 2).is_none());
+        }
+
+        #[test]
+        fn test_continuation() {
+            let mut tracker = EventTracker::new(1, Some(Duration::from_secs(10)));
+            let start = UNIX_EPOCH + Duration::from_secs(1657886400);
+            assert!(tracker.push_detection(detection(0.6), 1, start).is_none());
+            let update = tracker.push_detection(detection(0.7), 2, start + Duration::from_secs(5)).unwrap();
+            assert_eq!((update.event_id, update.continued_in), (1, Some(2)));
+            assert_eq!(notes("Human", &update, None), "Human (continued in event 2)");
+
+            // flushed while idle, the update for it comes with the next event
+            let update = tracker.clear().unwrap();
+            assert_eq!((update.continued_from, update.continued_in), (Some(1), None));
+            let update = tracker.push_detection(detection(0.8), 3, start + Duration::from_secs(12)).unwrap();
+            assert_eq!((update.event_id, update.continued_in), (2, Some(3)));
+            assert_eq!(notes("Human", &update, None), "Human (continued from event 1) (continued in event 3)");
+
+            // too long after
+            let update = tracker.push_detection(detection(0.8), 4, start + Duration::from_secs(30)).unwrap();
+            assert_eq!((update.event_id, update.continued_in), (3, None));
+            assert_eq!(tracker.clear().unwrap().continued_from, None);
+        }
+
+        #[test]
+        fn test_metadata() {
+            let mut tracker = EventTracker::new(1, None);
+            let start = UNIX_EPOCH + Duration::from_secs(1657886400);
+            tracker.push_detection(detection(0.6), 1, start);
+            let car = Detection {
+                class_id: 3,
+                ..detection(0.8)
+            };
+            tracker.push_detection(car, 1, start + Duration::from_millis(2500));
+            tracker.push_late_detection(detection(0.7), 1);
+            let update = tracker.clear().unwrap();
+            assert_eq!(
+                notes("Car", &update, Some(MetadataTarget::Notes)),
+                "Car\n{\"classes\":{\"Car\":1,\"Human\":2},\"max_confidence\":0.8,\"detections\":3,\"first\":1657886400.0,\"last\":1657886402.5}"
             );
-            Some(UpdateEvent {
-                event_id: current_event.event_id,
-                detection: detection.clone(),
-            })
+            assert_eq!(notes("Car", &update, Some(MetadataTarget::Table)), "Car");
         }
     }
 }
 
-trait Pacemaker {
-    fn tick(&mut self);
-    fn current_frequency(&self) -> f32;
-}
+/// MinHits: only pass on objects which were detected in several consecutive frames, to get rid of
+/// one-frame flickers (e.g. headlight glare as a Car).
+#[cfg(feature = "detect")]
+mod smoothing {
+    use zoneminder_aidect::ml::{iou, Detection};
 
-struct RealtimePacemaker {
-    target_interval: f32,
-    last_tick: Option<Instant>,
-    avg: simple_moving_average::NoSumSMA<f32, f32, 10>,
-    current_frequency: f32,
-}
+    /// Bounding boxes overlapping at least this much (intersection over union) are the same object
+    const MIN_IOU: f32 = 0.3;
+
+    pub struct HitTracker {
+        min_hits: u32,
+        /// Detections of the previous frame, with how many consecutive frames they were seen in
+        previous: Vec<(Detection, u32)>,
+    }
+
+    impl HitTracker {
+        pub fn new(min_hits: u32) -> HitTracker {
+            HitTracker {
+                min_hits,
+                previous: Vec::new(),
+            }
+        }
+
+        /// Takes all detections of a frame, returns those seen in at least min_hits consecutive frames.
+        pub fn filter(&mut self, detections: Vec<Detection>) -> Vec<Detection> {
+            if self.min_hits <= 1 {
+                return detections;
+            }
+            let current: Vec<(Detection, u32)> = detections
+                .into_iter()
+                .map(|d| {
+                    let hits = self
+                        .previous
+                        .iter()
+                        .filter(|(p, _)| {
+                            p.class_id == d.class_id && iou(&p.bounding_box, &d.bounding_box) >= MIN_IOU
+                        })
+                        .map(|(_, hits)| hits + 1)
+                        .max()
+                        .unwrap_or(1);
+                    (d, hits)
+                })
+                .collect();
+            let confirmed = current
+                .iter()
+                .filter(|(_, hits)| *hits >= self.min_hits)
+                .map(|(d, _)| d.clone())
+                .collect();
+            self.previous = current;
+            confirmed
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use opencv::core::Rect;
+
+        use super::*;
+
+        fn detection(class_id: i32, x: i32) -> Detection {
+            Detection {
+                confidence: 0.6,
+                class_id,
+                bounding_box: Rect::new(x, 0, 100, 100),
+                runner_up: None,
+                label: None,
+            }
+        }
 
-impl RealtimePacemaker {
-    fn new(frequency: f32) -> RealtimePacemaker {
-        RealtimePacemaker {
-            target_interval: 1.0f32 / frequency,
-            last_tick: None,
-            avg: simple_moving_average::NoSumSMA::new(),
-            current_frequency: 0.0,
+        #[test]
+        fn test_hit_tracker() {
+            let mut hits = HitTracker::new(3);
+            assert!(hits.filter(vec![detection(1, 0)]).is_empty());
+            // moved a bit, and a car appeared
+            assert!(hits.filter(vec![detection(1, 10), detection(3, 500)]).is_empty());
+            let confirmed = hits.filter(vec![detection(1, 20), detection(3, 500)]);
+            assert_eq!(confirmed.len(), 1);
+            assert_eq!(confirmed[0].class_id, 1);
+            // a frame without the person resets it
+            assert_eq!(hits.filter(vec![detection(3, 500)]).len(), 1);
+            assert!(hits.filter(vec![detection(1, 20), detection(3, 500)])[0].class_id == 3);
+
+            let mut hits = HitTracker::new(1);
+            assert_eq!(hits.filter(vec![detection(1, 0)]).len(), 1);
         }
     }
 }
 
-impl Pacemaker for RealtimePacemaker {
-    fn tick(&mut self) {
-        if let Some(last_iteration) = self.last_tick {
+/// Paces capture to the analysis fps: ticks are scheduled on a fixed grid (the previous deadline
+/// plus the interval), so a slow frame is made up for by the next one instead of shifting all
+/// later ticks, and the rate doesn't drift with the load.
+#[cfg(feature = "detect")]
+mod pacemaker {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// With IdleFPS, how long after the last detection inference slows down
+    const IDLE_AFTER: Duration = Duration::from_secs(10);
+
+    pub trait Pacemaker {
+        fn tick(&mut self);
+        fn current_frequency(&self) -> f32;
+        /// How late the last tick was for its deadline
+        fn jitter(&self) -> Duration;
+        /// Ticks called after their deadline had passed, i.e. the work took longer than the interval
+        fn overruns(&self) -> u64;
+    }
+
+    pub struct RealtimePacemaker {
+        pub target_interval: Duration,
+        next_tick: Option<Instant>,
+        last_tick: Option<Instant>,
+        /// The last intervals between ticks, current_frequency is averaged over these
+        intervals: VecDeque<Duration>,
+        window: usize,
+        jitter: Duration,
+        overruns: u64,
+    }
+
+    impl RealtimePacemaker {
+        pub fn new(frequency: f32, window: usize) -> RealtimePacemaker {
+            RealtimePacemaker {
+                target_interval: Duration::from_secs_f32(1.0 / frequency),
+                next_tick: None,
+                last_tick: None,
+                intervals: VecDeque::new(),
+                window: window.max(1),
+                jitter: Duration::ZERO,
+                overruns: 0,
+            }
+        }
+    }
+
+    /// Lets through one call per interval, for doing something less often than the loop it's in
+    /// runs. Stays on a fixed schedule like RealtimePacemaker, so that the loop's jitter doesn't
+    /// add up to a lower rate.
+    pub struct RateLimiter {
+        interval: Duration,
+        next: Option<Instant>,
+    }
+
+    impl RateLimiter {
+        pub fn new(frequency: f32) -> RateLimiter {
+            RateLimiter {
+                interval: Duration::from_secs_f32(1.0 / frequency),
+                next: None,
+            }
+        }
+
+        /// Returns whether it changed. A new frequency takes effect right away, the next call to
+        /// ready passes.
+        pub fn set_frequency(&mut self, frequency: f32) -> bool {
+            let interval = Duration::from_secs_f32(1.0 / frequency);
+            if interval == self.interval {
+                return false;
+            }
+            self.interval = interval;
+            self.next = None;
+            true
+        }
+
+        pub fn ready(&mut self, now: Instant) -> bool {
+            let deadline = *self.next.get_or_insert(now);
+            if now < deadline {
+                return false;
+            }
+            self.next = Some(next_deadline(deadline, now, self.interval));
+            true
+        }
+    }
+
+    /// Lets through bursts of up to capacity calls, and on average rate calls per second.
+    pub struct TokenBucket {
+        capacity: f32,
+        rate: f32,
+        tokens: f32,
+        last: Option<Instant>,
+    }
+
+    impl TokenBucket {
+        pub fn new(rate: f32, capacity: f32) -> TokenBucket {
+            TokenBucket {
+                capacity,
+                rate,
+                tokens: capacity,
+                last: None,
+            }
+        }
+
+        /// Takes a token if there is one
+        pub fn take(&mut self, now: Instant) -> bool {
+            if let Some(last) = self.last {
+                let refill = now.saturating_duration_since(last).as_secs_f32() * self.rate;
+                self.tokens = (self.tokens + refill).min(self.capacity);
+            }
+            self.last = Some(now);
+            if self.tokens < 1.0 {
+                return false;
+            }
+            self.tokens -= 1.0;
+            true
+        }
+    }
+
+    /// When inference last detected something, for capture to decide whether it's idle.
+    #[derive(Default)]
+    pub struct Activity {
+        last_detection: Mutex<Option<Instant>>,
+    }
+
+    impl Activity {
+        pub fn detected(&self, now: Instant) {
+            *self.last_detection.lock().unwrap() = Some(now);
+        }
+
+        /// Whether something was detected within IDLE_AFTER
+        pub fn recent(&self, now: Instant) -> bool {
+            self.last_detection
+                .lock()
+                .unwrap()
+                .map_or(false, |last| now.saturating_duration_since(last) < IDLE_AFTER)
+        }
+    }
+
+    /// The deadline after deadline. If the tick came more than a whole interval late, the missed
+    /// ticks are skipped rather than run back to back.
+    fn next_deadline(deadline: Instant, now: Instant, interval: Duration) -> Instant {
+        let next = deadline + interval;
+        if now > next {
+            now + interval
+        } else {
+            next
+        }
+    }
+
+    impl Pacemaker for RealtimePacemaker {
+        fn tick(&mut self) {
             let now = Instant::now();
-            let frame_duration = (now - last_iteration).as_secs_f32(); // how long the paced workload ran
-                                                                       // smoothing using moving average
-            self.avg.add_sample(frame_duration);
-            let average_duration = self.avg.get_average();
+            let deadline = *self.next_tick.get_or_insert(now);
+            if now < deadline {
+                std::thread::sleep(deadline - now);
+            } else if now > deadline {
+                self.overruns += 1;
+            }
+            let woke = Instant::now();
+            self.jitter = woke.saturating_duration_since(deadline);
+            self.next_tick = Some(next_deadline(deadline, woke, self.target_interval));
+
+            if let Some(last_tick) = self.last_tick {
+                if self.intervals.len() == self.window {
+                    self.intervals.pop_front();
+                }
+                self.intervals.push_back(woke - last_tick);
+            }
+            self.last_tick = Some(woke);
+        }
 
-            let sleep_duration = self.target_interval - average_duration;
-            if sleep_duration > 0.0 {
-                std::thread::sleep(Duration::from_secs_f32(sleep_duration));
+        fn current_frequency(&self) -> f32 {
+            if self.intervals.is_empty() {
+                return 0.0;
             }
+            let total: Duration = self.intervals.iter().sum();
+            self.intervals.len() as f32 / total.as_secs_f32()
+        }
+
+        fn jitter(&self) -> Duration {
+            self.jitter
+        }
+
+        fn overruns(&self) -> u64 {
+            self.overruns
+        }
+    }
 
-            // calculate current frequency from the tick interval (workload + sleeping)
-            let tick_interval = Instant::now() - last_iteration;
-            self.current_frequency = 1.0f32 / tick_interval.as_secs_f32();
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_next_deadline() {
+            let start = Instant::now();
+            let interval = Duration::from_millis(100);
+            let ms = |ms| start + Duration::from_millis(ms);
+            // on time or a bit late: stay on the grid
+            assert_eq!(next_deadline(start, start, interval), ms(100));
+            assert_eq!(next_deadline(start, ms(60), interval), ms(100));
+            // more than an interval behind: start over from now
+            assert_eq!(next_deadline(start, ms(250), interval), ms(350));
+        }
+
+        #[test]
+        fn test_rate_limiter() {
+            let start = Instant::now();
+            let ms = |ms| start + Duration::from_millis(ms);
+            let mut limiter = RateLimiter::new(2.0);
+            // frames captured at 10 fps, with a bit of jitter
+            let passed: Vec<u64> = [0, 100, 205, 300, 400, 501, 600, 700, 800, 900, 1003, 1100]
+                .into_iter()
+                .filter(|&t| limiter.ready(ms(t)))
+                .collect();
+            assert_eq!(passed, vec![0, 501, 1003]);
+
+            // speeding up doesn't wait for the slow schedule
+            assert!(!limiter.ready(ms(1100)));
+            assert!(limiter.set_frequency(10.0));
+            assert!(!limiter.set_frequency(10.0));
+            assert!(limiter.ready(ms(1100)));
+            assert!(!limiter.ready(ms(1150)));
+            assert!(limiter.ready(ms(1200)));
+        }
+
+        #[test]
+        fn test_token_bucket() {
+            let start = Instant::now();
+            let ms = |ms| start + Duration::from_millis(ms);
+            // one per second, bursts of three
+            let mut bucket = TokenBucket::new(1.0, 3.0);
+            let passed: Vec<u64> = [0, 100, 200, 300, 400, 1300, 1400, 5000, 5001, 5002, 5003]
+                .into_iter()
+                .filter(|&t| bucket.take(ms(t)))
+                .collect();
+            assert_eq!(passed, vec![0, 100, 200, 1300, 5000, 5001, 5002]);
+        }
+
+        #[test]
+        fn test_activity() {
+            let start = Instant::now();
+            let activity = Activity::default();
+            assert!(!activity.recent(start));
+            activity.detected(start);
+            assert!(activity.recent(start + Duration::from_secs(9)));
+            assert!(!activity.recent(start + IDLE_AFTER));
         }
-        self.last_tick = Some(Instant::now());
     }
+}
+
+/// Where the pipeline is busy or waiting, to tell what got stuck
+#[cfg(feature = "detect")]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+enum Stage {
+    #[default]
+    Capture,
+    Inference,
+    Database,
+    /// Connecting to the monitor and loading the model again after a failure
+    Reconnect,
+}
 
-    fn current_frequency(&self) -> f32 {
-        self.current_frequency
+#[cfg(feature = "detect")]
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Capture => "capture",
+            Stage::Inference => "inference",
+            Stage::Database => "database",
+            Stage::Reconnect => "reconnect",
+        }
     }
 }
 
+#[cfg(feature = "detect")]
 trait Watchdog {
     fn reset(&self) -> ();
+    /// Capture got a frame which inference doesn't need (IdleFPS). That's progress, unless
+    /// another stage is busy, which might be stuck.
+    fn skipped(&self) -> ();
+    /// Note what the pipeline is doing now, returns what it did before
+    fn enter(&self, stage: Stage) -> Stage;
+    /// The pipeline should stop, so that it can be restarted
+    fn expired(&self) -> bool;
+    fn timeout(&self) -> Duration;
 }
 
+#[cfg(feature = "detect")]
+#[derive(Default)]
+struct WatchdogState {
+    stage: Mutex<Stage>,
+    expired: AtomicBool,
+    /// reset() was called at least once
+    progressed: AtomicBool,
+}
+
+/// Expects reset() at least every timeout. The first time it doesn't come, the pipeline is asked
+/// to stop (expired()), so that run() can restart it. If it's still stuck after another timeout,
+/// there is nothing left to do but to exit.
+#[cfg(feature = "detect")]
+#[derive(Clone)]
 struct ThreadedWatchdog {
     tx: mpsc::Sender<()>,
+    state: Arc<WatchdogState>,
+    timeout: Duration,
 }
 
+#[cfg(feature = "detect")]
 impl ThreadedWatchdog {
-    fn new(timeout: Duration) -> ThreadedWatchdog {
+    fn new(monitor_id: u32, timeout: Duration) -> ThreadedWatchdog {
         let (tx, rx) = mpsc::channel();
+        let state = Arc::new(WatchdogState::default());
 
+        let watched = state.clone();
         std::thread::spawn(move || loop {
-            if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
-                error!("Watchdog expired, terminating.");
-                std::process::exit(1);
+            match rx.recv_timeout(timeout) {
+                Ok(()) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    instrumentation::WATCHDOG_EXPIRED.with_label_values(&[&monitor_id.to_string()]).inc();
+                    let stage = *watched.stage.lock().unwrap();
+                    if watched.expired.swap(true, Ordering::SeqCst) {
+                        error!(
+                            "{}: Watchdog expired again, stuck in {}, terminating.",
+                            monitor_id,
+                            stage.name()
+                        );
+                        std::process::exit(1);
+                    }
+                    warn!("{}: Watchdog expired, stuck in {}", monitor_id, stage.name());
+                }
+                // the pipeline is gone
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
             }
         });
 
-        ThreadedWatchdog { tx }
+        ThreadedWatchdog { tx, state, timeout }
+    }
+
+    fn stage(&self) -> Stage {
+        *self.state.stage.lock().unwrap()
+    }
+
+    fn progressed(&self) -> bool {
+        self.state.progressed.load(Ordering::SeqCst)
     }
 }
 
+#[cfg(feature = "detect")]
 impl Watchdog for ThreadedWatchdog {
     fn reset(&self) -> () {
+        self.state.progressed.store(true, Ordering::SeqCst);
         self.tx.send(()).unwrap()
     }
+
+    fn skipped(&self) -> () {
+        if self.stage() == Stage::Capture {
+            self.reset();
+        }
+    }
+
+    fn enter(&self, stage: Stage) -> Stage {
+        std::mem::replace(&mut *self.state.stage.lock().unwrap(), stage)
+    }
+
+    fn expired(&self) -> bool {
+        self.state.expired.load(Ordering::SeqCst)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// For replay, which has nothing to restart
+#[cfg(feature = "detect")]
+struct NoWatchdog;
+
+#[cfg(feature = "detect")]
+impl Watchdog for NoWatchdog {
+    fn reset(&self) -> () {}
+
+    fn skipped(&self) -> () {}
+
+    fn enter(&self, _stage: Stage) -> Stage {
+        Stage::default()
+    }
+
+    fn expired(&self) -> bool {
+        false
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::MAX
+    }
 }
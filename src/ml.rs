@@ -1,17 +1,39 @@
-use std::collections::HashMap;
+use std::env;
 use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
-use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Rect, Vector, CV_8U};
-use opencv::dnn::{
-    blob_from_image, nms_boxes, read_net, DictValue, LayerTraitConst, Net, NetTrait, NetTraitConst,
-};
-use opencv::types::{VectorOfMat, VectorOfRect};
+use anyhow::{anyhow, Result};
+use opencv::core::{Mat, MatTraitConst, Rect};
+
+use crate::zoneminder::zone::{Backend, Device, Precision, ZoneConfig};
+
+#[cfg(feature = "dnn")]
+pub use darknet::YoloV4Tiny;
+#[cfg(feature = "edgetpu")]
+pub use edgetpu::SsdEdgeTpu;
+#[cfg(feature = "onnx")]
+pub use onnx::YoloV5Onnx;
+
+#[cfg(feature = "dnn")]
+mod classify;
+#[cfg(feature = "dnn")]
+mod darknet;
+#[cfg(feature = "edgetpu")]
+mod edgetpu;
+#[cfg(feature = "onnx")]
+mod onnx;
+pub mod table;
 
 #[derive(Clone, Debug)]
 pub struct Detection {
     pub confidence: f32,
     pub class_id: i32,
     pub bounding_box: Rect,
+    /// The score of the next best class for the same box (like confidence), None if the model
+    /// doesn't give the other classes' scores
+    pub runner_up: Option<f32>,
+    /// What a classifier (Classify.<Class>=) made of the detection, e.g. "delivery van"
+    pub label: Option<String>,
 }
 
 impl Detection {
@@ -41,141 +63,405 @@ impl PartialEq<Self> for Detection {
 
 impl Eq for Detection {}
 
-pub struct YoloV4Tiny {
-    net: Net,
-    confidence_threshold: f32,
-    nms_threshold: f32,
-    size: u32,
+/// Channel order of 3-channel images. Frames are RGB24 by default, but capture doesn't convert
+/// BGR frames, since the backends can swap the channels while building their input anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelOrder {
+    Rgb,
+    Bgr,
+}
+
+impl PixelOrder {
+    /// image (in this order) in RGB order
+    pub fn into_rgb(self, image: Mat) -> Result<Mat> {
+        match self {
+            PixelOrder::Rgb => Ok(image),
+            PixelOrder::Bgr => {
+                let mut rgb = Mat::default();
+                opencv::imgproc::cvt_color(&image, &mut rgb, opencv::imgproc::COLOR_BGR2RGB, 0)?;
+                Ok(rgb)
+            }
+        }
+    }
+}
+
+pub const WEIGHTS: &str = "yolov4-tiny.weights";
+pub const CFG: &str = "yolov4-tiny.cfg";
+/// A YOLOv5 model exported to ONNX
+pub const ONNX_MODEL: &str = "yolov5.onnx";
+/// The same, quantized to INT8 (Precision=int8)
+pub const ONNX_INT8_MODEL: &str = "yolov5-int8.onnx";
+/// Coral's SSD MobileNet v2 trained on COCO, compiled for the Edge TPU
+pub const EDGETPU_MODEL: &str = "ssd_mobilenet_v2_coco_quant_postprocess_edgetpu.tflite";
 
-    out_names: Vector<String>,
+/// How the model is run, see the README for the zone keys.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub confidence_threshold: f32,
+    /// Input size (square)
+    pub size: u32,
+    pub backend: Backend,
+    pub device: Device,
+    /// Wanted, the backend may not support it
+    pub precision: Precision,
+    /// Of the input images, 1 for models trained on grayscale images
+    pub channels: u32,
 }
 
-impl YoloV4Tiny {
-    pub fn new(confidence_threshold: f32, size: u32, use_cuda: bool) -> opencv::Result<YoloV4Tiny> {
-        let mut net = read_net("yolov4-tiny.weights", "yolov4-tiny.cfg", "")?;
-        if use_cuda {
-            net.set_preferable_target(opencv::dnn::DNN_TARGET_CUDA)?;
-            net.set_preferable_backend(opencv::dnn::DNN_BACKEND_CUDA)?;
-        } else {
-            net.set_preferable_target(opencv::dnn::DNN_TARGET_CPU)?;
-            net.set_preferable_backend(opencv::dnn::DNN_BACKEND_OPENCV)?;
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            confidence_threshold: 0.5,
+            size: 256,
+            backend: Backend::OpenCv,
+            device: Device::Cpu,
+            precision: Precision::Fp32,
+            channels: 3,
         }
+    }
+}
 
-        let out_names = net.get_unconnected_out_layers_names()?;
-        let out_layers = net.get_unconnected_out_layers()?;
-        let out_layer_type = net
-            .get_layer(DictValue::from_i32(out_layers.get(0).unwrap())?)
-            .unwrap()
-            .typ();
-        assert_eq!(out_layer_type, "Region");
-
-        Ok(YoloV4Tiny {
-            net,
-            size,
-            out_names,
-            confidence_threshold,
-            nms_threshold: 0.4,
-        })
-    }
-
-    pub fn infer(&mut self, image: &Mat) -> opencv::Result<Vec<Detection>> {
-        let size = self.size as i32;
-        let size = (size, size);
-        let mean = (0.0, 0.0, 0.0);
-        let blob = blob_from_image(&image, 1.0, size.into(), mean.into(), false, false, CV_8U)?;
-        let scale = 1.0 / 255.0;
-        self.net.set_input(&blob, "", scale, mean.into())?;
-
-        let outs = {
-            let mut outs = VectorOfMat::new();
-            self.net.forward(&mut outs, &self.out_names)?;
-            outs
-        };
-
-        let image_width = image.cols() as f32;
-        let image_height = image.rows() as f32;
-
-        let detections: Vec<Detection> = outs
-            .iter()
-            .map(|out| {
-                // Network produces output blob with a shape NxC where N is a number of
-                // detected objects and C is a number of classes + 4 where the first 4
-                // numbers are [center_x, center_y, width, height]
-
-                (0..out.rows())
-                    .map(move |i| {
-                        let row = out.at_row::<f32>(i).unwrap();
-
-                        let get_bounding_box = |row: &[f32]| -> Rect {
-                            let (center_x, center_y) = (row[0], row[1]);
-                            let (width, height) = (row[2], row[3]);
-
-                            let center_x = (center_x * image_width) as i32;
-                            let center_y = (center_y * image_height) as i32;
-                            let width = (width * image_width) as i32;
-                            let height = (height * image_height) as i32;
-
-                            let left_edge = (center_x - width / 2).max(0);
-                            let top_edge = (center_y - height / 2).max(0);
-
-                            Rect::new(left_edge, top_edge, width, height)
-                        };
-
-                        let get_class = |row: &[f32]| {
-                            let class = row[4..]
-                                .iter()
-                                //.cloned()
-                                .zip(1..) // 1.. for 1-based class index, 0.. for 0-based
-                                .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-                            let (&confidence, class_id) = class.unwrap();
-                            (confidence, class_id)
-                        };
-
-                        let (confidence, class_id) = get_class(row);
-                        let bounding_box = get_bounding_box(row);
-
-                        Detection {
-                            confidence,
-                            class_id,
-                            bounding_box,
-                        }
-                    })
-                    .filter(|detection| detection.confidence >= self.confidence_threshold)
-            })
-            .flatten()
-            .collect();
-
-        // Perform NMS filtering
-        let mut class2detections: HashMap<i32, Vec<&Detection>> = HashMap::new();
-        for detection in &detections {
-            let dets = class2detections
-                .entry(detection.class_id)
-                .or_insert_with(Vec::new);
-            dets.push(&detection);
+impl Options {
+    pub fn from_zone(zone_config: &ZoneConfig) -> Options {
+        let default = Options::default();
+        Options {
+            confidence_threshold: zone_config.threshold.unwrap_or(default.confidence_threshold),
+            size: zone_config.size.unwrap_or(default.size),
+            backend: zone_config.backend.unwrap_or(default.backend),
+            device: zone_config.device.unwrap_or(default.device),
+            precision: zone_config.precision.unwrap_or(default.precision),
+            channels: zone_config.channels.unwrap_or(default.channels),
         }
+    }
+}
+
+/// A model's output for one input before the threshold and NMS: rows of row_len numbers, for the
+/// YOLO models center x, center y, width, height, objectness and the class scores. See `--dump-raw`.
+#[derive(Debug, Clone, Default)]
+pub struct RawOutput {
+    pub row_len: usize,
+    pub rows: Vec<f32>,
+}
+
+/// An object detection model. Class IDs are COCO's, 1-based.
+pub trait Detector: Send {
+    /// Bounding boxes are relative to image (RGB24, or 8-bit grayscale for Options::channels 1).
+    fn infer(&mut self, image: &Mat) -> Result<Vec<Detection>>;
 
-        let mut nms_detections = vec![];
-
-        for (_, detections) in &class2detections {
-            let bounding_boxes: VectorOfRect =
-                detections.iter().map(|det| det.bounding_box).collect();
-            let confidences: Vector<f32> = detections.iter().map(|det| det.confidence).collect();
-            let mut chosen_indices = Vector::new();
-            nms_boxes(
-                &bounding_boxes,
-                &confidences,
-                self.confidence_threshold,
-                self.nms_threshold,
-                &mut chosen_indices,
-                1.0,
-                0,
-            )?;
-
-            for index in chosen_indices {
-                nms_detections.push(detections[index as usize].clone());
+    /// The precision actually in effect
+    fn precision(&self) -> Precision {
+        Precision::Fp32
+    }
+
+    /// Class IDs the model outputs (1 to this), from the output geometry of the last inference
+    fn classes(&self) -> Option<usize> {
+        None
+    }
+
+    /// Keep the raw output of each input from now on, for raw_outputs. False if the backend can't.
+    fn keep_raw_outputs(&mut self) -> bool {
+        false
+    }
+
+    /// The raw output of each input of the last inference, if kept
+    fn raw_outputs(&self) -> &[RawOutput] {
+        &[]
+    }
+
+    /// Detections per image. Backends which can batch do a single forward pass for all of them.
+    fn infer_batch(&mut self, images: &[Mat]) -> Result<Vec<Vec<Detection>>> {
+        images.iter().map(|image| self.infer(image)).collect()
+    }
+
+    /// Like infer_batch for 3-channel images in the given order. Backends which can take BGR
+    /// directly override this, the others get converted copies.
+    fn infer_batch_ordered(&mut self, images: &[Mat], order: PixelOrder) -> Result<Vec<Vec<Detection>>> {
+        match order {
+            PixelOrder::Rgb => self.infer_batch(images),
+            PixelOrder::Bgr => {
+                let images = images
+                    .iter()
+                    .map(|image| order.into_rgb(image.try_clone()?))
+                    .collect::<Result<Vec<_>>>()?;
+                self.infer_batch(&images)
             }
         }
+    }
+}
+
+/// An image classification model, for the crops of detections.
+pub trait Classifier {
+    /// The score of each class for image (RGB24, or BGR24 in that order)
+    fn classify(&mut self, image: &Mat, order: PixelOrder) -> Result<Vec<f32>>;
+}
+
+/// The best class of scores and its probability. Raw scores (logits) are turned into probabilities
+/// with softmax, outputs which already are probabilities are taken as they are.
+pub fn best_class(scores: &[f32]) -> Option<(usize, f32)> {
+    let (best, &score) = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    let sum: f32 = scores.iter().sum();
+    let probabilities = scores.iter().all(|score| (0.0..=1.0).contains(score)) && (sum - 1.0).abs() < 0.01;
+    if probabilities {
+        return Some((best, score));
+    }
+    let exp_sum: f32 = scores.iter().map(|s| (s - score).exp()).sum();
+    Some((best, 1.0 / exp_sum))
+}
+
+/// The best of scores other than the one at best, None if there's no other
+pub fn runner_up(scores: &[f32], best: usize) -> Option<f32> {
+    scores
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != best)
+        .map(|(_, score)| *score)
+        .max_by(|a, b| a.total_cmp(b))
+}
+
+/// Loads the classifier of a models table entry (Classify.<Class>= zone key), with OpenCV DNN
+#[cfg_attr(not(feature = "dnn"), allow(unused_variables))]
+pub fn load_classifier(name: &str, entry: &table::ModelEntry, options: &Options) -> Result<Box<dyn Classifier>> {
+    #[cfg(feature = "dnn")]
+    match (&entry.onnx, &entry.weights) {
+        (Some(onnx), _) => return Ok(Box::new(classify::DnnClassifier::new(onnx, None, options)?)),
+        (None, Some(weights)) => {
+            return Ok(Box::new(classify::DnnClassifier::new(weights, entry.cfg.as_deref(), options)?))
+        }
+        (None, None) => {}
+    }
+    Err(anyhow!(
+        "Classifier {} needs {}",
+        name,
+        match cfg!(feature = "dnn") {
+            true => "onnx, or weights and cfg",
+            false => "OpenCV DNN (feature dnn)",
+        }
+    ))
+}
+
+/// Loads the model found in model_dir, with whichever backend supports it. If there are multiple,
+/// the Edge TPU model is preferred, then the ONNX model, since those backends had to be explicitly
+/// enabled. Backend and Device only apply to OpenCV DNN.
+#[cfg_attr(not(any(feature = "dnn", feature = "onnx", feature = "edgetpu")), allow(unused_variables))]
+pub fn load(model_dir: &Path, options: &Options) -> Result<Box<dyn Detector>> {
+    #[cfg(feature = "edgetpu")]
+    if model_dir.join(EDGETPU_MODEL).is_file() {
+        return Ok(Box::new(SsdEdgeTpu::new(model_dir, options)?));
+    }
+    #[cfg(feature = "onnx")]
+    if model_dir.join(ONNX_MODEL).is_file() {
+        return Ok(Box::new(YoloV5Onnx::new(model_dir, options)?));
+    }
+    #[cfg(feature = "dnn")]
+    if model_dir.join(WEIGHTS).is_file() && model_dir.join(CFG).is_file() {
+        return Ok(Box::new(YoloV4Tiny::new(model_dir, options)?));
+    }
+    Err(anyhow!("{} not found in {}", expected_files(), model_dir.display()))
+}
+
+/// Loads the model of a models table entry (Model= zone key)
+#[cfg_attr(not(any(feature = "dnn", feature = "onnx", feature = "edgetpu")), allow(unused_variables))]
+pub fn load_entry(name: &str, entry: &table::ModelEntry, options: &Options) -> Result<Box<dyn Detector>> {
+    #[cfg(feature = "edgetpu")]
+    if let Some(tflite) = &entry.tflite {
+        return Ok(Box::new(SsdEdgeTpu::from_file(tflite, entry.names.is_none(), options)?));
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(onnx) = &entry.onnx {
+        return Ok(Box::new(YoloV5Onnx::from_file(onnx, None, options)?));
+    }
+    #[cfg(feature = "dnn")]
+    if let (Some(weights), Some(cfg)) = (&entry.weights, &entry.cfg) {
+        return Ok(Box::new(YoloV4Tiny::from_files(weights, cfg, options)?));
+    }
+    Err(anyhow!(
+        "Model {} needs {}",
+        name,
+        expected_entry_files()
+    ))
+}
+
+/// The models table keys of the backends compiled in, for error messages
+fn expected_entry_files() -> String {
+    let mut keys = vec![];
+    if cfg!(feature = "edgetpu") {
+        keys.push("tflite");
+    }
+    if cfg!(feature = "onnx") {
+        keys.push("onnx");
+    }
+    if cfg!(feature = "dnn") {
+        keys.push("weights and cfg");
+    }
+    match keys.len() {
+        0 => "a detection backend (features dnn, onnx and edgetpu)".to_string(),
+        _ => keys.join(", or "),
+    }
+}
+
+/// The model files of the backends compiled in, for error messages
+fn expected_files() -> String {
+    let mut files = vec![];
+    if cfg!(feature = "dnn") {
+        files.push(format!("{} and {}", WEIGHTS, CFG));
+    }
+    if cfg!(feature = "onnx") {
+        files.push(ONNX_MODEL.to_string());
+    }
+    if cfg!(feature = "edgetpu") {
+        files.push(EDGETPU_MODEL.to_string());
+    }
+    if files.is_empty() {
+        return "A model (none of the detection backends, features dnn, onnx and edgetpu, are compiled in)"
+            .to_string();
+    }
+    files.join(" or ")
+}
+
+/// Where the model files are looked for: next to the executable (like in the release tarballs),
+/// the XDG data directories (e.g. /usr/share/zm-aidect) and the current directory (cargo run).
+pub fn model_dir_candidates() -> Vec<PathBuf> {
+    let exe_dir = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")));
+    let data_dirs =
+        env::var("XDG_DATA_DIRS").unwrap_or_else(|_| "/usr/local/share:/usr/share".into());
+    let data_dirs = data_home.into_iter().chain(
+        data_dirs
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+    );
+
+    exe_dir
+        .into_iter()
+        .chain(data_dirs.map(|dir| dir.join("zm-aidect")))
+        .chain(std::iter::once(PathBuf::from(".")))
+        .collect()
+}
+
+/// Intersection over union of two boxes, 1.0 if they're the same.
+pub fn iou(a: &Rect, b: &Rect) -> f32 {
+    let width = (a.x + a.width).min(b.x + b.width) - a.x.max(b.x);
+    let height = (a.y + a.height).min(b.y + b.height) - a.y.max(b.y);
+    if width <= 0 || height <= 0 {
+        return 0.0;
+    }
+    let intersection = (width * height) as f32;
+    intersection / ((a.area() + b.area()) as f32 - intersection)
+}
+
+/// Greedy non-maximum suppression per class, what OpenCV's NMSBoxes does for the darknet model.
+pub(crate) fn nms(mut detections: Vec<Detection>, threshold: f32) -> Vec<Detection> {
+    detections.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    let mut kept: Vec<Detection> = vec![];
+    for d in detections {
+        if !kept
+            .iter()
+            .any(|k| k.class_id == d.class_id && iou(&k.bounding_box, &d.bounding_box) > threshold)
+        {
+            kept.push(d);
+        }
+    }
+    kept
+}
+
+pub fn has_model_files(dir: &Path) -> bool {
+    (cfg!(feature = "dnn") && dir.join(WEIGHTS).is_file() && dir.join(CFG).is_file())
+        || (cfg!(feature = "onnx") && dir.join(ONNX_MODEL).is_file())
+        || (cfg!(feature = "edgetpu") && dir.join(EDGETPU_MODEL).is_file())
+}
+
+/// The given directory if it has the model files, otherwise the first of model_dir_candidates() which does.
+pub fn find_model_dir(model_dir: Option<&Path>) -> Result<PathBuf> {
+    match model_dir {
+        Some(dir) if has_model_files(dir) => Ok(dir.to_path_buf()),
+        Some(dir) => Err(anyhow!("{} not found in {}", expected_files(), dir.display())),
+        None => model_dir_candidates()
+            .into_iter()
+            .find(|dir| has_model_files(dir))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} not found in any of {}, use --model-dir",
+                    expected_files(),
+                    model_dir_candidates()
+                        .iter()
+                        .map(|dir| dir.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(class_id: i32, confidence: f32, x: i32) -> Detection {
+        Detection {
+            confidence,
+            class_id,
+            bounding_box: Rect::new(x, 0, 100, 100),
+            runner_up: None,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn test_best_class() {
+        let (best, probability) = best_class(&[0.1, 0.7, 0.2]).unwrap();
+        assert_eq!(best, 1);
+        assert!((probability - 0.7).abs() < 1e-6);
+        // logits
+        let (best, probability) = best_class(&[2.0, -1.0, 2.0f32.ln() + 2.0]).unwrap();
+        assert_eq!(best, 2);
+        assert!((probability - 2.0 / (3.0 + (-3.0f32).exp())).abs() < 1e-4);
+        assert_eq!(best_class(&[]), None);
+        // a broken model's output doesn't panic
+        assert!(best_class(&[0.1, f32::NAN, 0.2]).is_some());
+    }
+
+    #[test]
+    fn test_runner_up() {
+        assert_eq!(runner_up(&[0.1, 0.7, 0.2], 1), Some(0.2));
+        assert_eq!(runner_up(&[0.4, 0.4], 0), Some(0.4));
+        assert_eq!(runner_up(&[0.9], 0), None);
+        assert!(runner_up(&[0.9, f32::NAN, 0.2], 0).is_some());
+    }
+
+    #[test]
+    fn test_iou() {
+        let a = Rect::new(0, 0, 100, 100);
+        assert_eq!(iou(&a, &a), 1.0);
+        assert_eq!(iou(&a, &Rect::new(50, 0, 100, 100)), 1.0 / 3.0);
+        assert_eq!(iou(&a, &Rect::new(100, 0, 100, 100)), 0.0);
+    }
 
-        Ok(nms_detections)
+    #[test]
+    fn test_nms() {
+        let kept = nms(
+            vec![
+                detection(1, 0.5, 10),
+                detection(1, 0.9, 0),
+                detection(3, 0.6, 0),
+                detection(1, 0.7, 200),
+            ],
+            0.4,
+        );
+        assert_eq!(
+            kept,
+            vec![
+                detection(1, 0.9, 0),
+                detection(1, 0.7, 200),
+                detection(3, 0.6, 0),
+            ]
+        );
     }
 }
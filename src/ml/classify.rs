@@ -0,0 +1,59 @@
+//! Image classification with OpenCV DNN, for the crops of detections (Classify.<Class>=NAME).
+
+use std::path::Path;
+
+use anyhow::Result;
+use log::info;
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, CV_32F};
+use opencv::dnn::{blob_from_image, read_net, Net, NetTrait, NetTraitConst};
+
+use super::darknet::select_target;
+use super::{Classifier, Options, PixelOrder};
+
+/// A network with one output of class scores, like the ONNX exports of MobileNet or ResNet
+/// classifiers. The input is scaled to 0..1, without mean subtraction.
+pub struct DnnClassifier {
+    net: Net,
+    size: u32,
+}
+
+impl DnnClassifier {
+    /// config: the darknet .cfg, if model is darknet weights
+    pub fn new(model: &Path, config: Option<&Path>, options: &Options) -> opencv::Result<DnnClassifier> {
+        let config = config.map_or(String::new(), |config| config.to_string_lossy().into_owned());
+        let mut net = read_net(&model.to_string_lossy(), &config, "")?;
+        let (dnn_backend, dnn_target, precision) = select_target(options);
+        info!(
+            "Running the classifier {} with {:?} on {:?} at {:?}",
+            model.display(),
+            dnn_backend,
+            dnn_target,
+            precision
+        );
+        net.set_preferable_target(dnn_target as i32)?;
+        net.set_preferable_backend(dnn_backend as i32)?;
+        Ok(DnnClassifier {
+            net,
+            size: options.size,
+        })
+    }
+}
+
+impl Classifier for DnnClassifier {
+    fn classify(&mut self, image: &Mat, order: PixelOrder) -> Result<Vec<f32>> {
+        let size = self.size as i32;
+        let swap_rb = order == PixelOrder::Bgr && image.channels() == 3;
+        let blob = blob_from_image(
+            image,
+            1.0 / 255.0,
+            (size, size).into(),
+            (0.0, 0.0, 0.0).into(),
+            swap_rb,
+            false,
+            CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Default::default())?;
+        let out = self.net.forward_single("")?;
+        Ok(out.data_typed::<f32>()?.to_vec())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::slice;
+
+use log::{info, warn};
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Rect, Vector, CV_8U};
+use opencv::dnn::{
+    self, blob_from_image, blob_from_images, get_available_targets, nms_boxes, read_net, DictValue,
+    LayerTraitConst, Net, NetTrait, NetTraitConst,
+};
+use opencv::types::{VectorOfMat, VectorOfRect};
+
+use super::{runner_up, Detection, Detector, Options, PixelOrder, RawOutput, CFG, WEIGHTS};
+use crate::zoneminder::zone::{Backend, Device, Precision};
+
+/// The OpenCV DNN backend and target for Backend=, Device= and Precision=. Falls back to FP32,
+/// and then to OpenCV on the CPU, if this OpenCV build (or the machine) doesn't have them.
+pub(super) fn select_target(options: &Options) -> (dnn::Backend, dnn::Target, Precision) {
+    use dnn::Backend::*;
+    use dnn::Target::*;
+
+    let fp16 = options.precision == Precision::Fp16;
+    if options.precision == Precision::Int8 {
+        warn!("OpenCV DNN can't run yolov4-tiny quantized to INT8, using FP32");
+    }
+    let cpu = (DNN_BACKEND_OPENCV, DNN_TARGET_CPU, Precision::Fp32);
+    let mut candidates = vec![];
+    match (options.backend, options.device) {
+        (Backend::OpenCv, _) => {}
+        (Backend::Cuda, _) => {
+            if fp16 {
+                candidates.push((DNN_BACKEND_CUDA, DNN_TARGET_CUDA_FP16, Precision::Fp16));
+            }
+            candidates.push((DNN_BACKEND_CUDA, DNN_TARGET_CUDA, Precision::Fp32));
+        }
+        (Backend::OpenVino, Device::Cpu) => {
+            candidates.push((DNN_BACKEND_INFERENCE_ENGINE, DNN_TARGET_CPU, Precision::Fp32));
+        }
+        (Backend::OpenVino, Device::Gpu) => {
+            if fp16 {
+                candidates.push((
+                    DNN_BACKEND_INFERENCE_ENGINE,
+                    DNN_TARGET_OPENCL_FP16,
+                    Precision::Fp16,
+                ));
+            }
+            candidates.push((DNN_BACKEND_INFERENCE_ENGINE, DNN_TARGET_OPENCL, Precision::Fp32));
+        }
+        // The stick only does FP16
+        (Backend::OpenVino, Device::Myriad) => {
+            candidates.push((DNN_BACKEND_INFERENCE_ENGINE, DNN_TARGET_MYRIAD, Precision::Fp16));
+        }
+    }
+
+    let available = |(backend, target, _): &(dnn::Backend, dnn::Target, Precision)| {
+        matches!(get_available_targets(*backend), Ok(targets) if targets.iter().any(|t| t == *target))
+    };
+    let selected = candidates.into_iter().find(available).unwrap_or_else(|| {
+        if options.backend != Backend::OpenCv {
+            warn!(
+                "{:?} on {:?} is not available, falling back to OpenCV on the CPU",
+                options.backend, options.device
+            );
+        }
+        cpu
+    });
+    if fp16 && selected.2 != Precision::Fp16 {
+        warn!("FP16 is not available with {:?} on {:?}, using FP32", selected.0, selected.1);
+    }
+    selected
+}
+
+pub struct YoloV4Tiny {
+    net: Net,
+    confidence_threshold: f32,
+    nms_threshold: f32,
+    size: u32,
+    precision: Precision,
+    classes: Option<usize>,
+    /// Of the last inference, once kept
+    raw: Option<Vec<RawOutput>>,
+
+    out_names: Vector<String>,
+}
+
+impl YoloV4Tiny {
+    pub fn new(model_dir: &Path, options: &Options) -> opencv::Result<YoloV4Tiny> {
+        Self::from_files(&model_dir.join(WEIGHTS), &model_dir.join(CFG), options)
+    }
+
+    pub fn from_files(weights: &Path, cfg: &Path, options: &Options) -> opencv::Result<YoloV4Tiny> {
+        let mut net = read_net(&weights.to_string_lossy(), &cfg.to_string_lossy(), "")?;
+        let (dnn_backend, dnn_target, precision) = select_target(options);
+        info!(
+            "Running the model with {:?} on {:?} at {:?}",
+            dnn_backend, dnn_target, precision
+        );
+        net.set_preferable_target(dnn_target as i32)?;
+        net.set_preferable_backend(dnn_backend as i32)?;
+
+        let out_names = net.get_unconnected_out_layers_names()?;
+        let out_layers = net.get_unconnected_out_layers()?;
+        let out_layer_type = net
+            .get_layer(DictValue::from_i32(out_layers.get(0).unwrap())?)
+            .unwrap()
+            .typ();
+        assert_eq!(out_layer_type, "Region");
+
+        Ok(YoloV4Tiny {
+            net,
+            size: options.size,
+            precision,
+            classes: None,
+            raw: None,
+            out_names,
+            confidence_threshold: options.confidence_threshold,
+            nms_threshold: 0.4,
+        })
+    }
+}
+
+impl YoloV4Tiny {
+    /// rows: the network's output for one image, NxC where N is a number of detected objects and C
+    /// is a number of classes + 4 where the first 4 numbers are [center_x, center_y, width, height]
+    fn decode(&self, rows: &[f32], row_len: usize, image: &Mat) -> Vec<Detection> {
+        let image_width = image.cols() as f32;
+        let image_height = image.rows() as f32;
+
+        let get_bounding_box = |row: &[f32]| -> Rect {
+            let (center_x, center_y) = (row[0], row[1]);
+            let (width, height) = (row[2], row[3]);
+
+            let center_x = (center_x * image_width) as i32;
+            let center_y = (center_y * image_height) as i32;
+            let width = (width * image_width) as i32;
+            let height = (height * image_height) as i32;
+
+            let left_edge = (center_x - width / 2).max(0);
+            let top_edge = (center_y - height / 2).max(0);
+
+            Rect::new(left_edge, top_edge, width, height)
+        };
+
+        let get_class = |row: &[f32]| {
+            let class = row[4..]
+                .iter()
+                //.cloned()
+                .zip(1..) // 1.. for 1-based class index, 0.. for 0-based
+                .max_by(|a, b| a.0.total_cmp(b.0));
+            let (&confidence, class_id) = class.unwrap();
+            (confidence, class_id, runner_up(&row[4..], class_id as usize - 1))
+        };
+
+        rows.chunks_exact(row_len)
+            .map(|row| {
+                let (confidence, class_id, runner_up) = get_class(row);
+                let bounding_box = get_bounding_box(row);
+
+                Detection {
+                    confidence,
+                    class_id,
+                    bounding_box,
+                    runner_up,
+                    label: None,
+                }
+            })
+            .filter(|detection| detection.confidence >= self.confidence_threshold)
+            .collect()
+    }
+
+    fn nms(&self, detections: Vec<Detection>) -> opencv::Result<Vec<Detection>> {
+        let mut class2detections: HashMap<i32, Vec<&Detection>> = HashMap::new();
+        for detection in &detections {
+            let dets = class2detections
+                .entry(detection.class_id)
+                .or_insert_with(Vec::new);
+            dets.push(&detection);
+        }
+
+        let mut nms_detections = vec![];
+
+        for (_, detections) in &class2detections {
+            let bounding_boxes: VectorOfRect =
+                detections.iter().map(|det| det.bounding_box).collect();
+            let confidences: Vector<f32> = detections.iter().map(|det| det.confidence).collect();
+            let mut chosen_indices = Vector::new();
+            nms_boxes(
+                &bounding_boxes,
+                &confidences,
+                self.confidence_threshold,
+                self.nms_threshold,
+                &mut chosen_indices,
+                1.0,
+                0,
+            )?;
+
+            for index in chosen_indices {
+                nms_detections.push(detections[index as usize].clone());
+            }
+        }
+
+        Ok(nms_detections)
+    }
+}
+
+impl Detector for YoloV4Tiny {
+    fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    fn classes(&self) -> Option<usize> {
+        self.classes
+    }
+
+    fn keep_raw_outputs(&mut self) -> bool {
+        self.raw.get_or_insert_with(Vec::new);
+        true
+    }
+
+    fn raw_outputs(&self) -> &[RawOutput] {
+        self.raw.as_deref().unwrap_or_default()
+    }
+
+    fn infer(&mut self, image: &Mat) -> anyhow::Result<Vec<Detection>> {
+        Ok(self.infer_batch(slice::from_ref(image))?.remove(0))
+    }
+
+    fn infer_batch(&mut self, images: &[Mat]) -> anyhow::Result<Vec<Vec<Detection>>> {
+        self.infer_batch_ordered(images, PixelOrder::Rgb)
+    }
+
+    /// BGR images are swapped while building the blob, which copies them anyway.
+    fn infer_batch_ordered(
+        &mut self,
+        images: &[Mat],
+        order: PixelOrder,
+    ) -> anyhow::Result<Vec<Vec<Detection>>> {
+        if images.is_empty() {
+            return Ok(vec![]);
+        }
+        let size = self.size as i32;
+        let size = (size, size);
+        let mean = (0.0, 0.0, 0.0);
+        let swap_rb = order == PixelOrder::Bgr && images[0].channels() == 3;
+        let blob = match images {
+            [image] => blob_from_image(image, 1.0, size.into(), mean.into(), swap_rb, false, CV_8U)?,
+            _ => {
+                let images = images
+                    .iter()
+                    .map(Mat::copy)
+                    .collect::<opencv::Result<VectorOfMat>>()?;
+                blob_from_images(&images, 1.0, size.into(), mean.into(), swap_rb, false, CV_8U)?
+            }
+        };
+        let scale = 1.0 / 255.0;
+        self.net.set_input(&blob, "", scale, mean.into())?;
+
+        let outs = {
+            let mut outs = VectorOfMat::new();
+            self.net.forward(&mut outs, &self.out_names)?;
+            outs
+        };
+
+        let mut detections = vec![vec![]; images.len()];
+        if let Some(raw) = &mut self.raw {
+            *raw = vec![RawOutput::default(); images.len()];
+        }
+        for out in &outs {
+            // NxC for a single image, BxNxC for a batch of B images
+            let row_len = *out.mat_size().last().unwrap() as usize;
+            self.classes = Some(row_len.saturating_sub(4));
+            let data = out.data_typed::<f32>()?;
+            for (i, (rows, (image, detections))) in data
+                .chunks(data.len() / images.len())
+                .zip(images.iter().zip(detections.iter_mut()))
+                .enumerate()
+            {
+                if let Some(raw) = &mut self.raw {
+                    // the rows of all output layers
+                    raw[i].row_len = row_len;
+                    raw[i].rows.extend_from_slice(rows);
+                }
+                detections.extend(self.decode(rows, row_len, image));
+            }
+        }
+
+        Ok(detections
+            .into_iter()
+            .map(|detections| self.nms(detections))
+            .collect::<opencv::Result<_>>()?)
+    }
+}
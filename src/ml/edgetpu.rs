@@ -0,0 +1,325 @@
+use std::ffi::{c_void, CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Rect, Size};
+use opencv::imgproc;
+
+use super::{Detection, Detector, Options, EDGETPU_MODEL};
+use crate::zoneminder::zone::Precision;
+
+/// The parts of TensorFlow Lite's C API (tensorflow/lite/c/c_api.h) and libedgetpu's
+/// (edgetpu_c.h) used here.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub enum TfLiteModel {}
+    pub enum TfLiteInterpreterOptions {}
+    pub enum TfLiteInterpreter {}
+    pub enum TfLiteTensor {}
+    pub enum TfLiteDelegate {}
+
+    pub const TFLITE_OK: c_int = 0;
+    pub const TFLITE_FLOAT32: c_int = 1;
+    pub const TFLITE_UINT8: c_int = 3;
+
+    #[repr(C)]
+    pub struct edgetpu_device {
+        pub device_type: c_int,
+        pub path: *const c_char,
+    }
+
+    #[link(name = "tensorflowlite_c")]
+    extern "C" {
+        pub fn TfLiteModelCreateFromFile(model_path: *const c_char) -> *mut TfLiteModel;
+        pub fn TfLiteModelDelete(model: *mut TfLiteModel);
+        pub fn TfLiteInterpreterOptionsCreate() -> *mut TfLiteInterpreterOptions;
+        pub fn TfLiteInterpreterOptionsDelete(options: *mut TfLiteInterpreterOptions);
+        pub fn TfLiteInterpreterOptionsAddDelegate(
+            options: *mut TfLiteInterpreterOptions,
+            delegate: *mut TfLiteDelegate,
+        );
+        pub fn TfLiteInterpreterCreate(
+            model: *const TfLiteModel,
+            options: *const TfLiteInterpreterOptions,
+        ) -> *mut TfLiteInterpreter;
+        pub fn TfLiteInterpreterDelete(interpreter: *mut TfLiteInterpreter);
+        pub fn TfLiteInterpreterAllocateTensors(interpreter: *mut TfLiteInterpreter) -> c_int;
+        pub fn TfLiteInterpreterGetInputTensor(interpreter: *const TfLiteInterpreter, index: i32) -> *mut TfLiteTensor;
+        pub fn TfLiteInterpreterInvoke(interpreter: *mut TfLiteInterpreter) -> c_int;
+        pub fn TfLiteInterpreterGetOutputTensorCount(interpreter: *const TfLiteInterpreter) -> i32;
+        pub fn TfLiteInterpreterGetOutputTensor(
+            interpreter: *const TfLiteInterpreter,
+            index: i32,
+        ) -> *const TfLiteTensor;
+        pub fn TfLiteTensorType(tensor: *const TfLiteTensor) -> c_int;
+        pub fn TfLiteTensorNumDims(tensor: *const TfLiteTensor) -> i32;
+        pub fn TfLiteTensorDim(tensor: *const TfLiteTensor, dim_index: i32) -> i32;
+        pub fn TfLiteTensorByteSize(tensor: *const TfLiteTensor) -> usize;
+        pub fn TfLiteTensorCopyFromBuffer(tensor: *mut TfLiteTensor, data: *const c_void, size: usize) -> c_int;
+        pub fn TfLiteTensorCopyToBuffer(tensor: *const TfLiteTensor, data: *mut c_void, size: usize) -> c_int;
+    }
+
+    #[link(name = "edgetpu")]
+    extern "C" {
+        pub fn edgetpu_list_devices(num_devices: *mut usize) -> *mut edgetpu_device;
+        pub fn edgetpu_free_devices(devices: *mut edgetpu_device);
+        pub fn edgetpu_create_delegate(
+            device_type: c_int,
+            name: *const c_char,
+            options: *const c_void,
+            num_options: usize,
+        ) -> *mut TfLiteDelegate;
+        pub fn edgetpu_free_delegate(delegate: *mut TfLiteDelegate);
+    }
+}
+
+/// An SSD model compiled for the Coral Edge TPU (USB accelerator, M.2 or PCIe card), e.g. Coral's
+/// ssd_mobilenet_v2_coco_quant_postprocess_edgetpu.tflite, run with TensorFlow Lite and
+/// libedgetpu. The model's input size is fixed, Size doesn't apply. Without a names file the model
+/// is taken to output Coral's COCO labels (the 90 IDs of the original dataset, 0-based).
+pub struct SsdEdgeTpu {
+    model: *mut ffi::TfLiteModel,
+    options: *mut ffi::TfLiteInterpreterOptions,
+    delegate: *mut ffi::TfLiteDelegate,
+    interpreter: *mut ffi::TfLiteInterpreter,
+    input_size: Size,
+    confidence_threshold: f32,
+    /// Whether class indices are Coral's COCO labels, rather than the lines of a names file
+    coco_labels: bool,
+}
+
+impl SsdEdgeTpu {
+    pub fn new(model_dir: &Path, options: &Options) -> Result<SsdEdgeTpu> {
+        Self::from_file(&model_dir.join(EDGETPU_MODEL), true, options)
+    }
+
+    /// coco_labels: whether the model outputs Coral's COCO labels, false for a models table entry
+    /// with a names file
+    pub fn from_file(path: &Path, coco_labels: bool, options: &Options) -> Result<SsdEdgeTpu> {
+        let path_c = CString::new(path.as_os_str().as_bytes())?;
+        // Built up step by step, Drop frees whatever was created if a later step fails
+        let mut detector = SsdEdgeTpu {
+            model: std::ptr::null_mut(),
+            options: std::ptr::null_mut(),
+            delegate: std::ptr::null_mut(),
+            interpreter: std::ptr::null_mut(),
+            input_size: Size::default(),
+            confidence_threshold: options.confidence_threshold,
+            coco_labels,
+        };
+        let device = unsafe {
+            let mut count = 0;
+            let devices = ffi::edgetpu_list_devices(&mut count);
+            if devices.is_null() || count == 0 {
+                return Err(anyhow!("No Edge TPU found, is the Coral plugged in and libedgetpu installed?"));
+            }
+            let device = &*devices;
+            let device_type = device.device_type;
+            let device_path = CString::from(CStr::from_ptr(device.path));
+            ffi::edgetpu_free_devices(devices);
+            (device_type, device_path)
+        };
+        unsafe {
+            detector.model = ffi::TfLiteModelCreateFromFile(path_c.as_ptr());
+            if detector.model.is_null() {
+                return Err(anyhow!("Failed to load {}", path.display()));
+            }
+            detector.delegate =
+                ffi::edgetpu_create_delegate(device.0, device.1.as_ptr(), std::ptr::null(), 0);
+            if detector.delegate.is_null() {
+                return Err(anyhow!("Failed to open the Edge TPU at {}", device.1.to_string_lossy()));
+            }
+            detector.options = ffi::TfLiteInterpreterOptionsCreate();
+            ffi::TfLiteInterpreterOptionsAddDelegate(detector.options, detector.delegate);
+            detector.interpreter = ffi::TfLiteInterpreterCreate(detector.model, detector.options);
+            if detector.interpreter.is_null() {
+                return Err(anyhow!(
+                    "Failed to create an interpreter for {}, is it compiled for the Edge TPU?",
+                    path.display()
+                ));
+            }
+            if ffi::TfLiteInterpreterAllocateTensors(detector.interpreter) != ffi::TFLITE_OK {
+                return Err(anyhow!("Failed to allocate the tensors of {}", path.display()));
+            }
+        }
+
+        let input = detector.input();
+        let dims: Vec<i32> = unsafe {
+            (0..ffi::TfLiteTensorNumDims(input))
+                .map(|i| ffi::TfLiteTensorDim(input, i))
+                .collect()
+        };
+        match dims[..] {
+            [1, height, width, channels] if channels as u32 == options.channels => {
+                detector.input_size = Size::new(width, height)
+            }
+            [1, _, _, channels] => {
+                return Err(anyhow!(
+                    "{} takes images with {} channels, but Channels is {}",
+                    path.display(),
+                    channels,
+                    options.channels
+                ))
+            }
+            _ => return Err(anyhow!("{} has an unexpected input shape {:?}", path.display(), dims)),
+        }
+        if unsafe { ffi::TfLiteTensorType(input) } != ffi::TFLITE_UINT8 {
+            return Err(anyhow!("{} is not a quantized (uint8) model", path.display()));
+        }
+        if unsafe { ffi::TfLiteInterpreterGetOutputTensorCount(detector.interpreter) } != 4 {
+            return Err(anyhow!(
+                "{} is not an SSD model with the detection postprocessing (4 outputs)",
+                path.display()
+            ));
+        }
+        info!(
+            "Running {} on the Edge TPU at {} ({}x{})",
+            path.display(),
+            device.1.to_string_lossy(),
+            detector.input_size.width,
+            detector.input_size.height
+        );
+        Ok(detector)
+    }
+
+    fn input(&self) -> *mut ffi::TfLiteTensor {
+        unsafe { ffi::TfLiteInterpreterGetInputTensor(self.interpreter, 0) }
+    }
+
+    /// The output tensor as floats, which the postprocessing op outputs
+    fn output(&self, index: i32) -> Result<Vec<f32>> {
+        unsafe {
+            let tensor = ffi::TfLiteInterpreterGetOutputTensor(self.interpreter, index);
+            if ffi::TfLiteTensorType(tensor) != ffi::TFLITE_FLOAT32 {
+                return Err(anyhow!("Output {} is not float32", index));
+            }
+            let mut values = vec![0f32; ffi::TfLiteTensorByteSize(tensor) / 4];
+            let status = ffi::TfLiteTensorCopyToBuffer(
+                tensor,
+                values.as_mut_ptr() as *mut c_void,
+                values.len() * 4,
+            );
+            match status {
+                ffi::TFLITE_OK => Ok(values),
+                _ => Err(anyhow!("Failed to read output {}", index)),
+            }
+        }
+    }
+}
+
+impl Detector for SsdEdgeTpu {
+    fn precision(&self) -> Precision {
+        Precision::Int8
+    }
+
+    fn infer(&mut self, image: &Mat) -> Result<Vec<Detection>> {
+        let mut resized = Mat::default();
+        imgproc::resize(image, &mut resized, self.input_size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
+        let pixels = resized.data_bytes()?;
+        unsafe {
+            let input = self.input();
+            if ffi::TfLiteTensorCopyFromBuffer(input, pixels.as_ptr() as *const c_void, pixels.len())
+                != ffi::TFLITE_OK
+            {
+                return Err(anyhow!("Failed to set the input"));
+            }
+            if ffi::TfLiteInterpreterInvoke(self.interpreter) != ffi::TFLITE_OK {
+                return Err(anyhow!("Inference on the Edge TPU failed"));
+            }
+        }
+
+        // TF1 exports output boxes, classes, scores and count, TF2 exports scores, boxes, count
+        // and classes; the count is a single value
+        let outputs = (0..4).map(|i| self.output(i)).collect::<Result<Vec<_>>>()?;
+        let (boxes, classes, scores, count) = match outputs[3].len() {
+            1 => (&outputs[0], &outputs[1], &outputs[2], outputs[3][0]),
+            _ => (&outputs[1], &outputs[3], &outputs[0], outputs[2][0]),
+        };
+
+        let (width, height) = (image.cols() as f32, image.rows() as f32);
+        let mut detections = vec![];
+        for i in 0..(count as usize).min(scores.len()) {
+            if scores[i] < self.confidence_threshold {
+                continue;
+            }
+            let class_id = match self.coco_labels {
+                true => match coco_label_to_class_id(classes[i] as i32) {
+                    Some(class_id) => class_id,
+                    None => continue,
+                },
+                false => classes[i] as i32 + 1,
+            };
+            // ymin, xmin, ymax, xmax relative to the image
+            let [y0, x0, y1, x1] = [boxes[i * 4], boxes[i * 4 + 1], boxes[i * 4 + 2], boxes[i * 4 + 3]];
+            let (x0, y0) = ((x0.max(0.0) * width) as i32, (y0.max(0.0) * height) as i32);
+            let (x1, y1) = ((x1.min(1.0) * width) as i32, (y1.min(1.0) * height) as i32);
+            detections.push(Detection {
+                confidence: scores[i],
+                class_id,
+                bounding_box: Rect::new(x0, y0, x1 - x0, y1 - y0),
+                runner_up: None,
+                label: None,
+            });
+        }
+        Ok(detections)
+    }
+}
+
+// The TFLite objects belong to this struct alone and are only used through &mut self, so moving
+// them to another thread is fine.
+unsafe impl Send for SsdEdgeTpu {}
+
+impl Drop for SsdEdgeTpu {
+    fn drop(&mut self) {
+        // the interpreter uses the delegate, and both the model
+        unsafe {
+            if !self.interpreter.is_null() {
+                ffi::TfLiteInterpreterDelete(self.interpreter);
+            }
+            if !self.options.is_null() {
+                ffi::TfLiteInterpreterOptionsDelete(self.options);
+            }
+            if !self.delegate.is_null() {
+                ffi::edgetpu_free_delegate(self.delegate);
+            }
+            if !self.model.is_null() {
+                ffi::TfLiteModelDelete(self.model);
+            }
+        }
+    }
+}
+
+/// The (0-based) indices of Coral's COCO labels which are missing from the 80 classes, the gaps in
+/// the original dataset's IDs
+const COCO_LABEL_GAPS: [i32; 10] = [11, 25, 28, 29, 44, 65, 67, 68, 70, 82];
+
+/// The class ID (1-based, of the 80 classes) of a label index of Coral's COCO models
+fn coco_label_to_class_id(label: i32) -> Option<i32> {
+    if !(0..90).contains(&label) || COCO_LABEL_GAPS.contains(&label) {
+        return None;
+    }
+    let skipped = COCO_LABEL_GAPS.iter().filter(|gap| **gap < label).count() as i32;
+    Some(label - skipped + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coco_label_to_class_id() {
+        // person, car, bird, dog, toothbrush
+        assert_eq!(coco_label_to_class_id(0), Some(1));
+        assert_eq!(coco_label_to_class_id(2), Some(3));
+        assert_eq!(coco_label_to_class_id(15), Some(15));
+        assert_eq!(coco_label_to_class_id(17), Some(17));
+        assert_eq!(coco_label_to_class_id(89), Some(80));
+        assert_eq!(coco_label_to_class_id(11), None);
+        assert_eq!(coco_label_to_class_id(90), None);
+        assert_eq!(coco_label_to_class_id(-1), None);
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+use std::slice;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use ndarray::{Array4, CowArray};
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Rect, Size};
+use opencv::imgproc;
+use ort::tensor::OrtOwnedTensor;
+use ort::{Environment, GraphOptimizationLevel, Session, SessionBuilder, Value};
+
+use super::{nms, runner_up, Detection, Detector, Options, PixelOrder, RawOutput, ONNX_INT8_MODEL, ONNX_MODEL};
+use crate::zoneminder::zone::Precision;
+
+/// A YOLOv5 model exported to ONNX (export.py --include onnx --imgsz <Size>), run with ONNX Runtime.
+/// The input size is fixed at export, so it has to match the zone's Size. With Precision=int8 a
+/// quantized export is used, if there is one.
+pub struct YoloV5Onnx {
+    session: Session,
+    confidence_threshold: f32,
+    nms_threshold: f32,
+    size: u32,
+    precision: Precision,
+    classes: Option<usize>,
+    /// Of the last inference, once kept
+    raw: Option<Vec<RawOutput>>,
+}
+
+impl YoloV5Onnx {
+    pub fn new(model_dir: &Path, options: &Options) -> Result<YoloV5Onnx> {
+        Self::from_file(&model_dir.join(ONNX_MODEL), Some(&model_dir.join(ONNX_INT8_MODEL)), options)
+    }
+
+    /// int8_model: the quantized export, used for Precision=int8 if it exists
+    pub fn from_file(fp32_model: &Path, int8_model: Option<&Path>, options: &Options) -> Result<YoloV5Onnx> {
+        let size = options.size;
+        let (model, precision) = match (options.precision, int8_model) {
+            (Precision::Int8, Some(int8_model)) if int8_model.is_file() => (int8_model, Precision::Int8),
+            (Precision::Fp32, _) => (fp32_model, Precision::Fp32),
+            (wanted, _) => {
+                warn!(
+                    "{:?} needs {}, using {} (FP32)",
+                    wanted,
+                    match wanted {
+                        Precision::Fp16 => "OpenCV DNN",
+                        _ => ONNX_INT8_MODEL,
+                    },
+                    fp32_model.display()
+                );
+                (fp32_model, Precision::Fp32)
+            }
+        };
+        info!("Running {} with ONNX Runtime at {:?}", model.display(), precision);
+
+        let environment = Environment::builder()
+            .with_name("zm-aidect")
+            .build()?
+            .into_arc();
+        // Single-threaded for the same reasons as OpenCV DNN, see run()
+        let session = SessionBuilder::new(&environment)?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(1)?
+            .with_model_from_file(model)?;
+
+        if let [_, Some(channels), _, _] = session.inputs[0].dimensions[..] {
+            if channels != options.channels {
+                return Err(anyhow!(
+                    "{} takes images with {} channels, but Channels is {}",
+                    model.display(),
+                    channels,
+                    options.channels
+                ));
+            }
+        }
+        if let [_, _, Some(height), Some(width)] = session.inputs[0].dimensions[..] {
+            if (width, height) != (size, size) {
+                return Err(anyhow!(
+                    "{} was exported for {}x{} inputs, but Size is {}",
+                    model.display(),
+                    width,
+                    height,
+                    size
+                ));
+            }
+        }
+
+        Ok(YoloV5Onnx {
+            session,
+            confidence_threshold: options.confidence_threshold,
+            nms_threshold: 0.4,
+            size,
+            precision,
+            classes: None,
+            raw: None,
+        })
+    }
+}
+
+impl Detector for YoloV5Onnx {
+    fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    fn classes(&self) -> Option<usize> {
+        self.classes
+    }
+
+    fn keep_raw_outputs(&mut self) -> bool {
+        self.raw.get_or_insert_with(Vec::new);
+        true
+    }
+
+    fn raw_outputs(&self) -> &[RawOutput] {
+        self.raw.as_deref().unwrap_or_default()
+    }
+
+    fn infer(&mut self, image: &Mat) -> Result<Vec<Detection>> {
+        self.infer_batch_ordered(slice::from_ref(image), PixelOrder::Rgb)
+            .map(|mut detections| detections.remove(0))
+    }
+
+    fn infer_batch_ordered(&mut self, images: &[Mat], order: PixelOrder) -> Result<Vec<Vec<Detection>>> {
+        if let Some(raw) = &mut self.raw {
+            raw.clear();
+        }
+        images
+            .iter()
+            .map(|image| self.infer_ordered(image, order))
+            .collect()
+    }
+}
+
+impl YoloV5Onnx {
+    /// BGR images are swapped while converting to the input tensor, which copies them anyway.
+    fn infer_ordered(&mut self, image: &Mat, order: PixelOrder) -> Result<Vec<Detection>> {
+        let size = self.size as usize;
+        let mut resized = Mat::default();
+        imgproc::resize(
+            image,
+            &mut resized,
+            Size::new(size as i32, size as i32),
+            0.0,
+            0.0,
+            imgproc::INTER_LINEAR,
+        )?;
+        let pixels = resized.data_bytes()?;
+        let channels = resized.channels() as usize;
+        // HWC u8 -> NCHW f32
+        let input = Array4::from_shape_fn((1, channels, size, size), |(_, c, y, x)| {
+            pixels[(y * size + x) * channels + source_channel(c, channels, order)] as f32 / 255.0
+        });
+        let input = CowArray::from(input.into_dyn());
+
+        let outputs = self
+            .session
+            .run(vec![Value::from_array(self.session.allocator(), &input)?])?;
+        let output: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
+        let output = output.view();
+        // [1, candidates, 5 + classes]
+        let row_len = *output.shape().last().unwrap();
+        self.classes = Some(row_len.saturating_sub(5));
+        let output: Vec<f32> = output.iter().copied().collect();
+        if let Some(raw) = &mut self.raw {
+            raw.push(RawOutput {
+                row_len,
+                rows: output.clone(),
+            });
+        }
+
+        let scale = (
+            image.cols() as f32 / size as f32,
+            image.rows() as f32 / size as f32,
+        );
+        let detections = output
+            .chunks_exact(row_len)
+            .map(|row| decode_row(row, scale))
+            .filter(|d| d.confidence >= self.confidence_threshold)
+            .collect();
+        Ok(nms(detections, self.nms_threshold))
+    }
+}
+
+/// The channel of the image in the given order that is input channel c (R, G, B)
+fn source_channel(c: usize, channels: usize, order: PixelOrder) -> usize {
+    match order {
+        PixelOrder::Bgr if channels == 3 => 2 - c,
+        _ => c,
+    }
+}
+
+/// A row is center x, center y, width, height (in input pixels), objectness and the class scores.
+fn decode_row(row: &[f32], (scale_x, scale_y): (f32, f32)) -> Detection {
+    let (center_x, center_y) = (row[0] * scale_x, row[1] * scale_y);
+    let (width, height) = (row[2] * scale_x, row[3] * scale_y);
+    let (class_score, class_id) = row[5..]
+        .iter()
+        .zip(1..) // 1-based, like the darknet model
+        .max_by(|a, b| a.0.total_cmp(b.0))
+        .unwrap();
+    let second = runner_up(&row[5..], class_id as usize - 1);
+
+    Detection {
+        confidence: row[4] * class_score,
+        class_id,
+        bounding_box: Rect::new(
+            ((center_x - width / 2.0) as i32).max(0),
+            ((center_y - height / 2.0) as i32).max(0),
+            width as i32,
+            height as i32,
+        ),
+        runner_up: second.map(|score| row[4] * score),
+        label: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_row() {
+        let d = decode_row(&[50.0, 60.0, 20.0, 40.0, 0.8, 0.1, 0.5, 0.2], (2.0, 1.0));
+        assert_eq!(d.class_id, 2);
+        assert!((d.confidence - 0.4).abs() < 1e-6);
+        assert!((d.runner_up.unwrap() - 0.16).abs() < 1e-6);
+        assert_eq!(d.bounding_box, Rect::new(80, 40, 40, 40));
+    }
+
+    #[test]
+    fn test_source_channel() {
+        assert_eq!(source_channel(0, 3, PixelOrder::Rgb), 0);
+        assert_eq!(source_channel(0, 3, PixelOrder::Bgr), 2);
+        assert_eq!(source_channel(1, 3, PixelOrder::Bgr), 1);
+        assert_eq!(source_channel(0, 1, PixelOrder::Bgr), 0);
+    }
+}
@@ -0,0 +1,171 @@
+//! The models table: further networks in the model directory, chosen per monitor with the
+//! aidect zone's Model=NAME. models.toml holds a [models.NAME] table for each, e.g.
+//!
+//! ```toml
+//! [models.plates]
+//! weights = "plates/yolov4-tiny-plates.weights"
+//! cfg = "plates/yolov4-tiny-plates.cfg"
+//! names = "plates/plates.names"
+//! size = 416
+//! backend = "cuda"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use super::Options;
+use crate::zoneminder::zone::{Backend, ZoneConfig};
+
+pub const MODELS_FILE: &str = "models.toml";
+
+/// A [models.NAME] table. Relative paths are relative to the model directory.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelEntry {
+    pub weights: Option<PathBuf>,
+    pub cfg: Option<PathBuf>,
+    /// A YOLOv5 ONNX export, instead of weights and cfg
+    pub onnx: Option<PathBuf>,
+    /// An SSD model compiled for the Coral Edge TPU, instead of weights and cfg
+    pub tflite: Option<PathBuf>,
+    /// Class names, one per line in the order of the model's outputs
+    pub names: Option<PathBuf>,
+    pub size: Option<u32>,
+    #[serde(default, deserialize_with = "backend")]
+    pub backend: Option<Backend>,
+}
+
+fn backend<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Backend>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match Backend::parse(&name) {
+        Some(backend) => Ok(Some(backend)),
+        None => Err(D::Error::custom(format!("Unknown backend {}", name))),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelsFile {
+    #[serde(default)]
+    models: BTreeMap<String, ModelEntry>,
+}
+
+impl ModelEntry {
+    /// Size and Backend of the entry, where the zone doesn't set them
+    pub fn options(&self, zone_config: &ZoneConfig) -> Options {
+        let options = Options::from_zone(zone_config);
+        Options {
+            size: zone_config.size.or(self.size).unwrap_or(options.size),
+            backend: zone_config.backend.or(self.backend).unwrap_or(options.backend),
+            ..options
+        }
+    }
+
+    /// The class names in the names file, if any
+    pub fn class_names(&self) -> Result<Option<Vec<String>>> {
+        let path = match &self.names {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let names = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read class names {}", path.display()))?;
+        Ok(Some(
+            names
+                .lines()
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        ))
+    }
+}
+
+/// The entries of model_dir's models file, empty if there is none.
+pub fn load(model_dir: &Path) -> Result<BTreeMap<String, ModelEntry>> {
+    let path = model_dir.join(MODELS_FILE);
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&contents, model_dir).with_context(|| format!("Invalid {}", path.display()))
+}
+
+/// The entry for Model=name
+pub fn lookup(model_dir: &Path, name: &str) -> Result<ModelEntry> {
+    load(model_dir)?.remove(name).ok_or_else(|| {
+        anyhow!(
+            "Model {} not found, there is no [models.{}] in {}",
+            name,
+            name,
+            model_dir.join(MODELS_FILE).display()
+        )
+    })
+}
+
+fn parse(contents: &str, base_dir: &Path) -> Result<BTreeMap<String, ModelEntry>> {
+    let mut models = toml::from_str::<ModelsFile>(contents)?.models;
+    for entry in models.values_mut() {
+        let paths = [
+            &mut entry.weights,
+            &mut entry.cfg,
+            &mut entry.onnx,
+            &mut entry.tflite,
+            &mut entry.names,
+        ];
+        for path in paths.into_iter().flatten() {
+            *path = base_dir.join(&path);
+        }
+    }
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = r#"
+# the gate camera
+[models.plates]
+weights = "plates/plates.weights"
+cfg = "/opt/plates.cfg"  # absolute
+size = 416
+backend = "cuda"
+
+[models."bigger"]
+onnx = "yolov5m.onnx"
+
+[models.coral]
+tflite = "ssdlite_mobiledet_coco_qat_postprocess_edgetpu.tflite"
+"#;
+        let models = parse(contents, Path::new("/usr/share/zm-aidect")).unwrap();
+        assert_eq!(
+            models["plates"],
+            ModelEntry {
+                weights: Some("/usr/share/zm-aidect/plates/plates.weights".into()),
+                cfg: Some("/opt/plates.cfg".into()),
+                size: Some(416),
+                backend: Some(Backend::Cuda),
+                ..ModelEntry::default()
+            }
+        );
+        assert_eq!(models["bigger"].onnx, Some("/usr/share/zm-aidect/yolov5m.onnx".into()));
+        assert_eq!(
+            models["coral"].tflite,
+            Some("/usr/share/zm-aidect/ssdlite_mobiledet_coco_qat_postprocess_edgetpu.tflite".into())
+        );
+
+        let base = Path::new("/");
+        assert!(parse("size = 416", base).is_err());
+        assert!(parse("[plates]", base).is_err());
+        assert!(parse("[models.plates]\nsize = big", base).is_err());
+        assert!(parse("[models.plates]\nweights = plates.weights", base).is_err());
+        assert!(parse("[models.plates]\nlabels = \"x\"", base).is_err());
+        assert!(parse("[models.plates]\nbackend = \"tpu\"", base).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Where `model fetch` puts models if no --model-dir is given, and they aren't installed anywhere yet.
+pub const DEFAULT_INSTALL_DIR: &str = "/usr/local/share/zm-aidect";
+
+pub struct ModelFile {
+    pub name: &'static str,
+    url: &'static str,
+    sha256: &'static str,
+}
+
+pub struct Model {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub files: &'static [ModelFile],
+}
+
+pub const MODELS: &[Model] = &[Model {
+    name: "yolov4-tiny",
+    description: "YOLOv4-tiny trained on COCO (80 classes), the default",
+    files: &[
+        ModelFile {
+            name: zoneminder_aidect::ml::WEIGHTS,
+            url: "https://github.com/AlexeyAB/darknet/releases/download/darknet_yolo_v4_pre/yolov4-tiny.weights",
+            sha256: "cf9fbfd0f6d4869b35762f56100f50ed05268084078805f0e7989efe5bb8ca87",
+        },
+        ModelFile {
+            name: zoneminder_aidect::ml::CFG,
+            url: "https://raw.githubusercontent.com/AlexeyAB/darknet/master/cfg/yolov4-tiny.cfg",
+            sha256: "f858e3724962eedf3ac44e3b6cb3f0c3d9ed067c306bb831f539c578b924c90e",
+        },
+    ],
+}];
+
+pub fn by_name(name: &str) -> Result<&'static Model> {
+    MODELS
+        .iter()
+        .find(|model| model.name == name)
+        .ok_or_else(|| anyhow!("Unknown model {}, see model list", name))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FileState {
+    Missing,
+    Ok,
+    /// Present, but not the file we know (modified, truncated, or a newer upstream version)
+    Mismatch,
+}
+
+impl ModelFile {
+    pub fn state(&self, dir: &Path) -> Result<FileState> {
+        let path = dir.join(self.name);
+        if !path.is_file() {
+            return Ok(FileState::Missing);
+        }
+        Ok(if sha256_file(&path)? == self.sha256 {
+            FileState::Ok
+        } else {
+            FileState::Mismatch
+        })
+    }
+
+    /// Downloads the file into dir (using curl), unless it's already there. The file is only put
+    /// into place once its checksum was verified.
+    pub fn fetch(&self, dir: &Path, force: bool) -> Result<()> {
+        let path = dir.join(self.name);
+        if !force && self.state(dir)? == FileState::Ok {
+            println!("{} is already installed", path.display());
+            return Ok(());
+        }
+        println!("Downloading {} to {}", self.url, path.display());
+        let download_path = dir.join(format!("{}.download", self.name));
+        let status = Command::new("curl")
+            .args(["--fail", "--location", "--progress-bar", "--output"])
+            .arg(&download_path)
+            .arg(self.url)
+            .status()
+            .with_context(|| "Failed to run curl")?;
+        if !status.success() {
+            let _ = fs::remove_file(&download_path);
+            return Err(anyhow!("Failed to download {}: curl {}", self.url, status));
+        }
+
+        let sha256 = sha256_file(&download_path)?;
+        if sha256 != self.sha256 {
+            let _ = fs::remove_file(&download_path);
+            return Err(anyhow!(
+                "Checksum mismatch for {}: expected sha256 {}, got {}",
+                self.url,
+                self.sha256,
+                sha256
+            ));
+        }
+        fs::rename(&download_path, &path)
+            .with_context(|| format!("Failed to move download to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn fetch(model: &Model, dir: &Path, force: bool) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    for file in model.files {
+        file.fetch(dir, force)?;
+    }
+    println!("{} installed in {}", model.name, dir.display());
+    Ok(())
+}
+
+/// Prints the known models and in which of the directories they're installed.
+pub fn list(dirs: &[PathBuf]) -> Result<()> {
+    for model in MODELS {
+        println!("{} - {}", model.name, model.description);
+        let mut installed = false;
+        for dir in dirs {
+            let states = model
+                .files
+                .iter()
+                .map(|file| Ok((file.name, file.state(dir)?)))
+                .collect::<Result<Vec<_>>>()?;
+            if states.iter().all(|(_, state)| *state == FileState::Missing) {
+                continue;
+            }
+            installed = true;
+            for (name, state) in states {
+                let state = match state {
+                    FileState::Ok => "ok",
+                    FileState::Missing => "missing",
+                    FileState::Mismatch => "checksum mismatch",
+                };
+                println!("    {}: {}", dir.join(name).display(), state);
+            }
+        }
+        if !installed {
+            println!("    not installed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_file() {
+        let path = std::env::temp_dir().join(format!("zm-aidect-test-{}", std::process::id()));
+        fs::write(&path, "abc").unwrap();
+        let sha256 = sha256_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
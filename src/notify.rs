@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::{debug, error};
+use opencv::core::Mat;
+use serde::Serialize;
+
+use zoneminder_aidect::report::DetectionReport;
+
+use crate::annotate;
+
+/// Sent when zm-aidect triggered a new event.
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub monitor_id: u32,
+    pub event_id: u64,
+    /// Unix time
+    pub time: u64,
+    /// The detection which triggered the event
+    pub detection: DetectionReport,
+}
+
+impl Notification {
+    pub fn new(monitor_id: u32, event_id: u64, detection: DetectionReport) -> Notification {
+        Notification {
+            monitor_id,
+            event_id,
+            time: unix_time(),
+            detection,
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// None of these may block, the I/O thread calls them.
+pub trait Notifier {
+    /// zm-aidect triggered a new event
+    fn event(&mut self, notification: &Notification, snapshot: &Mat);
+
+    /// Every frame with detections
+    fn detections(&mut self, _detections: &[DetectionReport], _snapshot: &Mat) {}
+
+    /// Called regularly, for notifiers which send things later on
+    fn poll(&mut self) {}
+
+    /// Shutting down, send what's left
+    fn flush(&mut self) {}
+}
+
+/// Several notifiers at once, e.g. a webhook and Telegram
+impl Notifier for Vec<Box<dyn Notifier + Send>> {
+    fn event(&mut self, notification: &Notification, snapshot: &Mat) {
+        self.iter_mut().for_each(|notifier| notifier.event(notification, snapshot));
+    }
+
+    fn detections(&mut self, detections: &[DetectionReport], snapshot: &Mat) {
+        self.iter_mut().for_each(|notifier| notifier.detections(detections, snapshot));
+    }
+
+    fn poll(&mut self) {
+        self.iter_mut().for_each(|notifier| notifier.poll());
+    }
+
+    fn flush(&mut self) {
+        self.iter_mut().for_each(|notifier| notifier.flush());
+    }
+}
+
+/// POSTs notifications as JSON to a URL (using curl), optionally as multipart/form-data with
+/// the JSON in the "metadata" field and the frame as a JPEG in the "image" field.
+pub struct Webhook {
+    url: String,
+    attach_image: bool,
+}
+
+impl Webhook {
+    pub fn new(url: String, attach_image: bool) -> Webhook {
+        Webhook { url, attach_image }
+    }
+
+    fn post(&self, metadata: &impl Serialize, snapshot: &Mat) -> Result<()> {
+        let metadata = serde_json::to_string(metadata)?;
+        let mut command = Command::new("curl");
+        command.args(["--fail", "--silent", "--show-error", "--max-time", "30"]);
+        let body = if self.attach_image {
+            command
+                .arg("--form-string")
+                .arg(format!("metadata={}", metadata))
+                .args(["--form", "image=@-;filename=snapshot.jpg;type=image/jpeg"]);
+            annotate::encode_jpeg(snapshot)?
+        } else {
+            command.args([
+                "--header",
+                "Content-Type: application/json",
+                "--data-binary",
+                "@-",
+            ]);
+            metadata.into_bytes()
+        };
+        let mut child = command.arg(&self.url).stdin(Stdio::piped()).spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let url = self.url.clone();
+        // the receiving end may be slow, don't hold up the caller
+        std::thread::spawn(move || {
+            let written = stdin.write_all(&body);
+            drop(stdin);
+            match child.wait() {
+                Ok(status) if status.success() && written.is_ok() => debug!("Notified {}", url),
+                Ok(status) => error!("Webhook {} failed: curl {}", url, status),
+                Err(e) => error!("Webhook {} failed: {}", url, e),
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Notifier for Webhook {
+    fn event(&mut self, notification: &Notification, snapshot: &Mat) {
+        if let Err(e) = self.post(notification, snapshot) {
+            error!("Failed to run webhook {}: {:#}", self.url, e);
+        }
+    }
+}
+
+/// Sent by Digest instead of a Notification per event.
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub monitor_id: u32,
+    /// Unix time of the first detection in the window
+    pub since: u64,
+    pub until: u64,
+    /// Events triggered during the window
+    pub event_ids: Vec<u64>,
+    pub detections: usize,
+    pub detections_per_class: BTreeMap<String, usize>,
+    /// The most confident detection, its frame is the snapshot
+    pub best: DetectionReport,
+}
+
+struct Batch {
+    started: Instant,
+    summary: Summary,
+    snapshot: Mat,
+}
+
+/// Batches everything detected over a window (starting with the first detection) into a single
+/// Summary, instead of a notification per event.
+pub struct Digest {
+    monitor_id: u32,
+    webhook: Webhook,
+    window: Duration,
+    batch: Option<Batch>,
+}
+
+impl Digest {
+    pub fn new(monitor_id: u32, webhook: Webhook, window: Duration) -> Digest {
+        Digest {
+            monitor_id,
+            webhook,
+            window,
+            batch: None,
+        }
+    }
+
+    fn send(&mut self) {
+        if let Some(mut batch) = self.batch.take() {
+            batch.summary.until = unix_time();
+            if let Err(e) = self.webhook.post(&batch.summary, &batch.snapshot) {
+                error!("Failed to run webhook {}: {:#}", self.webhook.url, e);
+            }
+        }
+    }
+}
+
+impl Notifier for Digest {
+    fn event(&mut self, notification: &Notification, _snapshot: &Mat) {
+        // the detections were already counted
+        if let Some(batch) = self.batch.as_mut() {
+            batch.summary.event_ids.push(notification.event_id);
+        }
+    }
+
+    fn detections(&mut self, detections: &[DetectionReport], snapshot: &Mat) {
+        let best = match detections
+            .iter()
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        {
+            Some(best) => best,
+            None => return,
+        };
+        let monitor_id = self.monitor_id;
+        let batch = self.batch.get_or_insert_with(|| Batch {
+            started: Instant::now(),
+            summary: Summary {
+                monitor_id,
+                since: unix_time(),
+                until: 0,
+                event_ids: Vec::new(),
+                detections: 0,
+                detections_per_class: BTreeMap::new(),
+                best: best.clone(),
+            },
+            snapshot: snapshot.clone(),
+        });
+        add_detections(&mut batch.summary, detections);
+        if best.confidence > batch.summary.best.confidence {
+            batch.summary.best = best.clone();
+            batch.snapshot = snapshot.clone();
+        }
+    }
+
+    fn poll(&mut self) {
+        if matches!(&self.batch, Some(batch) if batch.started.elapsed() >= self.window) {
+            self.send();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+fn add_detections(summary: &mut Summary, detections: &[DetectionReport]) {
+    summary.detections += detections.len();
+    for d in detections {
+        *summary
+            .detections_per_class
+            .entry(d.class.clone())
+            .or_default() += 1;
+    }
+}
+
+/// Escapes a value for a double-quoted curl config parameter
+pub fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A file only we can read, for handing data to curl when its stdin carries the config (with
+/// secrets that mustn't show up in the process list). Removed on drop.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(suffix: &str, data: &[u8]) -> Result<TempFile> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "zm-aidect-{}-{}{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+                suffix
+            ));
+            // never follow or reuse what someone else put there
+            let mut file = match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let temp_file = TempFile { path };
+            file.write_all(data)?;
+            return Ok(temp_file);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(class: &str, confidence: f32) -> DetectionReport {
+        DetectionReport {
+            class_id: 0,
+            class: class.to_string(),
+            confidence,
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+            description: String::new(),
+            label: None,
+        }
+    }
+
+    #[test]
+    fn test_add_detections() {
+        let mut summary = Summary {
+            monitor_id: 1,
+            since: 0,
+            until: 0,
+            event_ids: vec![],
+            detections: 0,
+            detections_per_class: BTreeMap::new(),
+            best: detection("Human", 0.5),
+        };
+        add_detections(&mut summary, &[detection("Human", 0.5), detection("Car", 0.7)]);
+        add_detections(&mut summary, &[detection("Human", 0.9)]);
+        assert_eq!(summary.detections, 3);
+        assert_eq!(
+            summary.detections_per_class,
+            [("Car".to_string(), 1), ("Human".to_string(), 2)].into()
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(r#"se"cr\et"#), r#"se\"cr\\et"#);
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let a = TempFile::new(".jpg", b"a").unwrap();
+        let b = TempFile::new(".jpg", b"b").unwrap();
+        assert_ne!(a.path(), b.path());
+        assert_eq!(std::fs::read(a.path()).unwrap(), b"a");
+        let path = a.path().to_owned();
+        drop(a);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,159 @@
+//! Export of car crops for license plate recognition elsewhere (`run --plate-dir`, `--plate-command`):
+//! whenever a car is centered within an "aidect-plates" zone, an enlarged crop of it is written to
+//! a spool directory and/or piped into a command, e.g. one handing it to an ALPR service.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::{debug, error};
+use opencv::core::{Mat, MatTraitConst, Rect, Size};
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::report::DetectionReport;
+use zoneminder_aidect::zoneminder::zone::{self, SubZone};
+
+use crate::annotate;
+use crate::notify::{Notification, Notifier};
+
+/// How much the crop extends beyond the bounding box on each side, as a fraction of its size, so
+/// that a plate at the edge of the box is whole.
+const MARGIN: f32 = 0.2;
+
+pub struct PlateExport {
+    monitor_id: u32,
+    zones: Vec<SubZone>,
+    /// The resolution the detections are given in
+    monitor_size: (u32, u32),
+    dir: Option<PathBuf>,
+    command: Option<String>,
+    /// At most one crop this often, the frames of a car passing are much alike
+    interval: Duration,
+    last_export: Option<Instant>,
+}
+
+impl PlateExport {
+    pub fn new(
+        monitor_id: u32,
+        zones: Vec<SubZone>,
+        monitor_size: (u32, u32),
+        dir: Option<PathBuf>,
+        command: Option<String>,
+        interval: Duration,
+    ) -> PlateExport {
+        PlateExport {
+            monitor_id,
+            zones,
+            monitor_size,
+            dir,
+            command,
+            interval,
+            last_export: None,
+        }
+    }
+
+    /// Whether d is a car (or what the zone's Classes say) centered within one of the zones
+    fn wants(&self, d: &DetectionReport) -> bool {
+        let bounding_box = Rect::new(d.x, d.y, d.width, d.height);
+        let car = Some(d.class_id) == detect::class_id("Car");
+        self.zones
+            .iter()
+            .any(|zone| (car || zone.classes.is_some()) && zone.matches(d.class_id, &bounding_box))
+    }
+
+    fn export(&self, d: &DetectionReport, snapshot: &Mat) -> Result<()> {
+        let size = snapshot.size()?;
+        let bounding_box = zone::scale_rect(
+            Rect::new(d.x, d.y, d.width, d.height),
+            self.monitor_size,
+            (size.width as u32, size.height as u32),
+        );
+        let crop = match enlarge(bounding_box, MARGIN, size) {
+            Some(crop) => Mat::roi(snapshot, crop)?,
+            None => return Ok(()),
+        };
+        let jpeg = annotate::encode_jpeg(&crop)?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let mut path = None;
+        if let Some(dir) = &self.dir {
+            // renamed into place once complete, for whatever watches the directory
+            let name = format!("{}-{}.jpg", self.monitor_id, time);
+            let tmp_path = dir.join(format!(".{}.tmp", name));
+            std::fs::write(&tmp_path, &jpeg)?;
+            std::fs::rename(&tmp_path, dir.join(&name))?;
+            debug!("{}: Exported {}", self.monitor_id, name);
+            path = Some(dir.join(name));
+        }
+        if let Some(command) = &self.command {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(command)
+                .env("AIDECT_MONITOR_ID", self.monitor_id.to_string())
+                .env("AIDECT_DESCRIPTION", &d.description)
+                .env("AIDECT_CONFIDENCE", format!("{:.3}", d.confidence));
+            if let Some(path) = &path {
+                command.env("AIDECT_FILE", path);
+            }
+            let mut child = command.stdin(Stdio::piped()).spawn()?;
+            let mut stdin = child.stdin.take().unwrap();
+            let monitor_id = self.monitor_id;
+            // the command may take a while, e.g. waiting for an ALPR service
+            std::thread::spawn(move || {
+                let written = stdin.write_all(&jpeg);
+                drop(stdin);
+                match child.wait() {
+                    Ok(status) if status.success() && written.is_ok() => {}
+                    Ok(status) => error!("{}: Plate command failed: {}", monitor_id, status),
+                    Err(e) => error!("{}: Plate command failed: {}", monitor_id, e),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for PlateExport {
+    fn event(&mut self, _notification: &Notification, _snapshot: &Mat) {}
+
+    fn detections(&mut self, detections: &[DetectionReport], snapshot: &Mat) {
+        if self.last_export.map_or(false, |last| last.elapsed() < self.interval) {
+            return;
+        }
+        let best = detections
+            .iter()
+            .filter(|d| self.wants(d))
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence));
+        if let Some(d) = best {
+            self.last_export = Some(Instant::now());
+            if let Err(e) = self.export(d, snapshot) {
+                error!("{}: Failed to export plate crop: {:#}", self.monitor_id, e);
+            }
+        }
+    }
+}
+
+/// rect grown by margin (of its width and height) on each side, within an image of size. None if
+/// nothing of it is in the image.
+fn enlarge(rect: Rect, margin: f32, size: Size) -> Option<Rect> {
+    let (dx, dy) = ((rect.width as f32 * margin) as i32, (rect.height as f32 * margin) as i32);
+    let (x, y) = ((rect.x - dx).max(0), (rect.y - dy).max(0));
+    let right = (rect.x + rect.width + dx).min(size.width);
+    let bottom = (rect.y + rect.height + dy).min(size.height);
+    (right > x && bottom > y).then(|| Rect::new(x, y, right - x, bottom - y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enlarge() {
+        let size = Size::new(1920, 1080);
+        assert_eq!(enlarge(Rect::new(100, 100, 200, 100), 0.2, size), Some(Rect::new(60, 80, 280, 140)));
+        assert_eq!(enlarge(Rect::new(10, 1000, 200, 100), 0.2, size), Some(Rect::new(0, 980, 250, 100)));
+        assert_eq!(enlarge(Rect::new(2000, 0, 100, 100), 0.2, size), None);
+    }
+}
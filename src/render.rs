@@ -0,0 +1,127 @@
+//! `event --render`: the event's recording with the detections burned in, for sharing clips with
+//! people who don't use ZoneMinder, or to see what the detector saw.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use opencv::core::Rect;
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::report::{DetectionReport, EventReport, FrameReport};
+use zoneminder_aidect::vio;
+use zoneminder_aidect::zoneminder::db::Event;
+use zoneminder_aidect::zoneminder::zone::{Bounding, ZoneConfig};
+
+use crate::annotate;
+
+/// The detections to draw at time: those of the last analyzed frame, as long as it's at most hold
+/// seconds ago (the time between analyzed frames), so that boxes don't flicker.
+fn detections_at(frames: &[FrameReport], time: f32, hold: f32) -> &[DetectionReport] {
+    // analyzed and rendered frame times are computed from different frame rates
+    const EPSILON: f32 = 1e-3;
+    frames
+        .iter()
+        .rev()
+        .find(|frame| frame.time <= time + EPSILON)
+        .filter(|frame| time - frame.time < hold - EPSILON)
+        .map(|frame| frame.detections.as_slice())
+        .unwrap_or(&[])
+}
+
+/// e.g. "Event 1234 2022-01-27 18:45:59 +01:02.500"
+fn timestamp(event: &Event, time: f32) -> String {
+    let millis = (time * 1000.0) as u32;
+    format!(
+        "Event {} {} +{:02}:{:02}.{:03}",
+        event.id,
+        event.start_datetime(),
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Re-encodes the recording of event (at its own resolution and frame rate) into output, with the
+/// detections of report drawn on it. zone_config is scaled to the recording (after Rotate), fps is
+/// the frame rate the recording was analyzed at.
+pub fn render(
+    event: &Event,
+    report: &EventReport,
+    zone_config: &ZoneConfig,
+    fps: f32,
+    output: &Path,
+) -> Result<()> {
+    let video_path = event.video_path()?;
+    let props = vio::properties(&video_path)?;
+    let video_fps = props
+        .get_fps()
+        .ok_or_else(|| anyhow!("{} has no frame rate", video_path.display()))?;
+    let roi: Rect = zone_config.shape.bounding_box();
+    // the detections are in the transformed frames, and so is what's rendered
+    let (width, height) = zone_config.transform.size((props.width, props.height));
+    let mut writer = vio::encode_file(output, width, height, video_fps)
+        .with_context(|| format!("Failed to start encoding {}", output.display()))?;
+    let mut rendered = 0;
+    let stream = vio::stream_file(&video_path, props.width, props.height, video_fps)?;
+    for (index, image) in stream.enumerate() {
+        let image = detect::transform_frame(image, &zone_config.transform)?;
+        let time = index as f32 / video_fps;
+        let detections: Vec<Detection> = detections_at(&report.frames, time, 1.0 / fps)
+            .iter()
+            .map(|d| Detection {
+                confidence: d.confidence,
+                class_id: d.class_id,
+                bounding_box: Rect::new(d.x, d.y, d.width, d.height),
+                runner_up: None,
+                label: d.label.clone(),
+            })
+            .collect();
+        let mut annotated = annotate::annotate(&image, zone_config, roi, &detections, |d| {
+            format!("{} {:.0}%", detect::class_name(d.class_id), d.confidence * 100.0)
+        })?;
+        annotate::draw_caption(&mut annotated, &timestamp(event, time))?;
+        writer.write(&annotated)?;
+        rendered += 1;
+    }
+    writer.finish()?;
+    debug!("Rendered {} frames into {}", rendered, output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(time: f32) -> FrameReport {
+        FrameReport {
+            time,
+            frame_id: None,
+            inference_ms: 0.0,
+            detections: vec![DetectionReport {
+                class_id: 1,
+                class: "Human".to_string(),
+                confidence: 0.9,
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 20,
+                description: String::new(),
+                label: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_detections_at() {
+        // analyzed at 2 fps, detections in the frames at 1.0s and 1.5s
+        let frames = [frame(1.0), frame(1.5)];
+        assert!(detections_at(&frames, 0.96, 0.5).is_empty());
+        assert_eq!(detections_at(&frames, 1.0, 0.5).len(), 1);
+        assert_eq!(detections_at(&frames, 1.9, 0.5).len(), 1);
+        // the frame at 2.0s had no detections
+        assert!(detections_at(&frames, 2.0, 0.5).is_empty());
+        assert!(detections_at(&[], 1.0, 0.5).is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! `zm-aidect replay`: runs a recorded clip through what `run` does with a monitor's frames
+//! (pacing, InferenceFPS, MinHits, Stationary, triggering and coalescing detections into event
+//! notes), with a mock monitor instead of ZoneMinder, to reproduce problems offline.
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use opencv::core::Mat;
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::vio;
+use zoneminder_aidect::zoneminder::db::MonitorSettings;
+use zoneminder_aidect::zoneminder::zone::{Bounding, ZoneConfig};
+use zoneminder_aidect::zoneminder::ZoneMinderConf;
+
+use crate::coalescing::{EventTracker, UpdateEvent};
+use crate::pacemaker::Activity;
+use crate::{best_detection, stationary, InferenceOptions, NoWatchdog};
+
+/// Stands in for the trigger monitor: triggering starts an event, which ends post_event after the
+/// last trigger, like a ZoneMinder monitor with that much post event buffer.
+struct MockMonitor {
+    post_event: Duration,
+    next_event_id: u64,
+    /// Event ID and the time of its last trigger
+    current: Option<(u64, Duration)>,
+}
+
+impl MockMonitor {
+    fn new(post_event: Duration) -> MockMonitor {
+        MockMonitor {
+            post_event,
+            next_event_id: 1,
+            current: None,
+        }
+    }
+
+    /// Returns the event ID, and whether the trigger started the event.
+    fn trigger(&mut self, time: Duration) -> (u64, bool) {
+        self.end_if_over(time);
+        match self.current.as_mut() {
+            Some((event_id, last_trigger)) => {
+                *last_trigger = time;
+                (*event_id, false)
+            }
+            None => {
+                let event_id = self.next_event_id;
+                self.next_event_id += 1;
+                self.current = Some((event_id, time));
+                (event_id, true)
+            }
+        }
+    }
+
+    /// Ends the current event if it's over by time, returns when it ended.
+    fn end_if_over(&mut self, time: Duration) -> Option<Duration> {
+        let (_, last_trigger) = self.current?;
+        let end = last_trigger + self.post_event;
+        if time < end {
+            return None;
+        }
+        self.current = None;
+        Some(end)
+    }
+}
+
+/// "[mm:ss.mmm] " at time into the clip
+fn prefix(time: Duration) -> String {
+    format!(
+        "[{:02}:{:02}.{:03}] ",
+        time.as_secs() / 60,
+        time.as_secs() % 60,
+        time.subsec_millis()
+    )
+}
+
+fn print_update(time: Duration, update: Option<UpdateEvent>, zone_config: &ZoneConfig) {
+    if let Some(update) = update {
+        println!(
+            "{}Event {} notes: {} (max score {}, avg score {})",
+            prefix(time),
+            update.event_id,
+            detect::describe(&update.detection, zone_config),
+            update.max_score,
+            update.avg_score
+        );
+    }
+}
+
+/// Stands in for run's I/O thread: triggers the mock monitor and prints the events and their
+/// notes, for the detections of frames captured at start plus their time into the clip.
+fn replay_events(
+    monitor_id: u32,
+    post_event: Duration,
+    start: SystemTime,
+    zone_config: &ZoneConfig,
+    detections: mpsc::Receiver<(Vec<Detection>, Mat, SystemTime)>,
+) {
+    let mut monitor = MockMonitor::new(post_event);
+    let mut tracker = EventTracker::new(monitor_id, None);
+    let mut time = Duration::ZERO;
+    for (detections, _, captured) in detections {
+        time = captured.duration_since(start).unwrap_or_default();
+        if let Some(end) = monitor.end_if_over(time) {
+            print_update(end, tracker.clear(), zone_config);
+        }
+        let d = best_detection(&detections);
+        let (event_id, started) = monitor.trigger(time);
+        if started {
+            println!(
+                "{}Triggered event {}: {}",
+                prefix(time),
+                event_id,
+                detect::describe(d, zone_config)
+            );
+        }
+        print_update(time, tracker.push_detection(d.clone(), event_id, captured), zone_config);
+    }
+    print_update(time, tracker.clear(), zone_config);
+}
+
+/// Runs the clip through run's inference (see infer_frames), with capture_file as the source and
+/// replay_events in place of triggering ZoneMinder.
+pub fn replay(
+    zm_conf: &ZoneMinderConf,
+    model_dir: Option<&Path>,
+    file: &Path,
+    monitor_id: u32,
+    max_speed: bool,
+    post_event: Duration,
+) -> Result<()> {
+    let zone_config = ZoneConfig::get_zone_config(zm_conf, monitor_id)?;
+    let monitor_settings = MonitorSettings::query(zm_conf, monitor_id)?;
+
+    let props = vio::properties(file)?;
+    println!("Replaying {} ({}) as monitor {}", file.display(), props.to_string(), monitor_id);
+    let transform = zone_config.transform;
+    let monitor_size = transform.size(monitor_settings.image_dimensions());
+    let clip_dims = (props.width, props.height);
+    let clip_size = transform.size(clip_dims);
+    let mut zone_config = zone_config.scaled(monitor_size, clip_size);
+    let bounding_box = zone_config.shape.bounding_box();
+    let (rates, _) = zone_config
+        .analysis_rates(monitor_settings.analysis_fps_limit)
+        .ok_or_else(|| anyhow!("No analysis FPS limit above 0 set for monitor {}", monitor_id))?;
+    let (max_fps, inference_fps) = (rates.max_fps, rates.inference_fps);
+    let mut detector = crate::load_detector(monitor_id, model_dir, &mut zone_config, inference_fps, false, None)?;
+    let mut stationary = match zone_config.stationary {
+        Some(minutes) => Some(stationary::Stationary::new(
+            monitor_id,
+            Duration::from_secs_f32(minutes * 60.0),
+            None,
+        )?),
+        None => None,
+    };
+    let zone_config = &zone_config;
+    let fps_window = zone_config.fps_window.unwrap_or(10) as usize;
+    let activity = Activity::default();
+    let start = SystemTime::now();
+
+    std::thread::scope(|scope| {
+        let (frame_tx, frame_rx) = mpsc::sync_channel(0);
+        let (detections_tx, detections_rx) = mpsc::channel();
+        let capture = scope.spawn(move || {
+            crate::capture_file(
+                file,
+                clip_dims,
+                transform,
+                max_fps,
+                inference_fps,
+                fps_window,
+                max_speed,
+                start,
+                frame_tx,
+            )
+        });
+        let io = scope.spawn(move || replay_events(monitor_id, post_event, start, zone_config, detections_rx));
+
+        let inferred = crate::infer_frames(
+            monitor_id,
+            &mut detector,
+            zone_config,
+            (zone_config, bounding_box),
+            None,
+            clip_size,
+            &InferenceOptions {
+                target_interval: 1.0 / inference_fps,
+                near_misses: None,
+                dry_run: false,
+                blur_heads: None,
+            },
+            &activity,
+            None,
+            &mut None,
+            None,
+            stationary.as_mut(),
+            None,
+            &NoWatchdog,
+            &|| io.is_finished(),
+            frame_rx,
+            &detections_tx,
+        );
+        drop(detections_tx);
+        inferred?;
+
+        io.join().unwrap();
+        capture.join().unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_monitor() {
+        let secs = Duration::from_secs;
+        let mut monitor = MockMonitor::new(secs(10));
+        assert_eq!(monitor.end_if_over(secs(0)), None);
+        assert_eq!(monitor.trigger(secs(1)), (1, true));
+        assert_eq!(monitor.trigger(secs(5)), (1, false));
+        assert_eq!(monitor.end_if_over(secs(14)), None);
+        assert_eq!(monitor.end_if_over(secs(16)), Some(secs(15)));
+        assert_eq!(monitor.end_if_over(secs(17)), None);
+        assert_eq!(monitor.trigger(secs(20)), (2, true));
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(prefix(Duration::from_millis(75_250)), "[01:15.250] ");
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::ml::Detection;
+
+/// Machine-readable result of analyzing an event recording (`event --output json`).
+#[derive(Serialize, Debug)]
+pub struct EventReport {
+    pub event_id: u64,
+    pub monitor_id: u32,
+    pub video_path: String,
+    /// Only frames with detections
+    pub frames: Vec<FrameReport>,
+    pub totals: Totals,
+    pub inference: TimingStats,
+    /// Whether the whole recording was decoded, false if ffmpeg failed along the way
+    pub complete: bool,
+}
+
+impl EventReport {
+    /// The detection with the highest confidence in the whole event.
+    pub fn best_detection(&self) -> Option<&DetectionReport> {
+        self.frames
+            .iter()
+            .flat_map(|frame| &frame.detections)
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FrameReport {
+    /// Position in the recording, in seconds
+    pub time: f32,
+    /// The ZoneMinder frame (FrameId in the Frames table), if the event has Frames rows around it
+    pub frame_id: Option<u32>,
+    pub inference_ms: f64,
+    pub detections: Vec<DetectionReport>,
+}
+
+/// Result of analyzing a still image (`image --output json`).
+#[derive(Serialize, Debug)]
+pub struct ImageReport {
+    pub path: String,
+    pub inference_ms: f64,
+    pub detections: Vec<DetectionReport>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DetectionReport {
+    pub class_id: i32,
+    pub class: String,
+    pub confidence: f32,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub description: String,
+    /// The classifier's label, see Detection::label
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl DetectionReport {
+    pub fn new(d: &Detection, class: &str, description: String) -> DetectionReport {
+        DetectionReport {
+            class_id: d.class_id,
+            class: class.to_string(),
+            confidence: d.confidence,
+            x: d.bounding_box.x,
+            y: d.bounding_box.y,
+            width: d.bounding_box.width,
+            height: d.bounding_box.height,
+            description,
+            label: d.label.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Totals {
+    pub frames: usize,
+    pub frames_with_detections: usize,
+    pub detections: usize,
+    pub detections_per_class: BTreeMap<String, usize>,
+}
+
+impl Totals {
+    pub fn add_frame(&mut self, frame: &FrameReport) {
+        self.frames_with_detections += 1;
+        self.detections += frame.detections.len();
+        for d in &frame.detections {
+            *self.detections_per_class.entry(d.class.clone()).or_default() += 1;
+        }
+    }
+}
+
+/// Inference times in milliseconds
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct TimingStats {
+    pub total: f64,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p95: f64,
+}
+
+impl TimingStats {
+    pub fn new(durations: &[Duration]) -> TimingStats {
+        if durations.is_empty() {
+            return TimingStats::default();
+        }
+        let mut ms: Vec<f64> = durations.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        ms.sort_by(|a, b| a.total_cmp(b));
+        let total: f64 = ms.iter().sum();
+        let p95 = ms[((ms.len() as f64 * 0.95).ceil() as usize).clamp(1, ms.len()) - 1];
+        TimingStats {
+            total,
+            mean: total / ms.len() as f64,
+            min: ms[0],
+            max: ms[ms.len() - 1],
+            p95,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timing_stats() {
+        // quarter seconds are exact in floating point
+        let durations: Vec<Duration> = (1..=20).rev().map(|n| Duration::from_millis(n * 250)).collect();
+        let stats = TimingStats::new(&durations);
+        assert_eq!(stats.total, 52500.0);
+        assert_eq!(stats.mean, 2625.0);
+        assert_eq!(stats.min, 250.0);
+        assert_eq!(stats.max, 5000.0);
+        assert_eq!(stats.p95, 4750.0);
+
+        assert_eq!(TimingStats::new(&[]), TimingStats::default());
+    }
+}
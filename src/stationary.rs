@@ -0,0 +1,222 @@
+//! Stationary object suppression (Stationary=MINUTES): objects which stay where they are for that
+//! long, like a parked car, stop triggering until they move. `run --stationary-file` keeps them
+//! across restarts.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use opencv::core::Rect;
+use serde::{Deserialize, Serialize};
+
+use zoneminder_aidect::detect;
+use zoneminder_aidect::ml::{iou, Detection};
+
+use crate::instrumentation;
+
+/// Detections overlapping a known object at least this much (intersection over union) are it, not
+/// having moved. High, so that a car pulling out counts as moving early on.
+const MIN_IOU: f32 = 0.7;
+
+/// Objects not seen for this long are forgotten, which is how moved ones go away. Long enough to
+/// ride out some frames in which the model misses a parked car, e.g. while someone walks past it.
+/// Only time spent analyzing counts, see MAX_GAP.
+const FORGET_AFTER: Duration = Duration::from_secs(300);
+
+/// Longer gaps between analyzed frames (Gate=zm-motion without motion, a paused monitor) count
+/// only this much towards FORGET_AFTER, nobody was looking for the objects in the meantime.
+const MAX_GAP: Duration = Duration::from_secs(10);
+
+/// The state file is written at most this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Object {
+    class_id: i32,
+    /// x, y, width, height where it was first seen
+    bounding_box: (i32, i32, i32, i32),
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+    /// Time analyzed since it was last seen
+    #[serde(default)]
+    unseen: Duration,
+}
+
+impl Object {
+    fn rect(&self) -> Rect {
+        let (x, y, width, height) = self.bounding_box;
+        Rect::new(x, y, width, height)
+    }
+}
+
+pub struct Stationary {
+    monitor_id: u32,
+    /// How long an object has to stay put to be stationary
+    after: Duration,
+    objects: Vec<Object>,
+    file: Option<PathBuf>,
+    saved: Option<SystemTime>,
+    last_frame: Option<SystemTime>,
+}
+
+impl Stationary {
+    /// Picks up the objects in file, if it exists.
+    pub fn new(monitor_id: u32, after: Duration, file: Option<PathBuf>) -> Result<Stationary> {
+        let objects = match &file {
+            Some(path) => load(path)?,
+            None => Vec::new(),
+        };
+        info!(
+            "{}: Suppressing objects which stay put for {:?} ({} known)",
+            monitor_id,
+            after,
+            objects.len()
+        );
+        Ok(Stationary {
+            monitor_id,
+            after,
+            objects,
+            file,
+            saved: None,
+            last_frame: None,
+        })
+    }
+
+    /// Takes all detections of a frame, returns those which aren't stationary.
+    pub fn filter(&mut self, detections: Vec<Detection>, now: SystemTime) -> Vec<Detection> {
+        let seen_for = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        let analyzed = self.last_frame.map_or(Duration::ZERO, |last| seen_for(last).min(MAX_GAP));
+        self.last_frame = Some(now);
+        for object in self.objects.iter_mut() {
+            object.unseen += analyzed;
+        }
+        self.objects.retain(|object| object.unseen < FORGET_AFTER);
+
+        let mut moving = Vec::new();
+        for d in detections {
+            let known = self.objects.iter_mut().find(|object| {
+                object.class_id == d.class_id && iou(&object.rect(), &d.bounding_box) >= MIN_IOU
+            });
+            match known {
+                Some(object) => {
+                    object.last_seen = now;
+                    object.unseen = Duration::ZERO;
+                    if seen_for(object.first_seen) >= self.after {
+                        debug!(
+                            "{}: Ignoring stationary {} at {:?}",
+                            self.monitor_id,
+                            detect::class_name(d.class_id),
+                            d.bounding_box
+                        );
+                        instrumentation::STATIONARY_SUPPRESSED
+                            .with_label_values(&[&self.monitor_id.to_string()])
+                            .inc();
+                        continue;
+                    }
+                }
+                None => self.objects.push(Object {
+                    class_id: d.class_id,
+                    bounding_box: (d.bounding_box.x, d.bounding_box.y, d.bounding_box.width, d.bounding_box.height),
+                    first_seen: now,
+                    last_seen: now,
+                    unseen: Duration::ZERO,
+                }),
+            }
+            moving.push(d);
+        }
+        moving
+    }
+
+    /// Writes the state file every SAVE_INTERVAL.
+    pub fn save_if_due(&mut self, now: SystemTime) -> Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(saved) = self.saved {
+            if now.duration_since(saved).unwrap_or_default() < SAVE_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.saved = Some(now);
+        // write-and-rename, so that being interrupted never leaves a truncated file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&self.objects)?)?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to write stationary objects file {}", path.display()))
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Object>> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid stationary objects file {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(class_id: i32, x: i32) -> Detection {
+        Detection {
+            confidence: 0.8,
+            class_id,
+            bounding_box: Rect::new(x, 100, 200, 100),
+            runner_up: None,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut stationary = Stationary::new(1, Duration::from_secs(600), None).unwrap();
+        let start = SystemTime::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(stationary.filter(vec![detection(3, 0)], at(0)).len(), 1);
+        // parked, still new
+        assert_eq!(stationary.filter(vec![detection(3, 5)], at(300)).len(), 1);
+        // parked for long enough
+        assert_eq!(stationary.filter(vec![detection(3, 5)], at(600)).len(), 0);
+        // someone walking past it still counts, and so does a car parking next to it
+        let passing = vec![detection(3, 0), detection(1, 0), detection(3, 300)];
+        assert_eq!(stationary.filter(passing, at(660)).len(), 2);
+        // pulling out
+        assert_eq!(stationary.filter(vec![detection(3, 80)], at(720)).len(), 1);
+        // gone long enough to be forgotten, the next car parking there triggers
+        for secs in (725..1200).step_by(5) {
+            stationary.filter(vec![], at(secs));
+        }
+        assert_eq!(stationary.filter(vec![detection(3, 0)], at(1200)).len(), 1);
+    }
+
+    #[test]
+    fn test_filter_gated() {
+        let mut stationary = Stationary::new(1, Duration::from_secs(600), None).unwrap();
+        let start = SystemTime::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(stationary.filter(vec![detection(3, 0)], at(0)).len(), 1);
+        assert_eq!(stationary.filter(vec![detection(3, 0)], at(600)).len(), 0);
+        // nothing analyzed in between (no motion), it's still the parked car
+        assert_eq!(stationary.filter(vec![detection(1, 500)], at(4200)).len(), 1);
+        assert_eq!(stationary.filter(vec![detection(3, 0), detection(1, 500)], at(4205)).len(), 1);
+    }
+
+    #[test]
+    fn test_save() {
+        let path = std::env::temp_dir().join(format!("zm-aidect-stationary-{}.json", std::process::id()));
+        let now = SystemTime::now();
+        let mut stationary = Stationary::new(1, Duration::from_secs(600), Some(path.clone())).unwrap();
+        stationary.filter(vec![detection(3, 0)], now);
+        stationary.save_if_due(now).unwrap();
+
+        let restarted = Stationary::new(1, Duration::from_secs(600), Some(path.clone())).unwrap();
+        assert_eq!(restarted.objects, stationary.objects);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Telegram notifications (`run --telegram-token`): the frame and a caption for every event
+//! zm-aidect triggers, sent to a chat by a bot.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{debug, error};
+use opencv::core::Mat;
+
+use crate::annotate;
+use crate::notify::{quote, Notification, Notifier, TempFile};
+
+pub struct Telegram {
+    token: String,
+    chat_id: String,
+    /// Only events of these classes are sent, all if None
+    classes: Option<Vec<i32>>,
+    /// At most one message this often, events in between are only counted
+    interval: Duration,
+    last_sent: Option<Instant>,
+    skipped: u32,
+}
+
+impl Telegram {
+    pub fn new(token: String, chat_id: String, classes: Option<Vec<i32>>, interval: Duration) -> Telegram {
+        Telegram {
+            token,
+            chat_id,
+            classes,
+            interval,
+            last_sent: None,
+            skipped: 0,
+        }
+    }
+
+    /// Whether to send a message for the event, counting the ones held back by the rate limit.
+    fn wants(&mut self, notification: &Notification, now: Instant) -> bool {
+        if let Some(classes) = &self.classes {
+            if !classes.contains(&notification.detection.class_id) {
+                return false;
+            }
+        }
+        if matches!(self.last_sent, Some(last_sent) if now < last_sent + self.interval) {
+            self.skipped += 1;
+            return false;
+        }
+        self.last_sent = Some(now);
+        true
+    }
+
+    /// The URL contains the token, so it goes into a curl config on stdin (which is why the
+    /// photo goes through a temporary file), so that it doesn't show up in the process list.
+    fn send(&self, caption: String, snapshot: &Mat) -> Result<()> {
+        let photo = TempFile::new(".jpg", &annotate::encode_jpeg(snapshot)?)?;
+        let config = format!(
+            "url = \"https://api.telegram.org/bot{}/sendPhoto\"\n",
+            quote(&self.token)
+        );
+        let mut child = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--max-time", "30", "--config", "-"])
+            .arg("--form-string")
+            .arg(format!("chat_id={}", self.chat_id))
+            .arg("--form-string")
+            .arg(format!("caption={}", caption))
+            .arg("--form")
+            .arg(format!("photo=@{};filename=snapshot.jpg;type=image/jpeg", photo.path().display()))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let chat_id = self.chat_id.clone();
+        // Telegram may be slow, don't hold up the caller
+        std::thread::spawn(move || {
+            let written = stdin.write_all(config.as_bytes());
+            drop(stdin);
+            match child.wait() {
+                Ok(status) if status.success() && written.is_ok() => debug!("Sent to Telegram chat {}", chat_id),
+                Ok(status) => error!("Sending to Telegram chat {} failed: {}", chat_id, status),
+                Err(e) => error!("Sending to Telegram chat {} failed: {}", chat_id, e),
+            }
+            drop(photo);
+        });
+        Ok(())
+    }
+}
+
+fn caption(notification: &Notification, skipped: u32) -> String {
+    let mut caption = format!(
+        "Monitor {}, event {}: {}",
+        notification.monitor_id, notification.event_id, notification.detection.description
+    );
+    if skipped > 0 {
+        caption += &format!(" ({} more events since the last message)", skipped);
+    }
+    caption
+}
+
+impl Notifier for Telegram {
+    fn event(&mut self, notification: &Notification, snapshot: &Mat) {
+        if !self.wants(notification, Instant::now()) {
+            return;
+        }
+        let caption = caption(notification, self.skipped);
+        self.skipped = 0;
+        if let Err(e) = self.send(caption, snapshot) {
+            error!("Failed to send to Telegram chat {}: {:#}", self.chat_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zoneminder_aidect::report::DetectionReport;
+
+    fn notification(class_id: i32) -> Notification {
+        let detection = DetectionReport {
+            class_id,
+            class: "Human".to_string(),
+            confidence: 0.9,
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+            description: "Human (90.0%)".to_string(),
+            label: None,
+        };
+        Notification::new(2, 17, detection)
+    }
+
+    #[test]
+    fn test_wants() {
+        let mut telegram = Telegram::new("token".into(), "1".into(), Some(vec![1]), Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(telegram.wants(&notification(1), start));
+        assert!(!telegram.wants(&notification(3), start + Duration::from_secs(70)));
+        assert!(!telegram.wants(&notification(1), start + Duration::from_secs(30)));
+        assert!(!telegram.wants(&notification(1), start + Duration::from_secs(40)));
+        assert_eq!(telegram.skipped, 2);
+        assert!(telegram.wants(&notification(1), start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_caption() {
+        assert_eq!(caption(&notification(1), 0), "Monitor 2, event 17: Human (90.0%)");
+        assert_eq!(
+            caption(&notification(1), 2),
+            "Monitor 2, event 17: Human (90.0%) (2 more events since the last message)"
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! --trace: a JSON line per analyzed frame, to look into pacing, stalls and CPU spikes in more
+//! detail than the Prometheus metrics allow.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use zoneminder_aidect::report::DetectionReport;
+
+#[derive(Serialize, Debug)]
+pub struct FrameTrace<'a> {
+    /// run: Unix time the frame was captured (read from shared memory or the stream),
+    /// event: approximate position in the recording, both in seconds
+    pub time: f64,
+    /// Slot of the frame in the monitor's shared memory ring buffer
+    pub index: Option<u32>,
+    /// Conversion to RGB after capture
+    pub convert_ms: Option<f64>,
+    pub inference_ms: f64,
+    /// Mapping detections to the monitor's resolution and MinHits
+    pub postprocess_ms: Option<f64>,
+    pub detections: &'a [DetectionReport],
+}
+
+pub struct Trace {
+    out: BufWriter<File>,
+}
+
+impl Trace {
+    pub fn create(path: &Path) -> Result<Trace> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create trace file {}", path.display()))?;
+        Ok(Trace {
+            out: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, frame: &FrameTrace) -> Result<()> {
+        serde_json::to_writer(&mut self.out, frame)?;
+        self.out.write_all(b"\n")?;
+        // a line at a time, so that the trace is complete up to a crash or kill
+        self.out.flush()?;
+        Ok(())
+    }
+}
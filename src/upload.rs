@@ -0,0 +1,143 @@
+//! Upload of annotated event snapshots to an S3-compatible bucket (`run --s3-url`), e.g. AWS S3 or
+//! MinIO, as evidence kept apart from ZoneMinder's storage.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::Result;
+use log::{debug, error};
+use opencv::core::{Mat, MatTraitConst, Rect};
+
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::zoneminder::zone::{self, Bounding, ZoneConfig};
+
+use crate::annotate;
+use crate::notify::{quote, Notification, Notifier, TempFile};
+
+pub struct Uploader {
+    /// Bucket URL (path style), e.g. https://minio.example.com/evidence
+    url: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// The monitor's zones and resolution, which the detection is given in
+    zone_config: ZoneConfig,
+    monitor_size: (u32, u32),
+}
+
+impl Uploader {
+    pub fn new(
+        url: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        zone_config: ZoneConfig,
+        monitor_size: (u32, u32),
+    ) -> Uploader {
+        Uploader {
+            url: url.trim_end_matches('/').to_string(),
+            region,
+            access_key,
+            secret_key,
+            zone_config,
+            monitor_size,
+        }
+    }
+
+    /// The frame with zones and the detection drawn on it. The frame may be a substream's, in
+    /// which case zones and detection are scaled to it.
+    fn annotated(&self, notification: &Notification, snapshot: &Mat) -> Result<Mat> {
+        let report = &notification.detection;
+        let image_size = (snapshot.cols() as u32, snapshot.rows() as u32);
+        let zone_config = self.zone_config.scaled(self.monitor_size, image_size);
+        let d = Detection {
+            confidence: report.confidence,
+            class_id: report.class_id,
+            bounding_box: zone::scale_rect(
+                Rect::new(report.x, report.y, report.width, report.height),
+                self.monitor_size,
+                image_size,
+            ),
+            runner_up: None,
+            label: report.label.clone(),
+        };
+        let roi = zone_config.shape.bounding_box();
+        annotate::annotate(snapshot, &zone_config, roi, &[d], |_| report.description.clone())
+    }
+
+    /// PUTs the JPEG with curl, signed with AWS Signature V4. The credentials go into a curl config
+    /// on stdin, the image into a temporary file, so that the secret doesn't show up in the
+    /// process list.
+    fn upload(&self, key: String, jpeg: Vec<u8>) -> Result<()> {
+        let image = TempFile::new(".jpg", &jpeg)?;
+        let url = format!("{}/{}", self.url, key);
+        let config = format!(
+            "user = \"{}:{}\"\naws-sigv4 = \"aws:amz:{}:s3\"\n",
+            quote(&self.access_key),
+            quote(&self.secret_key),
+            quote(&self.region)
+        );
+        let mut child = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--max-time", "120", "--config", "-"])
+            .args(["--header", "Content-Type: image/jpeg", "--upload-file"])
+            .arg(image.path())
+            .arg(&url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        // uploads may take a while, don't hold up the caller
+        std::thread::spawn(move || {
+            let written = stdin.write_all(config.as_bytes());
+            drop(stdin);
+            match child.wait() {
+                Ok(status) if status.success() && written.is_ok() => debug!("Uploaded {}", url),
+                Ok(status) => error!("Uploading {} failed: {}", url, status),
+                Err(e) => error!("Uploading {} failed: {}", url, e),
+            }
+            drop(image);
+        });
+        Ok(())
+    }
+}
+
+/// MONITOR/YYYY-MM-DD/EVENT.jpg, dated in local time like ZoneMinder's own storage
+fn key(monitor_id: u32, unix_time: u64, event_id: u64) -> String {
+    let secs = unix_time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    format!(
+        "{}/{:04}-{:02}-{:02}/{}.jpg",
+        monitor_id,
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        event_id
+    )
+}
+
+impl Notifier for Uploader {
+    fn event(&mut self, notification: &Notification, snapshot: &Mat) {
+        let key = key(notification.monitor_id, notification.time, notification.event_id);
+        let result = self
+            .annotated(notification, snapshot)
+            .and_then(|annotated| annotate::encode_jpeg(&annotated))
+            .and_then(|jpeg| self.upload(key.clone(), jpeg));
+        if let Err(e) = result {
+            error!("Failed to upload {}: {:#}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        // noon UTC, the same day in any time zone within 12 hours
+        let key = key(3, 1657886400, 1234);
+        assert!(key.starts_with("3/2022-07-15/"), "{}", key);
+        assert!(key.ends_with("/1234.jpg"));
+    }
+}
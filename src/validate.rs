@@ -0,0 +1,251 @@
+//! `zm-aidect validate`: what `run` would pick up from ZoneMinder and the model directory if it
+//! (re)started now, without running anything, e.g. to check a fleet of monitors before restarting
+//! their services.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use zoneminder_aidect::ml::{self, table};
+use zoneminder_aidect::zoneminder::db::{self, MonitorSettings};
+use zoneminder_aidect::zoneminder::zone::{self, Gate, ZoneConfig};
+use zoneminder_aidect::zoneminder::ZoneMinderConf;
+
+use crate::zones;
+
+/// The effective settings of a monitor, as far as they could be determined
+#[derive(Serialize, Debug, Default)]
+pub struct Validation {
+    pub monitor_id: u32,
+    pub monitor: Option<String>,
+    /// Disabled monitors, or ones whose function doesn't allow triggering, are waited for
+    pub active: Option<bool>,
+    pub zone_id: Option<u32>,
+    /// Model= entry, None for the model directory's
+    pub model: Option<String>,
+    pub size: Option<u32>,
+    /// Size=auto, size is then picked at startup
+    pub auto_size: bool,
+    /// In percent
+    pub threshold: Option<f32>,
+    pub backend: Option<String>,
+    pub device: Option<String>,
+    pub precision: Option<String>,
+    pub channels: Option<u32>,
+    pub fps: Option<f32>,
+    pub inference_fps: Option<f32>,
+    pub idle_fps: Option<f32>,
+    pub trigger: Option<u32>,
+    pub gate: Option<String>,
+    /// Classify.<Class>= as "Class=NAME"
+    pub classifiers: Vec<String>,
+    /// Everything `run` would warn about or fail on
+    pub problems: Vec<String>,
+}
+
+/// Validates monitor_ids, all monitors with an aidect zone if empty. Errors if there are problems.
+pub fn validate(zm_conf: &ZoneMinderConf, model_dir: Option<&Path>, monitor_ids: &[u32], json: bool) -> Result<()> {
+    let monitor_ids = match monitor_ids.is_empty() {
+        true => with_aidect_zone(zm_conf)?,
+        false => monitor_ids.to_vec(),
+    };
+    let validations: Vec<_> = monitor_ids
+        .iter()
+        .map(|&monitor_id| validate_monitor(zm_conf, model_dir, monitor_id))
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&validations)?);
+    } else {
+        validations.iter().for_each(print);
+    }
+    let problems: usize = validations.iter().map(|v| v.problems.len()).sum();
+    match problems {
+        0 => Ok(()),
+        _ => Err(anyhow!("Found {} problems in {} monitors", problems, validations.len())),
+    }
+}
+
+fn with_aidect_zone(zm_conf: &ZoneMinderConf) -> Result<Vec<u32>> {
+    let mut monitor_ids = Vec::new();
+    for monitor_id in db::monitor_ids(zm_conf)? {
+        let zones = zone::monitor_zones(zm_conf, monitor_id)?;
+        if zones.iter().any(|(_, name, _)| zones::is_aidect(name)) {
+            monitor_ids.push(monitor_id);
+        }
+    }
+    Ok(monitor_ids)
+}
+
+fn validate_monitor(zm_conf: &ZoneMinderConf, model_dir: Option<&Path>, monitor_id: u32) -> Validation {
+    let mut validation = Validation {
+        monitor_id,
+        ..Validation::default()
+    };
+    if let Err(e) = fill(zm_conf, model_dir, &mut validation) {
+        validation.problems.push(format!("{:#}", e));
+    }
+    validation
+}
+
+fn fill(zm_conf: &ZoneMinderConf, model_dir: Option<&Path>, v: &mut Validation) -> Result<()> {
+    let settings = MonitorSettings::query(zm_conf, v.monitor_id)?;
+    v.monitor = Some(settings.name.clone());
+    v.active = Some(settings.is_active());
+
+    let zones = zone::monitor_zones(zm_conf, v.monitor_id)?;
+    for (zone_id, name, _) in &zones {
+        for problem in zones::problems(name) {
+            v.problems.push(format!("Zone {} \"{}\": {}", zone_id, name, problem));
+        }
+    }
+    let zone_config = ZoneConfig::from_zones(v.monitor_id, &zones, false)?;
+    v.zone_id = Some(zone_config.id);
+    v.trigger = Some(zone_config.trigger.unwrap_or(v.monitor_id));
+    v.gate = Some(match zone_config.gate.unwrap_or(Gate::Always) {
+        Gate::Always => "always".to_string(),
+        Gate::ZmMotion => "zm-motion".to_string(),
+    });
+
+    fill_rates(&zone_config, settings.analysis_fps_limit, v);
+
+    let model_dir = ml::find_model_dir(model_dir)?;
+    let options = match &zone_config.model {
+        Some(name) => {
+            v.model = Some(name.clone());
+            let entry = table::lookup(&model_dir, name)?;
+            check_files(name, &entry, &mut v.problems);
+            entry.options(&zone_config)
+        }
+        None => ml::Options::from_zone(&zone_config),
+    };
+    v.size = Some(options.size);
+    v.auto_size = zone_config.auto_size;
+    v.threshold = Some(options.confidence_threshold * 100.0);
+    v.backend = Some(format!("{:?}", options.backend));
+    v.device = Some(format!("{:?}", options.device));
+    v.precision = Some(format!("{:?}", options.precision));
+    v.channels = Some(options.channels);
+
+    for (class, name) in &zone_config.classifiers {
+        v.classifiers.push(format!("{}={}", class, name));
+        match table::lookup(&model_dir, name) {
+            Ok(entry) if entry.names.is_none() => {
+                v.problems.push(format!("Classifier {} has no names file", name));
+            }
+            Ok(entry) => check_files(name, &entry, &mut v.problems),
+            Err(e) => v.problems.push(format!("{:#}", e)),
+        }
+    }
+    Ok(())
+}
+
+/// FPS, InferenceFPS and IdleFPS as `run` derives them, with what it would ignore as problems
+fn fill_rates(zone_config: &ZoneConfig, analysis_fps_limit: Option<f32>, v: &mut Validation) {
+    match zone_config.analysis_rates(analysis_fps_limit) {
+        Some((rates, ignored)) => {
+            v.fps = Some(rates.max_fps);
+            v.inference_fps = Some(rates.inference_fps);
+            v.idle_fps = rates.idle_fps;
+            v.problems.extend(ignored);
+        }
+        None => v
+            .problems
+            .push("No analysis FPS limit set, neither in the monitor nor with FPS".to_string()),
+    }
+}
+
+/// The files of a models table entry which don't exist
+fn check_files(name: &str, entry: &table::ModelEntry, problems: &mut Vec<String>) {
+    let files = [&entry.weights, &entry.cfg, &entry.onnx, &entry.tflite, &entry.names];
+    for path in files.into_iter().flatten() {
+        if !path.is_file() {
+            problems.push(format!("Model {}: {} not found", name, path.display()));
+        }
+    }
+}
+
+fn print(v: &Validation) {
+    let unknown = || "?".to_string();
+    println!(
+        "Monitor {} ({}), zone {}{}",
+        v.monitor_id,
+        v.monitor.clone().unwrap_or_else(unknown),
+        v.zone_id.map_or_else(unknown, |id| id.to_string()),
+        if v.active == Some(false) { ", inactive" } else { "" }
+    );
+    if let Some(size) = v.size {
+        let size = match v.auto_size {
+            true => "auto".to_string(),
+            false => size.to_string(),
+        };
+        println!(
+            "  Model {}, Size {}, Threshold {:.0}%, {} on {} at {}, {} channels",
+            v.model.as_deref().unwrap_or("default"),
+            size,
+            v.threshold.unwrap_or_default(),
+            v.backend.clone().unwrap_or_else(unknown),
+            v.device.clone().unwrap_or_else(unknown),
+            v.precision.clone().unwrap_or_else(unknown),
+            v.channels.unwrap_or_default()
+        );
+    }
+    if let Some(trigger) = v.trigger {
+        let rate = |fps: Option<f32>| fps.map_or_else(unknown, |fps| fps.to_string());
+        println!(
+            "  FPS {} (inference {}, idle {}), Gate {}, triggers monitor {}",
+            rate(v.fps),
+            rate(v.inference_fps),
+            rate(v.idle_fps.or(v.inference_fps)),
+            v.gate.clone().unwrap_or_else(unknown),
+            trigger
+        );
+    }
+    if !v.classifiers.is_empty() {
+        println!("  Classify {}", v.classifiers.join(", "));
+    }
+    for problem in &v.problems {
+        println!("  Problem: {}", problem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rates(name: &str, analysis_fps_limit: Option<f32>) -> Validation {
+        let mut v = Validation::default();
+        let zone_config = ZoneConfig::parse(name, "0,0 64,0 64,32 0,32");
+        fill_rates(&zone_config, analysis_fps_limit, &mut v);
+        v
+    }
+
+    #[test]
+    fn test_fill_rates() {
+        let v = rates("aidect InferenceFPS=2 IdleFPS=0.5", Some(5.0));
+        assert_eq!((v.fps, v.inference_fps, v.idle_fps), (Some(5.0), Some(2.0), Some(0.5)));
+        assert!(v.problems.is_empty());
+
+        let v = rates("aidect FPS=3", Some(5.0));
+        assert_eq!((v.fps, v.inference_fps, v.idle_fps), (Some(3.0), Some(3.0), None));
+
+        let v = rates("aidect InferenceFPS=8 IdleFPS=6", Some(5.0));
+        assert_eq!((v.fps, v.inference_fps, v.idle_fps), (Some(5.0), Some(5.0), None));
+        assert_eq!(v.problems.len(), 2);
+
+        let v = rates("aidect", None);
+        assert_eq!((v.fps, v.inference_fps, v.idle_fps), (None, None, None));
+        assert_eq!(v.problems.len(), 1);
+    }
+
+    #[test]
+    fn test_check_files() {
+        let entry = table::ModelEntry {
+            onnx: Some("/nonexistent/model.onnx".into()),
+            ..table::ModelEntry::default()
+        };
+        let mut problems = Vec::new();
+        check_files("test", &entry, &mut problems);
+        assert_eq!(problems, ["Model test: /nonexistent/model.onnx not found"]);
+    }
+}
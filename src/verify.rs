@@ -0,0 +1,119 @@
+//! Late verification (`run --late-verify SECS`): some time after an event started, its recording
+//! so far is analyzed again, because the best view of an object often comes later than the
+//! detection which triggered the event.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{debug, warn};
+use opencv::core::Rect;
+
+use zoneminder_aidect::detect::{Detector, EventAnalyzer};
+use zoneminder_aidect::ml::Detection;
+use zoneminder_aidect::vio;
+use zoneminder_aidect::zoneminder::db::Event;
+use zoneminder_aidect::zoneminder::zone::{scale_rect, ZoneConfig};
+use zoneminder_aidect::zoneminder::ZoneMinderConf;
+
+/// The I/O thread's end
+pub struct LateVerify {
+    pub delay: Duration,
+    pub requests: mpsc::Sender<Request>,
+    pub results: mpsc::Receiver<Verified>,
+}
+
+pub struct Request {
+    pub trigger_id: u32,
+    pub event_id: u64,
+    pub due: Instant,
+}
+
+pub struct Verified {
+    pub trigger_id: u32,
+    pub event_id: u64,
+    /// All detections in the recording so far, at monitor resolution
+    pub detections: Vec<Detection>,
+}
+
+/// Analyzes the events requested once they're due, until requests is closed. Requests arrive in
+/// the order they're due. Failures (e.g. a recording which can't be read yet) are only logged.
+pub fn verify_events(
+    zm_conf: &ZoneMinderConf,
+    detector: &mut Detector,
+    zone_config: &ZoneConfig,
+    monitor_id: u32,
+    monitor_size: (u32, u32),
+    fps: f32,
+    requests: mpsc::Receiver<Request>,
+    results: mpsc::Sender<Verified>,
+) {
+    let mut pending: VecDeque<Request> = VecDeque::new();
+    loop {
+        // not sleeping until the next one is due, so that shutting down doesn't wait for it
+        let received = match pending.front() {
+            Some(request) => requests.recv_timeout(request.due.saturating_duration_since(Instant::now())),
+            None => requests.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(request) => pending.push_back(request),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        while pending.front().map_or(false, |request| request.due <= Instant::now()) {
+            let request = pending.pop_front().unwrap();
+            let mut analyzer = EventAnalyzer::new(detector, zone_config, monitor_id, monitor_size, fps);
+            match verify(zm_conf, &mut analyzer, zone_config, request.event_id, monitor_size) {
+                Ok(detections) => {
+                    debug!(
+                        "{}: Late verification of event {} found {} detections",
+                        monitor_id,
+                        request.event_id,
+                        detections.len()
+                    );
+                    let verified = Verified {
+                        trigger_id: request.trigger_id,
+                        event_id: request.event_id,
+                        detections,
+                    };
+                    if results.send(verified).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!(
+                    "{}: Failed to verify event {}: {:#}",
+                    monitor_id, request.event_id, e
+                ),
+            }
+        }
+    }
+}
+
+fn verify(
+    zm_conf: &ZoneMinderConf,
+    analyzer: &mut EventAnalyzer,
+    zone_config: &ZoneConfig,
+    event_id: u64,
+    monitor_size: (u32, u32),
+) -> Result<Vec<Detection>> {
+    let event = Event::query(zm_conf, event_id)?;
+    let props = vio::properties(event.video_path()?)?;
+    let report = analyzer.analyze(&event, |_, _| {})?;
+    Ok(report
+        .frames
+        .iter()
+        .flat_map(|frame| &frame.detections)
+        .map(|d| Detection {
+            confidence: d.confidence,
+            class_id: d.class_id,
+            bounding_box: scale_rect(
+                Rect::new(d.x, d.y, d.width, d.height),
+                zone_config.transform.size((props.width, props.height)),
+                monitor_size,
+            ),
+            runner_up: None,
+            label: d.label.clone(),
+        })
+        .collect())
+}
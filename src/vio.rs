@@ -1,9 +1,10 @@
-use std::io::Read;
+use std::ffi::OsStr;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
 
 use anyhow::{anyhow, Result};
-use opencv::core::{Mat, MatTraitManual};
+use opencv::core::{Mat, MatTraitConstManual, MatTraitManual};
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize, Eq, PartialEq)]
@@ -20,26 +21,29 @@ pub struct VideoProperties {
 }
 
 impl VideoProperties {
-    pub fn get_fps(&self) -> f32 {
-        let (a, b) = self.avg_frame_rate.split_once('/').unwrap();
-        let (a, b) = (a.parse::<f32>().unwrap(), (b.parse::<f32>().unwrap()));
-        a / b
+    /// None if ffprobe doesn't know it, e.g. "0/0" for a stream without timestamps
+    pub fn get_fps(&self) -> Option<f32> {
+        let (a, b) = self.avg_frame_rate.split_once('/')?;
+        let (a, b) = (a.parse::<f32>().ok()?, b.parse::<f32>().ok()?);
+        Some(a / b).filter(|fps| fps.is_finite() && *fps > 0.0)
     }
 }
 
 impl ToString for VideoProperties {
     fn to_string(&self) -> String {
         format!(
-            "{}x{} {:.1} fps ({})",
+            "{}x{} {} fps ({})",
             self.width,
             self.height,
-            self.get_fps(),
+            self.get_fps()
+                .map_or_else(|| "?".to_string(), |fps| format!("{:.1}", fps)),
             self.codec_name
         )
     }
 }
 
-pub fn properties(path: &Path) -> Result<VideoProperties> {
+/// Properties of the first video stream of a file or URL.
+pub fn properties(input: impl AsRef<OsStr>) -> Result<VideoProperties> {
     let output = Command::new("ffprobe")
         .args([
             "-v",
@@ -50,7 +54,7 @@ pub fn properties(path: &Path) -> Result<VideoProperties> {
             "v:0",
             "-show_streams",
         ])
-        .arg(path)
+        .arg(input)
         .output()?;
     if !output.status.success() {
         return Err(anyhow!(
@@ -86,12 +90,64 @@ impl Iterator for ImageStream {
     }
 }
 
+impl ImageStream {
+    /// Waits for ffmpeg to exit once the stream has ended, errors if it failed, e.g. on a broken
+    /// recording which only decoded partly.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.ffmpeg.stdout.take());
+        let status = self.ffmpeg.wait()?;
+        if !status.success() {
+            return Err(anyhow!("ffmpeg failed: {}", status));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ImageStream {
+    /// Stops ffmpeg if the stream is given up on early, e.g. on an error
+    fn drop(&mut self) {
+        stop(&mut self.ffmpeg);
+    }
+}
+
+/// Kills ffmpeg unless it exited already, and reaps it
+fn stop(ffmpeg: &mut Child) {
+    if let Ok(None) = ffmpeg.try_wait() {
+        let _ = ffmpeg.kill();
+        let _ = ffmpeg.wait();
+    }
+}
+
 pub fn stream_file(path: &Path, width: u32, height: u32, framerate: f32) -> Result<ImageStream> {
+    stream(path.as_os_str(), &[], width, height, framerate)
+}
+
+/// Decodes a live stream, e.g. a camera's RTSP substream. Frames are produced at (up to)
+/// framerate, the stream has to be read continuously to not fall behind.
+pub fn stream_url(url: &str, width: u32, height: u32, framerate: f32) -> Result<ImageStream> {
+    let input_args: &[&str] = if url.starts_with("rtsp://") {
+        // UDP tends to lose packets, which shows up as smeared frames
+        &["-rtsp_transport", "tcp"]
+    } else {
+        &[]
+    };
+    stream(OsStr::new(url), input_args, width, height, framerate)
+}
+
+fn stream(
+    input: &OsStr,
+    input_args: &[&str],
+    width: u32,
+    height: u32,
+    framerate: f32,
+) -> Result<ImageStream> {
     let video_size = format!("{}x{}", width, height);
     let framerate = framerate.to_string();
     let ffmpeg = Command::new("ffmpeg")
-        .args(["-v", "error", "-i"])
-        .arg(path)
+        .args(["-v", "error"])
+        .args(input_args)
+        .arg("-i")
+        .arg(input)
         .args([
             "-f",
             "rawvideo",
@@ -115,6 +171,71 @@ pub fn stream_file(path: &Path, width: u32, height: u32, framerate: f32) -> Resu
     })
 }
 
+/// Encodes RGB frames into a video file with ffmpeg (H.264 in whatever container the extension
+/// says, e.g. .mp4).
+pub struct VideoWriter {
+    ffmpeg: Child,
+}
+
+impl VideoWriter {
+    pub fn write(&mut self, image: &Mat) -> Result<()> {
+        let stdin = self
+            .ffmpeg
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("ffmpeg's input is closed"))?;
+        stdin.write_all(image.data_bytes()?)?;
+        Ok(())
+    }
+
+    /// Waits for ffmpeg to write the rest of the file.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.ffmpeg.stdin.take());
+        let status = self.ffmpeg.wait()?;
+        if !status.success() {
+            return Err(anyhow!("ffmpeg failed: {}", status));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VideoWriter {
+    /// Stops ffmpeg if writing is given up on early, e.g. on an error
+    fn drop(&mut self) {
+        stop(&mut self.ffmpeg);
+    }
+}
+
+pub fn encode_file(path: &Path, width: u32, height: u32, framerate: f32) -> Result<VideoWriter> {
+    let video_size = format!("{}x{}", width, height);
+    let framerate = framerate.to_string();
+    let ffmpeg = Command::new("ffmpeg")
+        .args(["-v", "error", "-y"])
+        .args([
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            "rgb24",
+            "-s:v",
+            &video_size,
+            "-r",
+            &framerate,
+            "-i",
+            "-",
+            "-c:v",
+            "libx264",
+            // what players (and browsers) can play
+            "-pix_fmt",
+            "yuv420p",
+            "-movflags",
+            "+faststart",
+        ])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    Ok(VideoWriter { ffmpeg })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,4 +315,19 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_get_fps() {
+        let props = |avg_frame_rate: &str| VideoProperties {
+            codec_name: "h264".to_string(),
+            avg_frame_rate: avg_frame_rate.to_string(),
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(props("30/1").get_fps(), Some(30.0));
+        assert_eq!(props("0/0").get_fps(), None);
+        assert_eq!(props("25/0").get_fps(), None);
+        assert_eq!(props("25").get_fps(), None);
+        assert_eq!(props("0/1").to_string(), "1920x1080 ? fps (h264)");
+    }
 }
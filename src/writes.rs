@@ -0,0 +1,130 @@
+//! What `run` writes to ZoneMinder besides triggering: at startup it cancels triggers an earlier
+//! instance left on and creates the metadata table, when done it clears the showtext. A dry run
+//! does none of it, a production instance may be analyzing the same monitors.
+
+use anyhow::Result;
+use log::{info, warn};
+
+use zoneminder_aidect::zoneminder::{db, Monitor, ZoneMinderConf};
+
+use crate::cause::Templates;
+use crate::coalescing::MetadataTarget;
+
+pub trait Writes {
+    /// See crate::clear_stale_triggers
+    fn clear_stale_triggers(&self);
+    fn create_event_metadata_table(&self) -> Result<()>;
+    /// Of the monitors triggered
+    fn clear_showtext(&self);
+}
+
+/// The writes of a monitor's pipeline
+pub struct ZoneMinderWrites<'a> {
+    pub zm_conf: &'a ZoneMinderConf,
+    pub monitor_id: u32,
+    pub templates: &'a Templates,
+    /// The trigger monitor and those of Trigger.<Class>=
+    pub triggered: Vec<&'a Monitor<'a>>,
+    pub linked: &'a [Monitor<'a>],
+}
+
+impl Writes for ZoneMinderWrites<'_> {
+    fn clear_stale_triggers(&self) {
+        let monitors = self.triggered.iter().copied().chain(self.linked.iter());
+        crate::clear_stale_triggers(self.monitor_id, self.templates, monitors);
+    }
+
+    fn create_event_metadata_table(&self) -> Result<()> {
+        db::create_event_metadata_table(self.zm_conf)
+    }
+
+    fn clear_showtext(&self) {
+        for monitor in &self.triggered {
+            if let Err(e) = monitor.set_showtext("") {
+                warn!("{}: Failed to set showtext: {:#}", self.monitor_id, e);
+            }
+        }
+    }
+}
+
+/// The writes before analyzing. Returns where the event metadata goes: in a dry run it's logged
+/// instead of stored in the table.
+pub fn startup(
+    writes: &impl Writes,
+    monitor_id: u32,
+    dry_run: bool,
+    metadata: Option<MetadataTarget>,
+) -> Result<Option<MetadataTarget>> {
+    if dry_run {
+        if metadata == Some(MetadataTarget::Table) {
+            info!("{}: Dry run, logging event metadata instead of storing it", monitor_id);
+            return Ok(Some(MetadataTarget::Log));
+        }
+        return Ok(metadata);
+    }
+    if metadata == Some(MetadataTarget::Table) {
+        writes.create_event_metadata_table()?;
+    }
+    writes.clear_stale_triggers();
+    Ok(metadata)
+}
+
+/// The writes after analyzing
+pub fn shutdown(writes: &impl Writes, dry_run: bool, show_text: bool) {
+    if show_text && !dry_run {
+        writes.clear_showtext();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingWrites {
+        writes: RefCell<Vec<&'static str>>,
+    }
+
+    impl Writes for RecordingWrites {
+        fn clear_stale_triggers(&self) {
+            self.writes.borrow_mut().push("clear_stale_triggers");
+        }
+
+        fn create_event_metadata_table(&self) -> Result<()> {
+            self.writes.borrow_mut().push("create_event_metadata_table");
+            Ok(())
+        }
+
+        fn clear_showtext(&self) {
+            self.writes.borrow_mut().push("clear_showtext");
+        }
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let writes = RecordingWrites::default();
+        let metadata = startup(&writes, 1, true, Some(MetadataTarget::Table)).unwrap();
+        assert_eq!(metadata, Some(MetadataTarget::Log));
+        assert_eq!(
+            startup(&writes, 1, true, Some(MetadataTarget::Notes)).unwrap(),
+            Some(MetadataTarget::Notes)
+        );
+        shutdown(&writes, true, true);
+        assert!(writes.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_writes() {
+        let writes = RecordingWrites::default();
+        let metadata = startup(&writes, 1, false, Some(MetadataTarget::Table)).unwrap();
+        assert_eq!(metadata, Some(MetadataTarget::Table));
+        shutdown(&writes, false, false);
+        shutdown(&writes, false, true);
+        assert_eq!(
+            *writes.writes.borrow(),
+            ["create_event_metadata_table", "clear_stale_triggers", "clear_showtext"]
+        );
+    }
+}
@@ -1,32 +1,172 @@
+//! Access to a ZoneMinder installation: monitor shared memory (images, state, triggering),
+//! the database (monitor settings, zones, events) or its API, and zm.conf.
+
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::mem::size_of;
-use std::os::unix::fs::{FileExt, MetadataExt};
-use std::time::Duration;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
-use libc::timeval;
-use log::error;
-use opencv::core::{Mat, MatTraitConst, MatTraitManual};
+use log::warn;
+
+use crate::error::Error;
 
-use crate::zoneminder::db::MonitorSettings;
+#[cfg(feature = "detect")]
+pub use crate::zoneminder::image::{Image, ImageStream};
 
+pub mod api;
 pub mod db;
+#[cfg(feature = "detect")]
+mod image;
 mod shm;
+#[cfg(feature = "detect")]
+pub mod zone;
+mod zmtrigger;
 
 pub trait MonitorTrait<'this> {
     // for lack of a better term
+    #[cfg(feature = "detect")]
     type ImageIterator: Iterator<Item = Result<Image>>;
 
+    #[cfg(feature = "detect")]
     fn stream_images(&'this self) -> Result<Self::ImageIterator>;
 
+    /// Whether the monitor is not in alarm or recording an event.
     fn is_idle(&self) -> Result<bool>;
 
+    /// Whether ZoneMinder's own motion detection fired, i.e. the monitor is in prealarm, alarm or alert.
+    /// Note that triggers also put a monitor into alarm.
+    fn in_motion(&self) -> Result<bool>;
+
+    /// Mark at least one frame as an alarm frame with the given score. Wait for event to be created,
+    /// then return event ID. Does not necessarily cause creation of a new event.
     fn trigger(&self, cause: &str, description: &str, score: u32) -> Result<u64>;
 
     fn id(&self) -> u32;
 }
 
+/// How alarms are raised by MonitorTrait::trigger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerMethod {
+    /// Write the trigger into the monitor's shared memory
+    Shm,
+    /// Ask zmtrigger to raise the alarm through its Unix socket
+    ZmTrigger,
+    /// Use shm, but switch to zmtrigger for good if that fails
+    Auto,
+}
+
+impl TryFrom<&str> for TriggerMethod {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "shm" => TriggerMethod::Shm,
+            "zmtrigger" => TriggerMethod::ZmTrigger,
+            "auto" => TriggerMethod::Auto,
+            _ => return Err(anyhow!("Invalid trigger method: {}", value)),
+        })
+    }
+}
+
+/// What to do when ZoneMinder doesn't act on a trigger in time, see TriggerTimeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutPolicy {
+    /// Trigger once more the same way
+    Retry,
+    /// Give up on it
+    Skip,
+    /// Trigger once more through zmtrigger, if it went through shm
+    ZmTrigger,
+}
+
+impl TryFrom<&str> for TimeoutPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "retry" => TimeoutPolicy::Retry,
+            "skip" => TimeoutPolicy::Skip,
+            "zmtrigger" => TimeoutPolicy::ZmTrigger,
+            _ => return Err(anyhow!("Invalid trigger timeout policy: {}", value)),
+        })
+    }
+}
+
+impl TimeoutPolicy {
+    /// Whether a trigger which timed out is raised again, and if so whether through zmtrigger.
+    /// Every trigger is only raised again once.
+    pub fn retry(&self, pending: &PendingTrigger) -> Option<bool> {
+        if pending.retried {
+            return None;
+        }
+        match self {
+            TimeoutPolicy::Retry => Some(false),
+            TimeoutPolicy::Skip => None,
+            TimeoutPolicy::ZmTrigger => pending.via_shm.then_some(true),
+        }
+    }
+}
+
+/// A trigger which was raised, but which ZoneMinder didn't act on yet, see Monitor::poll_trigger.
+#[derive(Debug)]
+pub struct PendingTrigger {
+    via_shm: bool,
+    started: Instant,
+    retried: bool,
+    // to raise it again
+    cause: String,
+    description: String,
+    score: u32,
+}
+
+/// ZoneMinder didn't go into alarm within the trigger timeout, the trigger was canceled.
+#[derive(Debug)]
+pub struct TriggerTimeout {
+    pub monitor_id: u32,
+    pub waited: Duration,
+}
+
+impl std::fmt::Display for TriggerTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Waited {:?} for monitor {} to go into alarm, gave up and canceled the trigger",
+            self.waited, self.monitor_id
+        )
+    }
+}
+
+impl std::error::Error for TriggerTimeout {}
+
+/// See Monitor::trigger_guard
+pub struct TriggerGuard<'a> {
+    monitor: &'a Monitor<'a>,
+}
+
+impl Drop for TriggerGuard<'_> {
+    fn drop(&mut self) {
+        if !self.monitor.shm_trigger_set.load(Ordering::Relaxed) {
+            return;
+        }
+        match self.monitor.reset_trigger() {
+            Ok(()) => warn!("{}: Canceled trigger left on", self.monitor.monitor_id),
+            Err(e) => warn!(
+                "{}: Failed to cancel trigger left on: {:#}",
+                self.monitor.monitor_id, e
+            ),
+        }
+    }
+}
+
+pub const TRIGGER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+pub const TRIGGER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A monitor's shared memory, i.e. what zmc captures.
 pub struct Monitor<'zmconf> {
     monitor_id: u32,
     zm_conf: &'zmconf ZoneMinderConf,
@@ -34,60 +174,44 @@ pub struct Monitor<'zmconf> {
     mmap_path: String,
     ino: u64,
     shm: shm::MonitorShm<File>,
+
+    trigger_method: TriggerMethod,
+    trigger_timeout: Duration,
+    shm_trigger_failed: AtomicBool,
+    /// Whether our shm trigger is on, i.e. has to be reset (see TriggerGuard)
+    shm_trigger_set: AtomicBool,
+    /// See set_showtext, kept across triggers
+    showtext: Mutex<String>,
 }
 
 impl<'this> MonitorTrait<'this> for Monitor<'this> {
+    #[cfg(feature = "detect")]
     type ImageIterator = ImageStream<'this>;
 
+    #[cfg(feature = "detect")]
     fn stream_images(&'this self) -> Result<Self::ImageIterator> {
-        let state = self.read()?;
-        let settings = MonitorSettings::query(self.zm_conf, self.monitor_id)?;
-        let image_buffer_count = settings.image_buffer_count;
-
-        // now that we have the image buffer size we can figure the dynamic offsets out
-        let shared_timestamps_offset = self.shm.read_field::<u32>(shm::ShmField::SHARED_SIZE)?
-            + self.shm.read_field::<u32>(shm::ShmField::TRIGGER_SIZE)?
-            + self.shm.read_field::<u32>(shm::ShmField::VIDEOSTORE_SIZE)?;
-        let shared_images_offset =
-            shared_timestamps_offset as usize + image_buffer_count as usize * size_of::<timeval>();
-        let shared_images_offset = shared_images_offset + 64 - (shared_images_offset % 64);
-
-        Ok(ImageStream {
-            width: settings.width,
-            height: settings.height,
-            image_buffer_count,
-            monitor: self,
-            last_read_index: image_buffer_count,
-            image_size: state.imagesize,
-            format: state.format,
-            shared_images_offset: shared_images_offset as u64,
-        })
+        ImageStream::new(self)
     }
 
     fn is_idle(&self) -> Result<bool> {
         Ok(self.read()?.state == shm::MonitorState::Idle)
     }
 
-    /// Mark at least one frame as an alarm frame with the given score. Wait for event to be created,
-    /// then return event ID. Does not necessarily cause creation of a new event.
+    fn in_motion(&self) -> Result<bool> {
+        Ok(matches!(
+            self.read()?.state,
+            shm::MonitorState::Prealarm | shm::MonitorState::Alarm | shm::MonitorState::Alert
+        ))
+    }
+
     fn trigger(&self, cause: &str, description: &str, score: u32) -> Result<u64> {
-        let poll_interval = 10;
-        self.set_trigger(cause, description, score)?;
-        for n in 0.. {
-            let state = self.read()?.state;
-            // Alarm sorta implies that we just triggered an alarm frame, while
-            // Alert sorta implies there's an on-going event.
-            // Wait for Alarm state to become active so that the frame is marked.
-            if state == shm::MonitorState::Alarm {
-                break;
-            }
-            std::thread::sleep(Duration::from_millis(poll_interval));
-            if n > 500 {
-                error!("Waited {} ms for zoneminder to notice our bulgy wulgy, giving up and canceling it :c", n * poll_interval);
+        let pending = self.start_trigger(cause, description, score)?;
+        loop {
+            if let Some(event_id) = self.poll_trigger(&pending)? {
+                return Ok(event_id);
             }
+            std::thread::sleep(TRIGGER_POLL_INTERVAL);
         }
-        self.reset_trigger()?;
-        Ok(self.read()?.last_event_id)
     }
 
     fn id(&self) -> u32 {
@@ -97,12 +221,19 @@ impl<'this> MonitorTrait<'this> for Monitor<'this> {
 
 impl Monitor<'_> {
     pub fn connect(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<Monitor> {
-        let mmap_path = format!("{}/zm.mmap.{}", zm_conf.mmap_path, monitor_id);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&mmap_path)
-            .with_context(|| {
+        let mmap_path = zm_conf.mmap_file(monitor_id);
+        let file = match OpenOptions::new().read(true).write(true).open(&mmap_path) {
+            // Good enough for watching, triggering can still go through zmtrigger
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                warn!(
+                    "{}: No write access to {}, shm triggering won't work",
+                    monitor_id, mmap_path
+                );
+                File::open(&mmap_path)
+            }
+            result => result,
+        }
+        .with_context(|| {
                 format!(
                     "Failed to open mmap file {} for monitor {}",
                     mmap_path, monitor_id
@@ -114,192 +245,210 @@ impl Monitor<'_> {
             zm_conf,
             mmap_path,
             ino: file.metadata()?.ino(),
-            shm: shm::MonitorShm::new(file)?,
+            shm: shm::MonitorShm::new(file, zm_conf.memory_pm()?)?,
+            trigger_method: TriggerMethod::Auto,
+            trigger_timeout: TRIGGER_TIMEOUT,
+            shm_trigger_failed: AtomicBool::new(false),
+            shm_trigger_set: AtomicBool::new(false),
+            showtext: Mutex::new(String::new()),
         })
     }
 
-    fn set_trigger(&self, cause: &str, description: &str, score: u32) -> Result<()> {
-        self.shm.write_string(shm::ShmField::TRIGGER_CAUSE, cause)?;
-        self.shm
-            .write_string(shm::ShmField::TRIGGER_TEXT, description)?;
-        self.shm.write_string(shm::ShmField::TRIGGER_SHOWTEXT, "")?;
-        self.shm.write_field(shm::ShmField::TRIGGER_SCORE, &score)?;
-        // all of this is terribly racy but pwritin' the data before the state change should reduce the odds of problems
-        self.shm
-            .write_field(shm::ShmField::TRIGGER_STATE, &shm::TriggerState::TriggerOn)
+    pub fn set_trigger_method(&mut self, trigger_method: TriggerMethod) {
+        self.trigger_method = trigger_method;
     }
 
-    fn reset_trigger(&self) -> Result<()> {
-        self.shm.write_string(shm::ShmField::TRIGGER_CAUSE, "")?;
-        self.shm.write_string(shm::ShmField::TRIGGER_TEXT, "")?;
-        self.shm.write_string(shm::ShmField::TRIGGER_SHOWTEXT, "")?;
-        self.shm.write_field(shm::ShmField::TRIGGER_SCORE, &0)?;
-        self.shm.write_field(
-            shm::ShmField::TRIGGER_STATE,
-            &shm::TriggerState::TriggerCancel,
-        )
+    /// How long triggering waits for ZoneMinder to go into alarm, TRIGGER_TIMEOUT by default.
+    pub fn set_trigger_timeout(&mut self, trigger_timeout: Duration) {
+        self.trigger_timeout = trigger_timeout;
     }
 
-    fn read(&self) -> Result<MonitorState> {
-        if self.shm.read_field::<u8>(shm::ShmField::VALID)? == 0 {
-            return Err(anyhow!("Monitor shm is not valid"));
-        }
-        self.check_file_stale()?;
+    /// Like MonitorTrait::trigger, but doesn't wait for ZoneMinder to mark the alarm frame.
+    /// Call poll_trigger until it returns the event ID.
+    pub fn start_trigger(
+        &self,
+        cause: &str,
+        description: &str,
+        score: u32,
+    ) -> Result<PendingTrigger> {
+        Ok(PendingTrigger {
+            via_shm: self.raise_trigger(cause, description, score, self.trigger_method)?,
+            started: Instant::now(),
+            retried: false,
+            cause: cause.to_string(),
+            description: description.to_string(),
+            score,
+        })
+    }
 
-        Ok(MonitorState {
-            last_write_index: self.shm.read_field(shm::ShmField::LAST_WRITE_INDEX)?,
-            state: self.shm.read_field(shm::ShmField::STATE)?,
-            last_event_id: self.shm.read_field(shm::ShmField::LAST_EVENT_ID)?,
-            format: self.shm.read_field(shm::ShmField::FORMAT)?,
-            imagesize: self.shm.read_field(shm::ShmField::IMAGESIZE)?,
+    /// Raises a trigger which timed out (see TimeoutPolicy::retry) again, through zmtrigger if
+    /// via_zmtrigger, otherwise with the configured method.
+    pub fn retry_trigger(&self, pending: &PendingTrigger, via_zmtrigger: bool) -> Result<PendingTrigger> {
+        let method = match via_zmtrigger {
+            true => TriggerMethod::ZmTrigger,
+            false => self.trigger_method,
+        };
+        Ok(PendingTrigger {
+            via_shm: self.raise_trigger(&pending.cause, &pending.description, pending.score, method)?,
+            started: Instant::now(),
+            retried: true,
+            cause: pending.cause.clone(),
+            description: pending.description.clone(),
+            score: pending.score,
         })
     }
 
-    fn check_file_stale(&self) -> Result<()> {
-        // Additional sanity check, if the file-on-tmpfs is now a different file, we're definitely listening to a stranger.
-        // ZM seems to be quite good about ensuring shared_data.valid gets flipped to 0 even when zmc crashes though.
-        if fs::metadata(&self.mmap_path)
-            .with_context(|| format!("Monitor mmap file {} does not exist", self.mmap_path))?
-            .ino()
-            != self.ino
-        {
-            return Err(anyhow!("Monitor shm fd is stale, must reconnect"));
+    /// Returns the event ID once ZoneMinder went into alarm. Gives up (and cancels the trigger)
+    /// with a TriggerTimeout error if that doesn't happen within the trigger timeout.
+    pub fn poll_trigger(&self, pending: &PendingTrigger) -> Result<Option<u64>> {
+        let state = self.read()?;
+        // Alarm sorta implies that we just triggered an alarm frame, while
+        // Alert sorta implies there's an on-going event.
+        // Wait for Alarm state to become active so that the frame is marked.
+        if state.state != shm::MonitorState::Alarm {
+            if pending.started.elapsed() < self.trigger_timeout {
+                return Ok(None);
+            }
+            if pending.via_shm {
+                self.reset_trigger()?;
+            }
+            return Err(TriggerTimeout {
+                monitor_id: self.monitor_id,
+                waited: pending.started.elapsed(),
+            }
+            .into());
         }
-        Ok(())
+        if pending.via_shm {
+            self.reset_trigger()?;
+        }
+        Ok(Some(self.read()?.last_event_id))
     }
-}
 
-fn zm_format_to_cv_format(format: shm::SubpixelOrder) -> i32 {
-    match format {
-        shm::SubpixelOrder::NONE => opencv::core::CV_8UC1,
-        shm::SubpixelOrder::RGB => opencv::core::CV_8UC3,
-        shm::SubpixelOrder::BGR => opencv::core::CV_8UC3,
-        shm::SubpixelOrder::BGRA => opencv::core::CV_8UC4,
-        shm::SubpixelOrder::RGBA => opencv::core::CV_8UC4,
-        shm::SubpixelOrder::ABGR => opencv::core::CV_8UC4,
-        shm::SubpixelOrder::ARGB => opencv::core::CV_8UC4,
+    /// Raise the alarm with method, returns whether the shm trigger was used, which has to be
+    /// reset afterwards. zmtrigger cancels the alarm on its own.
+    fn raise_trigger(
+        &self,
+        cause: &str,
+        description: &str,
+        score: u32,
+        method: TriggerMethod,
+    ) -> Result<bool> {
+        let use_shm = match method {
+            TriggerMethod::Shm => return self.set_trigger(cause, description, score).map(|_| true),
+            TriggerMethod::ZmTrigger => false,
+            TriggerMethod::Auto => !self.shm_trigger_failed.load(Ordering::Relaxed),
+        };
+        if use_shm {
+            match self.set_trigger(cause, description, score) {
+                Ok(()) => return Ok(true),
+                Err(e) => {
+                    warn!(
+                        "{}: Triggering through shm failed, using zmtrigger from now on: {:#}",
+                        self.monitor_id, e
+                    );
+                    self.shm_trigger_failed.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        let socket_path = Path::new(&self.zm_conf.socks_path).join("zmtrigger.sock");
+        zmtrigger::trigger_autocancel(
+            &socket_path,
+            self.monitor_id,
+            cause,
+            description,
+            score,
+            Duration::from_secs(1),
+        )?;
+        Ok(false)
     }
-}
 
-pub struct Image {
-    image: Mat,
-    format: shm::SubpixelOrder,
-}
+    /// Sets the text ZoneMinder shows in place of %Q in the monitor's timestamp label, and thereby
+    /// burns into recorded frames (through the shm, regardless of the trigger method). It stays
+    /// until it's changed again, triggering doesn't reset it.
+    pub fn set_showtext(&self, text: &str) -> Result<()> {
+        let mut showtext = self.showtext.lock().unwrap();
+        *showtext = text.to_string();
+        self.shm.write_showtext(&showtext)
+    }
 
-impl Image {
-    pub fn convert_to_rgb24(self) -> Result<Mat> {
-        let conversion = match self.format {
-            shm::SubpixelOrder::NONE => Some(opencv::imgproc::COLOR_GRAY2RGB),
-            shm::SubpixelOrder::RGB => None,
-            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2RGB),
-            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2RGB),
-            shm::SubpixelOrder::RGBA => Some(opencv::imgproc::COLOR_RGBA2RGB),
-            _ => panic!("Unsupported pixel format: {:?}", self.format),
-        };
-        self.convert(conversion)
-    }
-
-    #[allow(dead_code)]
-    pub fn convert_to_rgb32(self) -> Result<Mat> {
-        let conversion = match self.format {
-            shm::SubpixelOrder::NONE => Some(opencv::imgproc::COLOR_GRAY2RGBA),
-            shm::SubpixelOrder::RGB => Some(opencv::imgproc::COLOR_RGB2RGBA),
-            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2RGBA),
-            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2RGBA),
-            shm::SubpixelOrder::RGBA => None,
-            _ => panic!("Unsupported pixel format: {:?}", self.format),
-        };
-        self.convert(conversion)
-    }
-
-    #[allow(dead_code)]
-    pub fn convert_to_gray(self) -> Result<Mat> {
-        let conversion = match self.format {
-            shm::SubpixelOrder::NONE => None,
-            shm::SubpixelOrder::RGB => Some(opencv::imgproc::COLOR_RGB2GRAY),
-            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2GRAY),
-            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2GRAY),
-            shm::SubpixelOrder::RGBA => Some(opencv::imgproc::COLOR_RGBA2GRAY),
-            _ => panic!("Unsupported pixel format: {:?}", self.format),
-        };
-        self.convert(conversion)
+    /// Cancels the trigger on drop, should it still be on then, e.g. because an error ended
+    /// handling detections between starting and polling the trigger.
+    pub fn trigger_guard(&self) -> TriggerGuard<'_> {
+        TriggerGuard { monitor: self }
     }
 
-    fn convert(self, conversion: Option<i32>) -> Result<Mat> {
-        if let Some(conversion) = conversion {
-            let mut rgb_image = Mat::default();
-            // You could do this in-place as well, though it's probably not worth it
-            opencv::imgproc::cvt_color(&self.image, &mut rgb_image, conversion, 0)?;
-            return Ok(rgb_image);
+    /// Cancels a shm trigger left on by an earlier instance which died before resetting it (see
+    /// TriggerGuard, which can't help if the process is killed or aborts), recognized by
+    /// is_own_cause. Returns whether there was one.
+    pub fn clear_stale_trigger(&self, is_own_cause: impl Fn(&str) -> bool) -> Result<bool> {
+        let (on, cause) = self.shm.trigger()?;
+        if !on || !is_own_cause(&cause) {
+            return Ok(false);
         }
-        Ok(self.image)
+        warn!(
+            "{}: Canceling trigger \"{}\" left on, zm-aidect probably died while triggering",
+            self.monitor_id, cause
+        );
+        self.reset_trigger()?;
+        Ok(true)
     }
-}
 
-pub struct ImageStream<'mon> {
-    monitor: &'mon Monitor<'mon>,
-    last_read_index: u32,
-    width: u32,
-    height: u32,
-    image_size: u32,
-    format: shm::SubpixelOrder,
-    image_buffer_count: u32,
-    shared_images_offset: u64,
-}
-
-impl ImageStream<'_> {
-    fn wait_for_image(&mut self) -> Result<Image> {
-        loop {
-            let state = self.monitor.read()?;
-            let last_write_index = state.last_write_index as u32;
-            if last_write_index != self.last_read_index
-                && last_write_index != self.image_buffer_count
-            {
-                self.last_read_index = last_write_index;
-                let image = self.read_image(last_write_index)?;
-                return Ok(Image {
-                    image,
-                    format: self.format,
-                });
-            }
-            std::thread::sleep(Duration::from_millis(5));
-        }
+    fn set_trigger(&self, cause: &str, description: &str, score: u32) -> Result<()> {
+        // all of this is terribly racy but pwritin' the data before the state change should reduce the odds of problems
+        self.shm.write_trigger(&shm::TriggerData {
+            state: shm::TriggerState::TriggerOn,
+            score,
+            cause,
+            text: description,
+            showtext: &self.showtext.lock().unwrap(),
+        })?;
+        self.shm_trigger_set.store(true, Ordering::Relaxed);
+        Ok(())
     }
 
-    fn read_image(&self, index: u32) -> Result<Mat> {
-        assert_eq!(self.width * self.height * 4, self.image_size);
-        let mut mat = Mat::new_size_with_default(
-            (self.width as i32, self.height as i32).into(),
-            zm_format_to_cv_format(self.format),
-            0.into(),
-        )?;
-        self.read_image_into(index, &mut mat)?;
-        Ok(mat)
-    }
-
-    fn read_image_into(&self, index: u32, mat: &mut Mat) -> Result<()> {
-        assert_eq!(self.width * self.height, mat.total() as u32);
-        assert_eq!(mat.typ(), zm_format_to_cv_format(self.format));
-        self.monitor.check_file_stale()?;
-        let mut slice = mat.data_bytes_mut()?;
-        let image_offset = self.shared_images_offset as u64 + self.image_size as u64 * index as u64;
-        self.monitor
-            .shm
-            .file
-            .read_exact_at(&mut slice, image_offset)
-            .with_context(|| "Failed to read image")?;
+    fn reset_trigger(&self) -> Result<()> {
+        self.shm.write_trigger(&shm::TriggerData {
+            state: shm::TriggerState::TriggerCancel,
+            score: 0,
+            cause: "",
+            text: "",
+            showtext: &self.showtext.lock().unwrap(),
+        })?;
+        self.shm_trigger_set.store(false, Ordering::Relaxed);
         Ok(())
     }
-}
 
-impl Iterator for ImageStream<'_> {
-    type Item = Result<Image>;
+    fn read(&self) -> Result<MonitorState> {
+        if !self.shm.valid()? {
+            return Err(Error::ShmInvalid.into());
+        }
+        self.check_file_stale()?;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(self.wait_for_image())
+        Ok(MonitorState {
+            last_write_index: self.shm.last_write_index()?,
+            state: self.shm.state()?,
+            last_event_id: self.shm.last_event_id()?,
+            format: self.shm.format()?,
+            imagesize: self.shm.image_size()?,
+        })
+    }
+
+    fn check_file_stale(&self) -> Result<()> {
+        // Additional sanity check, if the file-on-tmpfs is now a different file, we're definitely listening to a stranger.
+        // ZM seems to be quite good about ensuring shared_data.valid gets flipped to 0 even when zmc crashes though.
+        if fs::metadata(&self.mmap_path)
+            .with_context(|| format!("Monitor mmap file {} does not exist", self.mmap_path))
+            .context(Error::ShmStale)?
+            .ino()
+            != self.ino
+        {
+            return Err(Error::ShmStale.into());
+        }
+        Ok(())
     }
 }
 
+#[cfg_attr(not(feature = "detect"), allow(dead_code))]
 struct MonitorState {
     pub last_write_index: i32,
     pub state: shm::MonitorState,
@@ -308,6 +457,7 @@ struct MonitorState {
     pub imagesize: u32,
 }
 
+/// Database credentials and paths from zm.conf.
 #[derive(Debug)]
 pub struct ZoneMinderConf {
     db_host: String,
@@ -315,10 +465,56 @@ pub struct ZoneMinderConf {
     db_user: String,
     db_password: String,
     mmap_path: String,
+    socks_path: String,
+    memory_pm: Option<PathBuf>,
+    /// Used instead of the database where it covers what's needed, see with_api
+    api: Option<api::Api>,
+    /// See with_strict_zones
+    strict_zones: bool,
+}
+
+const CONF_DIRS: &[&str] = &["/etc/zm", "/usr/local/etc/zm"];
+
+const PERL_LIB_DIRS: &[&str] = &[
+    "/usr/share/perl5",              // Debian, Ubuntu
+    "/usr/share/perl5/vendor_perl",  // Fedora, Arch
+    "/usr/lib/perl5/vendor_perl",    // openSUSE
+    "/usr/local/share/perl5",
+    "/usr/local/lib/perl5/site_perl",
+];
+
+/// Perl lib directories with the perl version in the path, e.g. /usr/local/share/perl/5.32.1
+/// for installs from source on Debian.
+const VERSIONED_PERL_LIB_DIRS: &[&str] = &["/usr/local/share/perl", "/usr/share/perl"];
+
+fn find_conf_dir() -> Result<PathBuf> {
+    CONF_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.join("zm.conf").is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not find zm.conf in any of {}, configure the ZoneMinder configuration directory",
+                CONF_DIRS.join(", ")
+            )
+        })
+}
+
+fn find_memory_pm() -> Option<PathBuf> {
+    let versioned = VERSIONED_PERL_LIB_DIRS
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()));
+    PERL_LIB_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .chain(versioned)
+        .map(|dir| dir.join("ZoneMinder/Memory.pm"))
+        .find(|path| path.is_file())
 }
 
 impl ZoneMinderConf {
-    fn parse_zm_conf(zm_conf_contents: &str) -> ZoneMinderConf {
+    fn parse_zm_conf(zm_conf_contents: &str, memory_pm: Option<PathBuf>) -> ZoneMinderConf {
         let keys: HashMap<&str, &str> = zm_conf_contents
             .lines()
             .map(|line| line.trim())
@@ -332,25 +528,87 @@ impl ZoneMinderConf {
             db_user: keys["ZM_DB_USER"].to_string(),
             db_password: keys["ZM_DB_PASS"].to_string(),
             mmap_path: keys["ZM_PATH_MAP"].to_string(),
+            socks_path: keys.get("ZM_PATH_SOCKS").unwrap_or(&"/run/zm").to_string(),
+            memory_pm,
+            api: None,
+            strict_zones: false,
         }
     }
 
+    /// Reads zm.conf with the overrides from conf.d from the usual location (e.g. /etc/zm).
     pub fn parse_default() -> Result<ZoneMinderConf> {
-        let zm_conf = "/etc/zm/zm.conf";
-        let zm_conf_d = "/etc/zm/conf.d";
-        let contents = fs::read_to_string(zm_conf).with_context(|| {
-            format!("Failed to parse Zoneminder configuration file {}", zm_conf)
+        Self::parse(None, None)
+    }
+
+    /// Reads zm.conf and conf.d from conf_dir, and uses the ZoneMinder perl modules (for
+    /// Memory.pm) in perl_lib. Either is searched for in the usual locations if not given.
+    pub fn parse(conf_dir: Option<&Path>, perl_lib: Option<&Path>) -> Result<ZoneMinderConf> {
+        let conf_dir = match conf_dir {
+            Some(conf_dir) => conf_dir.to_path_buf(),
+            None => find_conf_dir()?,
+        };
+        let memory_pm = match perl_lib {
+            Some(perl_lib) => {
+                let memory_pm = perl_lib.join("ZoneMinder/Memory.pm");
+                if !memory_pm.is_file() {
+                    return Err(anyhow!("{} does not exist", memory_pm.display()));
+                }
+                Some(memory_pm)
+            }
+            // Not needed for everything, complain once it's actually used
+            None => find_memory_pm(),
+        };
+
+        let zm_conf = conf_dir.join("zm.conf");
+        let zm_conf_d = conf_dir.join("conf.d");
+        let contents = fs::read_to_string(&zm_conf).with_context(|| {
+            format!("Failed to parse Zoneminder configuration file {}", zm_conf.display())
         })?;
         let contents = contents
             + "\n"
-            + &fs::read_dir(zm_conf_d)
-                .with_context(|| format!("Failed to read Zoneminder overrides from {}", zm_conf_d))?
+            + &fs::read_dir(&zm_conf_d)
+                .with_context(|| format!("Failed to read Zoneminder overrides from {}", zm_conf_d.display()))?
                 .filter_map(Result::ok)
                 .map(|entry| fs::read_to_string(entry.path()))
                 .filter_map(Result::ok)
                 .fold(String::new(), |a, b| a + "\n" + &b); // O(n**2)
 
-        Ok(Self::parse_zm_conf(&contents))
+        Ok(Self::parse_zm_conf(&contents, memory_pm))
+    }
+
+    /// Query monitor settings and zones, and update event notes through ZoneMinder's API (as a
+    /// ZoneMinder user) instead of the database.
+    pub fn with_api(self, api: Option<api::Api>) -> ZoneMinderConf {
+        ZoneMinderConf { api, ..self }
+    }
+
+    /// Fail on problems in the names of aidect zones (unknown keys, values which don't parse)
+    /// instead of logging them and ignoring the offending items.
+    pub fn with_strict_zones(self, strict_zones: bool) -> ZoneMinderConf {
+        ZoneMinderConf { strict_zones, ..self }
+    }
+
+    /// Shared memory file of a monitor, which exists while zmc runs for it.
+    pub fn mmap_file(&self, monitor_id: u32) -> String {
+        format!("{}/zm.mmap.{}", self.mmap_path, monitor_id)
+    }
+
+    /// Reads the shm layout from Memory.pm and checks it against what zm-aidect expects, which
+    /// otherwise only happens when connecting to a monitor. Returns the path of Memory.pm.
+    pub fn check_shm_layout(&self) -> Result<&Path> {
+        let memory_pm = self.memory_pm()?;
+        shm::load_layout(memory_pm)?;
+        Ok(memory_pm)
+    }
+
+    fn memory_pm(&self) -> Result<&Path> {
+        self.memory_pm.as_deref().ok_or_else(|| {
+            anyhow!(
+                "Could not find ZoneMinder's Memory.pm in any of {}, {} - configure the perl lib directory of ZoneMinder",
+                PERL_LIB_DIRS.join(", "),
+                VERSIONED_PERL_LIB_DIRS.iter().map(|dir| format!("{}/*", dir)).collect::<Vec<_>>().join(", ")
+            )
+        })
     }
 }
 
@@ -375,13 +633,33 @@ ZM_DB_USER=zmuser
 ZM_DB_PASS=zmpass
 
 ZM_PATH_MAP=/dev/shm
+ZM_PATH_SOCKS=/var/run/zm
 ";
 
-        let parsed = ZoneMinderConf::parse_zm_conf(conf);
+        let parsed = ZoneMinderConf::parse_zm_conf(conf, None);
         assert_eq!(parsed.db_host, "localhost");
         assert_eq!(parsed.db_name, "zm");
         assert_eq!(parsed.db_user, "zmuser");
         assert_eq!(parsed.db_password, "zmpass");
         assert_eq!(parsed.mmap_path, "/dev/shm");
+        assert_eq!(parsed.socks_path, "/var/run/zm");
+    }
+
+    #[test]
+    fn test_timeout_policy() {
+        let pending = |via_shm, retried| PendingTrigger {
+            via_shm,
+            started: Instant::now(),
+            retried,
+            cause: "aidect".to_string(),
+            description: String::new(),
+            score: 90,
+        };
+        assert_eq!(TimeoutPolicy::Retry.retry(&pending(true, false)), Some(false));
+        assert_eq!(TimeoutPolicy::Retry.retry(&pending(true, true)), None);
+        assert_eq!(TimeoutPolicy::Skip.retry(&pending(true, false)), None);
+        assert_eq!(TimeoutPolicy::ZmTrigger.retry(&pending(true, false)), Some(true));
+        // it went through zmtrigger already
+        assert_eq!(TimeoutPolicy::ZmTrigger.retry(&pending(false, false)), None);
     }
 }
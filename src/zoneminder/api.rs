@@ -0,0 +1,356 @@
+//! ZoneMinder's API as a stand-in for the database where it can't be reached (e.g. firewalled),
+//! logging in as a ZoneMinder user with auth tokens. Covers what `run` needs: monitor settings,
+//! zones and event notes.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::zoneminder::db::{Function, MonitorSettings, Orientation};
+
+/// Tokens are renewed this long before they expire, so that none expires in flight
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+struct ApiConf {
+    /// e.g. https://zm.example.com/zm/api
+    url: String,
+    user: String,
+    password: String,
+}
+
+impl ApiConf {
+    /// ZM_API_URL, ZM_API_USER and ZM_API_PASS from the environment, or KEY=VALUE lines in file
+    /// (same as zm.conf). The environment takes precedence. None if neither sets the URL.
+    fn load(file: Option<&Path>) -> Result<Option<ApiConf>> {
+        let contents = match file {
+            Some(file) => std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read API configuration {}", file.display()))?,
+            None => String::new(),
+        };
+        let env: Vec<(String, String)> = ["ZM_API_URL", "ZM_API_USER", "ZM_API_PASS"]
+            .iter()
+            .filter_map(|key| Some((key.to_string(), std::env::var(key).ok()?)))
+            .collect();
+        let conf = Self::parse(&contents, &env)?;
+        if conf.is_none() && file.is_some() {
+            return Err(anyhow!("No ZM_API_URL in {}", file.unwrap().display()));
+        }
+        Ok(conf)
+    }
+
+    fn parse(contents: &str, env: &[(String, String)]) -> Result<Option<ApiConf>> {
+        let mut keys: HashMap<&str, &str> = contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with("ZM_"))
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        keys.extend(env.iter().map(|(key, value)| (key.as_str(), value.as_str())));
+        let url = match keys.get("ZM_API_URL") {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => return Ok(None),
+        };
+        let get = |key| {
+            keys.get(key)
+                .map(|value| value.to_string())
+                .ok_or_else(|| anyhow!("ZM_API_URL is set, but {} is not", key))
+        };
+        Ok(Some(ApiConf {
+            url,
+            user: get("ZM_API_USER")?,
+            password: get("ZM_API_PASS")?,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct Tokens {
+    access: String,
+    access_expires: Instant,
+    refresh: Option<String>,
+    refresh_expires: Instant,
+}
+
+impl Tokens {
+    /// Parses the reply of host/login.json. Refreshing only returns a new access token, in that
+    /// case the refresh token of previous is kept.
+    fn parse(reply: &Value, now: Instant, previous: Option<Tokens>) -> Result<Tokens> {
+        let access = reply["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No access token in login reply, is OPT_USE_APIS/AUTH_HASH_LOGINS enabled?"))?;
+        let expires = |key: &str| now + Duration::from_secs(reply[key].as_u64().unwrap_or(0));
+        let (refresh, refresh_expires) = match reply["refresh_token"].as_str() {
+            Some(refresh) => (Some(refresh.to_string()), expires("refresh_token_expires")),
+            None => match previous {
+                Some(previous) => (previous.refresh, previous.refresh_expires),
+                None => (None, now),
+            },
+        };
+        Ok(Tokens {
+            access: access.to_string(),
+            access_expires: expires("access_token_expires"),
+            refresh,
+            refresh_expires,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Api {
+    conf: ApiConf,
+    tokens: Mutex<Option<Tokens>>,
+}
+
+impl Api {
+    /// See ApiConf::load, None if the API isn't configured.
+    pub fn from_config(file: Option<&Path>) -> Result<Option<Api>> {
+        Ok(ApiConf::load(file)?.map(|conf| Api {
+            conf,
+            tokens: Mutex::new(None),
+        }))
+    }
+
+    pub(super) fn monitor_settings(&self, monitor_id: u32) -> Result<MonitorSettings> {
+        let reply = self.get(&format!("monitors/{}.json", monitor_id))?;
+        parse_monitor_settings(&reply["monitor"]["Monitor"])
+            .with_context(|| format!("Invalid API reply for monitor {}", monitor_id))
+    }
+
+    /// Id, Name and Coords of each zone of the monitor
+    pub(super) fn zones(&self, monitor_id: u32) -> Result<Vec<(u32, String, String)>> {
+        let reply = self.get(&format!("zones/forMonitor/{}.json", monitor_id))?;
+        parse_zones(&reply).with_context(|| format!("Invalid API reply for the zones of monitor {}", monitor_id))
+    }
+
+    pub(super) fn active_run_state(&self) -> Result<Option<String>> {
+        let reply = self.get("states.json")?;
+        parse_active_state(&reply).context("Invalid API reply for the run states")
+    }
+
+    pub(super) fn update_event_notes(&self, event_id: u64, notes: &str) -> Result<()> {
+        self.request(
+            &format!("events/{}.json", event_id),
+            &["--request", "PUT", "--data-urlencode", &format!("Event[Notes]={}", notes)],
+        )?;
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Result<Value> {
+        self.request(path, &["--get"])
+    }
+
+    /// The token goes through stdin (as form data, or query with --get), so that it doesn't show
+    /// up in the process list. Any failure drops the tokens, in case they were revoked.
+    fn request(&self, path: &str, args: &[&str]) -> Result<Value> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = self.token(&mut tokens)?;
+        let url = format!("{}/{}", self.conf.url, path);
+        curl(&url, args, "token@-", &token)
+            .map_err(|e| {
+                *tokens = None;
+                e
+            })
+            .context(Error::Db)
+    }
+
+    /// A valid access token, logging in or refreshing as needed
+    fn token(&self, tokens: &mut Option<Tokens>) -> Result<String> {
+        let now = Instant::now();
+        let login_url = format!("{}/host/login.json", self.conf.url);
+        match tokens.take() {
+            Some(current) if current.access_expires > now + EXPIRY_MARGIN => *tokens = Some(current),
+            Some(current) if current.refresh.is_some() && current.refresh_expires > now + EXPIRY_MARGIN => {
+                debug!("Refreshing API access token");
+                let refresh = current.refresh.clone().unwrap();
+                let reply = curl(&login_url, &[], "token@-", &refresh).context(Error::Db)?;
+                *tokens = Some(Tokens::parse(&reply, now, Some(current))?);
+            }
+            _ => {
+                debug!("Logging into the API as {}", self.conf.user);
+                let user = format!("user={}", self.conf.user);
+                let reply = curl(&login_url, &["--data-urlencode", &user], "pass@-", &self.conf.password)
+                    .context("API login failed")
+                    .context(Error::Db)?;
+                *tokens = Some(Tokens::parse(&reply, now, None)?);
+            }
+        }
+        Ok(tokens.as_ref().unwrap().access.clone())
+    }
+}
+
+/// Runs curl on url with args, plus secret url-encoded as the form field stdin_field ("name@-")
+fn curl(url: &str, args: &[&str], stdin_field: &str, secret: &str) -> Result<Value> {
+    let mut child = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--max-time", "30"])
+        .args(args)
+        .args(["--data-urlencode", stdin_field])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run curl")?;
+    child.stdin.take().unwrap().write_all(secret.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Request to {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// The API returns most columns as strings, and some versions numbers or booleans
+fn field<T: std::str::FromStr>(object: &Value, name: &str) -> Result<T> {
+    let value = match &object[name] {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => (*b as u8).to_string(),
+        _ => return Err(anyhow!("{} missing", name)),
+    };
+    value.parse().map_err(|_| anyhow!("Invalid {}: {}", name, value))
+}
+
+fn parse_monitor_settings(monitor: &Value) -> Result<MonitorSettings> {
+    let function = match field::<String>(monitor, "Function") {
+        Ok(function) => Function::try_from(function.as_str())?,
+        // ZM 1.37 and later
+        Err(_) => Function::from_modes(
+            &field::<String>(monitor, "Capturing")?,
+            &field::<String>(monitor, "Analysing")?,
+            &field::<String>(monitor, "Recording")?,
+        )?,
+    };
+    let orientation: String = field(monitor, "Orientation")?;
+    Ok(MonitorSettings {
+        name: field(monitor, "Name")?,
+        storage_id: field(monitor, "StorageId")?,
+        enabled: field::<u8>(monitor, "Enabled")? != 0,
+        function,
+        width: field(monitor, "Width")?,
+        height: field(monitor, "Height")?,
+        colours: field(monitor, "Colours")?,
+        image_buffer_count: field(monitor, "ImageBufferCount")?,
+        analysis_fps_limit: field(monitor, "AnalysisFPSLimit").ok(),
+        orientation: Orientation::try_from(orientation.as_str())?,
+    })
+}
+
+fn parse_zones(reply: &Value) -> Result<Vec<(u32, String, String)>> {
+    reply["zones"]
+        .as_array()
+        .ok_or_else(|| anyhow!("zones missing"))?
+        .iter()
+        .map(|zone| {
+            let zone = &zone["Zone"];
+            Ok((field(zone, "Id")?, field(zone, "Name")?, field(zone, "Coords")?))
+        })
+        .collect()
+}
+
+fn parse_active_state(reply: &Value) -> Result<Option<String>> {
+    for state in reply["states"].as_array().ok_or_else(|| anyhow!("states missing"))? {
+        let state = &state["State"];
+        if field::<u8>(state, "IsActive")? != 0 {
+            return Ok(Some(field(state, "Name")?));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_conf() {
+        let file = "ZM_API_URL=https://zm.example.com/zm/api/\nZM_API_USER=aidect\nZM_API_PASS=secret\n";
+        let conf = ApiConf::parse(file, &[]).unwrap().unwrap();
+        assert_eq!(conf.url, "https://zm.example.com/zm/api");
+        assert_eq!(conf.user, "aidect");
+
+        let env = [("ZM_API_PASS".to_string(), "other".to_string())];
+        assert_eq!(ApiConf::parse(file, &env).unwrap().unwrap().password, "other");
+        assert_eq!(ApiConf::parse("", &[]).unwrap(), None);
+        assert!(ApiConf::parse("ZM_API_URL=http://zm/api", &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_tokens() {
+        let now = Instant::now();
+        let login = json!({
+            "access_token": "a1",
+            "access_token_expires": 3600,
+            "refresh_token": "r1",
+            "refresh_token_expires": 86400,
+        });
+        let tokens = Tokens::parse(&login, now, None).unwrap();
+        assert_eq!(tokens.access_expires, now + Duration::from_secs(3600));
+
+        let refreshed = json!({"access_token": "a2", "access_token_expires": 3600});
+        let later = now + Duration::from_secs(3000);
+        let tokens = Tokens::parse(&refreshed, later, Some(tokens)).unwrap();
+        assert_eq!(tokens.access, "a2");
+        assert_eq!(tokens.refresh.as_deref(), Some("r1"));
+        assert_eq!(tokens.refresh_expires, now + Duration::from_secs(86400));
+
+        assert!(Tokens::parse(&json!({"success": false}), now, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_monitor_settings() {
+        let monitor = json!({
+            "Name": "Driveway",
+            "StorageId": "0",
+            "Enabled": true,
+            "Function": "Modect",
+            "Width": "1920",
+            "Height": "1080",
+            "Colours": "4",
+            "ImageBufferCount": "3",
+            "AnalysisFPSLimit": null,
+            "Orientation": "ROTATE_90",
+        });
+        let settings = parse_monitor_settings(&monitor).unwrap();
+        assert!(settings.is_active());
+        assert_eq!(settings.analysis_fps_limit, None);
+        assert_eq!(settings.image_dimensions(), (1080, 1920));
+
+        // ZM 1.37 has no Function anymore
+        let mut monitor = monitor;
+        monitor.as_object_mut().unwrap().remove("Function");
+        monitor["Capturing"] = json!("Always");
+        monitor["Analysing"] = json!("None");
+        monitor["Recording"] = json!("OnMotion");
+        assert_eq!(parse_monitor_settings(&monitor).unwrap().function, Function::Nodect);
+
+        let zones = json!({"zones": [{"Zone": {"Id": "7", "Name": "aidect Size=416", "Coords": "0,0 10,0 10,10"}}]});
+        assert_eq!(
+            parse_zones(&zones).unwrap(),
+            vec![(7, "aidect Size=416".to_string(), "0,0 10,0 10,10".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_active_state() {
+        let states = json!({"states": [
+            {"State": {"Id": "1", "Name": "Home", "Definition": "1:Monitor:1", "IsActive": "0"}},
+            {"State": {"Id": "2", "Name": "Away", "Definition": "1:Modect:1", "IsActive": "1"}},
+        ]});
+        assert_eq!(parse_active_state(&states).unwrap().as_deref(), Some("Away"));
+        assert_eq!(parse_active_state(&json!({"states": []})).unwrap(), None);
+        assert!(parse_active_state(&json!({})).is_err());
+    }
+}
@@ -1,14 +1,13 @@
-use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use mysql::params;
 use mysql::prelude::Queryable;
-use opencv::core::Rect;
 
 use crate::zoneminder::ZoneMinderConf;
 
-trait ZoneMinderDB {
+pub(super) trait ZoneMinderDB {
     fn connect_db(&self) -> mysql::Result<mysql::Conn>;
 }
 
@@ -23,7 +22,129 @@ impl ZoneMinderDB for ZoneMinderConf {
     }
 }
 
+/// Tables and columns zm-aidect uses.
+const SCHEMA: &[(&str, &[&str])] = &[
+    ("Monitors", &["Id", "Name", "StorageId", "Enabled", "Width", "Height", "Colours", "ImageBufferCount", "AnalysisFPSLimit", "Orientation", "LinkedMonitors"]),
+    ("Events", &["Id", "Name", "MonitorId", "StorageId", "Cause", "Notes", "MaxScore", "AvgScore", "TotScore", "DefaultVideo", "StartDateTime", "EndDateTime"]),
+    ("Zones", &["Id", "MonitorId", "Name", "Type", "Coords"]),
+    ("Storage", &["Id", "Name", "Path", "Type", "Scheme"]),
+    ("States", &["Name", "IsActive"]),
+    ("Frames", &["EventId", "FrameId", "TimeStamp", "Delta"]),
+    ("Stats", &["MonitorId", "ZoneId", "EventId", "FrameId", "PixelDiff", "AlarmPixels", "FilterPixels", "BlobPixels", "Blobs", "MinBlobSize", "MaxBlobSize", "MinX", "MinY", "MaxX", "MaxY", "Score"]),
+];
+
+/// Connects to the database and checks that all tables and columns zm-aidect uses exist.
+/// Errors if the database can't be reached, otherwise returns the problems found.
+pub fn check_schema(zm_conf: &ZoneMinderConf) -> Result<Vec<String>> {
+    let mut db = zm_conf.connect_db()?;
+    let mut problems = Vec::new();
+    for (table, columns) in SCHEMA {
+        let query = format!("SELECT {} FROM {} LIMIT 0", columns.join(", "), table);
+        if let Err(e) = db.query_drop(query) {
+            problems.push(format!("{}: {}", table, e));
+        }
+    }
+    if let Err(e) = db.query_drop(format!("SELECT {} FROM Monitors LIMIT 0", function_columns(&mut db)?)) {
+        problems.push(format!("Monitors: {}", e));
+    }
+    Ok(problems)
+}
+
+/// The columns MonitorSettings::query reads the monitor function from: Function up to ZM 1.36,
+/// which 1.37 split into Capturing, Analysing and Recording.
+fn function_columns(db: &mut mysql::Conn) -> Result<&'static str> {
+    let function: Option<u32> = db.query_first(
+        "SELECT COUNT(*) FROM information_schema.COLUMNS \
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'Monitors' AND COLUMN_NAME = 'Function'",
+    )?;
+    Ok(match function.unwrap_or(0) {
+        0 => "CONCAT_WS(',', Capturing, Analysing, Recording)",
+        _ => "Function",
+    })
+}
+
+/// IDs of all monitors.
+pub fn monitor_ids(zm_conf: &ZoneMinderConf) -> Result<Vec<u32>> {
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.query("SELECT Id FROM Monitors ORDER BY Id")?)
+}
+
+/// The name of ZoneMinder's active run state (e.g. "Home", "Away"), None if there is none.
+pub fn active_run_state(zm_conf: &ZoneMinderConf) -> Result<Option<String>> {
+    if let Some(api) = &zm_conf.api {
+        return api.active_run_state();
+    }
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.query_first("SELECT Name FROM States WHERE IsActive = 1 ORDER BY Id LIMIT 1")?)
+}
+
+/// Which of ZoneMinder's run states zm-aidect runs in (`run --run-states Home=off,Away=on`).
+/// Unlisted states count as off if any state is on, and as on otherwise.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunStates(Vec<(String, bool)>);
+
+impl RunStates {
+    pub fn parse(items: &[String]) -> Result<RunStates> {
+        items
+            .iter()
+            .map(|item| {
+                let (name, on) = item
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid run state {}, expected NAME=on or NAME=off", item))?;
+                let on = match on.trim() {
+                    "on" => true,
+                    "off" => false,
+                    other => return Err(anyhow!("Invalid run state {}={}, expected on or off", name, other)),
+                };
+                Ok((name.trim().to_string(), on))
+            })
+            .collect::<Result<_>>()
+            .map(RunStates)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether zm-aidect runs while active (the active state's name, case doesn't matter)
+    pub fn allows(&self, active: Option<&str>) -> bool {
+        let listed = active.and_then(|active| self.0.iter().find(|(name, _)| name.eq_ignore_ascii_case(active)));
+        match listed {
+            Some((_, on)) => *on,
+            None => !self.0.iter().any(|(_, on)| *on),
+        }
+    }
+}
+
+/// IDs of the monitors which have monitor_id among their Linked Monitors, i.e. which ZoneMinder
+/// puts into alarm as well when monitor_id goes into alarm (if their analysis is running).
+pub fn linked_to(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<Vec<u32>> {
+    let mut db = zm_conf.connect_db()?;
+    let rows: Vec<(u32, String)> = db.query(
+        "SELECT Id, LinkedMonitors FROM Monitors WHERE LinkedMonitors IS NOT NULL AND LinkedMonitors != '' ORDER BY Id",
+    )?;
+    Ok(rows
+        .into_iter()
+        .filter(|(_, linked)| parse_linked_monitors(linked).contains(&monitor_id))
+        .map(|(id, _)| id)
+        .collect())
+}
+
+/// The monitor IDs in a LinkedMonitors value, which is a comma-separated list up to ZM 1.36 and an
+/// expression like "1:3|(2&4)" (monitor:zone, and/or) since. Which combination of them has to be
+/// in alarm doesn't matter here.
+fn parse_linked_monitors(linked: &str) -> Vec<u32> {
+    linked
+        .split(|c| matches!(c, ',' | '|' | '&' | '(' | ')'))
+        .filter_map(|item| item.split(':').next()?.trim().parse().ok())
+        .collect()
+}
+
+/// Replaces the notes of an event, which ZoneMinder otherwise fills with the alarm cause(s).
 pub fn update_event_notes(zm_conf: &ZoneMinderConf, event_id: u64, notes: &str) -> Result<()> {
+    if let Some(api) = &zm_conf.api {
+        return api.update_event_notes(event_id, notes);
+    }
     let mut db = zm_conf.connect_db()?;
     Ok(db.exec_drop(
         "UPDATE Events SET Notes = :notes WHERE Id = :id",
@@ -34,39 +155,271 @@ pub fn update_event_notes(zm_conf: &ZoneMinderConf, event_id: u64, notes: &str)
     )?)
 }
 
+/// Creates the table zm-aidect keeps its event metadata in (run --event-metadata table) if it
+/// doesn't exist yet. Rows go away with their event, whoever deletes it; a table from before that
+/// gets its foreign key here.
+pub fn create_event_metadata_table(zm_conf: &ZoneMinderConf) -> Result<()> {
+    let mut db = zm_conf.connect_db()?;
+    db.query_drop(
+        "CREATE TABLE IF NOT EXISTS AidectEvents (EventId BIGINT UNSIGNED NOT NULL PRIMARY KEY, \
+         MonitorId INT UNSIGNED NOT NULL, Metadata TEXT NOT NULL, KEY MonitorId (MonitorId), \
+         FOREIGN KEY (EventId) REFERENCES Events (Id) ON DELETE CASCADE)",
+    )
+    .context("Failed to create the AidectEvents table, does the database user have the CREATE privilege?")?;
+    let foreign_keys: Option<u32> = db.query_first(
+        "SELECT COUNT(*) FROM information_schema.REFERENTIAL_CONSTRAINTS \
+         WHERE CONSTRAINT_SCHEMA = DATABASE() AND TABLE_NAME = 'AidectEvents'",
+    )?;
+    if foreign_keys == Some(0) {
+        db.query_drop("DELETE FROM AidectEvents WHERE EventId NOT IN (SELECT Id FROM Events)")?;
+        db.query_drop("ALTER TABLE AidectEvents ADD FOREIGN KEY (EventId) REFERENCES Events (Id) ON DELETE CASCADE")
+            .context(
+                "Failed to add the foreign key to AidectEvents, does the database user have the ALTER privilege?",
+            )?;
+    }
+    Ok(())
+}
+
+/// Stores (or replaces) the JSON metadata of an event in AidectEvents.
+pub fn upsert_event_metadata(zm_conf: &ZoneMinderConf, event_id: u64, monitor_id: u32, json: &str) -> Result<()> {
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.exec_drop(
+        "INSERT INTO AidectEvents SET EventId = :event_id, MonitorId = :monitor_id, Metadata = :metadata \
+         ON DUPLICATE KEY UPDATE Metadata = :metadata",
+        params! {
+            "event_id" => event_id,
+            "monitor_id" => monitor_id,
+            "metadata" => json,
+        },
+    )?)
+}
+
+/// Sets MaxScore to the given score, unless it's already higher.
+pub fn raise_event_max_score(zm_conf: &ZoneMinderConf, event_id: u64, score: u32) -> Result<()> {
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.exec_drop(
+        "UPDATE Events SET MaxScore = GREATEST(MaxScore, :score) WHERE Id = :id",
+        params! {
+            "id" => event_id,
+            "score" => score,
+        },
+    )?)
+}
+
+/// Alarm area of a detection, in the form ZoneMinder's Stats table keeps per zone and frame.
+#[derive(Debug)]
+pub struct AlarmStats {
+    pub monitor_id: u32,
+    pub zone_id: u32,
+    pub event_id: u64,
+    /// Top left and bottom right corner (inclusive)
+    pub min: (i32, i32),
+    pub max: (i32, i32),
+    pub score: u32,
+}
+
+/// Adds a Stats row for the detection, so that ZoneMinder's event view shows where the object was.
+/// Which frame the detection was in isn't known, it's attributed to the latest frame ZoneMinder
+/// has written for the event so far.
+pub fn insert_alarm_stats(zm_conf: &ZoneMinderConf, stats: &AlarmStats) -> Result<()> {
+    let mut db = zm_conf.connect_db()?;
+    let frame_id: Option<Option<u32>> = db.exec_first(
+        "SELECT MAX(FrameId) FROM Frames WHERE EventId = :event_id",
+        params! { "event_id" => stats.event_id },
+    )?;
+    let pixels = (stats.max.0 - stats.min.0 + 1) * (stats.max.1 - stats.min.1 + 1);
+    Ok(db.exec_drop(
+        "INSERT INTO Stats SET MonitorId = :monitor_id, ZoneId = :zone_id, EventId = :event_id, FrameId = :frame_id, \
+         PixelDiff = 0, AlarmPixels = :pixels, FilterPixels = :pixels, BlobPixels = :pixels, Blobs = 1, \
+         MinBlobSize = :pixels, MaxBlobSize = :pixels, MinX = :min_x, MinY = :min_y, MaxX = :max_x, MaxY = :max_y, Score = :score",
+        params! {
+            "monitor_id" => stats.monitor_id,
+            "zone_id" => stats.zone_id,
+            "event_id" => stats.event_id,
+            "frame_id" => frame_id.flatten().unwrap_or(1),
+            "pixels" => pixels,
+            "min_x" => stats.min.0,
+            "min_y" => stats.min.1,
+            "max_x" => stats.max.0,
+            "max_y" => stats.max.1,
+            "score" => stats.score,
+        },
+    )?)
+}
+
+/// Replaces MaxScore and AvgScore, e.g. with scores derived from detection confidences.
+pub fn update_event_scores(
+    zm_conf: &ZoneMinderConf,
+    event_id: u64,
+    max_score: u32,
+    avg_score: u32,
+) -> Result<()> {
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.exec_drop(
+        "UPDATE Events SET MaxScore = :max_score, AvgScore = :avg_score WHERE Id = :id",
+        params! {
+            "id" => event_id,
+            "max_score" => max_score,
+            "avg_score" => avg_score,
+        },
+    )?)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Orientation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+}
+
+impl TryFrom<&str> for Orientation {
+    type Error = anyhow::Error;
+
+    fn try_from(input: &str) -> std::result::Result<Orientation, Self::Error> {
+        Ok(match input {
+            // ZM 1.32 and older stored the rotation as degrees
+            "ROTATE_0" | "0" => Orientation::Rotate0,
+            "ROTATE_90" | "90" => Orientation::Rotate90,
+            "ROTATE_180" | "180" => Orientation::Rotate180,
+            "ROTATE_270" | "270" => Orientation::Rotate270,
+            "FLIP_HORI" | "hori" => Orientation::FlipHorizontal,
+            "FLIP_VERT" | "vert" => Orientation::FlipVertical,
+            _ => return Err(anyhow!("Invalid/unknown monitor orientation {}", input)),
+        })
+    }
+}
+
+/// Monitor function (what zmc/zma do with the images)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Function {
+    None,
+    Monitor,
+    Modect,
+    Record,
+    Mocord,
+    Nodect,
+}
+
+impl Function {
+    /// Whether triggers create alarms in this function. Nodect is meant exactly for external triggers.
+    pub fn is_triggerable(&self) -> bool {
+        matches!(self, Function::Modect | Function::Mocord | Function::Nodect)
+    }
+}
+
+impl Function {
+    /// The function equivalent to ZM 1.37's Capturing, Analysing and Recording columns, which
+    /// replaced Function.
+    pub fn from_modes(capturing: &str, analysing: &str, recording: &str) -> Result<Function> {
+        Ok(match (capturing, analysing, recording) {
+            ("None", _, _) => Function::None,
+            ("Ondemand" | "Always", "None", "None") => Function::Monitor,
+            // analysis off, but triggers still start events
+            ("Ondemand" | "Always", "None", "OnMotion") => Function::Nodect,
+            ("Ondemand" | "Always", "None", "Always") => Function::Record,
+            // motion detection without recording creates no events to trigger
+            ("Ondemand" | "Always", "Always", "None") => Function::Monitor,
+            ("Ondemand" | "Always", "Always", "OnMotion") => Function::Modect,
+            ("Ondemand" | "Always", "Always", "Always") => Function::Mocord,
+            _ => {
+                return Err(anyhow!(
+                    "Invalid/unknown monitor modes Capturing={} Analysing={} Recording={}",
+                    capturing,
+                    analysing,
+                    recording
+                ))
+            }
+        })
+    }
+}
+
+impl TryFrom<&str> for Function {
+    type Error = anyhow::Error;
+
+    /// A Function value, or Capturing,Analysing,Recording of ZM 1.37 and later
+    fn try_from(input: &str) -> std::result::Result<Function, Self::Error> {
+        Ok(match input {
+            "None" => Function::None,
+            "Monitor" => Function::Monitor,
+            "Modect" => Function::Modect,
+            "Record" => Function::Record,
+            "Mocord" => Function::Mocord,
+            "Nodect" => Function::Nodect,
+            _ => match input.split(',').collect::<Vec<_>>()[..] {
+                [capturing, analysing, recording] => Function::from_modes(capturing, analysing, recording)?,
+                _ => return Err(anyhow!("Invalid/unknown monitor function {}", input)),
+            },
+        })
+    }
+}
+
+/// The parts of a monitor's configuration (Monitors table) which are relevant for analysis.
 #[derive(Debug)]
 pub struct MonitorSettings {
     pub name: String,
     pub storage_id: u32,
     pub enabled: bool,
+    pub function: Function,
+    /// Width of the camera image, see image_dimensions() for the size of images in shm
     pub width: u32,
     pub height: u32,
     pub colours: u32,
     pub image_buffer_count: u32,
     pub analysis_fps_limit: Option<f32>,
+    pub orientation: Orientation,
 }
 
 impl MonitorSettings {
     pub fn query(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<MonitorSettings> {
+        if let Some(api) = &zm_conf.api {
+            return api.monitor_settings(monitor_id);
+        }
         let mut db = zm_conf.connect_db()?;
-        Ok(db.exec_map("SELECT Name, StorageId, Enabled, Width, Height, Colours, ImageBufferCount, AnalysisFPSLimit FROM Monitors WHERE Id = :id",
+        let query = format!(
+            "SELECT Name, StorageId, Enabled, {}, Width, Height, Colours, ImageBufferCount, AnalysisFPSLimit, Orientation FROM Monitors WHERE Id = :id",
+            function_columns(&mut db)?
+        );
+        db.exec_map(query,
                        params! { "id" => monitor_id },
-                       |(name, storage_id, enabled, width, height, colours, image_buffer_count, analysis_fps_limit)| {
-                           MonitorSettings {
+                       |(name, storage_id, enabled, function, width, height, colours, image_buffer_count, analysis_fps_limit, orientation)| -> Result<MonitorSettings> {
+                           let function: String = function;
+                           let orientation: String = orientation;
+                           Ok(MonitorSettings {
                                name,
                                storage_id,
                                enabled,
+                               function: Function::try_from(function.as_str())?,
                                width,
                                height,
                                colours,
                                image_buffer_count,
                                analysis_fps_limit,
-                           }
+                               orientation: Orientation::try_from(orientation.as_str())?,
+                           })
                        }
-        )?.remove(0))
+        )?.remove(0)
+    }
+
+    /// Whether analyzing this monitor and triggering it makes sense at all.
+    pub fn is_active(&self) -> bool {
+        self.enabled && self.function.is_triggerable()
+    }
+
+    /// Dimensions of the images zmc produces. ZM rotates images right after capture, so for monitors
+    /// rotated by 90/270 degrees these are swapped compared to the configured Width/Height.
+    /// (Deinterlacing is done in-place and doesn't change the dimensions.)
+    pub fn image_dimensions(&self) -> (u32, u32) {
+        match self.orientation {
+            Orientation::Rotate90 | Orientation::Rotate270 => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
     }
 }
 
+/// A recorded event (Events table).
 #[derive(Debug)]
 pub struct Event {
     pub id: u64,
@@ -77,10 +430,35 @@ pub struct Event {
     pub total_score: u32,
     default_video: String,
     start_datetime: String, // local time, 2022-01-27 18:45:59
+    /// The event's rows in the Frames table, by FrameId
+    pub frames: Vec<Frame>,
 
     storage: Storage,
 }
 
+/// A row of the Frames table. ZoneMinder doesn't necessarily write one for every frame, only for
+/// alarm frames and every ZM_BULK_FRAME_INTERVAL frames otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub frame_id: u32,
+    pub timestamp: String, // local time, 2022-01-27 18:45:59
+    /// Seconds since the start of the event
+    pub delta: f64,
+}
+
+/// The FrameId at time seconds into the event, interpolated between the surrounding rows of the
+/// Frames table. None before the first and after the last.
+pub fn frame_id_at(frames: &[Frame], time: f64) -> Option<u32> {
+    let next = frames.iter().position(|frame| frame.delta >= time)?;
+    let after = &frames[next];
+    if after.delta == time {
+        return Some(after.frame_id);
+    }
+    let before = frames[..next].last()?;
+    let fraction = (time - before.delta) / (after.delta - before.delta);
+    Some(before.frame_id + (fraction * (after.frame_id - before.frame_id) as f64).round() as u32)
+}
+
 impl Event {
     pub fn query(zm_conf: &ZoneMinderConf, event_id: u64) -> Result<Event> {
         let mut db = zm_conf.connect_db()?;
@@ -91,6 +469,16 @@ impl Event {
         )?;
         let storage = get_storage_by_id(&mut db, storage_id.unwrap())?;
 
+        let frames = db.exec_map(
+            "SELECT FrameId, CAST(TimeStamp AS CHAR), Delta FROM Frames WHERE EventId = :id ORDER BY FrameId",
+            params! { "id" => event_id },
+            |(frame_id, timestamp, delta)| Frame {
+                frame_id,
+                timestamp,
+                delta,
+            },
+        )?;
+
         // the "date time" handling here is janky af but sufficient for what's needed (only used to derive the file name)
         Ok(db.exec_map("SELECT Name, MonitorId, MaxScore, AvgScore, TotScore, DefaultVideo, CAST(StartDateTime AS CHAR) FROM Events WHERE Id = :id",
                        params! { "id" => event_id },
@@ -104,12 +492,96 @@ impl Event {
                                total_score,
                                default_video,
                                start_datetime,
+                               frames: frames.clone(),
                                storage: storage.clone(),
                            }
                        }
         )?.remove(0))
     }
 
+    /// IDs of the monitor's events in the range, in ascending order.
+    pub fn ids(zm_conf: &ZoneMinderConf, monitor_id: u32, range: &EventRange) -> Result<Vec<u64>> {
+        let mut db = zm_conf.connect_db()?;
+        Ok(db.exec(
+            "SELECT Id FROM Events WHERE MonitorId = :monitor_id AND Id BETWEEN :first_id AND :last_id AND StartDateTime BETWEEN :since AND :until ORDER BY Id",
+            params! {
+                "monitor_id" => monitor_id,
+                "first_id" => range.first_id.unwrap_or(0),
+                "last_id" => range.last_id.unwrap_or(u64::MAX),
+                "since" => range.since.as_deref().unwrap_or("1970-01-01 00:00:00"),
+                "until" => range.until.as_deref().unwrap_or("9999-12-31 23:59:59"),
+            },
+        )?)
+    }
+
+    /// IDs of the monitor's events after after_id which were started by ZoneMinder's motion detection
+    /// and ended at least `settled_secs` seconds ago, in ascending order.
+    pub fn closed_motion_ids(
+        zm_conf: &ZoneMinderConf,
+        monitor_id: u32,
+        after_id: u64,
+        settled_secs: u64,
+    ) -> Result<Vec<u64>> {
+        let mut db = zm_conf.connect_db()?;
+        Ok(db.exec(
+            "SELECT Id FROM Events WHERE MonitorId = :monitor_id AND Id > :after_id AND Cause LIKE \"%Motion%\" AND EndDateTime IS NOT NULL AND EndDateTime <= NOW() - INTERVAL :settled SECOND ORDER BY Id",
+            params! {
+                "monitor_id" => monitor_id,
+                "after_id" => after_id,
+                "settled" => settled_secs,
+            },
+        )?)
+    }
+
+    /// ID of the monitor's most recent event, if any.
+    pub fn latest_id(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<Option<u64>> {
+        let mut db = zm_conf.connect_db()?;
+        Ok(db.exec_first(
+            "SELECT MAX(Id) FROM Events WHERE MonitorId = :monitor_id",
+            params! { "monitor_id" => monitor_id },
+        )?
+        .flatten())
+    }
+
+    /// Deletes the event: its directory, then its database rows, so that a failure doesn't leave
+    /// recordings behind which ZoneMinder no longer knows of.
+    pub fn delete(&self, zm_conf: &ZoneMinderConf) -> Result<()> {
+        let video_path = self.video_path()?;
+        if let Some(event_dir) = video_path.parent() {
+            match std::fs::remove_dir_all(event_dir) {
+                Ok(()) => {}
+                // already gone, e.g. deleted by hand, the rows still have to go
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to delete event directory {}", event_dir.display()))
+                }
+            }
+        }
+
+        let mut db = zm_conf.connect_db()?;
+        for table in ["Frames", "Stats"] {
+            db.exec_drop(
+                format!("DELETE FROM {} WHERE EventId = :id", table),
+                params! { "id" => self.id },
+            )?;
+        }
+        // AidectEvents rows go with it
+        db.exec_drop("DELETE FROM Events WHERE Id = :id", params! { "id" => self.id })?;
+        Ok(())
+    }
+
+    /// When the event started, local time like 2022-01-27 18:45:59
+    pub fn start_datetime(&self) -> &str {
+        &self.start_datetime
+    }
+
+    /// When the event started
+    pub fn start_time(&self) -> Result<SystemTime> {
+        parse_local_datetime(&self.start_datetime)
+            .ok_or_else(|| anyhow!("Invalid StartDateTime {} of event {}", self.start_datetime, self.id))
+    }
+
+    /// Path of the event's video file in its storage area.
     pub fn video_path(&self) -> Result<PathBuf> {
         if self.storage.storage_type != "local" {
             return Err(anyhow!(
@@ -146,6 +618,16 @@ impl Event {
     }
 }
 
+/// Selects events by ID and/or start time, all bounds are inclusive.
+#[derive(Debug, Default)]
+pub struct EventRange {
+    pub first_id: Option<u64>,
+    pub last_id: Option<u64>,
+    /// Local time like StartDateTime, e.g. 2022-01-27 18:45:59
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
 #[derive(Debug, Copy, Clone)]
 enum StorageScheme {
     Deep,
@@ -197,124 +679,156 @@ fn get_storage_by_id(db: &mut mysql::Conn, storage_id: u64) -> Result<Storage> {
         .remove(0)?)
 }
 
-pub type ZoneShape = Vec<(i32, i32)>;
-
-pub trait Bounding {
-    fn bounding_box(&self) -> Rect;
-}
-
-impl Bounding for ZoneShape {
-    fn bounding_box(&self) -> Rect {
-        let min_x = self.iter().map(|xy| xy.0).min().unwrap();
-        let min_y = self.iter().map(|xy| xy.1).min().unwrap();
-        let max_x = self.iter().map(|xy| xy.0).max().unwrap();
-        let max_y = self.iter().map(|xy| xy.1).max().unwrap();
-
-        let width = max_x - min_x;
-        let height = max_y - min_y;
-        Rect {
-            x: min_x,
-            y: min_y,
-            width,
-            height,
+/// The point in time of a DATETIME column in local time, like 2022-01-27 18:45:59
+fn parse_local_datetime(datetime: &str) -> Option<SystemTime> {
+    let fields: Vec<i32> = datetime
+        .split(|c| c == '-' || c == ' ' || c == ':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    match fields[..] {
+        [year, month, day, hour, minute, second] => {
+            tm.tm_year = year - 1900;
+            tm.tm_mon = month - 1;
+            tm.tm_mday = day;
+            tm.tm_hour = hour;
+            tm.tm_min = minute;
+            tm.tm_sec = second;
         }
+        _ => return None,
+    }
+    // whether DST applies is up to the time zone
+    tm.tm_isdst = -1;
+    let secs = unsafe { libc::mktime(&mut tm) };
+    match secs {
+        -1 => None,
+        secs => Some(UNIX_EPOCH + Duration::from_secs(secs as u64)),
     }
 }
 
-#[derive(Debug)]
-pub struct ZoneConfig {
-    pub size: Option<u32>,
-    pub threshold: Option<f32>,
-    pub shape: ZoneShape,
-    pub trigger: Option<u32>,
-    pub fps: Option<f32>,
-    pub min_area: Option<u32>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl ZoneConfig {
-    pub fn get_zone_config(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<ZoneConfig> {
-        let mut db = zm_conf.connect_db()?;
-        let dbzone = db.exec_first(
-            "SELECT Name, Type, Coords FROM Zones WHERE MonitorId = :id AND Name LIKE \"aidect%\"",
-            params! { "id" => monitor_id },
-        )?;
-        let dbzone: mysql::Row =
-            dbzone.ok_or(anyhow!("No aidect zone found for monitor {}", monitor_id))?;
+    #[test]
+    fn test_run_states() {
+        let states = |items: &[&str]| RunStates::parse(&items.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        let home_off = states(&["Home=off"]).unwrap();
+        assert!(!home_off.allows(Some("home")));
+        assert!(home_off.allows(Some("Away")));
+        assert!(home_off.allows(None));
+
+        let away_on = states(&["Away=on", "Night=on"]).unwrap();
+        assert!(away_on.allows(Some("Night")));
+        assert!(!away_on.allows(Some("Home")));
+        assert!(!away_on.allows(None));
+
+        assert!(RunStates::default().allows(Some("Home")));
+        assert!(states(&["Home"]).is_err());
+        assert!(states(&["Home=disabled"]).is_err());
+    }
 
-        Ok(ZoneConfig::parse(
-            &dbzone.get::<String, &str>("Name").unwrap(),
-            &dbzone.get::<String, &str>("Coords").unwrap(),
-        ))
+    #[test]
+    fn test_frame_id_at() {
+        let frame = |frame_id, delta| Frame {
+            frame_id,
+            timestamp: String::new(),
+            delta,
+        };
+        // bulk frames every 10 frames at 5 fps, plus an alarm frame
+        let frames = [frame(1, 0.0), frame(11, 2.0), frame(14, 2.6), frame(21, 4.0)];
+        assert_eq!(frame_id_at(&frames, 0.0), Some(1));
+        assert_eq!(frame_id_at(&frames, 1.0), Some(6));
+        assert_eq!(frame_id_at(&frames, 2.6), Some(14));
+        assert_eq!(frame_id_at(&frames, 3.0), Some(16));
+        assert_eq!(frame_id_at(&frames, -0.2), None);
+        assert_eq!(frame_id_at(&frames, 4.2), None);
+        assert_eq!(frame_id_at(&[], 1.0), None);
     }
 
-    fn parse(name: &str, coords: &str) -> ZoneConfig {
-        ZoneConfig {
-            shape: Self::parse_zone_coords(coords),
-            ..Self::parse_zone_name(name)
-        }
+    #[test]
+    fn test_parse_local_datetime() {
+        let time = parse_local_datetime("2022-01-27 18:45:59").unwrap();
+        assert_eq!(crate::zoneminder::zone::local_minute_of_day(time), 18 * 60 + 45);
+        assert_eq!(
+            parse_local_datetime("2022-01-27 18:46:09").unwrap(),
+            time + Duration::from_secs(10)
+        );
+        assert_eq!(parse_local_datetime("2022-01-27"), None);
+        assert_eq!(parse_local_datetime("2022-01-27 18:45:xx"), None);
     }
 
-    fn parse_zone_name(zone_name: &str) -> ZoneConfig {
-        let keys: HashMap<&str, &str> = zone_name
-            .split_ascii_whitespace()
-            .skip(1)
-            .map(|item| item.split_once('='))
-            .filter_map(|x| x)
-            .collect();
-
-        let get_int = |key| keys.get(key).and_then(|v| v.trim().parse::<u32>().ok());
-        let get_f32 = |key| keys.get(key).and_then(|v| v.trim().parse::<f32>().ok());
-
-        ZoneConfig {
-            shape: Vec::new(),
-            threshold: keys
-                .get("Threshold")
-                .and_then(|v| v.trim().parse::<f32>().ok())
-                .map(|v| v / 100.0),
-            size: get_int("Size"),
-            trigger: get_int("Trigger"),
-            fps: get_f32("FPS"),
-            min_area: get_int("MinArea"),
-        }
+    #[test]
+    fn test_parse_linked_monitors() {
+        assert_eq!(parse_linked_monitors("3"), vec![3]);
+        assert_eq!(parse_linked_monitors("1,2, 5"), vec![1, 2, 5]);
+        assert_eq!(parse_linked_monitors("1:3|(2&4:7)"), vec![1, 2, 4]);
+        assert_eq!(parse_linked_monitors(""), Vec::<u32>::new());
     }
 
-    fn parse_zone_coords(coords: &str) -> ZoneShape {
-        let parse = |v: &str| v.trim().parse::<i32>().unwrap();
-        coords
-            .split_ascii_whitespace()
-            .map(|point| point.split_once(','))
-            .filter_map(|v| v)
-            .map(|(x, y)| (parse(x), parse(y)))
-            .collect()
+    fn monitor_settings(width: u32, height: u32, orientation: Orientation) -> MonitorSettings {
+        MonitorSettings {
+            name: "Test".to_string(),
+            storage_id: 0,
+            enabled: true,
+            function: Function::Modect,
+            width,
+            height,
+            colours: 4,
+            image_buffer_count: 3,
+            analysis_fps_limit: None,
+            orientation,
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_parse_orientation() {
+        assert_eq!(Orientation::try_from("ROTATE_0").unwrap(), Orientation::Rotate0);
+        assert_eq!(Orientation::try_from("ROTATE_270").unwrap(), Orientation::Rotate270);
+        assert_eq!(Orientation::try_from("FLIP_HORI").unwrap(), Orientation::FlipHorizontal);
+        assert_eq!(Orientation::try_from("90").unwrap(), Orientation::Rotate90);
+        assert!(Orientation::try_from("ROTATE_45").is_err());
+    }
 
     #[test]
-    fn test_parse_zone_name_basic() {
-        let zone_name = "aidect";
-        let parsed = ZoneConfig::parse_zone_name(zone_name);
-        assert_eq!(parsed.shape.len(), 0);
-        assert_eq!(parsed.threshold, None);
-        assert_eq!(parsed.size, None);
+    fn test_parse_function() {
+        assert_eq!(Function::try_from("Nodect").unwrap(), Function::Nodect);
+        assert!(Function::try_from("Nodect").unwrap().is_triggerable());
+        assert!(!Function::try_from("Monitor").unwrap().is_triggerable());
+        assert!(!Function::try_from("Record").unwrap().is_triggerable());
+        assert!(Function::try_from("Motion").is_err());
+
+        // ZM 1.37: Capturing, Analysing, Recording
+        assert_eq!(Function::try_from("Always,Always,OnMotion").unwrap(), Function::Modect);
+        assert_eq!(Function::try_from("Always,None,OnMotion").unwrap(), Function::Nodect);
+        assert_eq!(Function::try_from("Ondemand,Always,Always").unwrap(), Function::Mocord);
+        assert_eq!(Function::try_from("Always,Always,None").unwrap(), Function::Monitor);
+        assert_eq!(Function::try_from("None,Always,Always").unwrap(), Function::None);
+        assert!(Function::try_from("Always,Sometimes,None").is_err());
     }
 
     #[test]
-    fn test_parse_zone_name() {
-        let zone_name = "aidect Size=128 Threshold=50";
-        let parsed = ZoneConfig::parse_zone_name(zone_name);
-        assert_eq!(parsed.shape.len(), 0);
-        assert_eq!(parsed.threshold, Some(0.5));
-        assert_eq!(parsed.size, Some(128));
+    fn test_is_active() {
+        let mut settings = monitor_settings(1280, 720, Orientation::Rotate0);
+        assert!(settings.is_active());
+        settings.function = Function::Monitor;
+        assert!(!settings.is_active());
+        settings.function = Function::Mocord;
+        settings.enabled = false;
+        assert!(!settings.is_active());
     }
 
     #[test]
-    fn test_parse_zone_coords() {
-        let coords = "123,56 899,41 687,425";
-        let parsed = ZoneConfig::parse_zone_coords(coords);
-        assert_eq!(parsed, vec![(123, 56), (899, 41), (687, 425)]);
+    fn test_image_dimensions_rotated() {
+        let settings = monitor_settings(1280, 720, Orientation::Rotate0);
+        assert_eq!(settings.image_dimensions(), (1280, 720));
+        let settings = monitor_settings(1280, 720, Orientation::Rotate90);
+        assert_eq!(settings.image_dimensions(), (720, 1280));
+        let settings = monitor_settings(1280, 720, Orientation::Rotate180);
+        assert_eq!(settings.image_dimensions(), (1280, 720));
+        let settings = monitor_settings(1280, 720, Orientation::Rotate270);
+        assert_eq!(settings.image_dimensions(), (720, 1280));
+        let settings = monitor_settings(1280, 720, Orientation::FlipVertical);
+        assert_eq!(settings.image_dimensions(), (1280, 720));
     }
 }
@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use libc::timeval;
+use opencv::core::{Mat, MatTraitConst, MatTraitManual, Vector};
+
+use crate::ml::PixelOrder;
+use crate::zoneminder::db::MonitorSettings;
+use crate::zoneminder::{shm, Monitor};
+
+fn zm_format_to_cv_format(format: shm::SubpixelOrder) -> i32 {
+    match format {
+        shm::SubpixelOrder::NONE => opencv::core::CV_8UC1,
+        shm::SubpixelOrder::RGB => opencv::core::CV_8UC3,
+        shm::SubpixelOrder::BGR => opencv::core::CV_8UC3,
+        shm::SubpixelOrder::BGRA => opencv::core::CV_8UC4,
+        shm::SubpixelOrder::RGBA => opencv::core::CV_8UC4,
+        shm::SubpixelOrder::ABGR => opencv::core::CV_8UC4,
+        shm::SubpixelOrder::ARGB => opencv::core::CV_8UC4,
+    }
+}
+
+/// Pixels read from shared memory at a time when dropping the alpha channel, so that the 32-bit
+/// frame only ever passes through the cache instead of being copied out as a whole first.
+const STRIP_ALPHA_CHUNK: usize = 16 * 1024;
+
+/// An image in the monitor's pixel format, convert it to get at the pixels. 32-bit formats are
+/// RGB24 already, see ImageStream::read_image.
+pub struct Image {
+    image: Mat,
+    format: shm::SubpixelOrder,
+    /// Older frame which was missed during a stall, see ImageStream::catch_up
+    pub backlog: bool,
+    /// Frames captured since the previous image which the stream skipped over, not counting those
+    /// returned later on as backlog.
+    pub skipped: u32,
+    /// Slot in the shared memory ring buffer the image was read from
+    pub index: u32,
+}
+
+impl Image {
+    pub fn convert_to_rgb24(self) -> Result<Mat> {
+        let conversion = match self.format {
+            shm::SubpixelOrder::NONE => Some(opencv::imgproc::COLOR_GRAY2RGB),
+            shm::SubpixelOrder::RGB => None,
+            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2RGB),
+            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2RGB),
+            shm::SubpixelOrder::RGBA => Some(opencv::imgproc::COLOR_RGBA2RGB),
+            // OpenCV has no conversions for alpha-first formats, pick the channels by hand
+            shm::SubpixelOrder::ABGR => {
+                return self.mix_channels(&[3, 0, 2, 1, 1, 2], opencv::core::CV_8UC3)
+            }
+            shm::SubpixelOrder::ARGB => {
+                return self.mix_channels(&[1, 0, 2, 1, 3, 2], opencv::core::CV_8UC3)
+            }
+        };
+        self.convert(conversion)
+    }
+
+    /// RGB24 or BGR24, whichever is cheaper: BGR images are returned as they are, since the
+    /// models can take them without another conversion (see ml::PixelOrder).
+    pub fn convert_to_rgb24_or_bgr24(self) -> Result<(Mat, PixelOrder)> {
+        match self.format {
+            shm::SubpixelOrder::BGR => Ok((self.image, PixelOrder::Bgr)),
+            _ => Ok((self.convert_to_rgb24()?, PixelOrder::Rgb)),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn convert_to_rgb32(self) -> Result<Mat> {
+        let conversion = match self.format {
+            shm::SubpixelOrder::NONE => Some(opencv::imgproc::COLOR_GRAY2RGBA),
+            shm::SubpixelOrder::RGB => Some(opencv::imgproc::COLOR_RGB2RGBA),
+            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2RGBA),
+            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2RGBA),
+            shm::SubpixelOrder::RGBA => None,
+            shm::SubpixelOrder::ABGR => {
+                return self.mix_channels(&[3, 0, 2, 1, 1, 2, 0, 3], opencv::core::CV_8UC4)
+            }
+            shm::SubpixelOrder::ARGB => {
+                return self.mix_channels(&[1, 0, 2, 1, 3, 2, 0, 3], opencv::core::CV_8UC4)
+            }
+        };
+        self.convert(conversion)
+    }
+
+    #[allow(dead_code)]
+    pub fn convert_to_gray(self) -> Result<Mat> {
+        let conversion = match self.format {
+            shm::SubpixelOrder::NONE => None,
+            shm::SubpixelOrder::RGB => Some(opencv::imgproc::COLOR_RGB2GRAY),
+            shm::SubpixelOrder::BGR => Some(opencv::imgproc::COLOR_BGR2GRAY),
+            shm::SubpixelOrder::BGRA => Some(opencv::imgproc::COLOR_BGRA2GRAY),
+            shm::SubpixelOrder::RGBA => Some(opencv::imgproc::COLOR_RGBA2GRAY),
+            shm::SubpixelOrder::ABGR | shm::SubpixelOrder::ARGB => {
+                let rgb = self.convert_to_rgb24()?;
+                let mut gray = Mat::default();
+                opencv::imgproc::cvt_color(&rgb, &mut gray, opencv::imgproc::COLOR_RGB2GRAY, 0)?;
+                return Ok(gray);
+            }
+        };
+        self.convert(conversion)
+    }
+
+    /// from_to are pairs of (source channel, destination channel)
+    fn mix_channels(self, from_to: &[i32], typ: i32) -> Result<Mat> {
+        let mut mixed = Mat::new_rows_cols_with_default(
+            self.image.rows(),
+            self.image.cols(),
+            typ,
+            0.into(),
+        )?;
+        opencv::core::mix_channels(&self.image, &mut mixed, &from_to.iter().copied().collect::<Vector<i32>>())?;
+        Ok(mixed)
+    }
+
+    fn convert(self, conversion: Option<i32>) -> Result<Mat> {
+        if let Some(conversion) = conversion {
+            let mut rgb_image = Mat::default();
+            // You could do this in-place as well, though it's probably not worth it
+            opencv::imgproc::cvt_color(&self.image, &mut rgb_image, conversion, 0)?;
+            return Ok(rgb_image);
+        }
+        Ok(self.image)
+    }
+}
+
+/// Endless stream of the images captured by a monitor. Waits for the next image and always
+/// yields the most recent one, so images are skipped if the consumer is slower than capture.
+pub struct ImageStream<'mon> {
+    monitor: &'mon Monitor<'mon>,
+    last_read_index: u32,
+    width: u32,
+    height: u32,
+    image_size: u32,
+    format: shm::SubpixelOrder,
+    image_buffer_count: u32,
+    shared_images_offset: u64,
+    catch_up: Option<usize>,
+    backlog: VecDeque<u32>,
+    timeout: Option<Duration>,
+}
+
+impl ImageStream<'_> {
+    pub(super) fn new<'mon>(monitor: &'mon Monitor<'mon>) -> Result<ImageStream<'mon>> {
+        let state = monitor.read()?;
+        let settings = MonitorSettings::query(monitor.zm_conf, monitor.monitor_id)?;
+        let image_buffer_count = settings.image_buffer_count;
+
+        // now that we have the image buffer size we can figure the dynamic offsets out
+        let shared_timestamps_offset = monitor.shm.header_size()?;
+        let shared_images_offset =
+            shared_timestamps_offset as usize + image_buffer_count as usize * size_of::<timeval>();
+        let shared_images_offset = shared_images_offset + 64 - (shared_images_offset % 64);
+
+        let (width, height) = settings.image_dimensions();
+        Ok(ImageStream {
+            width,
+            height,
+            image_buffer_count,
+            monitor,
+            last_read_index: image_buffer_count,
+            image_size: state.imagesize,
+            format: state.format,
+            shared_images_offset: shared_images_offset as u64,
+            catch_up: None,
+            backlog: VecDeque::new(),
+            timeout: None,
+        })
+    }
+
+    /// After the next image, also return (up to max_frames) images which were skipped since the
+    /// previous one, newest first. Meant for catching up after a stall, as usually the stream just
+    /// skips to the most recent image.
+    pub fn catch_up(&mut self, max_frames: usize) {
+        self.catch_up = Some(max_frames);
+    }
+
+    /// Error if the monitor doesn't capture a new image for this long, e.g. because zmc hangs,
+    /// instead of waiting forever.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    fn wait_for_image(&mut self) -> Result<Image> {
+        if let Some(index) = self.backlog.pop_front() {
+            let image = self.read_image(index)?;
+            return Ok(Image {
+                image,
+                format: self.image_format(),
+                backlog: true,
+                skipped: 0,
+                index,
+            });
+        }
+        let waiting_since = Instant::now();
+        loop {
+            let state = self.monitor.read()?;
+            let last_write_index = state.last_write_index as u32;
+            if last_write_index != self.last_read_index
+                && last_write_index != self.image_buffer_count
+            {
+                let mut skipped =
+                    missed_count(self.last_read_index, last_write_index, self.image_buffer_count);
+                if let Some(max_frames) = self.catch_up.take() {
+                    self.queue_missed(last_write_index, max_frames);
+                    skipped -= self.backlog.len() as u32;
+                }
+                self.last_read_index = last_write_index;
+                let image = self.read_image(last_write_index)?;
+                return Ok(Image {
+                    image,
+                    format: self.image_format(),
+                    backlog: false,
+                    skipped,
+                    index: last_write_index,
+                });
+            }
+            if let Some(timeout) = self.timeout {
+                if waiting_since.elapsed() > timeout {
+                    return Err(anyhow!("No new image for {:?}", timeout));
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn queue_missed(&mut self, last_write_index: u32, max_frames: usize) {
+        self.backlog = missed_indices(
+            self.last_read_index,
+            last_write_index,
+            self.image_buffer_count,
+            max_frames,
+        );
+    }
+
+    /// The format of the images read, the alpha channel of 32-bit formats is dropped while reading
+    fn image_format(&self) -> shm::SubpixelOrder {
+        match self.format.colour_type() {
+            shm::ColourType::RGB32 => shm::SubpixelOrder::RGB,
+            _ => self.format,
+        }
+    }
+
+    fn read_image(&self, index: u32) -> Result<Mat> {
+        let bytes_per_pixel = self.format.colour_type().bytes_per_pixel();
+        if self.width * self.height * bytes_per_pixel != self.image_size {
+            return Err(anyhow!(
+                "Image size mismatch: Expected {}x{} (x{}, {:?}) = {} bytes, but shm says {} bytes",
+                self.width,
+                self.height,
+                bytes_per_pixel,
+                self.format,
+                self.width * self.height * bytes_per_pixel,
+                self.image_size
+            ));
+        }
+        let mut mat = Mat::new_size_with_default(
+            (self.width as i32, self.height as i32).into(),
+            zm_format_to_cv_format(self.image_format()),
+            0.into(),
+        )?;
+        match self.format.colour_type() {
+            shm::ColourType::RGB32 => self.read_image_strip_alpha(index, &mut mat)?,
+            _ => self.read_image_into(index, &mut mat)?,
+        }
+        Ok(mat)
+    }
+
+    /// Reads a 32-bit image into an RGB24 mat chunk by chunk, instead of reading it whole and
+    /// converting it afterwards, which on small ARM boards costs as much as the rest of capture.
+    fn read_image_strip_alpha(&self, index: u32, mat: &mut Mat) -> Result<()> {
+        assert_eq!(self.width * self.height, mat.total() as u32);
+        assert_eq!(mat.typ(), opencv::core::CV_8UC3);
+        self.monitor.check_file_stale()?;
+        let rgb = mat.data_bytes_mut()?;
+        let image_offset = self.shared_images_offset as u64 + self.image_size as u64 * index as u64;
+        let mut chunk = vec![0u8; STRIP_ALPHA_CHUNK * 4];
+        for (i, rgb) in rgb.chunks_mut(STRIP_ALPHA_CHUNK * 3).enumerate() {
+            let chunk = &mut chunk[..rgb.len() / 3 * 4];
+            self.monitor
+                .shm
+                .file
+                .read_exact_at(chunk, image_offset + (i * STRIP_ALPHA_CHUNK * 4) as u64)
+                .with_context(|| "Failed to read image")?;
+            strip_alpha(self.format, chunk, rgb);
+        }
+        Ok(())
+    }
+
+    fn read_image_into(&self, index: u32, mat: &mut Mat) -> Result<()> {
+        assert_eq!(self.width * self.height, mat.total() as u32);
+        assert_eq!(mat.typ(), zm_format_to_cv_format(self.format));
+        self.monitor.check_file_stale()?;
+        let mut slice = mat.data_bytes_mut()?;
+        let image_offset = self.shared_images_offset as u64 + self.image_size as u64 * index as u64;
+        self.monitor
+            .shm
+            .file
+            .read_exact_at(&mut slice, image_offset)
+            .with_context(|| "Failed to read image")?;
+        Ok(())
+    }
+}
+
+/// Converts 32-bit pixels in src to RGB24 in dst, which holds as many pixels.
+fn strip_alpha(format: shm::SubpixelOrder, src: &[u8], dst: &mut [u8]) {
+    assert_eq!(src.len() / 4, dst.len() / 3);
+    match format {
+        shm::SubpixelOrder::RGBA => pick_channels::<0, 1, 2>(src, dst),
+        shm::SubpixelOrder::BGRA => pick_channels::<2, 1, 0>(src, dst),
+        shm::SubpixelOrder::ABGR => pick_channels::<3, 2, 1>(src, dst),
+        shm::SubpixelOrder::ARGB => pick_channels::<1, 2, 3>(src, dst),
+        _ => unreachable!("{:?} is not a 32-bit format", format),
+    }
+}
+
+/// Copies channels R, G and B of each 4-byte pixel in src into 3-byte pixels in dst. With the
+/// channels known at compile time, LLVM turns the loop into shuffles on x86; on aarch64 NEON
+/// deinterleaves 16 pixels per load.
+fn pick_channels<const R: usize, const G: usize, const B: usize>(src: &[u8], dst: &mut [u8]) {
+    #[cfg(target_arch = "aarch64")]
+    let done = pick_channels_neon::<R, G, B>(src, dst);
+    #[cfg(not(target_arch = "aarch64"))]
+    let done = 0;
+    for (s, d) in src[done * 4..].chunks_exact(4).zip(dst[done * 3..].chunks_exact_mut(3)) {
+        d[0] = s[R];
+        d[1] = s[G];
+        d[2] = s[B];
+    }
+}
+
+/// pick_channels for blocks of 16 pixels, returns how many pixels it converted
+#[cfg(target_arch = "aarch64")]
+fn pick_channels_neon<const R: usize, const G: usize, const B: usize>(src: &[u8], dst: &mut [u8]) -> usize {
+    use std::arch::aarch64::{uint8x16x3_t, uint8x16x4_t, uint8x16_t, vld4q_u8, vst3q_u8};
+
+    fn channel(pixels: &uint8x16x4_t, c: usize) -> uint8x16_t {
+        match c {
+            0 => pixels.0,
+            1 => pixels.1,
+            2 => pixels.2,
+            _ => pixels.3,
+        }
+    }
+
+    let blocks = (src.len() / 4).min(dst.len() / 3) / 16;
+    for i in 0..blocks {
+        // SAFETY: NEON is part of the aarch64 baseline, and blocks keeps both pointers within
+        // their slices: src holds 64 bytes and dst 48 bytes from each block's offset.
+        unsafe {
+            let pixels = vld4q_u8(src.as_ptr().add(i * 64));
+            let rgb = uint8x16x3_t(channel(&pixels, R), channel(&pixels, G), channel(&pixels, B));
+            vst3q_u8(dst.as_mut_ptr().add(i * 48), rgb);
+        }
+    }
+    blocks * 16
+}
+
+/// Number of ring buffer slots between last_read_index and last_write_index (both exclusive).
+/// Falling behind by more than a whole ring buffer can't be told apart from this.
+fn missed_count(last_read_index: u32, last_write_index: u32, image_buffer_count: u32) -> u32 {
+    if last_read_index >= image_buffer_count {
+        // nothing read yet
+        return 0;
+    }
+    let n = image_buffer_count;
+    ((last_write_index + n - last_read_index) % n).saturating_sub(1)
+}
+
+/// Ring buffer indices between last_read_index and last_write_index (both exclusive), newest first.
+/// At most half the ring buffer is returned, since zmc is busy overwriting the oldest slots.
+fn missed_indices(
+    last_read_index: u32,
+    last_write_index: u32,
+    image_buffer_count: u32,
+    max_frames: usize,
+) -> VecDeque<u32> {
+    let n = image_buffer_count;
+    let missed = missed_count(last_read_index, last_write_index, n) as usize;
+    let max_frames = max_frames.min(n as usize / 2);
+    (1..=missed.min(max_frames) as u32)
+        .map(|k| (last_write_index + n - k) % n)
+        .collect()
+}
+
+impl Iterator for ImageStream<'_> {
+    type Item = Result<Image>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.wait_for_image())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_alpha() {
+        // more than a NEON block, and a remainder
+        let src: Vec<u8> = (0..37 * 4).map(|i| i as u8).collect();
+        let expected = |order: [usize; 3]| -> Vec<u8> {
+            src.chunks_exact(4).flat_map(|p| order.map(|c| p[c])).collect()
+        };
+        let mut dst = vec![0; 37 * 3];
+        strip_alpha(shm::SubpixelOrder::RGBA, &src, &mut dst);
+        assert_eq!(dst, expected([0, 1, 2]));
+        strip_alpha(shm::SubpixelOrder::BGRA, &src, &mut dst);
+        assert_eq!(dst, expected([2, 1, 0]));
+        strip_alpha(shm::SubpixelOrder::ABGR, &src, &mut dst);
+        assert_eq!(dst, expected([3, 2, 1]));
+        strip_alpha(shm::SubpixelOrder::ARGB, &src, &mut dst);
+        assert_eq!(dst, expected([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_missed_count() {
+        assert_eq!(missed_count(3, 4, 50), 0);
+        assert_eq!(missed_count(3, 7, 50), 3);
+        assert_eq!(missed_count(48, 2, 50), 3);
+        assert_eq!(missed_count(50, 7, 50), 0);
+    }
+
+    #[test]
+    fn test_missed_indices() {
+        assert_eq!(missed_indices(3, 7, 50, 10), [6, 5, 4]);
+        assert_eq!(missed_indices(3, 7, 50, 2), [6, 5]);
+        assert!(missed_indices(3, 4, 50, 10).is_empty());
+        // wrapped around
+        assert_eq!(missed_indices(48, 2, 50, 10), [1, 0, 49]);
+        // capped to half the buffer
+        assert_eq!(missed_indices(0, 9, 10, 10), [8, 7, 6, 5, 4]);
+        // nothing read yet
+        assert!(missed_indices(50, 7, 50, 10).is_empty());
+    }
+}
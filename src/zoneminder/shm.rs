@@ -1,6 +1,8 @@
+use std::fs::File;
 use std::io::Read;
 use std::mem::{align_of, size_of};
 use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::slice;
 
 use anyhow::{anyhow, Context, Result};
@@ -8,9 +10,9 @@ use lazy_static::lazy_static;
 use libc::time_t;
 use regex::Regex;
 
-// TODO: panic! wrapper which adds a bit that this requires maintainer attention
+use crate::error::Error;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 struct Type {
     size: usize,
     alignment: usize,
@@ -32,10 +34,12 @@ impl Type {
     }
 }
 
-fn parse_basic_typename(typename: &str) -> Type {
-    match typename {
+fn parse_basic_typename(typename: &str) -> Result<Type> {
+    Ok(match typename {
         "uint8" => Type::new::<u8>(),
         "int8" => Type::new::<i8>(),
+        "uint16" => Type::new::<u16>(),
+        "int16" => Type::new::<i16>(),
         "uint32" => Type::new::<u32>(),
         "int32" => Type::new::<i32>(),
         "uint64" => Type::new::<u64>(),
@@ -43,30 +47,20 @@ fn parse_basic_typename(typename: &str) -> Type {
         "float" => Type::new::<f32>(),
         "double" => Type::new::<f64>(),
         "time_t64" => Type::new::<time_t>(),
-        _ => panic!(
-            "Unhandled ABI type in Memory.pm shm definition: {}",
-            typename
-        ),
-    }
+        _ => return Err(anyhow!("Unhandled ABI type in Memory.pm shm definition: {}", typename)),
+    })
 }
 
-fn parse_typename(typename: &str) -> Type {
+fn parse_typename(typename: &str) -> Result<Type> {
     match typename.split_once('[') {
         None => parse_basic_typename(typename),
         Some((basic_typename, array_size)) => {
-            let t = parse_basic_typename(basic_typename);
-            assert!(array_size.ends_with(']'));
-            let array_size = &array_size[0..array_size.len() - 1];
+            let t = parse_basic_typename(basic_typename)?;
             let elements = array_size
-                .parse::<usize>()
-                .with_context(|| {
-                    format!(
-                        "Could not parse array size in Memory.pm shm definition: {}",
-                        typename
-                    )
-                })
-                .unwrap();
-            t.array_of(elements)
+                .strip_suffix(']')
+                .and_then(|array_size| array_size.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("Could not parse array size in Memory.pm shm definition: {}", typename))?;
+            Ok(t.array_of(elements))
         }
     }
 }
@@ -77,21 +71,20 @@ struct ParsedField {
     typ: Type,
 }
 
-fn parse_field_definition(line: &str) -> ParsedField {
+fn parse_field_definition(line: &str) -> Result<ParsedField> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"(\w+)\s+=> \{ type=>'([a-z0-9_\[\]]+)'").unwrap();
     }
-    let m = RE
-        .captures(line)
-        .ok_or(anyhow!(
+    let m = RE.captures(line).ok_or_else(|| {
+        anyhow!(
             "Could not parse field definition in Memory.pm shm definition: {:?}",
             line
-        ))
-        .unwrap();
-    ParsedField {
+        )
+    })?;
+    Ok(ParsedField {
         name: m[1].to_string(),
-        typ: parse_typename(&m[2]),
-    }
+        typ: parse_typename(&m[2]).with_context(|| format!("In field definition {:?}", line))?,
+    })
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -100,7 +93,7 @@ struct ParsedStruct {
     fields: Vec<ParsedField>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 struct Field {
     name: String,
     offset: usize,
@@ -114,6 +107,43 @@ struct Struct {
     fields: Vec<Field>,
 }
 
+impl Struct {
+    fn find(&self, field: ShmField) -> Option<&Field> {
+        std::iter::once(field.name())
+            .chain(field.aliases().iter().copied())
+            .find_map(|name| self.fields.iter().find(|f| f.name == name))
+    }
+}
+
+/// Checks that all fields zm-aidect uses are present with the expected types, so that an
+/// unsupported ZoneMinder version is noticed right away instead of when something reads a field.
+fn validate_layout(layout: &Struct) -> Result<()> {
+    let problems: Vec<String> = ShmField::ALL
+        .into_iter()
+        .filter_map(|f| match (layout.find(f), f.expected_type()) {
+            (None, _) => Some(format!("{} is missing", f.name())),
+            (Some(field), Some(typ)) if field.typ != typ => Some(format!(
+                "{} has type {:?}, expected {:?}",
+                f.name(),
+                field.typ,
+                typ
+            )),
+            // strings: char arrays of any length
+            (Some(field), None) if field.typ.alignment != 1 => {
+                Some(format!("{} is not a string", f.name()))
+            }
+            _ => None,
+        })
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Unsupported ZoneMinder shared memory layout, this version of ZoneMinder is probably not supported (yet):\n  {}",
+        problems.join("\n  ")
+    ))
+}
+
 impl ParsedStruct {
     fn calculate_offsets(self) -> Struct {
         let mut offset = 0;
@@ -145,27 +175,30 @@ fn align_to(offset: usize, alignment: usize) -> usize {
     }
 }
 
-fn parse_struct_definition(input: &mut std::str::Lines) -> Option<ParsedStruct> {
+fn parse_struct_definition(input: &mut std::str::Lines) -> Result<Option<ParsedStruct>> {
     lazy_static! {
         static ref RE: Regex =
             Regex::new(r"\w+\s+=> \{ type=>'(\w+)', seq=>\$mem_seq\+\+, '?contents'?").unwrap();
     }
-    let struct_def = input.next().expect("Empty struct definition in Memory.pm");
+    let struct_def = input
+        .next()
+        .ok_or_else(|| anyhow!("Memory.pm shm definition ends without an end => entry"))?;
     if struct_def.trim_start().starts_with("end =>") {
-        return None;
+        return Ok(None);
     }
 
-    let m = RE
-        .captures(struct_def)
-        .ok_or(anyhow!(
+    let m = RE.captures(struct_def).ok_or_else(|| {
+        anyhow!(
             "Could not parse struct definition in Memory.pm shm definition: {:?}",
             struct_def
-        ))
-        .unwrap();
+        )
+    })?;
 
     let mut fields = vec![];
     loop {
-        let line = input.next().expect("Unexpected EOR in Memory.pm");
+        let line = input
+            .next()
+            .ok_or_else(|| anyhow!("Memory.pm shm definition ends within struct {}", &m[1]))?;
         let line = line.trim_start();
         if line == "}" {
             continue;
@@ -173,32 +206,28 @@ fn parse_struct_definition(input: &mut std::str::Lines) -> Option<ParsedStruct>
         if line == "}," {
             break;
         }
-        fields.push(parse_field_definition(line));
+        fields.push(parse_field_definition(line).with_context(|| format!("In struct {}", &m[1]))?);
     }
 
-    Some(ParsedStruct {
+    Ok(Some(ParsedStruct {
         name: m[1].to_string(),
         fields,
-    })
+    }))
 }
 
-fn parse_memory_pm(input: &str) -> ParsedStruct {
+fn parse_memory_pm(input: &str) -> Result<ParsedStruct> {
     let re = Regex::new(r"(?ms)our \$mem_data = \{\n(.*?)};").unwrap();
     let m = re
         .captures(input)
-        .expect("No shm definitions found in Memory.pm");
+        .ok_or_else(|| anyhow!("No shm definitions found in Memory.pm"))?;
 
     let mut lines = m[1].lines();
     let mut fields = vec![];
-    loop {
-        if let Some(s) = parse_struct_definition(&mut lines) {
-            fields.extend(s.fields.into_iter().map(|f| ParsedField {
-                name: format!("{}::{}", s.name, f.name),
-                ..f
-            }));
-        } else {
-            break;
-        }
+    while let Some(s) = parse_struct_definition(&mut lines)? {
+        fields.extend(s.fields.into_iter().map(|f| ParsedField {
+            name: format!("{}::{}", s.name, f.name),
+            ..f
+        }));
     }
 
     // Memory.pm does not define this struct, but we need to read this field to calculate
@@ -208,10 +237,10 @@ fn parse_memory_pm(input: &str) -> ParsedStruct {
         typ: Type::new::<u32>(),
     });
 
-    ParsedStruct {
+    Ok(ParsedStruct {
         name: "memory".into(),
         fields,
-    }
+    })
 }
 
 fn read_memory_pm<T: Read>(mut input: T) -> Result<Struct> {
@@ -220,74 +249,144 @@ fn read_memory_pm<T: Read>(mut input: T) -> Result<Struct> {
         input.read_to_string(&mut contents)?;
         contents
     };
-    Ok(parse_memory_pm(&input).calculate_offsets())
+    Ok(parse_memory_pm(&input)?.calculate_offsets())
 }
 
-lazy_static! {
-    static ref LAYOUT: Struct = {
-        let file = std::fs::File::open("/usr/share/perl5/ZoneMinder/Memory.pm").expect("Failed to open ZoneMinder Memory.pm - ZM not installed or installed in unknown location.");
-        read_memory_pm(file).unwrap()
-    };
+pub(super) fn load_layout(memory_pm: &Path) -> Result<Struct> {
+    let file = File::open(memory_pm)
+        .with_context(|| format!("Failed to open ZoneMinder {}", memory_pm.display()))?;
+    let layout = read_memory_pm(file)
+        .with_context(|| format!("Reading {}", memory_pm.display()))
+        .context(Error::ShmLayout)?;
+    validate_layout(&layout)
+        .with_context(|| format!("Checking {}", memory_pm.display()))
+        .context(Error::ShmLayout)?;
+    Ok(layout)
 }
 
 #[non_exhaustive]
+#[cfg_attr(not(feature = "detect"), allow(dead_code))]
 pub struct MonitorShm<T: Read> {
     pub file: T,
     pub videostore_size: u32,
+    /// Resolved from Memory.pm once, indexed by ShmField
+    fields: Vec<Field>,
 }
 
-impl<File: FileExt + Read> MonitorShm<File> {
-    pub fn new(file: File) -> Result<MonitorShm<File>> {
+impl<F: FileExt + Read> MonitorShm<F> {
+    /// memory_pm: ZoneMinder's Memory.pm, which describes the layout
+    pub fn new(file: F, memory_pm: &Path) -> Result<MonitorShm<F>> {
+        let layout = load_layout(memory_pm)?;
         let mut mshm = MonitorShm {
             file,
             videostore_size: 0,
+            // validate_layout made sure all of them are there
+            fields: ShmField::ALL
+                .iter()
+                .map(|&f| layout.find(f).unwrap().clone())
+                .collect(),
         };
-        mshm.videostore_size = mshm.read_field("VideoStoreData::size")?;
+        mshm.videostore_size = mshm.read_field(ShmField::VideoStoreSize)?;
         Ok(mshm)
     }
 
-    fn lookup_field(&self, name: &str) -> &Field {
-        for field in LAYOUT.fields.iter() {
-            if field.name == name {
-                return field;
-            }
-        }
-        panic!("Field not found in Memory.pm: {name}");
+    fn lookup_field(&self, field: ShmField) -> &Field {
+        &self.fields[field as usize]
     }
 
-    fn typecheck<T>(&self, field: &Field) {
+    fn typecheck<T>(&self, field: &Field) -> Result<()> {
         let typ = Type::new::<T>();
         if field.typ != typ {
-            panic!(
-                "Mismatched field type for {} (wanted: {typ:?}, got: {:?}",
-                field.name, field.typ
-            );
+            return Err(anyhow!(
+                "Mismatched field type for {} (wanted: {typ:?}, got: {:?})",
+                field.name,
+                field.typ
+            ))
+            .context(Error::ShmLayout);
         }
+        Ok(())
     }
 
-    pub fn read_field<T>(&self, name: &str) -> Result<T> {
-        let field = self.lookup_field(name);
-        self.typecheck::<T>(field);
+    pub fn last_write_index(&self) -> Result<i32> {
+        self.read_field(ShmField::LastWriteIndex)
+    }
+
+    pub fn state(&self) -> Result<MonitorState> {
+        self.read_field::<u32>(ShmField::State)?.try_into()
+    }
+
+    pub fn last_event_id(&self) -> Result<u64> {
+        self.read_field(ShmField::LastEventId)
+    }
+
+    /// False while zmc (re)initializes the shm, or after it exited
+    pub fn valid(&self) -> Result<bool> {
+        Ok(self.read_field::<u8>(ShmField::Valid)? != 0)
+    }
+
+    pub fn format(&self) -> Result<SubpixelOrder> {
+        self.read_field::<u8>(ShmField::Format)?.try_into()
+    }
+
+    pub fn image_size(&self) -> Result<u32> {
+        self.read_field(ShmField::ImageSize)
+    }
+
+    /// Size of SharedData, TriggerData and VideoStoreData, after which the timestamps follow
+    pub fn header_size(&self) -> Result<u32> {
+        Ok(self.read_field::<u32>(ShmField::SharedSize)?
+            + self.read_field::<u32>(ShmField::TriggerSize)?
+            + self.read_field::<u32>(ShmField::VideoStoreSize)?)
+    }
+
+    /// Writes the trigger data. The state goes last, so that ZoneMinder (hopefully) doesn't act on
+    /// a half-written trigger. Strings too long for their field are truncated.
+    pub fn write_trigger(&self, trigger: &TriggerData) -> Result<()> {
+        self.write_string(ShmField::TriggerCause, trigger.cause)?;
+        self.write_string(ShmField::TriggerText, trigger.text)?;
+        self.write_string(ShmField::TriggerShowtext, trigger.showtext)?;
+        self.write_field(ShmField::TriggerScore, &trigger.score)?;
+        self.write_field(ShmField::TriggerState, &(trigger.state as u32))
+    }
+
+    /// Whether a trigger is on, and its cause
+    pub fn trigger(&self) -> Result<(bool, String)> {
+        let state = self.read_field::<u32>(ShmField::TriggerState)?;
+        Ok((state == TriggerState::TriggerOn as u32, self.read_string(ShmField::TriggerCause)?))
+    }
+
+    /// Only the showtext, leaving the trigger alone
+    pub fn write_showtext(&self, showtext: &str) -> Result<()> {
+        self.write_string(ShmField::TriggerShowtext, showtext)
+    }
+
+    fn read_field<T>(&self, field: ShmField) -> Result<T> {
+        let field = self.lookup_field(field);
+        self.typecheck::<T>(field)?;
         self.pread(field.offset)
     }
 
-    pub fn write_field<T>(&self, name: &str, value: &T) -> Result<()> {
-        let field = self.lookup_field(name);
-        self.typecheck::<T>(field);
+    fn write_field<T>(&self, field: ShmField, value: &T) -> Result<()> {
+        let field = self.lookup_field(field);
+        self.typecheck::<T>(field)?;
         self.pwrite(field.offset, value)
     }
 
-    pub fn write_string(&self, name: &str, value: &str) -> Result<()> {
-        let field = self.lookup_field(name);
-        let terminated_len = value.len() + 1;
-        assert!(field.typ.size >= terminated_len);
-        let mut s = String::with_capacity(terminated_len);
-        s.push_str(value);
-        s.push('\0');
-        self.file.write_all_at(s.as_bytes(), field.offset as u64)?;
+    fn write_string(&self, field: ShmField, value: &str) -> Result<()> {
+        let field = self.lookup_field(field);
+        self.file
+            .write_all_at(&terminated(value, field.typ.size), field.offset as u64)?;
         Ok(())
     }
 
+    fn read_string(&self, field: ShmField) -> Result<String> {
+        let field = self.lookup_field(field);
+        let mut buf = vec![0; field.typ.size];
+        self.file.read_exact_at(&mut buf, field.offset as u64)?;
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
     fn pread<T>(&self, offset: usize) -> Result<T> {
         let mut buf = Vec::new();
         buf.resize(size_of::<T>(), 0);
@@ -302,28 +401,113 @@ impl<File: FileExt + Read> MonitorShm<File> {
     }
 }
 
-#[non_exhaustive]
-pub(super) struct ShmField;
+/// value as a NUL-terminated C string of at most size bytes, cut at a character boundary if it's
+/// too long.
+fn terminated(value: &str, size: usize) -> Vec<u8> {
+    let mut len = value.len().min(size.saturating_sub(1));
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut bytes = value.as_bytes()[..len].to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// What MonitorShm::write_trigger writes
+pub(super) struct TriggerData<'a> {
+    pub state: TriggerState,
+    pub score: u32,
+    pub cause: &'a str,
+    pub text: &'a str,
+    pub showtext: &'a str,
+}
+
+/// The fields zm-aidect uses. Their offsets come from Memory.pm, so they always match the
+/// installed ZoneMinder.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(not(feature = "detect"), allow(dead_code))]
+pub(super) enum ShmField {
+    LastWriteIndex,
+    State,
+    LastEventId,
+    Valid,
+    Format,
+    ImageSize,
+
+    TriggerState,
+    TriggerScore,
+    TriggerCause,
+    TriggerText,
+    TriggerShowtext,
+
+    SharedSize,
+    TriggerSize,
+    VideoStoreSize,
+}
 
-// TODO: This should be an enum and we should associate the name and expected type internally,
-// TODO: so that all fields we may can be validated after parsing Memory.pm
 impl ShmField {
-    pub const LAST_WRITE_INDEX: &'static str = "SharedData::last_write_index";
-    pub const STATE: &'static str = "SharedData::state";
-    pub const LAST_EVENT_ID: &'static str = "SharedData::last_event";
-    pub const VALID: &'static str = "SharedData::valid";
-    pub const FORMAT: &'static str = "SharedData::format";
-    pub const IMAGESIZE: &'static str = "SharedData::imagesize";
+    /// In declaration order, MonitorShm indexes by the discriminant
+    const ALL: [ShmField; 14] = [
+        ShmField::LastWriteIndex,
+        ShmField::State,
+        ShmField::LastEventId,
+        ShmField::Valid,
+        ShmField::Format,
+        ShmField::ImageSize,
+        ShmField::TriggerState,
+        ShmField::TriggerScore,
+        ShmField::TriggerCause,
+        ShmField::TriggerText,
+        ShmField::TriggerShowtext,
+        ShmField::SharedSize,
+        ShmField::TriggerSize,
+        ShmField::VideoStoreSize,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ShmField::LastWriteIndex => "SharedData::last_write_index",
+            ShmField::State => "SharedData::state",
+            ShmField::LastEventId => "SharedData::last_event",
+            ShmField::Valid => "SharedData::valid",
+            ShmField::Format => "SharedData::format",
+            ShmField::ImageSize => "SharedData::imagesize",
+            ShmField::TriggerState => "TriggerData::trigger_state",
+            ShmField::TriggerScore => "TriggerData::trigger_score",
+            ShmField::TriggerCause => "TriggerData::trigger_cause",
+            ShmField::TriggerText => "TriggerData::trigger_text",
+            ShmField::TriggerShowtext => "TriggerData::trigger_showtext",
+            ShmField::SharedSize => "SharedData::size",
+            ShmField::TriggerSize => "TriggerData::size",
+            ShmField::VideoStoreSize => "VideoStoreData::size",
+        }
+    }
 
-    pub const TRIGGER_STATE: &'static str = "TriggerData::trigger_state";
-    pub const TRIGGER_SCORE: &'static str = "TriggerData::trigger_score";
-    pub const TRIGGER_CAUSE: &'static str = "TriggerData::trigger_cause";
-    pub const TRIGGER_TEXT: &'static str = "TriggerData::trigger_text";
-    pub const TRIGGER_SHOWTEXT: &'static str = "TriggerData::trigger_showtext";
+    /// Names the field had in other ZoneMinder versions
+    fn aliases(self) -> &'static [&'static str] {
+        match self {
+            ShmField::LastEventId => &["SharedData::last_event_id"],
+            _ => &[],
+        }
+    }
 
-    pub const SHARED_SIZE: &'static str = "SharedData::size";
-    pub const TRIGGER_SIZE: &'static str = "TriggerData::size";
-    pub const VIDEOSTORE_SIZE: &'static str = "VideoStoreData::size";
+    /// The type the field is accessed as, None for strings
+    fn expected_type(self) -> Option<Type> {
+        match self {
+            ShmField::LastWriteIndex => Some(Type::new::<i32>()),
+            ShmField::State => Some(Type::new::<u32>()),
+            ShmField::LastEventId => Some(Type::new::<u64>()),
+            ShmField::Valid => Some(Type::new::<u8>()),
+            ShmField::Format => Some(Type::new::<u8>()),
+            ShmField::ImageSize
+            | ShmField::TriggerScore
+            | ShmField::SharedSize
+            | ShmField::TriggerSize
+            | ShmField::VideoStoreSize => Some(Type::new::<u32>()),
+            ShmField::TriggerState => Some(Type::new::<u32>()),
+            ShmField::TriggerCause | ShmField::TriggerText | ShmField::TriggerShowtext => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -332,33 +516,35 @@ mod tests {
 
     #[test]
     fn test_parse_typename() {
-        assert_eq!(parse_typename("int32"), Type::new::<i32>());
-        assert_eq!(parse_typename("int32[44]"), Type::new::<i32>().array_of(44));
+        assert_eq!(parse_typename("int32").unwrap(), Type::new::<i32>());
+        assert_eq!(parse_typename("int32[44]").unwrap(), Type::new::<i32>().array_of(44));
     }
 
     #[test]
-    #[should_panic]
-    fn test_parse_typename_panic() {
-        parse_typename("int32[44x]");
+    fn test_bytes_per_pixel() {
+        assert_eq!(SubpixelOrder::NONE.colour_type().bytes_per_pixel(), 1);
+        assert_eq!(SubpixelOrder::BGR.colour_type().bytes_per_pixel(), 3);
+        assert_eq!(SubpixelOrder::ARGB.colour_type().bytes_per_pixel(), 4);
     }
 
     #[test]
-    #[should_panic]
-    fn test_parse_typename_panic2() {
-        parse_typename("int32[44");
+    fn test_parse_typename_error() {
+        assert!(parse_typename("int32[44x]").is_err());
+        assert!(parse_typename("int32[44").is_err());
+        assert!(parse_typename("int128").is_err());
     }
 
     #[test]
     fn test_parse_field_definition() {
         assert_eq!(
-            parse_field_definition("  size             => { type=>'uint32', seq=>$mem_seq++ },"),
+            parse_field_definition("  size             => { type=>'uint32', seq=>$mem_seq++ },").unwrap(),
             ParsedField {
                 name: "size".into(),
                 typ: Type::new::<u32>(),
             }
         );
         assert_eq!(
-            parse_field_definition("  size             => { type=>'uint32[5]', seq=>$mem_seq++ },"),
+            parse_field_definition("  size             => { type=>'uint32[5]', seq=>$mem_seq++ },").unwrap(),
             ParsedField {
                 name: "size".into(),
                 typ: Type::new::<u32>().array_of(5),
@@ -377,6 +563,7 @@ mod tests {
   },"#
                 .lines()
             )
+            .unwrap()
             .unwrap(),
             ParsedStruct {
                 name: "TriggerData".into(),
@@ -419,7 +606,7 @@ sub zmMemInit {
     #[test]
     fn test_parse_memory_pm() {
         assert_eq!(
-            parse_memory_pm(INPUT),
+            parse_memory_pm(INPUT).unwrap(),
             ParsedStruct {
                 name: "memory".into(),
                 fields: vec![
@@ -452,6 +639,51 @@ sub zmMemInit {
         );
     }
 
+    #[test]
+    fn test_parse_memory_pm_unknown_type() {
+        let input = INPUT.replace("type=>'time_t64'", "type=>'time_t128'");
+        let error = read_memory_pm(input.as_bytes()).unwrap_err();
+        let error = format!("{:#}", error);
+        assert!(error.contains("time_t128"), "{}", error);
+        assert!(error.contains("startup_time"), "{}", error);
+        assert!(error.contains("SharedData"), "{}", error);
+
+        // also when the struct or the definitions end early
+        assert!(parse_memory_pm(&INPUT.replace("  end => { seq=>$mem_seq++, size=>0 }\n", "")).is_err());
+        assert!(parse_memory_pm(&INPUT.replace("  }\n  },\n  end", "  end")).is_err());
+        assert!(parse_memory_pm("").is_err());
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        let layout = read_memory_pm(INPUT.as_bytes()).unwrap();
+        let error = validate_layout(&layout).unwrap_err().to_string();
+        assert!(error.contains("SharedData::last_event is missing"));
+        assert!(error.contains("TriggerData::trigger_text is missing"));
+        assert!(!error.contains("TriggerData::trigger_cause"));
+        assert!(!error.contains("SharedData::size"));
+    }
+
+    #[test]
+    fn test_field_alias() {
+        let layout = read_memory_pm(
+            INPUT
+                .replace("audio_fifo       => { type=>'int8[64]'", "last_event_id    => { type=>'uint64'")
+                .as_bytes(),
+        )
+        .unwrap();
+        let field = layout.find(ShmField::LastEventId).unwrap();
+        assert_eq!(field.name, "SharedData::last_event_id");
+        assert_eq!(field.typ, Type::new::<u64>());
+    }
+
+    #[test]
+    fn test_shm_field_all() {
+        for (i, field) in ShmField::ALL.into_iter().enumerate() {
+            assert_eq!(field as usize, i);
+        }
+    }
+
     #[test]
     fn test_align_to() {
         assert_eq!(align_to(6, 8), 8);
@@ -460,6 +692,72 @@ sub zmMemInit {
         assert_eq!(align_to(7, 1), 7);
     }
 
+    const MEMORY_PM_1_36: &str = include_str!("testdata/memory-1.36.pm");
+    const MEMORY_PM_1_37: &str = include_str!("testdata/memory-1.37.pm");
+
+    #[test]
+    fn test_bundled_memory_pm() {
+        // SharedData grew and last_event was renamed in 1.37, TriggerData stayed the same
+        for (input, shared_size, last_event) in [(MEMORY_PM_1_36, 760, 32), (MEMORY_PM_1_37, 864, 56)] {
+            let layout = read_memory_pm(input.as_bytes()).unwrap();
+            validate_layout(&layout).unwrap();
+            let offset = |field| layout.find(field).unwrap().offset;
+            assert_eq!(offset(ShmField::LastEventId), last_event);
+            assert_eq!(offset(ShmField::TriggerSize), shared_size);
+            assert_eq!(offset(ShmField::TriggerCause), shared_size + 16);
+            assert_eq!(offset(ShmField::TriggerShowtext), shared_size + 16 + 32 + 256);
+            assert_eq!(offset(ShmField::VideoStoreSize), shared_size + 560);
+        }
+    }
+
+    #[test]
+    fn test_terminated() {
+        assert_eq!(terminated("abc", 8), b"abc\0");
+        assert_eq!(terminated("abcdef", 4), b"abc\0");
+        // not in the middle of the ä
+        assert_eq!(terminated("aä", 3), b"a\0");
+        assert_eq!(terminated("", 1), b"\0");
+    }
+
+    #[test]
+    fn test_monitor_shm() {
+        let path = std::env::temp_dir().join(format!("zm-aidect-test-shm-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(2048).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let memory_pm = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/zoneminder/testdata/memory-1.36.pm");
+        let shm = MonitorShm::new(file, &memory_pm).unwrap();
+
+        // all zeroes, like before zmc initialized it
+        assert!(!shm.valid().unwrap());
+        assert_eq!(shm.state().unwrap(), MonitorState::Unknown);
+        assert!(shm.format().is_err());
+
+        shm.write_trigger(&TriggerData {
+            state: TriggerState::TriggerOn,
+            score: 87,
+            cause: &"ä".repeat(20),
+            text: "Human (87%)",
+            showtext: "",
+        })
+        .unwrap();
+        // 31 bytes fit into trigger_cause
+        assert_eq!(shm.read_string(ShmField::TriggerCause).unwrap(), "ä".repeat(15));
+        assert_eq!(shm.read_string(ShmField::TriggerText).unwrap(), "Human (87%)");
+        assert_eq!(shm.read_field::<u32>(ShmField::TriggerScore).unwrap(), 87);
+        assert_eq!(
+            shm.read_field::<u32>(ShmField::TriggerState).unwrap(),
+            TriggerState::TriggerOn as u32
+        );
+        assert_eq!(shm.trigger().unwrap(), (true, "ä".repeat(15)));
+        assert_eq!(shm.header_size().unwrap(), 0);
+    }
+
     #[test]
     fn test_read_memory_pm() {
         assert_eq!(
@@ -516,17 +814,38 @@ pub(super) enum MonitorState {
     Tape,     // I think this is the idle state of Mocord and Record
 }
 
+impl TryFrom<u32> for MonitorState {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => MonitorState::Unknown,
+            1 => MonitorState::Idle,
+            2 => MonitorState::Prealarm,
+            3 => MonitorState::Alarm,
+            4 => MonitorState::Alert,
+            5 => MonitorState::Tape,
+            _ => return Err(anyhow!("Invalid monitor state {} in shm", value)),
+        })
+    }
+}
+
 // zm_rgb.h
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u32)]
-#[allow(dead_code)]
 pub(super) enum ColourType {
     GRAY8 = 1,
     RGB24 = 3,
     RGB32 = 4,
 }
 
+impl ColourType {
+    pub fn bytes_per_pixel(&self) -> u32 {
+        *self as u32
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -540,6 +859,33 @@ pub(super) enum SubpixelOrder {
     ARGB = 10,
 }
 
+impl TryFrom<u8> for SubpixelOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            2 => SubpixelOrder::NONE,
+            6 => SubpixelOrder::RGB,
+            5 => SubpixelOrder::BGR,
+            7 => SubpixelOrder::BGRA,
+            8 => SubpixelOrder::RGBA,
+            9 => SubpixelOrder::ABGR,
+            10 => SubpixelOrder::ARGB,
+            _ => return Err(anyhow!("Invalid subpixel order {} in shm", value)),
+        })
+    }
+}
+
+impl SubpixelOrder {
+    pub fn colour_type(&self) -> ColourType {
+        match self {
+            SubpixelOrder::NONE => ColourType::GRAY8,
+            SubpixelOrder::RGB | SubpixelOrder::BGR => ColourType::RGB24,
+            _ => ColourType::RGB32,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u32)]
 #[allow(dead_code)]
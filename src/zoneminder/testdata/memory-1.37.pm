@@ -0,0 +1,60 @@
+# ZoneMinder 1.37 Memory.pm, trimmed to the shared memory definition
+our $mem_seq = 0;
+
+our $mem_data = {
+  shared_data => { type=>'SharedData', seq=>$mem_seq++, contents=> {
+      size             => { type=>'uint32', seq=>$mem_seq++ },
+      last_write_index => { type=>'int32', seq=>$mem_seq++ },
+      last_read_index  => { type=>'int32', seq=>$mem_seq++ },
+      image_count      => { type=>'int32', seq=>$mem_seq++ },
+      state            => { type=>'uint32', seq=>$mem_seq++ },
+      capture_fps      => { type=>'double', seq=>$mem_seq++ },
+      analysis_fps     => { type=>'double', seq=>$mem_seq++ },
+      latitude         => { type=>'double', seq=>$mem_seq++ },
+      longitude        => { type=>'double', seq=>$mem_seq++ },
+      last_event_id    => { type=>'uint64', seq=>$mem_seq++ },
+      action           => { type=>'uint32', seq=>$mem_seq++ },
+      brightness       => { type=>'int32', seq=>$mem_seq++ },
+      hue              => { type=>'int32', seq=>$mem_seq++ },
+      colour           => { type=>'int32', seq=>$mem_seq++ },
+      contrast         => { type=>'int32', seq=>$mem_seq++ },
+      alarm_x          => { type=>'int32', seq=>$mem_seq++ },
+      alarm_y          => { type=>'int32', seq=>$mem_seq++ },
+      valid            => { type=>'uint8', seq=>$mem_seq++ },
+      capturing        => { type=>'uint8', seq=>$mem_seq++ },
+      analysing        => { type=>'uint8', seq=>$mem_seq++ },
+      recording        => { type=>'uint8', seq=>$mem_seq++ },
+      signal           => { type=>'uint8', seq=>$mem_seq++ },
+      format           => { type=>'uint8', seq=>$mem_seq++ },
+      reserved1        => { type=>'uint8', seq=>$mem_seq++ },
+      reserved2        => { type=>'uint8', seq=>$mem_seq++ },
+      imagesize        => { type=>'uint32', seq=>$mem_seq++ },
+      last_frame_score => { type=>'uint32', seq=>$mem_seq++ },
+      audio_frequency  => { type=>'uint32', seq=>$mem_seq++ },
+      audio_channels   => { type=>'uint32', seq=>$mem_seq++ },
+      startup_time     => { type=>'time_t64', seq=>$mem_seq++ },
+      heartbeat_time   => { type=>'time_t64', seq=>$mem_seq++ },
+      last_write_time  => { type=>'time_t64', seq=>$mem_seq++ },
+      last_read_time   => { type=>'time_t64', seq=>$mem_seq++ },
+      last_viewed_time => { type=>'time_t64', seq=>$mem_seq++ },
+      control_state    => { type=>'uint8[256]', seq=>$mem_seq++ },
+      alarm_cause      => { type=>'int8[256]', seq=>$mem_seq++ },
+      video_fifo       => { type=>'int8[64]', seq=>$mem_seq++ },
+      audio_fifo       => { type=>'int8[64]', seq=>$mem_seq++ },
+      janus_pin        => { type=>'int8[64]', seq=>$mem_seq++ },
+    }
+  },
+  trigger_data => { type=>'TriggerData', seq=>$mem_seq++, 'contents'=> {
+      size             => { type=>'uint32', seq=>$mem_seq++ },
+      trigger_state    => { type=>'uint32', seq=>$mem_seq++ },
+      trigger_score    => { type=>'uint32', seq=>$mem_seq++ },
+      padding          => { type=>'uint32', seq=>$mem_seq++ },
+      trigger_cause    => { type=>'int8[32]', seq=>$mem_seq++ },
+      trigger_text     => { type=>'int8[256]', seq=>$mem_seq++ },
+      trigger_showtext => { type=>'int8[256]', seq=>$mem_seq++ },
+    }
+  },
+  end => { seq=>$mem_seq++, size=>0 }
+};
+
+our $mem_size = 0;
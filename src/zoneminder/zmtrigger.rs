@@ -0,0 +1,63 @@
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// Forces an alarm on the monitor through zmtrigger's Unix socket, which is cancelled again
+/// by zmtrigger after `duration`. Unlike the shm trigger this only needs zmtrigger to be running,
+/// but no write access to the monitor's shared memory.
+pub fn trigger_autocancel(
+    socket_path: &Path,
+    monitor_id: u32,
+    cause: &str,
+    description: &str,
+    score: u32,
+    duration: Duration,
+) -> Result<()> {
+    let message = format_message(monitor_id, cause, description, score, duration);
+    let mut socket = UnixStream::connect(socket_path)
+        .with_context(|| format!("Failed to connect to zmtrigger at {}", socket_path.display()))?;
+    socket
+        .write_all(message.as_bytes())
+        .with_context(|| format!("Failed to send trigger to {}", socket_path.display()))?;
+    Ok(())
+}
+
+/// id|action|score|cause|text|showtext, see zmtrigger.pl
+fn format_message(
+    monitor_id: u32,
+    cause: &str,
+    description: &str,
+    score: u32,
+    duration: Duration,
+) -> String {
+    // zmtrigger splits on | and reads line by line, no escaping is possible
+    let sanitize = |s: &str| s.replace(['|', '\n', '\r'], " ");
+    format!(
+        "{}|on+{}|{}|{}|{}|\n",
+        monitor_id,
+        duration.as_secs().max(1),
+        score,
+        sanitize(cause),
+        sanitize(description)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(
+            format_message(3, "aidect", "Person (87%)", 87, Duration::from_secs(5)),
+            "3|on+5|87|aidect|Person (87%)|\n"
+        );
+        assert_eq!(
+            format_message(3, "ai|dect", "a\nb", 1, Duration::from_millis(10)),
+            "3|on+1|1|ai dect|a b|\n"
+        );
+    }
+}
@@ -0,0 +1,1568 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use mysql::params;
+use mysql::prelude::Queryable;
+use opencv::core::Rect;
+
+use crate::detect;
+use crate::error::Error;
+use crate::zoneminder::db::{MonitorSettings, ZoneMinderDB};
+use crate::zoneminder::ZoneMinderConf;
+
+/// Zone polygon as (x, y) points, in image coordinates.
+pub type ZoneShape = Vec<(i32, i32)>;
+
+/// The name of aidect zones whose polygon doesn't matter, see is_full_frame_zone
+const FULL_FRAME_ZONE: &str = "aidect-full";
+
+pub trait Bounding {
+    fn bounding_box(&self) -> Rect;
+}
+
+pub trait Polygon {
+    /// Even-odd rule, points on the edge may go either way.
+    fn contains(&self, x: i32, y: i32) -> bool;
+
+    fn area(&self) -> f64;
+
+    /// Area of the part of the polygon covered by the rectangle.
+    fn intersection_area(&self, rect: &Rect) -> f64;
+}
+
+fn shoelace(points: &[(f64, f64)]) -> f64 {
+    let mut sum = 0.0;
+    for (i, &(x1, y1)) in points.iter().enumerate() {
+        let (x2, y2) = points[(i + 1) % points.len()];
+        sum += x1 * y2 - x2 * y1;
+    }
+    (sum / 2.0).abs()
+}
+
+/// Sutherland-Hodgman, the clip polygon (rect) is convex so this works for any zone shape.
+fn clip_to_rect(points: Vec<(f64, f64)>, rect: &Rect) -> Vec<(f64, f64)> {
+    let (left, top) = (rect.x as f64, rect.y as f64);
+    let (right, bottom) = (left + rect.width as f64, top + rect.height as f64);
+
+    // (is inside, intersect edge p-q with the clip line)
+    type Edge = (
+        Box<dyn Fn((f64, f64)) -> bool>,
+        Box<dyn Fn((f64, f64), (f64, f64)) -> (f64, f64)>,
+    );
+    let at_x = |x: f64| move |p: (f64, f64), q: (f64, f64)| (x, p.1 + (q.1 - p.1) * (x - p.0) / (q.0 - p.0));
+    let at_y = |y: f64| move |p: (f64, f64), q: (f64, f64)| (p.0 + (q.0 - p.0) * (y - p.1) / (q.1 - p.1), y);
+    let edges: [Edge; 4] = [
+        (Box::new(move |p| p.0 >= left), Box::new(at_x(left))),
+        (Box::new(move |p| p.0 <= right), Box::new(at_x(right))),
+        (Box::new(move |p| p.1 >= top), Box::new(at_y(top))),
+        (Box::new(move |p| p.1 <= bottom), Box::new(at_y(bottom))),
+    ];
+
+    let mut output = points;
+    for (inside, intersect) in edges.iter() {
+        let input = std::mem::take(&mut output);
+        for (i, &current) in input.iter().enumerate() {
+            let previous = input[(i + input.len() - 1) % input.len()];
+            match (inside(previous), inside(current)) {
+                (true, true) => output.push(current),
+                (true, false) => output.push(intersect(previous, current)),
+                (false, true) => {
+                    output.push(intersect(previous, current));
+                    output.push(current);
+                }
+                (false, false) => {}
+            }
+        }
+        if output.is_empty() {
+            break;
+        }
+    }
+    output
+}
+
+impl Bounding for ZoneShape {
+    fn bounding_box(&self) -> Rect {
+        let min_x = self.iter().map(|xy| xy.0).min().unwrap();
+        let min_y = self.iter().map(|xy| xy.1).min().unwrap();
+        let max_x = self.iter().map(|xy| xy.0).max().unwrap();
+        let max_y = self.iter().map(|xy| xy.1).max().unwrap();
+
+        let width = max_x - min_x;
+        let height = max_y - min_y;
+        Rect {
+            x: min_x,
+            y: min_y,
+            width,
+            height,
+        }
+    }
+}
+
+impl Polygon for ZoneShape {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        let (x, y) = (x as i64, y as i64);
+        let mut inside = false;
+        for (i, &(x1, y1)) in self.iter().enumerate() {
+            let (x2, y2) = self[(i + 1) % self.len()];
+            let (x1, y1, x2, y2) = (x1 as i64, y1 as i64, x2 as i64, y2 as i64);
+            if (y1 > y) != (y2 > y) {
+                // x coordinate of the edge at height y, compared without dividing
+                let lhs = (x - x1) * (y2 - y1);
+                let rhs = (x2 - x1) * (y - y1);
+                if (y2 > y1 && lhs < rhs) || (y2 < y1 && lhs > rhs) {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+
+    fn area(&self) -> f64 {
+        let points: Vec<(f64, f64)> = self.iter().map(|&(x, y)| (x as f64, y as f64)).collect();
+        shoelace(&points)
+    }
+
+    fn intersection_area(&self, rect: &Rect) -> f64 {
+        let points = self.iter().map(|&(x, y)| (x as f64, y as f64)).collect();
+        let clipped = clip_to_rect(points, rect);
+        if clipped.is_empty() {
+            return 0.0;
+        }
+        shoelace(&clipped)
+    }
+}
+
+/// How a detection has to relate to the zone polygon to count.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overlap {
+    /// The center of the bounding box must lie within the zone.
+    Centroid,
+    /// At least this fraction of the bounding box must overlap the zone.
+    Fraction(f32),
+}
+
+impl Overlap {
+    fn parse(value: &str) -> Option<Overlap> {
+        match value.trim() {
+            "centroid" | "Centroid" => Some(Overlap::Centroid),
+            pct => pct.parse::<f32>().ok().map(|pct| Overlap::Fraction(pct / 100.0)),
+        }
+    }
+
+    pub fn accepts(&self, shape: &ZoneShape, bounding_box: &Rect) -> bool {
+        match self {
+            Overlap::Centroid => shape.contains(
+                bounding_box.x + bounding_box.width / 2,
+                bounding_box.y + bounding_box.height / 2,
+            ),
+            Overlap::Fraction(fraction) => {
+                let area = (bounding_box.width * bounding_box.height) as f64;
+                area > 0.0 && shape.intersection_area(bounding_box) / area >= *fraction as f64
+            }
+        }
+    }
+}
+
+/// When frames are analyzed at all.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Gate {
+    /// Continuously, at the configured FPS
+    Always,
+    /// Only while ZoneMinder's own motion detection fired (prealarm, alarm or alert), making
+    /// zm-aidect a false-positive filter for Modect.
+    ZmMotion,
+}
+
+impl Gate {
+    fn parse(value: &str) -> Option<Gate> {
+        match value.trim() {
+            "always" => Some(Gate::Always),
+            "zm-motion" => Some(Gate::ZmMotion),
+            _ => None,
+        }
+    }
+}
+
+/// A step of preprocessing the image handed to the model, e.g. to bring out contrast in IR images.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preprocess {
+    /// Contrast limited adaptive histogram equalization of the lightness, with this clip limit
+    Clahe(f32),
+    /// Histogram equalization of the lightness
+    Equalize,
+    /// Gamma correction, below 1 brightens dark parts
+    Gamma(f32),
+    /// Multiplies pixel values by this
+    Contrast(f32),
+    /// Adds this to pixel values
+    Brightness(f32),
+}
+
+impl Preprocess {
+    /// Comma-separated steps, e.g. "clahe,gamma:0.8" or "contrast:1.5,brightness:-20", applied in
+    /// order. None if any of them is invalid.
+    fn parse_list(value: &str) -> Option<Vec<Preprocess>> {
+        value.trim().split(',').map(Preprocess::parse).collect()
+    }
+
+    fn parse(step: &str) -> Option<Preprocess> {
+        let (name, argument) = match step.split_once(':') {
+            Some((name, argument)) => (name, Some(argument.parse::<f32>().ok()?)),
+            None => (step, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), argument) {
+            ("clahe", clip_limit) => Some(Preprocess::Clahe(clip_limit.unwrap_or(2.0))),
+            ("equalize", None) => Some(Preprocess::Equalize),
+            ("gamma", Some(gamma)) if gamma > 0.0 => Some(Preprocess::Gamma(gamma)),
+            ("contrast", Some(alpha)) => Some(Preprocess::Contrast(alpha)),
+            ("brightness", Some(beta)) => Some(Preprocess::Brightness(beta)),
+            _ => None,
+        }
+    }
+}
+
+/// Rotate=90|180|270, clockwise.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    fn parse(value: &str) -> Option<Option<Rotation>> {
+        match value.trim() {
+            "0" => Some(None),
+            "90" => Some(Some(Rotation::Rotate90)),
+            "180" => Some(Some(Rotation::Rotate180)),
+            "270" => Some(Some(Rotation::Rotate270)),
+            _ => None,
+        }
+    }
+}
+
+/// Rotate= and Keystone=: what turns frames into the image the zones are drawn on, for cameras
+/// mounted sideways or tilted which ZoneMinder only rotates on playback (or not at all). Applied to
+/// every frame before anything else, see detect::transform_frame.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct FrameTransform {
+    pub rotation: Option<Rotation>,
+    /// Keystone=P as a fraction: how much narrower the top edge (below 0 the bottom edge) of the
+    /// scene is in the rotated image, it's stretched to the full width
+    pub keystone: Option<f32>,
+}
+
+impl FrameTransform {
+    pub fn is_identity(&self) -> bool {
+        self.rotation.is_none() && self.keystone.is_none()
+    }
+
+    /// The size frames of size have after the transform, e.g. that of the image zones are drawn on
+    pub fn size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match self.rotation {
+            Some(Rotation::Rotate90 | Rotation::Rotate270) => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    /// Keystone: the corners (top left, top right, bottom right, bottom left) of the area of an
+    /// image of size which is stretched to the whole image
+    pub fn keystone_quad(keystone: f32, (width, height): (u32, u32)) -> [(f32, f32); 4] {
+        let (width, height) = (width as f32, height as f32);
+        let inset = width * keystone.abs() / 2.0;
+        if keystone >= 0.0 {
+            [(inset, 0.0), (width - inset, 0.0), (width, height), (0.0, height)]
+        } else {
+            [(0.0, 0.0), (width, 0.0), (width - inset, height), (inset, height)]
+        }
+    }
+}
+
+/// Keystone=P, percent between -50 and 50
+fn parse_keystone(value: &str) -> Option<f32> {
+    let percent = value.trim().parse::<f32>().ok()?;
+    (-50.0..=50.0).contains(&percent).then(|| percent / 100.0)
+}
+
+/// Where OpenCV DNN runs the model.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// OpenCV's own implementation, on the CPU
+    OpenCv,
+    Cuda,
+    /// Intel's OpenVINO (Inference Engine), on Device
+    OpenVino,
+}
+
+impl Backend {
+    pub(crate) fn parse(value: &str) -> Option<Backend> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "opencv" => Some(Backend::OpenCv),
+            "cuda" => Some(Backend::Cuda),
+            "openvino" => Some(Backend::OpenVino),
+            _ => None,
+        }
+    }
+}
+
+/// OpenVINO device
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Device {
+    Cpu,
+    /// Integrated GPU, through OpenCL
+    Gpu,
+    /// Neural Compute Stick (VPU)
+    Myriad,
+}
+
+impl Device {
+    fn parse(value: &str) -> Option<Device> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "CPU" => Some(Device::Cpu),
+            "GPU" => Some(Device::Gpu),
+            "MYRIAD" => Some(Device::Myriad),
+            _ => None,
+        }
+    }
+}
+
+/// Numeric precision the model runs at.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Precision {
+    Fp32,
+    /// Half precision, with CUDA or OpenCL (OpenVINO GPU)
+    Fp16,
+    /// A quantized model, with ONNX Runtime
+    Int8,
+}
+
+impl Precision {
+    fn parse(value: &str) -> Option<Precision> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "fp32" => Some(Precision::Fp32),
+            "fp16" => Some(Precision::Fp16),
+            "int8" => Some(Precision::Int8),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<&str> for Precision {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Precision::parse(value).ok_or_else(|| anyhow!("Unknown precision {}", value))
+    }
+}
+
+/// Pixels-per-meter calibration for estimating real-world object sizes. Without a horizon the
+/// scale is assumed to be the same everywhere, otherwise it shrinks linearly towards the horizon,
+/// which is roughly true for a camera looking at flat ground.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Calibration {
+    pub pixels_per_meter: f32,
+    /// Y coordinate at which pixels_per_meter was measured
+    pub reference_y: Option<i32>,
+    pub horizon_y: Option<i32>,
+}
+
+impl Calibration {
+    /// Estimated height in meters of an object standing on the bottom edge of the bounding box.
+    pub fn height_m(&self, bounding_box: &Rect) -> f32 {
+        let scale = match (self.reference_y, self.horizon_y) {
+            (Some(reference_y), Some(horizon_y)) if reference_y != horizon_y => {
+                let foot_y = bounding_box.y + bounding_box.height;
+                (foot_y - horizon_y) as f32 / (reference_y - horizon_y) as f32
+            }
+            _ => 1.0,
+        };
+        let pixels_per_meter = self.pixels_per_meter * scale;
+        if pixels_per_meter <= 0.0 {
+            return f32::NAN;
+        }
+        bounding_box.height as f32 / pixels_per_meter
+    }
+}
+
+/// Bounds on the height / width ratio of a class's bounding boxes, e.g. people standing up are
+/// taller than wide.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AspectRatio {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl AspectRatio {
+    /// "MIN-MAX", either may be left out, e.g. "1.2-" for at least 1.2.
+    fn parse(value: &str) -> Option<AspectRatio> {
+        let (min, max) = value.trim().split_once('-')?;
+        let bound = |bound: &str| -> Option<Option<f32>> {
+            match bound.trim() {
+                "" => Some(None),
+                bound => bound.parse().ok().filter(|ratio: &f32| *ratio > 0.0).map(Some),
+            }
+        };
+        let (min, max) = (bound(min)?, bound(max)?);
+        match (min, max) {
+            (None, None) => None,
+            (Some(min), Some(max)) if min > max => None,
+            _ => Some(AspectRatio { min, max }),
+        }
+    }
+
+    pub fn accepts(&self, bounding_box: &Rect) -> bool {
+        if bounding_box.width <= 0 {
+            return false;
+        }
+        let ratio = bounding_box.height as f32 / bounding_box.width as f32;
+        self.min.map_or(true, |min| ratio >= min) && self.max.map_or(true, |max| ratio <= max)
+    }
+}
+
+/// The times of day (local time) a class counts, as minutes since midnight. Windows may span
+/// midnight, e.g. 22:00-06:00. No windows at all means never.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<(u32, u32)>,
+}
+
+impl Schedule {
+    /// "HH:MM-HH:MM" or several of them separated by commas, or "never".
+    fn parse(value: &str) -> Option<Schedule> {
+        if value.trim() == "never" {
+            return Some(Schedule { windows: vec![] });
+        }
+        let windows = value
+            .split(',')
+            .map(|window| {
+                let (start, end) = window.trim().split_once('-')?;
+                Some((parse_time(start)?, parse_time(end)?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Schedule { windows })
+    }
+
+    pub fn active(&self, minute: u32) -> bool {
+        self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                (start..end).contains(&minute)
+            } else {
+                minute >= start || minute < end
+            }
+        })
+    }
+}
+
+/// "HH:MM" as minutes since midnight, up to 24:00.
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    let minute = hours * 60 + minutes;
+    (minutes < 60 && minute <= 24 * 60).then_some(minute)
+}
+
+/// Minutes since midnight in the local time zone.
+pub fn local_minute_of_day(time: SystemTime) -> u32 {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// The least texture a class's bounding boxes must show, shadows and the noise of low-light frames
+/// taken for objects are mostly flat.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Texture {
+    /// Standard deviation of the gray levels (0-255)
+    pub contrast: f32,
+    /// Fraction of edge pixels
+    pub edges: f32,
+}
+
+impl Texture {
+    /// "CONTRAST,EDGES" with EDGES in percent, e.g. "12,2". EDGES may be left out.
+    fn parse(value: &str) -> Option<Texture> {
+        let (contrast, edges) = match value.trim().split_once(',') {
+            Some((contrast, edges)) => (contrast, Some(edges)),
+            None => (value, None),
+        };
+        let contrast: f32 = contrast.trim().parse().ok().filter(|c| (0.0..=255.0).contains(c))?;
+        let edges: f32 = match edges {
+            Some(edges) => edges.trim().parse().ok().filter(|e| (0.0..=100.0).contains(e))?,
+            None => 0.0,
+        };
+        Some(Texture {
+            contrast,
+            edges: edges / 100.0,
+        })
+    }
+
+    pub fn accepts(&self, contrast: f32, edges: f32) -> bool {
+        contrast >= self.contrast && edges >= self.edges
+    }
+}
+
+/// See ZoneConfig::analysis_rates
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AnalysisRates {
+    /// Capture rate: FPS=, else the monitor's Analysis FPS
+    pub max_fps: f32,
+    /// InferenceFPS=, if it's below max_fps
+    pub inference_fps: f32,
+    /// IdleFPS=, if it's below inference_fps
+    pub idle_fps: Option<f32>,
+}
+
+/// Configuration of a monitor's "aidect" zone, see the README for the keys.
+#[derive(Debug, Clone)]
+pub struct ZoneConfig {
+    /// Id in the Zones table, 0 if not from the database
+    pub id: u32,
+    pub size: Option<u32>,
+    /// Size=auto, the size is picked at startup by how fast the model runs
+    pub auto_size: bool,
+    pub threshold: Option<f32>,
+    pub shape: ZoneShape,
+    pub trigger: Option<u32>,
+    /// Trigger.<Class>=XX, class names (or IDs) and the monitor they trigger instead of trigger
+    pub class_triggers: Vec<(String, u32)>,
+    /// Aspect.<Class>=MIN-MAX, class names (or IDs) and the aspect ratios detections of them must have
+    pub aspect_ratios: Vec<(String, AspectRatio)>,
+    /// Margin.<Class>=XX, class names (or IDs) and by how much (0-1) the confidence of detections of
+    /// them must exceed that of the next best class
+    pub margins: Vec<(String, f32)>,
+    /// Texture.<Class>=CONTRAST,EDGES, class names (or IDs) and the texture detections of them must have
+    pub textures: Vec<(String, Texture)>,
+    /// Schedule.<Class>=HH:MM-HH:MM, class names (or IDs) and when detections of them count
+    pub schedules: Vec<(String, Schedule)>,
+    /// Classify.<Class>=NAME, class names (or IDs) and the classifier (models table entry) their crops go through
+    pub classifiers: Vec<(String, String)>,
+    /// Linked=XX,YY, monitors triggered along with the trigger monitor(s)
+    pub linked: Vec<u32>,
+    /// Capture rate
+    pub fps: Option<f32>,
+    /// InferenceFPS=N, how often the model runs at most, if less often than frames are captured
+    pub inference_fps: Option<f32>,
+    /// IdleFPS=N, the inference rate while nothing was detected recently and there's no motion
+    pub idle_fps: Option<f32>,
+    /// Frames the reported fps is averaged over
+    pub fps_window: Option<u32>,
+    pub min_area: Option<u32>,
+    /// MinAreaPct=XX and MaxAreaPct=XX, the detection's size relative to the zone polygon's area
+    pub min_area_fraction: Option<f32>,
+    pub max_area_fraction: Option<f32>,
+    /// Consecutive frames an object has to be detected in
+    pub min_hits: Option<u32>,
+    /// Stationary=MINUTES, objects staying put for this long stop triggering until they move
+    pub stationary: Option<f32>,
+    pub overlap: Option<Overlap>,
+    pub gate: Option<Gate>,
+    pub calibration: Option<Calibration>,
+    pub backend: Option<Backend>,
+    pub device: Option<Device>,
+    pub precision: Option<Precision>,
+    /// Frames analyzed in one forward pass, when analyzing recordings
+    pub batch: Option<u32>,
+    /// OpenCV threads used for inference
+    pub threads: Option<u32>,
+    /// CPUs the process is pinned to
+    pub cpus: Option<Vec<usize>>,
+    /// Monitors covering the same area, see `zm-aidect dedup`
+    pub dedup_group: Option<String>,
+    /// Model=NAME, an entry of the models table (ml::table) instead of the default model
+    pub model: Option<String>,
+    /// Applied to the image before inference
+    pub preprocess: Vec<Preprocess>,
+    /// Channels the model takes, 1 for grayscale models and 3 (RGB) otherwise
+    pub channels: Option<u32>,
+    /// Rotate= and Keystone=, zones are drawn on the transformed frames
+    pub transform: FrameTransform,
+    /// Larger RoIs are scaled down to at most this many times Size before inference
+    pub downscale: Option<f32>,
+    /// Tiles=CxR, the RoI is split into C columns and R rows of overlapping tiles
+    pub tiles: Option<(u32, u32)>,
+    pub ignore: Vec<SubZone>,
+    pub alarm: Vec<SubZone>,
+    /// aidect-plates zones, where crops of cars are exported for license plate recognition
+    pub plates: Vec<SubZone>,
+    /// From an aidect-full zone, shape is the whole frame rather than the zone's polygon
+    pub full_frame: bool,
+}
+
+impl ZoneConfig {
+    /// Errors if the monitor has no aidect zone. Problems in the names of the zones used (see
+    /// check_zone_name) are logged, or with ZoneMinderConf::with_strict_zones errors as well.
+    pub fn get_zone_config(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<ZoneConfig> {
+        let mut zone_config =
+            ZoneConfig::from_zones(monitor_id, &monitor_zones(zm_conf, monitor_id)?, zm_conf.strict_zones)?;
+        if zone_config.full_frame {
+            let size = MonitorSettings::query(zm_conf, monitor_id)?.image_dimensions();
+            zone_config.shape = frame_shape(zone_config.transform.size(size));
+        }
+        Ok(zone_config)
+    }
+
+    /// The same as get_zone_config, from the Id, Name and Coords of all zones of the monitor
+    pub fn from_zones(monitor_id: u32, zones: &[(u32, String, String)], strict: bool) -> Result<ZoneConfig> {
+        let is_aidect = |name: &str| {
+            let lowercase = name.to_lowercase();
+            (lowercase.starts_with("aidect") && !lowercase.starts_with("aidect-")) || is_full_frame_zone(name)
+        };
+        let (id, name, coords) = zones
+            .iter()
+            .find(|(_, name, _)| is_aidect(name))
+            .ok_or_else(|| anyhow!("No aidect (or aidect-full) zone found for monitor {}", monitor_id))
+            .context(Error::Config)?;
+        let is_sub_zone = |name: &str| {
+            let name = name.to_lowercase();
+            ["aidect-ignore", "aidect-alarm", "aidect-plates"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        };
+        for (zone_id, zone_name, _) in zones {
+            let problems = if zone_id == id {
+                check_zone_name(zone_name)
+            } else if is_sub_zone(zone_name) {
+                check_sub_zone_name(zone_name)
+            } else {
+                continue;
+            };
+            if problems.is_empty() {
+                continue;
+            }
+            if strict {
+                return Err(anyhow!(
+                    "Zone {} \"{}\" of monitor {}: {}",
+                    zone_id,
+                    zone_name,
+                    monitor_id,
+                    problems.join(", ")
+                )
+                .context(Error::Config));
+            }
+            for problem in problems {
+                warn!("{}: Zone {} \"{}\": {}, ignoring it", monitor_id, zone_id, zone_name, problem);
+            }
+        }
+        let subzones = |prefix: &str| {
+            zones
+                .iter()
+                .filter(|(_, name, _)| name.to_lowercase().starts_with(prefix))
+                .map(|(_, name, coords)| SubZone::parse(name, coords))
+                .collect()
+        };
+        Ok(ZoneConfig {
+            id: *id,
+            ignore: subzones("aidect-ignore"),
+            alarm: subzones("aidect-alarm"),
+            plates: subzones("aidect-plates"),
+            full_frame: is_full_frame_zone(name),
+            ..ZoneConfig::parse(name, coords)
+        })
+    }
+
+    /// Zones are drawn at the monitor's resolution (from), this maps everything given in pixels onto
+    /// images of another resolution (to), e.g. recordings or a substream.
+    pub fn scaled(&self, from: (u32, u32), to: (u32, u32)) -> ZoneConfig {
+        let (sx, sy) = (to.0 as f64 / from.0 as f64, to.1 as f64 / from.1 as f64);
+        let scale_y = |y: i32| (y as f64 * sy).round() as i32;
+        let scale_shape = |shape: &ZoneShape| -> ZoneShape {
+            shape
+                .iter()
+                .map(|&(x, y)| ((x as f64 * sx).round() as i32, scale_y(y)))
+                .collect()
+        };
+        let scale_zones = |zones: &[SubZone]| -> Vec<SubZone> {
+            zones
+                .iter()
+                .map(|zone| SubZone {
+                    shape: scale_shape(&zone.shape),
+                    ..zone.clone()
+                })
+                .collect()
+        };
+        ZoneConfig {
+            shape: scale_shape(&self.shape),
+            min_area: self.min_area.map(|area| (area as f64 * sx * sy).round() as u32),
+            calibration: self.calibration.map(|calibration| Calibration {
+                pixels_per_meter: calibration.pixels_per_meter * sy as f32,
+                reference_y: calibration.reference_y.map(scale_y),
+                horizon_y: calibration.horizon_y.map(scale_y),
+            }),
+            // a substream squeezed horizontally makes everything look taller
+            aspect_ratios: self
+                .aspect_ratios
+                .iter()
+                .map(|(class, ratio)| {
+                    let scale = |bound: f32| (bound as f64 * sy / sx) as f32;
+                    let ratio = AspectRatio {
+                        min: ratio.min.map(scale),
+                        max: ratio.max.map(scale),
+                    };
+                    (class.clone(), ratio)
+                })
+                .collect(),
+            ignore: scale_zones(&self.ignore),
+            alarm: scale_zones(&self.alarm),
+            plates: scale_zones(&self.plates),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the bounding box is within MinAreaPct and MaxAreaPct of the zone polygon.
+    pub fn accepts_area(&self, bounding_box: &Rect) -> bool {
+        if self.min_area_fraction.is_none() && self.max_area_fraction.is_none() {
+            return true;
+        }
+        let zone_area = self.shape.area();
+        if zone_area <= 0.0 {
+            return true;
+        }
+        let fraction = (bounding_box.width * bounding_box.height) as f64 / zone_area;
+        self.min_area_fraction.map_or(true, |min| fraction >= min as f64)
+            && self.max_area_fraction.map_or(true, |max| fraction <= max as f64)
+    }
+
+    /// The rates the monitor is analyzed at, with analysis_fps_limit its Analysis FPS. None
+    /// without a capture rate above 0. Also returns why InferenceFPS or IdleFPS are ignored, if
+    /// they are.
+    pub fn analysis_rates(&self, analysis_fps_limit: Option<f32>) -> Option<(AnalysisRates, Vec<String>)> {
+        let positive = |fps: &f32| *fps > 0.0;
+        let max_fps = self.fps.filter(positive).or(analysis_fps_limit.filter(positive))?;
+        let mut ignored = Vec::new();
+        let inference_fps = match self.inference_fps {
+            Some(inference_fps) if inference_fps < max_fps => inference_fps,
+            Some(inference_fps) => {
+                ignored.push(format!("InferenceFPS={} is not below the capture rate, ignoring it", inference_fps));
+                max_fps
+            }
+            None => max_fps,
+        };
+        let idle_fps = match self.idle_fps {
+            Some(idle_fps) if idle_fps < inference_fps => Some(idle_fps),
+            Some(idle_fps) => {
+                ignored.push(format!("IdleFPS={} is not below the inference rate, ignoring it", idle_fps));
+                None
+            }
+            None => None,
+        };
+        let rates = AnalysisRates {
+            max_fps,
+            inference_fps,
+            idle_fps,
+        };
+        Some((rates, ignored))
+    }
+
+    /// From the name (with the keys) and Coords of a zone, without the database.
+    pub fn parse(name: &str, coords: &str) -> ZoneConfig {
+        ZoneConfig {
+            shape: Self::parse_zone_coords(coords),
+            ..Self::parse_zone_name(name)
+        }
+    }
+
+    fn parse_zone_name(zone_name: &str) -> ZoneConfig {
+        let keys = parse_zone_keys(zone_name);
+
+        let get_int = |key| keys.get(key).and_then(|v| v.trim().parse::<u32>().ok());
+        let get_f32 = |key| keys.get(key).and_then(|v| v.trim().parse::<f32>().ok());
+        let get_i32 = |key| keys.get(key).and_then(|v| v.trim().parse::<i32>().ok());
+
+        ZoneConfig {
+            id: 0,
+            shape: Vec::new(),
+            threshold: keys
+                .get("Threshold")
+                .and_then(|v| v.trim().parse::<f32>().ok())
+                .map(|v| v / 100.0),
+            size: get_int("Size"),
+            auto_size: keys.get("Size").map_or(false, |v| v.trim().eq_ignore_ascii_case("auto")),
+            trigger: get_int("Trigger"),
+            class_triggers: parse_class_triggers(&keys),
+            aspect_ratios: parse_aspect_ratios(&keys),
+            textures: parse_textures(&keys),
+            margins: parse_margins(&keys),
+            schedules: parse_schedules(&keys),
+            classifiers: parse_classifiers(&keys),
+            linked: keys
+                .get("Linked")
+                .map(|v| v.split(',').filter_map(|id| id.trim().parse().ok()).collect())
+                .unwrap_or_default(),
+            fps: get_f32("FPS"),
+            inference_fps: get_f32("InferenceFPS").filter(|fps| *fps > 0.0),
+            idle_fps: get_f32("IdleFPS").filter(|fps| *fps > 0.0),
+            fps_window: get_int("FpsWindow"),
+            min_area: get_int("MinArea"),
+            min_area_fraction: get_f32("MinAreaPct").map(|pct| pct / 100.0),
+            max_area_fraction: get_f32("MaxAreaPct").filter(|pct| *pct > 0.0).map(|pct| pct / 100.0),
+            min_hits: get_int("MinHits"),
+            stationary: get_f32("Stationary").filter(|minutes| *minutes > 0.0),
+            overlap: keys.get("Overlap").and_then(|v| Overlap::parse(v)),
+            gate: keys.get("Gate").and_then(|v| Gate::parse(v)),
+            calibration: get_f32("PxPerM").map(|pixels_per_meter| Calibration {
+                pixels_per_meter,
+                reference_y: get_i32("RefY"),
+                horizon_y: get_i32("Horizon"),
+            }),
+            backend: keys.get("Backend").and_then(|v| Backend::parse(v)),
+            device: keys.get("Device").and_then(|v| Device::parse(v)),
+            precision: keys.get("Precision").and_then(|v| Precision::parse(v)),
+            batch: get_int("Batch"),
+            threads: get_int("Threads"),
+            cpus: keys.get("CPUs").and_then(|v| parse_cpu_list(v)),
+            dedup_group: keys.get("DedupGroup").map(|v| v.to_string()),
+            model: keys.get("Model").map(|v| v.to_string()),
+            preprocess: keys
+                .get("Preprocess")
+                .and_then(|v| Preprocess::parse_list(v))
+                .unwrap_or_default(),
+            channels: get_int("Channels").filter(|channels| matches!(channels, 1 | 3)),
+            transform: FrameTransform {
+                rotation: keys.get("Rotate").and_then(|v| Rotation::parse(v)).flatten(),
+                keystone: keys
+                    .get("Keystone")
+                    .and_then(|v| parse_keystone(v))
+                    .filter(|keystone| *keystone != 0.0),
+            },
+            downscale: get_f32("Downscale").filter(|factor| *factor >= 1.0),
+            tiles: keys.get("Tiles").and_then(|v| parse_tiles(v)),
+            ignore: Vec::new(),
+            alarm: Vec::new(),
+            plates: Vec::new(),
+            full_frame: false,
+        }
+    }
+
+    fn parse_zone_coords(coords: &str) -> ZoneShape {
+        let parse = |v: &str| v.trim().parse::<i32>().unwrap();
+        coords
+            .split_ascii_whitespace()
+            .map(|point| point.split_once(','))
+            .filter_map(|v| v)
+            .map(|(x, y)| (parse(x), parse(y)))
+            .collect()
+    }
+}
+
+/// Id, Name and Coords of all zones of the monitor, from the database or the API.
+pub fn monitor_zones(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<Vec<(u32, String, String)>> {
+    if let Some(api) = &zm_conf.api {
+        return api.zones(monitor_id);
+    }
+    let mut db = zm_conf.connect_db()?;
+    Ok(db.exec(
+        "SELECT Id, Name, Coords FROM Zones WHERE MonitorId = :id ORDER BY Id",
+        params! { "id" => monitor_id },
+    )?)
+}
+
+/// The monitor's zones other than aidect's (e.g. ZoneMinder's own "Driveway"), by name, out of
+/// monitor_zones.
+pub fn named_zones(zones: &[(u32, String, String)]) -> Vec<(String, ZoneShape)> {
+    zones
+        .iter()
+        .filter(|(_, name, _)| !name.to_lowercase().starts_with("aidect"))
+        .map(|(_, name, coords)| (name.clone(), ZoneConfig::parse_zone_coords(coords)))
+        .collect()
+}
+
+/// Whether the zone is an aidect-full zone, an aidect zone covering the whole frame whatever its
+/// polygon, for cameras where everything they see matters.
+pub fn is_full_frame_zone(zone_name: &str) -> bool {
+    zone_name
+        .split_ascii_whitespace()
+        .next()
+        .map_or(false, |name| name.eq_ignore_ascii_case(FULL_FRAME_ZONE))
+}
+
+/// The whole frame of size as a zone shape, its bounding box is the frame
+pub fn frame_shape((width, height): (u32, u32)) -> ZoneShape {
+    let (width, height) = (width as i32, height as i32);
+    vec![(0, 0), (width, 0), (width, height), (0, height)]
+}
+
+/// What parse_zone_name ignores in the name of an aidect zone: items which aren't KEY=VALUE,
+/// unknown keys, values which don't parse and unknown classes. With Model=, classes are only known
+/// once the model's names are loaded, and checked then.
+pub fn check_zone_name(zone_name: &str) -> Vec<String> {
+    let any_class = zone_name
+        .split_ascii_whitespace()
+        .any(|item| item.starts_with("Model="));
+    check_items(zone_name, |key, value| check_key(key, value, any_class))
+}
+
+/// The same for aidect-ignore, aidect-alarm and aidect-plates zones.
+pub fn check_sub_zone_name(zone_name: &str) -> Vec<String> {
+    check_items(zone_name, |key, value| match key {
+        "Classes" => Some(
+            value
+                .split(',')
+                .all(|class| matches!(class.trim().parse(), Ok(id) if detect::class_name(id) != "?")),
+        ),
+        _ => None,
+    })
+}
+
+fn check_items(zone_name: &str, check: impl Fn(&str, &str) -> Option<bool>) -> Vec<String> {
+    let mut problems = Vec::new();
+    for item in zone_name.split_ascii_whitespace().skip(1) {
+        match item.split_once('=') {
+            None => problems.push(format!("{} is not KEY=VALUE", item)),
+            Some((key, value)) => match check(key, value.trim()) {
+                Some(true) => {}
+                Some(false) => problems.push(format!("Invalid value in {}", item)),
+                None => problems.push(format!("Unknown key {}", key)),
+            },
+        }
+    }
+    problems
+}
+
+/// Whether value is valid for key, None for unknown keys. Mirrors parse_zone_name.
+fn check_key(key: &str, value: &str, any_class: bool) -> Option<bool> {
+    let uint = || value.parse::<u32>().is_ok();
+    let float = |valid: fn(f32) -> bool| value.parse::<f32>().map_or(false, valid);
+    if let Some((prefix, class)) = key.split_once('.') {
+        let valid = match prefix {
+            "Trigger" => uint(),
+            "Aspect" => AspectRatio::parse(value).is_some(),
+            "Texture" => Texture::parse(value).is_some(),
+            "Margin" => float(|v| (0.0..=100.0).contains(&v)),
+            "Schedule" => Schedule::parse(value).is_some(),
+            "Classify" => !value.is_empty(),
+            _ => return None,
+        };
+        return Some(valid && (any_class || detect::class_id(class).is_some()));
+    }
+    Some(match key {
+        "Size" => uint() || value.eq_ignore_ascii_case("auto"),
+        "Trigger" | "FpsWindow" | "MinArea" | "MinHits" | "Batch" | "Threads" => uint(),
+        "Threshold" | "FPS" | "MinAreaPct" | "PxPerM" => float(|v| v.is_finite()),
+        "InferenceFPS" | "IdleFPS" | "MaxAreaPct" | "Stationary" => float(|v| v > 0.0),
+        "Downscale" => float(|v| v >= 1.0),
+        "RefY" | "Horizon" => value.parse::<i32>().is_ok(),
+        "Linked" => value.split(',').all(|id| id.trim().parse::<u32>().is_ok()),
+        "Overlap" => Overlap::parse(value).is_some(),
+        "Gate" => Gate::parse(value).is_some(),
+        "Backend" => Backend::parse(value).is_some(),
+        "Device" => Device::parse(value).is_some(),
+        "Precision" => Precision::parse(value).is_some(),
+        "CPUs" => parse_cpu_list(value).is_some(),
+        "DedupGroup" | "Model" => !value.is_empty(),
+        "Preprocess" => Preprocess::parse_list(value).is_some(),
+        "Channels" => matches!(value.parse::<u32>(), Ok(1 | 3)),
+        "Tiles" => parse_tiles(value).is_some(),
+        "Rotate" => Rotation::parse(value).is_some(),
+        "Keystone" => parse_keystone(value).is_some(),
+        _ => return None,
+    })
+}
+
+fn parse_zone_keys(zone_name: &str) -> HashMap<&str, &str> {
+    zone_name
+        .split_ascii_whitespace()
+        .skip(1)
+        .map(|item| item.split_once('='))
+        .filter_map(|x| x)
+        .collect()
+}
+
+fn parse_class_triggers(keys: &HashMap<&str, &str>) -> Vec<(String, u32)> {
+    let mut class_triggers: Vec<(String, u32)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Trigger.")?;
+            Some((class.to_string(), value.trim().parse().ok()?))
+        })
+        .collect();
+    class_triggers.sort();
+    class_triggers
+}
+
+fn parse_aspect_ratios(keys: &HashMap<&str, &str>) -> Vec<(String, AspectRatio)> {
+    let mut aspect_ratios: Vec<(String, AspectRatio)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Aspect.")?;
+            Some((class.to_string(), AspectRatio::parse(value)?))
+        })
+        .collect();
+    aspect_ratios.sort_by(|a, b| a.0.cmp(&b.0));
+    aspect_ratios
+}
+
+fn parse_margins(keys: &HashMap<&str, &str>) -> Vec<(String, f32)> {
+    let mut margins: Vec<(String, f32)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Margin.")?;
+            let pct: f32 = value.trim().parse().ok().filter(|pct| (0.0..=100.0).contains(pct))?;
+            Some((class.to_string(), pct / 100.0))
+        })
+        .collect();
+    margins.sort_by(|a, b| a.0.cmp(&b.0));
+    margins
+}
+
+fn parse_textures(keys: &HashMap<&str, &str>) -> Vec<(String, Texture)> {
+    let mut textures: Vec<(String, Texture)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Texture.")?;
+            Some((class.to_string(), Texture::parse(value)?))
+        })
+        .collect();
+    textures.sort_by(|a, b| a.0.cmp(&b.0));
+    textures
+}
+
+fn parse_schedules(keys: &HashMap<&str, &str>) -> Vec<(String, Schedule)> {
+    let mut schedules: Vec<(String, Schedule)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Schedule.")?;
+            Some((class.to_string(), Schedule::parse(value)?))
+        })
+        .collect();
+    schedules.sort_by(|a, b| a.0.cmp(&b.0));
+    schedules
+}
+
+fn parse_classifiers(keys: &HashMap<&str, &str>) -> Vec<(String, String)> {
+    let mut classifiers: Vec<(String, String)> = keys
+        .iter()
+        .filter_map(|(key, value)| {
+            let class = key.strip_prefix("Classify.")?;
+            Some((class.to_string(), value.to_string()))
+        })
+        .filter(|(_, model)| !model.is_empty())
+        .collect();
+    classifiers.sort();
+    classifiers
+}
+
+/// Maps a rectangle from an image of one resolution onto another, like ZoneConfig::scaled.
+pub fn scale_rect(rect: Rect, from: (u32, u32), to: (u32, u32)) -> Rect {
+    let (sx, sy) = (to.0 as f64 / from.0 as f64, to.1 as f64 / from.1 as f64);
+    Rect::new(
+        (rect.x as f64 * sx).round() as i32,
+        (rect.y as f64 * sy).round() as i32,
+        (rect.width as f64 * sx).round() as i32,
+        (rect.height as f64 * sy).round() as i32,
+    )
+}
+
+/// Parses tile grids like "2x1" (columns x rows), up to 8 in either direction.
+fn parse_tiles(grid: &str) -> Option<(u32, u32)> {
+    let (columns, rows) = grid.trim().split_once('x')?;
+    let (columns, rows) = (columns.parse().ok()?, rows.parse().ok()?);
+    let valid = |count: u32| (1..=8).contains(&count);
+    (valid(columns) && valid(rows)).then_some((columns, rows))
+}
+
+/// Parses CPU lists like "2,3" or "4-7" (as in taskset -c and /sys/devices/system/cpu).
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for item in list.trim().split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(item.trim().parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Additional zones named "aidect-<purpose>" select parts of the aidect zone, e.g. "aidect-ignore"
+/// drops detections centered within it. Classes=1,3 restricts a zone to the given classes,
+/// otherwise it applies to all classes.
+#[derive(Debug, Clone)]
+pub struct SubZone {
+    pub shape: ZoneShape,
+    pub classes: Option<Vec<i32>>,
+}
+
+impl SubZone {
+    fn parse(name: &str, coords: &str) -> SubZone {
+        let keys = parse_zone_keys(name);
+        SubZone {
+            shape: ZoneConfig::parse_zone_coords(coords),
+            classes: keys.get("Classes").map(|v| {
+                v.split(',')
+                    .filter_map(|class| class.trim().parse::<i32>().ok())
+                    .collect()
+            }),
+        }
+    }
+
+    pub fn matches(&self, class_id: i32, bounding_box: &Rect) -> bool {
+        let applies = match &self.classes {
+            Some(classes) => classes.contains(&class_id),
+            None => true,
+        };
+        applies
+            && self.shape.contains(
+                bounding_box.x + bounding_box.width / 2,
+                bounding_box.y + bounding_box.height / 2,
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_zone_name_basic() {
+        let zone_name = "aidect";
+        let parsed = ZoneConfig::parse_zone_name(zone_name);
+        assert_eq!(parsed.shape.len(), 0);
+        assert_eq!(parsed.threshold, None);
+        assert_eq!(parsed.size, None);
+    }
+
+    #[test]
+    fn test_parse_zone_name() {
+        let zone_name = "aidect Size=128 Threshold=50";
+        let parsed = ZoneConfig::parse_zone_name(zone_name);
+        assert_eq!(parsed.shape.len(), 0);
+        assert_eq!(parsed.threshold, Some(0.5));
+        assert_eq!(parsed.size, Some(128));
+        assert!(!parsed.auto_size);
+
+        let parsed = ZoneConfig::parse_zone_name("aidect Size=auto");
+        assert_eq!((parsed.size, parsed.auto_size), (None, true));
+        assert_eq!(check_key("Size", "auto", false), Some(true));
+        assert_eq!(check_key("Size", "big", false), Some(false));
+    }
+
+    #[test]
+    fn test_parse_class_triggers() {
+        let parsed = ZoneConfig::parse_zone_name("aidect Trigger=4 Trigger.Human=5 Trigger.Car=6 Trigger.Dog=x");
+        assert_eq!(parsed.trigger, Some(4));
+        assert_eq!(
+            parsed.class_triggers,
+            vec![("Car".to_string(), 6), ("Human".to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn test_aspect_ratio() {
+        let parsed = ZoneConfig::parse_zone_name("aidect Aspect.Human=1.2-5 Aspect.Car=-1 Aspect.Dog=2-1 Aspect.Bird=-");
+        let human = AspectRatio {
+            min: Some(1.2),
+            max: Some(5.0),
+        };
+        let car = AspectRatio {
+            min: None,
+            max: Some(1.0),
+        };
+        assert_eq!(
+            parsed.aspect_ratios,
+            vec![("Car".to_string(), car), ("Human".to_string(), human)]
+        );
+        assert!(human.accepts(&Rect::new(0, 0, 40, 100)));
+        // a person's shadow lying on the ground
+        assert!(!human.accepts(&Rect::new(0, 0, 100, 40)));
+        assert!(car.accepts(&Rect::new(0, 0, 100, 40)));
+        assert!(!car.accepts(&Rect::new(0, 0, 0, 40)));
+
+        // a 16:9 monitor analyzed through a 4:3 substream
+        let scaled = parsed.scaled((1600, 900), (800, 600));
+        let scaled_min = scaled.aspect_ratios[1].1.min.unwrap();
+        assert!((scaled_min - 1.6).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_margins() {
+        let parsed = ZoneConfig::parse_zone_name("aidect Margin.Car=25 Margin.Human=5 Margin.Dog=150 Margin.Cat=x");
+        assert_eq!(
+            parsed.margins,
+            vec![("Car".to_string(), 0.25), ("Human".to_string(), 0.05)]
+        );
+        assert_eq!(check_key("Margin.Car", "25", false), Some(true));
+        assert_eq!(check_key("Margin.Car", "150", false), Some(false));
+    }
+
+    #[test]
+    fn test_texture() {
+        let parsed =
+            ZoneConfig::parse_zone_name("aidect Texture.Human=12,2 Texture.Car=8 Texture.Dog=5,200 Texture.Cat=x");
+        let car = Texture {
+            contrast: 8.0,
+            edges: 0.0,
+        };
+        let human = Texture {
+            contrast: 12.0,
+            edges: 0.02,
+        };
+        assert_eq!(
+            parsed.textures,
+            vec![("Car".to_string(), car), ("Human".to_string(), human)]
+        );
+        assert!(human.accepts(30.0, 0.05));
+        // a flat, dark blob in the noise of a night frame
+        assert!(!human.accepts(4.0, 0.05));
+        assert!(!human.accepts(30.0, 0.01));
+        assert_eq!(check_key("Texture.Human", "12,2", false), Some(true));
+        assert_eq!(check_key("Texture.Human", "-1", false), Some(false));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let parsed = ZoneConfig::parse_zone_name("aidect Schedule.Human=22:00-06:00 Schedule.Cat=never Schedule.Car=7:00");
+        assert_eq!(parsed.schedules.len(), 2);
+        let (class, night) = &parsed.schedules[1];
+        assert_eq!(class, "Human");
+        assert!(night.active(23 * 60));
+        assert!(night.active(5 * 60 + 59));
+        assert!(!night.active(6 * 60));
+        assert!(!night.active(12 * 60));
+        assert!(!parsed.schedules[0].1.active(12 * 60));
+
+        let working_hours = Schedule::parse("08:00-12:00, 13:00-17:30").unwrap();
+        assert!(working_hours.active(8 * 60));
+        assert!(!working_hours.active(12 * 60 + 30));
+        assert!(working_hours.active(17 * 60 + 29));
+        assert_eq!(Schedule::parse("08:00-25:00"), None);
+        assert_eq!(Schedule::parse("08:60-09:00"), None);
+    }
+
+    #[test]
+    fn test_parse_linked() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").linked, Vec::<u32>::new());
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Linked=3,4").linked, vec![3, 4]);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Linked=3,x").linked, vec![3]);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let parsed = ZoneConfig::parse_zone_name("aidect PxPerM=100");
+        let calibration = parsed.calibration.unwrap();
+        assert_eq!(calibration.height_m(&Rect::new(0, 300, 50, 170)), 1.7);
+
+        let parsed = ZoneConfig::parse_zone_name("aidect PxPerM=100 RefY=500 Horizon=100");
+        let calibration = parsed.calibration.unwrap();
+        // standing on the reference line
+        assert_eq!(calibration.height_m(&Rect::new(0, 330, 50, 170)), 1.7);
+        // standing halfway to the horizon, where things are half as large
+        assert_eq!(calibration.height_m(&Rect::new(0, 215, 50, 85)), 1.7);
+    }
+
+    #[test]
+    fn test_parse_gate() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").gate, None);
+        assert_eq!(
+            ZoneConfig::parse_zone_name("aidect Gate=zm-motion").gate,
+            Some(Gate::ZmMotion)
+        );
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Gate=always").gate, Some(Gate::Always));
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Gate=foo").gate, None);
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        let parsed = ZoneConfig::parse_zone_name("aidect Backend=openvino Device=MYRIAD");
+        assert_eq!(parsed.backend, Some(Backend::OpenVino));
+        assert_eq!(parsed.device, Some(Device::Myriad));
+        let parsed = ZoneConfig::parse_zone_name("aidect Backend=CUDA Device=gpu");
+        assert_eq!(parsed.backend, Some(Backend::Cuda));
+        assert_eq!(parsed.device, Some(Device::Gpu));
+        let parsed = ZoneConfig::parse_zone_name("aidect Precision=FP16");
+        assert_eq!(parsed.precision, Some(Precision::Fp16));
+        let parsed = ZoneConfig::parse_zone_name("aidect Backend=tpu Device=npu");
+        assert_eq!(parsed.backend, None);
+        assert_eq!(parsed.device, None);
+    }
+
+    #[test]
+    fn test_parse_preprocess() {
+        assert!(ZoneConfig::parse_zone_name("aidect").preprocess.is_empty());
+        assert_eq!(
+            ZoneConfig::parse_zone_name("aidect Preprocess=CLAHE,gamma:0.8").preprocess,
+            vec![Preprocess::Clahe(2.0), Preprocess::Gamma(0.8)]
+        );
+        assert_eq!(
+            ZoneConfig::parse_zone_name("aidect Preprocess=clahe:3,equalize,contrast:1.5,brightness:-20").preprocess,
+            vec![
+                Preprocess::Clahe(3.0),
+                Preprocess::Equalize,
+                Preprocess::Contrast(1.5),
+                Preprocess::Brightness(-20.0)
+            ]
+        );
+        // all or nothing
+        assert!(ZoneConfig::parse_zone_name("aidect Preprocess=clahe,gamma").preprocess.is_empty());
+        assert!(ZoneConfig::parse_zone_name("aidect Preprocess=sharpen").preprocess.is_empty());
+    }
+
+    #[test]
+    fn test_parse_channels() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").channels, None);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Channels=1").channels, Some(1));
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Channels=2").channels, None);
+    }
+
+    #[test]
+    fn test_parse_downscale() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").downscale, None);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Downscale=2").downscale, Some(2.0));
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Downscale=0.5").downscale, None);
+    }
+
+    #[test]
+    fn test_parse_inference_fps() {
+        let config = ZoneConfig::parse_zone_name("aidect FPS=10 InferenceFPS=2");
+        assert_eq!((config.fps, config.inference_fps), (Some(10.0), Some(2.0)));
+        assert_eq!(ZoneConfig::parse_zone_name("aidect InferenceFPS=0").inference_fps, None);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect IdleFPS=0.5").idle_fps, Some(0.5));
+    }
+
+    #[test]
+    fn test_analysis_rates() {
+        let rates = |name: &str, limit| ZoneConfig::parse_zone_name(name).analysis_rates(limit);
+        let (config_rates, ignored) = rates("aidect InferenceFPS=2 IdleFPS=0.5", Some(5.0)).unwrap();
+        assert_eq!(
+            config_rates,
+            AnalysisRates {
+                max_fps: 5.0,
+                inference_fps: 2.0,
+                idle_fps: Some(0.5)
+            }
+        );
+        assert!(ignored.is_empty());
+        // FPS= wins over the monitor's
+        assert_eq!(rates("aidect FPS=10", Some(5.0)).unwrap().0.max_fps, 10.0);
+        let (config_rates, ignored) = rates("aidect InferenceFPS=8 IdleFPS=6", Some(5.0)).unwrap();
+        assert_eq!((config_rates.inference_fps, config_rates.idle_fps), (5.0, None));
+        assert_eq!(ignored.len(), 2);
+        assert_eq!(rates("aidect", None), None);
+        assert_eq!(rates("aidect FPS=0", Some(0.0)), None);
+        assert_eq!(rates("aidect FPS=0", Some(3.0)).unwrap().0.max_fps, 3.0);
+    }
+
+    #[test]
+    fn test_parse_stationary() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").stationary, None);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Stationary=15").stationary, Some(15.0));
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Stationary=0").stationary, None);
+    }
+
+    #[test]
+    fn test_frame_transform() {
+        let transform = ZoneConfig::parse_zone_name("aidect Rotate=90 Keystone=20").transform;
+        assert_eq!(transform.rotation, Some(Rotation::Rotate90));
+        assert_eq!(transform.keystone, Some(0.2));
+        assert_eq!(transform.size((1920, 1080)), (1080, 1920));
+        assert!(ZoneConfig::parse_zone_name("aidect Rotate=0 Keystone=0").transform.is_identity());
+        assert!(ZoneConfig::parse_zone_name("aidect Rotate=45 Keystone=60").transform.is_identity());
+        assert_eq!(FrameTransform::default().size((1920, 1080)), (1920, 1080));
+
+        assert_eq!(
+            FrameTransform::keystone_quad(0.2, (100, 50)),
+            [(10.0, 0.0), (90.0, 0.0), (100.0, 50.0), (0.0, 50.0)]
+        );
+        assert_eq!(
+            FrameTransform::keystone_quad(-0.2, (100, 50)),
+            [(0.0, 0.0), (100.0, 0.0), (90.0, 50.0), (10.0, 50.0)]
+        );
+    }
+
+    #[test]
+    fn test_parse_tiles() {
+        assert_eq!(ZoneConfig::parse_zone_name("aidect").tiles, None);
+        assert_eq!(ZoneConfig::parse_zone_name("aidect Tiles=2x1").tiles, Some((2, 1)));
+        assert_eq!(parse_tiles("3x2"), Some((3, 2)));
+        assert_eq!(parse_tiles("0x1"), None);
+        assert_eq!(parse_tiles("2"), None);
+        assert_eq!(parse_tiles("2x1x1"), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3"), Some(vec![3]));
+        assert_eq!(parse_cpu_list("0,2"), Some(vec![0, 2]));
+        assert_eq!(parse_cpu_list("4-7,1"), Some(vec![4, 5, 6, 7, 1]));
+        assert_eq!(parse_cpu_list("7-4"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+        assert_eq!(parse_cpu_list(""), None);
+
+        let parsed = ZoneConfig::parse_zone_name("aidect MinHits=3 Threads=2 CPUs=2-3 Batch=4");
+        assert_eq!(parsed.min_hits, Some(3));
+        assert_eq!(parsed.batch, Some(4));
+        assert_eq!(parsed.threads, Some(2));
+        assert_eq!(parsed.cpus, Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn test_scaled() {
+        let mut config = ZoneConfig::parse(
+            "aidect MinArea=10000 PxPerM=100 RefY=500 Horizon=100",
+            "0,0 1920,0 1920,1080 0,1080",
+        );
+        config.ignore.push(SubZone::parse("aidect-ignore Classes=3", "960,540 1920,540 1920,1080"));
+
+        let scaled = config.scaled((1920, 1080), (640, 360));
+        assert_eq!(scaled.shape, vec![(0, 0), (640, 0), (640, 360), (0, 360)]);
+        assert_eq!(scaled.ignore[0].shape, vec![(320, 180), (640, 180), (640, 360)]);
+        assert_eq!(scaled.ignore[0].classes, Some(vec![3]));
+        assert_eq!(scaled.min_area, Some(1111));
+        let calibration = scaled.calibration.unwrap();
+        assert_eq!(calibration.reference_y, Some(167));
+        assert_eq!(calibration.horizon_y, Some(33));
+        // a 1.7m person on the reference line is 170px tall at full resolution
+        let height = calibration.height_m(&Rect::new(0, 110, 20, 57));
+        assert!((height - 1.7).abs() < 0.02);
+
+        assert_eq!(
+            scale_rect(Rect::new(320, 180, 64, 36), (640, 360), (1920, 1080)),
+            Rect::new(960, 540, 192, 108)
+        );
+
+        let same = config.scaled((1920, 1080), (1920, 1080));
+        assert_eq!(same.shape, config.shape);
+        assert_eq!(same.min_area, config.min_area);
+    }
+
+    #[test]
+    fn test_parse_zone_coords() {
+        let coords = "123,56 899,41 687,425";
+        let parsed = ZoneConfig::parse_zone_coords(coords);
+        assert_eq!(parsed, vec![(123, 56), (899, 41), (687, 425)]);
+    }
+
+    #[test]
+    fn test_polygon_contains() {
+        let shape: ZoneShape = vec![(0, 0), (100, 0), (100, 100), (50, 50), (0, 100)];
+        assert!(shape.contains(10, 10));
+        assert!(shape.contains(90, 80));
+        assert!(!shape.contains(50, 80));
+        assert!(!shape.contains(150, 10));
+        assert!(!shape.contains(-1, 50));
+    }
+
+    #[test]
+    fn test_polygon_area() {
+        let shape: ZoneShape = vec![(0, 0), (100, 0), (100, 100), (50, 50), (0, 100)];
+        assert_eq!(shape.area(), 7500.0);
+        assert_eq!(shape.intersection_area(&Rect::new(0, 0, 100, 100)), 7500.0);
+        assert_eq!(shape.intersection_area(&Rect::new(0, 0, 50, 50)), 2500.0);
+        assert_eq!(shape.intersection_area(&Rect::new(0, 50, 100, 50)), 2500.0);
+        assert_eq!(shape.intersection_area(&Rect::new(200, 0, 50, 50)), 0.0);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let shape: ZoneShape = vec![(0, 0), (100, 0), (100, 100), (0, 100)];
+        assert_eq!(Overlap::parse("centroid"), Some(Overlap::Centroid));
+        assert_eq!(Overlap::parse("50"), Some(Overlap::Fraction(0.5)));
+        assert_eq!(Overlap::parse("foo"), None);
+
+        let half_in = Rect::new(50, 0, 100, 20);
+        assert!(Overlap::Fraction(0.5).accepts(&shape, &half_in));
+        assert!(!Overlap::Fraction(0.6).accepts(&shape, &half_in));
+        assert!(!Overlap::Centroid.accepts(&shape, &Rect::new(70, 0, 100, 20)));
+        assert!(Overlap::Centroid.accepts(&shape, &Rect::new(40, 0, 100, 20)));
+    }
+
+    #[test]
+    fn test_accepts_area() {
+        let coords = "0,0 200,0 200,100 0,100";
+        let config = ZoneConfig::parse("aidect MinAreaPct=1 MaxAreaPct=50", coords);
+        assert_eq!(config.min_area_fraction, Some(0.01));
+        assert_eq!(config.max_area_fraction, Some(0.5));
+        assert!(!config.accepts_area(&Rect::new(0, 0, 10, 10)));
+        assert!(config.accepts_area(&Rect::new(0, 0, 20, 10)));
+        assert!(config.accepts_area(&Rect::new(0, 0, 100, 100)));
+        assert!(!config.accepts_area(&Rect::new(0, 0, 200, 60)));
+        // relative to the polygon, so the same at any resolution
+        let scaled = config.scaled((200, 100), (400, 200));
+        assert!(scaled.accepts_area(&Rect::new(0, 0, 40, 20)));
+        assert!(!scaled.accepts_area(&Rect::new(0, 0, 20, 20)));
+        assert!(ZoneConfig::parse("aidect", coords).accepts_area(&Rect::new(0, 0, 1, 1)));
+    }
+
+    #[test]
+    fn test_parse_sub_zone() {
+        let zone = SubZone::parse("aidect-ignore Classes=3,8", "0,0 100,0 100,100 0,100");
+        assert_eq!(zone.classes, Some(vec![3, 8]));
+        assert!(zone.matches(3, &Rect::new(10, 10, 20, 20)));
+        assert!(!zone.matches(1, &Rect::new(10, 10, 20, 20)));
+        assert!(!zone.matches(3, &Rect::new(90, 90, 40, 40)));
+
+        let zone = SubZone::parse("aidect-ignore", "0,0 100,0 100,100 0,100");
+        assert_eq!(zone.classes, None);
+        assert!(zone.matches(1, &Rect::new(10, 10, 20, 20)));
+    }
+
+    #[test]
+    fn test_full_frame() {
+        let zone = |id: u32, name: &str| (id, name.to_string(), "10,10 100,10 100,100".to_string());
+        let zones = [zone(1, "aidect-ignore Classes=3"), zone(2, "aidect-full Threshold=40")];
+        let config = ZoneConfig::from_zones(1, &zones, true).unwrap();
+        assert_eq!((config.id, config.threshold, config.full_frame), (2, Some(0.4), true));
+        assert_eq!(config.ignore.len(), 1);
+        assert!(is_full_frame_zone("AIDECT-FULL"));
+        assert!(!is_full_frame_zone("aidect-fullframe"));
+        assert!(ZoneConfig::from_zones(1, &zones[..1], false).is_err());
+        assert_eq!(frame_shape((1920, 1080)).bounding_box(), Rect::new(0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn test_from_zones() {
+        let zone = |id: u32, name: &str| (id, name.to_string(), "0,0 100,0 100,100 0,100".to_string());
+        let zones = [
+            zone(1, "All"),
+            zone(2, "aidect-ignore Classes=3"),
+            zone(3, "aidect Size=416"),
+            zone(4, "aidect-alarm"),
+        ];
+        let config = ZoneConfig::from_zones(1, &zones, true).unwrap();
+        assert_eq!(config.id, 3);
+        assert_eq!(config.size, Some(416));
+        assert_eq!((config.ignore.len(), config.alarm.len()), (1, 1));
+        assert!(ZoneConfig::from_zones(1, &zones[..2], false).is_err());
+
+        let typo = [zone(1, "aidect Size=416 Threshold=5O"), zone(2, "aidect-ignore Classes=Car")];
+        let config = ZoneConfig::from_zones(1, &typo, false).unwrap();
+        assert_eq!((config.size, config.threshold), (Some(416), None));
+        let error = ZoneConfig::from_zones(1, &typo, true).unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Zone 1 \"aidect Size=416 Threshold=5O\" of monitor 1: Invalid value in Threshold=5O"
+        );
+        assert_eq!(Error::kind(&error), Some(Error::Config));
+    }
+
+    #[test]
+    fn test_check_zone_name() {
+        let name = "aidect Size=416 Threshold=50 Trigger.Car=5 Aspect.Human=1.2-4 Gate=zm-motion Preprocess=clahe";
+        assert!(check_zone_name(name).is_empty(), "{:?}", check_zone_name(name));
+        assert_eq!(
+            check_zone_name("aidect Threshold=5O Treshold=50 MinHits Trigger.Unicorn=5 Channels=2"),
+            vec![
+                "Invalid value in Threshold=5O",
+                "Unknown key Treshold",
+                "MinHits is not KEY=VALUE",
+                "Invalid value in Trigger.Unicorn=5",
+                "Invalid value in Channels=2",
+            ]
+        );
+        assert!(check_zone_name("aidect Model=plates Trigger.Plate=5").is_empty());
+        assert!(check_zone_name("aidect Classify.Car=vehicles").is_empty());
+        assert_eq!(
+            ZoneConfig::parse_zone_name("aidect Classify.Car=vehicles Classify.Human=").classifiers,
+            vec![("Car".to_string(), "vehicles".to_string())]
+        );
+        assert!(check_sub_zone_name("aidect-ignore Classes=1,3").is_empty());
+        assert_eq!(check_sub_zone_name("aidect-ignore Classes=Car"), vec!["Invalid value in Classes=Car"]);
+    }
+}
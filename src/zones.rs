@@ -0,0 +1,119 @@
+//! `zm-aidect zone`: shows what zm-aidect makes of a monitor's zones, and puts together aidect
+//! zone names, since parsing them silently ignores what it doesn't understand.
+
+use anyhow::{anyhow, Result};
+
+use zoneminder_aidect::zoneminder::zone::{self, ZoneConfig};
+use zoneminder_aidect::zoneminder::ZoneMinderConf;
+
+/// Zones.Name is a VARCHAR(64), longer names are cut off
+const MAX_NAME_LENGTH: usize = 64;
+
+enum Kind {
+    Aidect,
+    Sub,
+    Other,
+}
+
+fn kind(name: &str) -> Kind {
+    if zone::is_full_frame_zone(name) {
+        return Kind::Aidect;
+    }
+    let name = name.to_lowercase();
+    if name.starts_with("aidect-") {
+        Kind::Sub
+    } else if name.starts_with("aidect") {
+        Kind::Aidect
+    } else {
+        Kind::Other
+    }
+}
+
+/// Whether name is that of an aidect zone, rather than a sub zone or one of ZoneMinder's
+pub fn is_aidect(name: &str) -> bool {
+    matches!(kind(name), Kind::Aidect)
+}
+
+/// Problems with a zone's name, for the zones zm-aidect reads
+pub fn problems(name: &str) -> Vec<String> {
+    let mut problems = match kind(name) {
+        Kind::Aidect => zone::check_zone_name(name),
+        Kind::Sub => zone::check_sub_zone_name(name),
+        Kind::Other => return Vec::new(),
+    };
+    if name.len() > MAX_NAME_LENGTH {
+        problems.push(format!(
+            "{} characters, ZoneMinder keeps at most {}",
+            name.len(),
+            MAX_NAME_LENGTH
+        ));
+    }
+    problems
+}
+
+/// Lists the monitor's zones with the problems in the names of aidect zones. Errors if there are
+/// any, so that scripts can check zones.
+pub fn list(zm_conf: &ZoneMinderConf, monitor_id: u32) -> Result<()> {
+    let zones = zone::monitor_zones(zm_conf, monitor_id)?;
+    let mut found = 0;
+    for (id, name, coords) in &zones {
+        println!("{:>5}  {}", id, name);
+        for problem in problems(name) {
+            println!("         {}", problem);
+            found += 1;
+        }
+        if matches!(kind(name), Kind::Aidect) {
+            let config = ZoneConfig::parse(name, coords);
+            println!(
+                "         Size {}, Threshold {}%, {} points",
+                config.size.map_or("default".to_string(), |size| size.to_string()),
+                config.threshold.map_or("default".to_string(), |t| format!("{:.0}", t * 100.0)),
+                config.shape.len()
+            );
+        }
+    }
+    let aidect: Vec<u32> = zones
+        .iter()
+        .filter(|(_, name, _)| matches!(kind(name), Kind::Aidect))
+        .map(|(id, _, _)| *id)
+        .collect();
+    match aidect.len() {
+        0 => return Err(anyhow!("Monitor {} has no aidect zone", monitor_id)),
+        1 => {}
+        _ => println!("Only the first aidect zone ({}) is used", aidect[0]),
+    }
+    if found > 0 {
+        return Err(anyhow!("Found {} problems in the zones of monitor {}", found, monitor_id));
+    }
+    Ok(())
+}
+
+/// Prints an aidect zone name with the given KEY=VALUE items, checking them first.
+pub fn name(items: &[String]) -> Result<()> {
+    let name = std::iter::once("aidect")
+        .chain(items.iter().map(|item| item.as_str()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let problems = problems(&name);
+    if !problems.is_empty() {
+        return Err(anyhow!("{}", problems.join(", ")));
+    }
+    println!("{}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        assert!(problems("All").is_empty());
+        assert!(problems("aidect Size=416").is_empty());
+        assert_eq!(problems("aidect-alarm Classes=Car"), vec!["Invalid value in Classes=Car"]);
+        // keys of the aidect zone, not of sub zones
+        assert_eq!(problems("aidect-full Classes=3"), vec!["Unknown key Classes"]);
+        let long = format!("aidect {}", "Size=416 ".repeat(8));
+        assert_eq!(problems(&long), vec!["79 characters, ZoneMinder keeps at most 64"]);
+    }
+}